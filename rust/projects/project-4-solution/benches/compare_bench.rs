@@ -0,0 +1,78 @@
+#[macro_use]
+extern crate criterion;
+
+use std::sync::Arc;
+
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use tempfile::TempDir;
+
+use kvs::clock::SystemClock;
+use kvs::thread_pool::*;
+use kvs::vfs::MemFs;
+use kvs::workload::Workload;
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
+
+const THREADS: u32 = 4;
+
+/// Runs the same YCSB workload B against every engine with every thread pool.
+pub fn compare_bench(c: &mut Criterion) {
+    let workload = Workload::ycsb_b(1 << 10).operations(1 << 12);
+    let mut group = c.benchmark_group("compare_bench");
+    group.sample_size(10);
+
+    bench_engine(&mut group, &workload, "kvs", |dir| {
+        KvStore::open(dir.path()).unwrap()
+    });
+    bench_engine(&mut group, &workload, "sled", |dir| {
+        SledKvsEngine::new(sled::Db::open(dir.path()).unwrap())
+    });
+    bench_engine(&mut group, &workload, "memory", |_| {
+        KvStore::open_with_vfs("/kvs", Arc::new(MemFs::new()), Arc::new(SystemClock)).unwrap()
+    });
+    group.finish();
+}
+
+fn bench_engine<E, F>(
+    group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>,
+    workload: &Workload,
+    engine_name: &str,
+    open: F,
+) where
+    E: KvsEngine,
+    F: Fn(&TempDir) -> E + Copy,
+{
+    bench_pool::<E, F, NaiveThreadPool>(group, workload, engine_name, "naive", open);
+    bench_pool::<E, F, SharedQueueThreadPool>(group, workload, engine_name, "shared-queue", open);
+    bench_pool::<E, F, RayonThreadPool>(group, workload, engine_name, "rayon", open);
+}
+
+fn bench_pool<E, F, P>(
+    group: &mut criterion::BenchmarkGroup<criterion::measurement::WallTime>,
+    workload: &Workload,
+    engine_name: &str,
+    pool_name: &str,
+    open: F,
+) where
+    E: KvsEngine,
+    F: Fn(&TempDir) -> E,
+    P: ThreadPool,
+{
+    let pool = P::new(THREADS).unwrap();
+    group.bench_function(BenchmarkId::new(engine_name, pool_name), |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                let engine = open(&temp_dir);
+                workload.load(&engine).unwrap();
+                (temp_dir, engine)
+            },
+            |(_temp_dir, engine)| {
+                workload.run_on(&engine, &pool, THREADS as usize).unwrap();
+            },
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group!(benches, compare_bench);
+criterion_main!(benches);
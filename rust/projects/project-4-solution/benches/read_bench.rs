@@ -0,0 +1,53 @@
+#[macro_use]
+extern crate criterion;
+
+use criterion::{Bencher, BenchmarkId, Criterion};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+use kvs::{KvStore, KvsEngine};
+
+/// Compares gets through `BufReaderWithPos` file handles with gets through
+/// memory maps, from a compacted log file and from the active one.
+pub fn get_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_bench");
+    for &compacted in &[false, true] {
+        group.bench_with_input(
+            BenchmarkId::new("buffered", compacted),
+            &compacted,
+            |b, &compacted| get_random(b, false, compacted),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("mmap", compacted),
+            &compacted,
+            |b, &compacted| get_random(b, true, compacted),
+        );
+    }
+    group.finish();
+}
+
+fn get_random(b: &mut Bencher, mmap: bool, compacted: bool) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = KvStore::builder()
+        .mmap_reads(mmap)
+        .compaction_threshold(u64::MAX)
+        .open(temp_dir.path())
+        .unwrap();
+    for key_i in 1..(1 << 12) {
+        engine
+            .set(format!("key{}", key_i), "value".repeat(20))
+            .unwrap();
+    }
+    if compacted {
+        engine.compact().unwrap();
+    }
+    let mut rng = SmallRng::from_seed([0; 16]);
+    b.iter(|| {
+        engine
+            .get(format!("key{}", rng.gen_range(1, 1 << 12)))
+            .unwrap();
+    });
+}
+
+criterion_group!(benches, get_bench);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+#![no_main]
+use kvs::testing::{apply_ops, Op};
+use kvs::SledKvsEngine;
+use libfuzzer_sys::fuzz_target;
+use tempfile::TempDir;
+
+fuzz_target!(|ops: Vec<Op>| {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::new(sled::Db::open(temp_dir.path()).unwrap());
+    apply_ops(&engine, &ops).unwrap();
+});
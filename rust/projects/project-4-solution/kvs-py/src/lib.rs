@@ -0,0 +1,125 @@
+//! Python bindings of `kvs::KvStore`, built with maturin:
+//!
+//! ```python
+//! import kvs_py
+//!
+//! store = kvs_py.open("data")
+//! store["key"] = "value"
+//! assert store.get("key") == "value"
+//! assert store.scan("a", "z") == [("key", "value")]
+//! ```
+//!
+//! Disk operations release the GIL, so other Python threads keep running
+//! while the store reads or writes its log.
+
+use std::path::PathBuf;
+
+use kvs::{KvsEngine, KvsError};
+use pyo3::exceptions::{PyIOError, PyKeyError, PyValueError};
+use pyo3::prelude::*;
+
+/// A durable string-to-string map backed by a log-structured store.
+#[pyclass]
+struct KvStore {
+    inner: kvs::KvStore,
+}
+
+#[pymethods]
+impl KvStore {
+    #[new]
+    fn new(py: Python<'_>, path: PathBuf) -> PyResult<Self> {
+        let inner = py
+            .allow_threads(|| kvs::KvStore::open(path))
+            .map_err(to_py_err)?;
+        Ok(KvStore { inner })
+    }
+
+    /// Returns the value of `key`, or `None` if it does not exist.
+    fn get(&self, py: Python<'_>, key: String) -> PyResult<Option<String>> {
+        py.allow_threads(|| self.inner.get(key)).map_err(to_py_err)
+    }
+
+    /// Sets the value of `key`.
+    fn set(&self, py: Python<'_>, key: String, value: String) -> PyResult<()> {
+        py.allow_threads(|| self.inner.set(key, value))
+            .map_err(to_py_err)
+    }
+
+    /// Removes `key`. Raises `KeyError` if it does not exist.
+    fn remove(&self, py: Python<'_>, key: String) -> PyResult<()> {
+        py.allow_threads(|| self.inner.remove(key.clone()))
+            .map_err(|e| match e {
+                KvsError::KeyNotFound => PyKeyError::new_err(key),
+                e => to_py_err(e),
+            })
+    }
+
+    /// Makes every write so far durable on disk.
+    fn flush(&self, py: Python<'_>) -> PyResult<()> {
+        py.allow_threads(|| self.inner.flush()).map_err(to_py_err)
+    }
+
+    /// Returns the `(key, value)` pairs with `start <= key < end`, in key
+    /// order. Both bounds are optional.
+    #[pyo3(signature = (start=None, end=None))]
+    fn scan(
+        &self,
+        py: Python<'_>,
+        start: Option<String>,
+        end: Option<String>,
+    ) -> PyResult<Vec<(String, String)>> {
+        py.allow_threads(|| {
+            let mut pairs = Vec::new();
+            for pair in self.inner.iter() {
+                let (key, value) = pair?;
+                if start.as_ref().is_some_and(|start| &key < start) {
+                    continue;
+                }
+                if end.as_ref().is_some_and(|end| &key >= end) {
+                    break;
+                }
+                pairs.push((key, value));
+            }
+            Ok(pairs)
+        })
+        .map_err(to_py_err)
+    }
+
+    fn __getitem__(&self, py: Python<'_>, key: String) -> PyResult<String> {
+        self.get(py, key.clone())?
+            .ok_or_else(|| PyKeyError::new_err(key))
+    }
+
+    fn __setitem__(&self, py: Python<'_>, key: String, value: String) -> PyResult<()> {
+        self.set(py, key, value)
+    }
+
+    fn __delitem__(&self, py: Python<'_>, key: String) -> PyResult<()> {
+        self.remove(py, key)
+    }
+
+    fn __contains__(&self, py: Python<'_>, key: String) -> PyResult<bool> {
+        Ok(self.get(py, key)?.is_some())
+    }
+}
+
+/// Opens the store in the directory `path`, creating it if needed.
+#[pyfunction]
+fn open(py: Python<'_>, path: PathBuf) -> PyResult<KvStore> {
+    KvStore::new(py, path)
+}
+
+fn to_py_err(e: KvsError) -> PyErr {
+    match e {
+        KvsError::KeyNotFound => PyKeyError::new_err(e.to_string()),
+        KvsError::Utf8(_) => PyValueError::new_err(e.to_string()),
+        e => PyIOError::new_err(e.to_string()),
+    }
+}
+
+#[pymodule]
+fn kvs_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<KvStore>()?;
+    m.add_function(wrap_pyfunction!(open, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,90 @@
+//! This module provides the targets of `KvStore::backup_to`, which decide
+//! where the backed up files are written. Custom targets should implement the
+//! `BackupTarget` trait.
+
+#[cfg(feature = "fs")]
+use std::fs::{self, File};
+use std::io::{self, Read};
+#[cfg(feature = "fs")]
+use std::path::PathBuf;
+
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "s3")]
+mod sigv4;
+
+#[cfg(feature = "s3")]
+pub use self::s3::S3Target;
+
+/// The trait that all backup targets should implement.
+///
+/// A backup is a named set of files, put one at a time, then committed once
+/// they are all put. A backup that was not committed, e.g. because of a
+/// crash, must not be mistaken for a complete one.
+pub trait BackupTarget: Send + Sync {
+    /// Writes file `name` of backup `backup` with the `len` bytes of `reader`.
+    fn put(&self, backup: &str, name: &str, reader: &mut dyn Read, len: u64) -> io::Result<()>;
+
+    /// Completes backup `backup`, whose files are all put.
+    fn commit(&self, backup: &str) -> io::Result<()>;
+
+    /// Gives up backup `backup` after a failure, e.g. to delete the files
+    /// already put. The default implementation does nothing.
+    fn abort(&self, _backup: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A backup target writing every backup to a directory of its own, named after
+/// the backup, in a parent directory. `KvStore::open` can load it as is.
+///
+/// The files are written to a temporary directory, renamed once the backup
+/// is committed.
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone)]
+pub struct DirTarget {
+    dir: PathBuf,
+}
+
+#[cfg(feature = "fs")]
+impl DirTarget {
+    /// Creates a target writing backups to directories in `dir`.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirTarget { dir: dir.into() }
+    }
+
+    /// Returns the directory of backup `backup` once committed.
+    pub fn backup_dir(&self, backup: &str) -> PathBuf {
+        self.dir.join(backup)
+    }
+
+    fn tmp_dir(&self, backup: &str) -> PathBuf {
+        self.dir.join(format!("{}.tmp", backup))
+    }
+}
+
+#[cfg(feature = "fs")]
+impl BackupTarget for DirTarget {
+    fn put(&self, backup: &str, name: &str, reader: &mut dyn Read, len: u64) -> io::Result<()> {
+        let dir = self.tmp_dir(backup);
+        fs::create_dir_all(&dir)?;
+        let mut file = File::create(dir.join(name))?;
+        io::copy(&mut reader.take(len), &mut file)?;
+        file.sync_all()
+    }
+
+    fn commit(&self, backup: &str) -> io::Result<()> {
+        let backup_dir = self.backup_dir(backup);
+        if backup_dir.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("backup {} already exists", backup_dir.display()),
+            ));
+        }
+        fs::rename(self.tmp_dir(backup), backup_dir)
+    }
+
+    fn abort(&self, backup: &str) -> io::Result<()> {
+        fs::remove_dir_all(self.tmp_dir(backup))
+    }
+}
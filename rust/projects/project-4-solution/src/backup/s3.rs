@@ -0,0 +1,344 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime};
+
+use super::sigv4::{amz_date, canonical_query, hex, sha256, uri_encode, SignedRequest, Signer};
+use super::BackupTarget;
+
+/// Objects larger than this are uploaded in parts of this size by default.
+const DEFAULT_PART_SIZE: usize = 8 * 1024 * 1024;
+
+const DEFAULT_REGION: &str = "us-east-1";
+
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A backup target writing to a bucket of an S3-compatible object storage,
+/// e.g. MinIO, with path-style requests signed with AWS Signature Version 4.
+///
+/// Every file of backup `backup` is written to object `<prefix><backup>/<file>`,
+/// in parts with a multipart upload if it is larger than the part size. An
+/// empty `<prefix><backup>/COMPLETE` object is written once the backup is
+/// committed: backups without it are incomplete.
+///
+/// Only plain `http://` endpoints are supported.
+#[derive(Clone)]
+pub struct S3Target {
+    /// The `host:port` of the endpoint.
+    host: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    part_size: usize,
+}
+
+impl fmt::Debug for S3Target {
+    // Leaves the secret key out.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("S3Target")
+            .field("host", &self.host)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .field("access_key", &self.access_key)
+            .field("part_size", &self.part_size)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+impl S3Target {
+    /// Creates a target writing to `bucket` at `endpoint`, e.g.
+    /// `http://127.0.0.1:9000`, in region `us-east-1` and without credentials.
+    ///
+    /// # Errors
+    ///
+    /// It returns an `InvalidInput` I/O error if `endpoint` is not an
+    /// `http://` URL.
+    pub fn new(endpoint: &str, bucket: &str) -> io::Result<Self> {
+        let host = endpoint
+            .strip_prefix("http://")
+            .map(|host| host.trim_end_matches('/'))
+            .filter(|host| !host.is_empty() && !host.contains('/'))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unsupported S3 endpoint: {}", endpoint),
+                )
+            })?;
+        Ok(S3Target {
+            host: host.to_owned(),
+            bucket: bucket.to_owned(),
+            prefix: String::new(),
+            region: DEFAULT_REGION.to_owned(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            part_size: DEFAULT_PART_SIZE,
+        })
+    }
+
+    /// Sets the region the requests are signed for.
+    pub fn region(mut self, region: &str) -> Self {
+        self.region = region.to_owned();
+        self
+    }
+
+    /// Sets the access key and the secret key the requests are signed with.
+    pub fn credentials(mut self, access_key: &str, secret_key: &str) -> Self {
+        self.access_key = access_key.to_owned();
+        self.secret_key = secret_key.to_owned();
+        self
+    }
+
+    /// Sets the prefix of the object keys, e.g. `backups/`.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_owned();
+        self
+    }
+
+    /// Sets the size of the parts of multipart uploads, 8 MiB by default.
+    /// S3 requires at least 5 MiB.
+    pub fn part_size(mut self, bytes: usize) -> Self {
+        self.part_size = bytes.max(1);
+        self
+    }
+
+    fn object_key(&self, backup: &str, name: &str) -> String {
+        format!("{}{}/{}", self.prefix, backup, name)
+    }
+
+    /// Sends a signed request for object `key` and returns the response, or
+    /// an error if its status is not a success.
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<Response> {
+        let path = format!(
+            "/{}/{}",
+            uri_encode(&self.bucket, false),
+            uri_encode(key, true)
+        );
+        let payload_hash = hex(&sha256(body));
+        let amz_date = amz_date(SystemTime::now());
+        let headers = [
+            ("host", self.host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        let signer = Signer {
+            access_key: &self.access_key,
+            secret_key: &self.secret_key,
+            region: &self.region,
+            service: "s3",
+        };
+        let authorization = signer.authorization(
+            &SignedRequest {
+                method,
+                path: &path,
+                query,
+                headers: &headers,
+                payload_hash: &payload_hash,
+            },
+            &amz_date,
+        );
+
+        let query = canonical_query(query);
+        let target = if query.is_empty() {
+            path
+        } else {
+            format!("{}?{}", path, query)
+        };
+        let mut request = format!("{} {} HTTP/1.1\r\n", method, target);
+        for (name, value) in &headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str(&format!(
+            "authorization: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+            authorization,
+            body.len()
+        ));
+
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        stream.write_all(request.as_bytes())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw)?;
+
+        let response = parse_response(&raw)?;
+        // S3 may report a failure in the body of a 200 response.
+        let failed = String::from_utf8_lossy(&response.body).contains("<Error>");
+        if (200..300).contains(&response.status) && !failed {
+            Ok(response)
+        } else {
+            Err(io::Error::other(format!(
+                "S3 {} of {} failed with status {}: {}",
+                method,
+                key,
+                response.status,
+                String::from_utf8_lossy(&response.body)
+            )))
+        }
+    }
+
+    fn multipart_upload(&self, key: &str, reader: &mut dyn Read, len: u64) -> io::Result<()> {
+        let response = self.request("POST", key, &[("uploads", "")], b"")?;
+        let upload_id = xml_element(&response.body, "UploadId")
+            .ok_or_else(|| invalid_response("no UploadId in the response"))?;
+
+        let res = self.upload_parts(key, &upload_id, reader, len);
+        if res.is_err() {
+            // Let the storage free the uploaded parts.
+            let _ = self.request("DELETE", key, &[("uploadId", &upload_id)], b"");
+        }
+        res
+    }
+
+    fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        reader: &mut dyn Read,
+        len: u64,
+    ) -> io::Result<()> {
+        let mut complete = String::from("<CompleteMultipartUpload>");
+        let mut remaining = len;
+        let mut part_number = 1;
+        let mut part = Vec::with_capacity(self.part_size);
+        while remaining > 0 {
+            part.clear();
+            let part_len = remaining.min(self.part_size as u64);
+            reader.take(part_len).read_to_end(&mut part)?;
+            if (part.len() as u64) < part_len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            let number = part_number.to_string();
+            let response = self.request(
+                "PUT",
+                key,
+                &[("partNumber", &number), ("uploadId", upload_id)],
+                &part,
+            )?;
+            let etag = response
+                .header("etag")
+                .ok_or_else(|| invalid_response("no ETag in the response"))?;
+            complete.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                part_number, etag
+            ));
+            remaining -= part_len;
+            part_number += 1;
+        }
+        complete.push_str("</CompleteMultipartUpload>");
+        self.request("POST", key, &[("uploadId", upload_id)], complete.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl BackupTarget for S3Target {
+    fn put(&self, backup: &str, name: &str, reader: &mut dyn Read, len: u64) -> io::Result<()> {
+        let key = self.object_key(backup, name);
+        if len > self.part_size as u64 {
+            return self.multipart_upload(&key, reader, len);
+        }
+        let mut body = Vec::with_capacity(len as usize);
+        reader.take(len).read_to_end(&mut body)?;
+        self.request("PUT", &key, &[], &body)?;
+        Ok(())
+    }
+
+    fn commit(&self, backup: &str) -> io::Result<()> {
+        self.request("PUT", &self.object_key(backup, "COMPLETE"), &[], b"")?;
+        Ok(())
+    }
+}
+
+fn invalid_response(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns the text of the first `name` element of an XML document.
+fn xml_element(xml: &[u8], name: &str) -> Option<String> {
+    let xml = String::from_utf8_lossy(xml);
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", name))?;
+    Some(xml[start..end].to_owned())
+}
+
+/// Parses an HTTP/1.1 response read until the connection was closed.
+fn parse_response(raw: &[u8]) -> io::Result<Response> {
+    let head_end = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| invalid_response("truncated HTTP response"))?;
+    let head = String::from_utf8_lossy(&raw[..head_end]);
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid_response("malformed HTTP status line"))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim().to_owned(), value.trim().to_owned()))
+        })
+        .collect();
+    let mut response = Response {
+        status,
+        headers,
+        body: raw[head_end + 4..].to_vec(),
+    };
+    if matches!(
+        response.header("transfer-encoding"),
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked")
+    ) {
+        response.body = dechunk(&response.body)?;
+    }
+    Ok(response)
+}
+
+/// Decodes a body with the chunked transfer encoding.
+fn dechunk(mut raw: &[u8]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = raw
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or_else(|| invalid_response("truncated chunk"))?;
+        let size = String::from_utf8_lossy(&raw[..line_end]);
+        let size = size.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| invalid_response("malformed chunk size"))?;
+        raw = &raw[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        if raw.len() < size {
+            return Err(invalid_response("truncated chunk"));
+        }
+        body.extend_from_slice(&raw[..size]);
+        raw = raw.get(size + 2..).unwrap_or(&[]);
+    }
+}
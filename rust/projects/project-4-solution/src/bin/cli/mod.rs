@@ -28,6 +28,12 @@ pub enum SubCommand {
         #[structopt(name = "VALUE", required = true)]
         /// The string value of the key
         value: String,
+        /// Only sets the key if it does not exist
+        #[structopt(long, conflicts_with = "xx")]
+        nx: bool,
+        /// Only sets the key if it already exists
+        #[structopt(long)]
+        xx: bool,
         /// Sets the server address
         #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
         addr: SocketAddr,
@@ -37,6 +43,36 @@ pub enum SubCommand {
         #[structopt(name = "KEY", required = true)]
         /// A string key
         key: String,
+        /// Succeeds even if the key does not exist
+        #[structopt(long)]
+        ignore_missing: bool,
+        /// Sets the server address
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
+    /// Make a given key expire after a number of seconds
+    Expire {
+        #[structopt(name = "KEY", required = true)]
+        /// A string key
+        key: String,
+        #[structopt(name = "SECONDS", required = true)]
+        /// The time to live of the key
+        seconds: u64,
+        /// Sets the server address
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
+    /// Remove the expiry of a given key
+    Persist {
+        #[structopt(name = "KEY", required = true)]
+        /// A string key
+        key: String,
+        /// Sets the server address
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
+    /// Show the latency percentiles of the operations served by the server
+    Stats {
         /// Sets the server address
         #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
         addr: SocketAddr,
@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use kvs::{KvsClient, KvsError, Result};
+
+// A struct to hold command line arguments parsed.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-admin")]
+pub struct Options {
+    #[structopt(subcommand)]
+    pub cmd: SubCommand,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum SubCommand {
+    /// Replace the data set of a running server with a backup
+    Restore {
+        /// The backup directory, relative to the backup root of the server
+        /// (`kvs-server --backup-root`), e.g. a snapshot
+        #[structopt(long, value_name = "BACKUP")]
+        from: String,
+        /// Sets the admin address of the server, see `kvs-server --admin-addr`
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4001")]
+        addr: SocketAddr,
+    },
+    /// Compact the data set of a running server now
+    Compact {
+        /// Sets the server address
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4000")]
+        addr: SocketAddr,
+    },
+    /// List the connections served by a server
+    Clients {
+        /// Sets the admin address of the server, see `kvs-server --admin-addr`
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4001")]
+        addr: SocketAddr,
+    },
+    /// Make a server reject writes, or accept them again
+    ReadOnly {
+        #[structopt(possible_values = &["on", "off"])]
+        mode: String,
+        /// Sets the admin address of the server, see `kvs-server --admin-addr`
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4001")]
+        addr: SocketAddr,
+    },
+    /// Close a connection of a server
+    Kill {
+        /// The ID of the connection, as listed by `clients`
+        #[structopt(long)]
+        id: u64,
+        /// Sets the admin address of the server, see `kvs-server --admin-addr`
+        #[structopt(long, value_name = "IP:PORT", default_value = "127.0.0.1:4001")]
+        addr: SocketAddr,
+    },
+}
+
+fn main() {
+    let opts = Options::from_args();
+    if let Err(e) = run(opts) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opts: Options) -> Result<()> {
+    match opts.cmd {
+        SubCommand::Restore { from, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.restore(from.clone())?;
+            println!("Restored from {}", from);
+        }
+        SubCommand::Compact { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.compact()?;
+            println!("Compacted");
+        }
+        SubCommand::Clients { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            println!(
+                "{:>6} {:<21} {:<12} {:>3} {:>9} {:>9} {:<16} {:>8} {:>10} {:>10}",
+                "ID",
+                "PEER",
+                "IDENTITY",
+                "DB",
+                "AGE_S",
+                "IDLE_S",
+                "LAST_OP",
+                "REQS",
+                "BYTES_IN",
+                "BYTES_OUT"
+            );
+            for info in client.client_list()? {
+                println!(
+                    "{:>6} {:<21} {:<12} {:>3} {:>9.1} {:>9.1} {:<16} {:>8} {:>10} {:>10}",
+                    info.id,
+                    info.peer,
+                    info.identity.as_deref().unwrap_or("-"),
+                    info.db,
+                    info.age_ms as f64 / 1000.0,
+                    info.idle_ms as f64 / 1000.0,
+                    info.last_op.as_deref().unwrap_or("-"),
+                    info.requests,
+                    info.bytes_read,
+                    info.bytes_written
+                );
+            }
+        }
+        SubCommand::ReadOnly { mode, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.set_read_only(mode == "on")?;
+            println!("Read-only mode {}", mode);
+        }
+        SubCommand::Kill { id, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            if !client.client_kill(id)? {
+                return Err(KvsError::StringError(format!("No connection {}", id)));
+            }
+            println!("Closed connection {}", id);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,273 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use std::time::Instant;
+
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
+
+use kvs::clock::SystemClock;
+use kvs::thread_pool::*;
+use kvs::vfs::MemFs;
+use kvs::workload::{KeyDistribution, Workload, WorkloadReport};
+#[cfg(feature = "sled")]
+use kvs::SledKvsEngine;
+use kvs::{KvStore, KvsEngine, Result};
+
+// A struct to hold command line arguments parsed.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-bench")]
+pub struct Options {
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+    /// Sets the storage engine
+    #[structopt(
+        long,
+        value_name = "ENGINE-NAME",
+        default_value = "kvs",
+        case_insensitive = true,
+        possible_values = &Engine::variants()
+    )]
+    engine: Engine,
+    /// Sets the data directory [default: a temporary directory]
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    dir: Option<PathBuf>,
+    /// Sets the YCSB core workload
+    #[structopt(
+        long,
+        value_name = "WORKLOAD",
+        default_value = "a",
+        case_insensitive = true,
+        possible_values = &Preset::variants()
+    )]
+    workload: Preset,
+    /// Sets the number of records inserted by the load phase
+    #[structopt(long, value_name = "N", default_value = "10000")]
+    records: u64,
+    /// Sets the number of operations of the run phase
+    #[structopt(long, value_name = "N", default_value = "100000")]
+    operations: u64,
+    /// Overrides the fraction of reads of the workload
+    #[structopt(long, value_name = "RATIO")]
+    read_ratio: Option<f64>,
+    /// Overrides the key distribution of the workload
+    #[structopt(
+        long,
+        value_name = "DISTRIBUTION",
+        case_insensitive = true,
+        possible_values = &Distribution::variants()
+    )]
+    distribution: Option<Distribution>,
+    /// Sets the minimum value size in bytes
+    #[structopt(long, value_name = "BYTES", default_value = "100")]
+    min_value_size: usize,
+    /// Sets the maximum value size in bytes
+    #[structopt(long, value_name = "BYTES", default_value = "100")]
+    max_value_size: usize,
+    /// Sets the seed of the generated keys and values
+    #[structopt(long, value_name = "SEED", default_value = "0")]
+    seed: u64,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Run the workload against every engine with every thread pool and print a comparison table
+    Compare {
+        /// Sets the number of concurrent clients [default: the number of CPUs]
+        #[structopt(long, value_name = "N")]
+        threads: Option<u32>,
+    },
+}
+
+arg_enum! {
+    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+    enum Engine {
+        Kvs,
+        Sled,
+        Memory,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+    enum Preset {
+        A,
+        B,
+        C,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+    enum Distribution {
+        Uniform,
+        Zipfian,
+    }
+}
+
+fn main() {
+    let opt = Options::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Options) -> Result<()> {
+    let mut workload = match opt.workload {
+        Preset::A => Workload::ycsb_a(opt.records),
+        Preset::B => Workload::ycsb_b(opt.records),
+        Preset::C => Workload::ycsb_c(opt.records),
+    }
+    .operations(opt.operations)
+    .value_size(opt.min_value_size..=opt.max_value_size)
+    .seed(opt.seed);
+    if let Some(read_ratio) = opt.read_ratio {
+        workload = workload.read_ratio(read_ratio);
+    }
+    if let Some(distribution) = opt.distribution {
+        workload = workload.distribution(match distribution {
+            Distribution::Uniform => KeyDistribution::Uniform,
+            Distribution::Zipfian => KeyDistribution::YCSB_ZIPFIAN,
+        });
+    }
+
+    if let Some(Command::Compare { threads }) = opt.cmd {
+        return compare(&workload, threads.unwrap_or(num_cpus::get() as u32));
+    }
+
+    let (dir, temporary) = match opt.dir {
+        Some(dir) => (dir, false),
+        None => (temp_dir("run"), true),
+    };
+    let res = match opt.engine {
+        Engine::Kvs => bench(KvStore::open(&dir)?, &workload),
+        #[cfg(feature = "sled")]
+        Engine::Sled => bench(SledKvsEngine::open(&dir)?, &workload),
+        #[cfg(not(feature = "sled"))]
+        Engine::Sled => Err(without_sled()),
+        Engine::Memory => bench(open_memory()?, &workload),
+    };
+    if temporary {
+        remove_dir(&dir)?;
+    }
+    print_report(&res?);
+    Ok(())
+}
+
+fn bench<E: KvsEngine>(engine: E, workload: &Workload) -> Result<WorkloadReport> {
+    let start = Instant::now();
+    workload.load(&engine)?;
+    println!("load: {:.3}s", start.elapsed().as_secs_f64());
+    workload.run(&engine)
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("kvs-bench-{}-{}", std::process::id(), name))
+}
+
+/// Opens a `KvStore` on an in-memory file system.
+fn open_memory() -> Result<KvStore> {
+    KvStore::open_with_vfs("/kvs", Arc::new(MemFs::new()), Arc::new(SystemClock))
+}
+
+#[cfg(not(feature = "sled"))]
+fn without_sled() -> kvs::KvsError {
+    kvs::KvsError::StringError("kvs-bench was built without the sled feature".to_owned())
+}
+
+/// Runs the workload on a fresh instance of every engine, with every thread pool.
+fn compare(workload: &Workload, threads: u32) -> Result<()> {
+    println!(
+        "{:<8} {:<14} {:>10} {:>12} {:>12} {:>12} {:>12}",
+        "engine", "pool", "ops/s", "get p50(us)", "get p99(us)", "set p50(us)", "set p99(us)"
+    );
+    for &engine in &[Engine::Kvs, Engine::Sled, Engine::Memory] {
+        if cfg!(not(feature = "sled")) && engine == Engine::Sled {
+            continue;
+        }
+        compare_pool::<NaiveThreadPool>(workload, engine, "naive", threads)?;
+        compare_pool::<SharedQueueThreadPool>(workload, engine, "shared-queue", threads)?;
+        compare_pool::<RayonThreadPool>(workload, engine, "rayon", threads)?;
+    }
+    Ok(())
+}
+
+fn compare_pool<P: ThreadPool>(
+    workload: &Workload,
+    engine: Engine,
+    pool_name: &str,
+    threads: u32,
+) -> Result<()> {
+    let pool = P::new(threads)?;
+    let dir = temp_dir(&format!("{}-{}", engine, pool_name));
+    let res = match engine {
+        Engine::Kvs => bench_on(KvStore::open(&dir)?, workload, &pool, threads),
+        #[cfg(feature = "sled")]
+        Engine::Sled => bench_on(SledKvsEngine::open(&dir)?, workload, &pool, threads),
+        #[cfg(not(feature = "sled"))]
+        Engine::Sled => Err(without_sled()),
+        Engine::Memory => bench_on(open_memory()?, workload, &pool, threads),
+    };
+    remove_dir(&dir)?;
+    let report = res?;
+
+    let percentiles = |op: &str| {
+        report
+            .latency
+            .iter()
+            .find(|latency| latency.op == op)
+            .map_or((0, 0), |latency| (latency.p50_us, latency.p99_us))
+    };
+    let (get_p50, get_p99) = percentiles("get");
+    let (set_p50, set_p99) = percentiles("set");
+    println!(
+        "{:<8} {:<14} {:>10.0} {:>12} {:>12} {:>12} {:>12}",
+        engine.to_string().to_lowercase(),
+        pool_name,
+        report.throughput(),
+        get_p50,
+        get_p99,
+        set_p50,
+        set_p99
+    );
+    Ok(())
+}
+
+fn bench_on<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    workload: &Workload,
+    pool: &P,
+    threads: u32,
+) -> Result<WorkloadReport> {
+    workload.load(&engine)?;
+    workload.run_on(&engine, pool, threads as usize)
+}
+
+fn remove_dir(dir: &Path) -> Result<()> {
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+fn print_report(report: &WorkloadReport) {
+    println!(
+        "run: {} operations in {:.3}s ({:.0} ops/s)",
+        report.operations,
+        report.elapsed.as_secs_f64(),
+        report.throughput()
+    );
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "op", "count", "p50(us)", "p95(us)", "p99(us)", "max(us)"
+    );
+    for stats in &report.latency {
+        println!(
+            "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            stats.op, stats.count, stats.p50_us, stats.p95_us, stats.p99_us, stats.max_us
+        );
+    }
+}
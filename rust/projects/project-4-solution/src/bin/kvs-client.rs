@@ -1,7 +1,8 @@
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, KvsError, Result};
 
 mod cli;
 use cli::{Options, SubCommand};
@@ -26,13 +27,62 @@ fn run(opts: Options) -> Result<()> {
 
             println!("{}", output);
         }
-        SubCommand::Set { key, value, addr } => {
+        SubCommand::Set {
+            key,
+            value,
+            nx,
+            xx,
+            addr,
+        } => {
             let mut client = KvsClient::connect(addr)?;
-            client.set(key, value)?;
+            if nx {
+                if !client.set_nx(key, value)? {
+                    return Err(KvsError::StringError("Key exists".to_owned()));
+                }
+            } else if xx {
+                if !client.set_xx(key, value)? {
+                    return Err(KvsError::KeyNotFound);
+                }
+            } else {
+                client.set(key, value)?;
+            }
         }
-        SubCommand::Rm { key, addr } => {
+        SubCommand::Rm {
+            key,
+            ignore_missing,
+            addr,
+        } => {
             let mut client = KvsClient::connect(addr)?;
-            client.remove(key)?;
+            if ignore_missing {
+                client.remove_if_exists(key)?;
+            } else {
+                client.remove(key)?;
+            }
+        }
+        SubCommand::Expire { key, seconds, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            if !client.expire(key, Duration::from_secs(seconds))? {
+                return Err(KvsError::KeyNotFound);
+            }
+        }
+        SubCommand::Persist { key, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            if !client.persist(key)? {
+                println!("Key not found or has no expiry");
+            }
+        }
+        SubCommand::Stats { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            println!(
+                "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                "op", "count", "p50(us)", "p95(us)", "p99(us)", "max(us)"
+            );
+            for stats in client.stats()? {
+                println!(
+                    "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                    stats.op, stats.count, stats.p50_us, stats.p95_us, stats.p99_us, stats.max_us
+                );
+            }
         }
     }
     Ok(())
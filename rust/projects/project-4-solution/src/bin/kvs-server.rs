@@ -1,28 +1,47 @@
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
 use std::env;
 use std::fs;
-use std::net::SocketAddr;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
-use log::LevelFilter;
 use structopt::clap::arg_enum;
 use structopt::StructOpt;
+use tracing::Level;
 
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+#[cfg(feature = "sled")]
+use kvs::SledKvsEngine;
+use kvs::{
+    KvStore, KvsEngine, KvsServer, LatencyStats, OpenProgress, Result, ShadowEngine,
+    SnapshotRetention, SocketOptions,
+};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::Kvs;
+const SHADOW_REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
 // A struct to hold command line arguments parsed.
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 pub struct Options {
-    /// Sets the listening address
-    #[structopt(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS, parse(try_from_str))]
-    addr: SocketAddr,
+    /// Sets the listening address. Repeat it to listen on several addresses,
+    /// e.g. `--addr 0.0.0.0:4000 --addr [::]:4000`
+    #[structopt(
+        long,
+        value_name = "IP:PORT",
+        default_value = DEFAULT_LISTENING_ADDRESS,
+        number_of_values = 1,
+        parse(try_from_str)
+    )]
+    addr: Vec<SocketAddr>,
     /// Sets the storage engine
     #[structopt(
         long,
@@ -31,6 +50,106 @@ pub struct Options {
         possible_values = &Engine::variants()
     )]
     engine: Option<Engine>,
+    /// Sets the log output format
+    #[structopt(
+        long,
+        value_name = "FORMAT",
+        default_value = "text",
+        case_insensitive = true,
+        possible_values = &LogFormat::variants()
+    )]
+    log_format: LogFormat,
+    /// Serves the admin requests of `kvs-admin` on this address, e.g.
+    /// `127.0.0.1:4001`. They are rejected on the other addresses
+    #[structopt(
+        long,
+        value_name = "IP:PORT",
+        number_of_values = 1,
+        parse(try_from_str)
+    )]
+    admin_addr: Vec<SocketAddr>,
+    /// Serves latency percentiles in the Prometheus text format on this address,
+    /// and a readiness probe on its /ready path
+    #[structopt(long, value_name = "IP:PORT", parse(try_from_str))]
+    metrics_addr: Option<SocketAddr>,
+    /// Compresses responses larger than this many bytes [default: 16384]
+    #[structopt(long, value_name = "BYTES", conflicts_with = "no-compression")]
+    compress_above: Option<usize>,
+    /// Never compresses responses
+    #[structopt(long)]
+    no_compression: bool,
+    /// Closes the connections sending requests larger than this many bytes
+    /// [default: 67108864]
+    #[structopt(long, value_name = "BYTES")]
+    max_request_size: Option<usize>,
+    /// Rejects writes while the data directory has less free space than this
+    /// many bytes (kvs engine only)
+    #[structopt(long, value_name = "BYTES", default_value = "0")]
+    min_free_space: u64,
+    /// Compacts the log once its stale commands take more than this many
+    /// bytes (kvs engine only) [default: 1024]
+    #[structopt(long, value_name = "BYTES")]
+    compaction_threshold: Option<u64>,
+    /// Rolls over to a new log file once the active one takes this many
+    /// bytes (kvs engine only)
+    #[structopt(long, value_name = "BYTES")]
+    segment_size: Option<u64>,
+    /// Restores the backups of `kvs-admin restore` from this directory only
+    #[structopt(long, value_name = "DIR", parse(from_os_str))]
+    backup_root: Option<PathBuf>,
+    /// Takes periodic snapshots of the data directory in this directory
+    /// (kvs engine only)
+    #[structopt(long, value_name = "DIR", parse(from_os_str))]
+    snapshot_dir: Option<PathBuf>,
+    /// Sets the interval between snapshots
+    #[structopt(long, value_name = "SECONDS", default_value = "3600")]
+    snapshot_interval: u64,
+    /// Keeps only this many of the newest snapshots
+    #[structopt(long, value_name = "COUNT")]
+    snapshot_keep: Option<usize>,
+    /// Sets the thread pool serving the connections
+    #[structopt(
+        long,
+        value_name = "POOL",
+        default_value = "rayon",
+        case_insensitive = true,
+        possible_values = &Pool::VARIANTS
+    )]
+    pool: Pool,
+    /// Sets the number of threads of the pool [default: the number of CPUs]
+    #[structopt(long, value_name = "N")]
+    threads: Option<u32>,
+    /// Lets Nagle's algorithm delay small responses (no TCP_NODELAY)
+    #[structopt(long)]
+    no_tcp_nodelay: bool,
+    /// Does not set SO_REUSEADDR on the listening socket
+    #[structopt(long)]
+    no_reuse_address: bool,
+    /// Sets the size of the send buffer of the connections
+    #[structopt(long, value_name = "BYTES")]
+    send_buffer_size: Option<usize>,
+    /// Sets the size of the receive buffer of the connections
+    #[structopt(long, value_name = "BYTES")]
+    recv_buffer_size: Option<usize>,
+    /// Sets the most connections waiting to be accepted
+    #[structopt(long, value_name = "N", default_value = "128")]
+    backlog: i32,
+    /// Mirrors every request to a second engine of this kind, and logs where
+    /// its results diverge
+    #[structopt(
+        long,
+        value_name = "ENGINE-NAME",
+        case_insensitive = true,
+        possible_values = &Engine::variants(),
+        requires = "shadow-dir"
+    )]
+    shadow_engine: Option<Engine>,
+    /// Sets the data directory of the shadow engine
+    #[structopt(long, value_name = "DIR", parse(from_os_str))]
+    shadow_dir: Option<PathBuf>,
+    /// Rejects writes until turned off with `kvs-admin read-only off`
+    #[structopt(long)]
+    read_only: bool,
 }
 
 arg_enum! {
@@ -41,12 +160,41 @@ arg_enum! {
     }
 }
 
-fn main() {
-    env_logger::builder()
-        .filter_level(LevelFilter::Debug)
-        .init();
+arg_enum! {
+    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+    enum LogFormat {
+        Text,
+        Json,
+    }
+}
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Pool {
+    SharedQueue,
+    Rayon,
+    Naive,
+}
+
+impl Pool {
+    const VARIANTS: [&'static str; 3] = ["shared-queue", "rayon", "naive"];
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "rayon" => Ok(Pool::Rayon),
+            "naive" => Ok(Pool::Naive),
+            _ => Err(format!("unknown thread pool: {}", s)),
+        }
+    }
+}
+
+fn main() {
     let mut opts = Options::from_args();
+    init_logging(opts.log_format);
 
     let res = current_engine().and_then(move |curr_engine| {
         if opts.engine.is_none() {
@@ -65,38 +213,282 @@ fn main() {
     }
 }
 
+fn init_logging(format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(Level::DEBUG)
+        .with_writer(io::stderr);
+
+    match format {
+        LogFormat::Text => builder.init(),
+        // One JSON object per event. Event fields are flattened to the top level and the
+        // fields of the enclosing request span (peer, op, key) are kept under "span".
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
 fn run(opt: Options) -> Result<()> {
     let engine = opt.engine.unwrap_or(DEFAULT_ENGINE);
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
-    info!("Listening on {}", opt.addr);
+    for addr in &opt.addr {
+        info!("Listening on {}", addr);
+    }
+    for addr in &opt.admin_addr {
+        info!("Serving admin requests on {}", addr);
+    }
+
+    #[cfg(not(feature = "sled"))]
+    {
+        if engine == Engine::Sled {
+            return Err(kvs::KvsError::StringError(
+                "kvs-server was built without the sled feature".to_owned(),
+            ));
+        }
+    }
 
     // Write engine to file.
     fs::write(env::current_dir()?.join("engine"), format!("{}", engine))?;
 
-    let thread_pool = RayonThreadPool::new(num_cpus::get() as u32)?;
+    // Serve the metrics while the engine is opening, so that the readiness probe
+    // can tell it is not ready yet.
+    let served_stats = ServedStats::default();
+    if let Some(metrics_addr) = opt.metrics_addr {
+        serve_metrics(metrics_addr, Arc::clone(&served_stats))?;
+    }
+
+    let threads = opt.threads.unwrap_or(num_cpus::get() as u32);
+    if threads == 0 {
+        return Err(kvs::KvsError::StringError(
+            "the thread pool needs at least one thread".to_owned(),
+        ));
+    }
+    info!("Thread pool: {:?} with {} threads", opt.pool, threads);
+    match opt.pool {
+        Pool::SharedQueue => run_pool(SharedQueueThreadPool::new(threads)?, &opt, &served_stats),
+        Pool::Rayon => run_pool(RayonThreadPool::new(threads)?, &opt, &served_stats),
+        Pool::Naive => run_pool(NaiveThreadPool::new(threads)?, &opt, &served_stats),
+    }
+}
 
-    match engine {
-        Engine::Kvs => run_with(KvStore::open(env::current_dir()?)?, thread_pool, opt.addr)?,
-        Engine::Sled => run_with(
-            SledKvsEngine::new(sled::Db::open(env::current_dir()?)?),
+fn run_pool<P: ThreadPool>(
+    thread_pool: P,
+    opt: &Options,
+    served_stats: &ServedStats,
+) -> Result<()> {
+    match opt.engine.unwrap_or(DEFAULT_ENGINE) {
+        Engine::Kvs => run_shadowed(open_kvs(opt)?, thread_pool, opt, served_stats),
+        #[cfg(feature = "sled")]
+        Engine::Sled => run_shadowed(
+            SledKvsEngine::open(env::current_dir()?)?,
             thread_pool,
-            opt.addr,
-        )?,
+            opt,
+            served_stats,
+        ),
+        #[cfg(not(feature = "sled"))]
+        Engine::Sled => unreachable!("rejected before writing the engine file"),
     }
+}
 
-    Ok(())
+/// Runs the server on `engine`, mirrored to the shadow engine if any.
+fn run_shadowed<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    thread_pool: P,
+    opt: &Options,
+    served_stats: &ServedStats,
+) -> Result<()> {
+    let (shadow_engine, shadow_dir) = match (opt.shadow_engine, &opt.shadow_dir) {
+        (Some(shadow_engine), Some(shadow_dir)) => (shadow_engine, shadow_dir),
+        _ => return run_with(engine, thread_pool, opt, served_stats),
+    };
+    info!(
+        "Shadow engine: {} in {}",
+        shadow_engine,
+        shadow_dir.display()
+    );
+    fs::create_dir_all(shadow_dir)?;
+    match shadow_engine {
+        Engine::Kvs => {
+            let engine = ShadowEngine::new(engine, KvStore::open(shadow_dir)?);
+            log_shadow_reports(engine.clone());
+            run_with(engine, thread_pool, opt, served_stats)
+        }
+        #[cfg(feature = "sled")]
+        Engine::Sled => {
+            let shadow = SledKvsEngine::open(shadow_dir)?;
+            let engine = ShadowEngine::new(engine, shadow);
+            log_shadow_reports(engine.clone());
+            run_with(engine, thread_pool, opt, served_stats)
+        }
+        #[cfg(not(feature = "sled"))]
+        Engine::Sled => Err(kvs::KvsError::StringError(
+            "kvs-server was built without the sled feature".to_owned(),
+        )),
+    }
 }
 
+/// Logs the divergences of the shadow engine every minute.
+fn log_shadow_reports<P: KvsEngine, S: KvsEngine>(engine: ShadowEngine<P, S>) {
+    thread::spawn(move || loop {
+        thread::sleep(SHADOW_REPORT_INTERVAL);
+        let report = engine.report();
+        info!(
+            mirrored = report.mirrored,
+            mismatched = report.mismatched,
+            dropped = report.dropped,
+            "Shadow engine report"
+        );
+        for primary in &report.primary {
+            if let Some(shadow) = report.shadow.iter().find(|shadow| shadow.op == primary.op) {
+                info!(
+                    op = primary.op.as_str(),
+                    primary_p99_us = primary.p99_us,
+                    shadow_p99_us = shadow.p99_us,
+                    "Shadow engine latency"
+                );
+            }
+        }
+    });
+}
+
+/// Opens the kvs engine in the current directory, logging the progress of the
+/// log replay every 10% of the bytes.
+fn open_kvs(opt: &Options) -> Result<KvStore> {
+    let mut reported_percent = None;
+    let mut builder = KvStore::builder().min_free_space(opt.min_free_space);
+    if let Some(bytes) = opt.compaction_threshold {
+        builder = builder.compaction_threshold(bytes);
+    }
+    if let Some(bytes) = opt.segment_size {
+        builder = builder.segment_size(bytes);
+    }
+    let store = builder.open_with_progress(env::current_dir()?, |progress: &OpenProgress| {
+        let percent = (progress.bytes_replayed * 100)
+            .checked_div(progress.bytes_total)
+            .unwrap_or(100);
+        if !matches!(reported_percent, Some(reported) if percent < reported + 10) {
+            reported_percent = Some(percent - percent % 10);
+            info!(
+                segments = progress.segments_replayed,
+                segments_total = progress.segments_total,
+                bytes = progress.bytes_replayed,
+                bytes_total = progress.bytes_total,
+                "Replaying the log: {}%",
+                percent
+            );
+        }
+    })?;
+    if let Some(snapshot_dir) = &opt.snapshot_dir {
+        let retention = SnapshotRetention {
+            max_count: opt.snapshot_keep,
+            max_age: None,
+        };
+        let interval = Duration::from_secs(opt.snapshot_interval);
+        store.snapshot_every(interval, snapshot_dir, retention)?;
+    }
+    Ok(store)
+}
+
+/// The latency histograms of the running server, or `None` until the engine is
+/// open and the server is ready.
+type ServedStats = Arc<RwLock<Option<LatencyStats>>>;
+
 fn run_with<E: KvsEngine, P: ThreadPool>(
     engine: E,
     thread_pool: P,
-    addr: SocketAddr,
+    opt: &Options,
+    served_stats: &ServedStats,
 ) -> Result<()> {
     // The trait `KvsEngine` is implemented for `KvStore`. So, the trait
     // bound `KvStore: KvsEngine` is satisfied.
-    let server = KvsServer::new(engine, thread_pool);
-    server.run(addr)
+    let mut server = KvsServer::new(engine, thread_pool)
+        .read_only(opt.read_only)
+        .socket_options(SocketOptions {
+            nodelay: !opt.no_tcp_nodelay,
+            reuse_address: !opt.no_reuse_address,
+            send_buffer_size: opt.send_buffer_size,
+            recv_buffer_size: opt.recv_buffer_size,
+            backlog: opt.backlog,
+        });
+    if opt.no_compression {
+        server = server.compress_responses_above(None);
+    } else if let Some(threshold) = opt.compress_above {
+        server = server.compress_responses_above(Some(threshold));
+    }
+    if let Some(limit) = opt.max_request_size {
+        server = server.max_request_size(Some(limit));
+    }
+    for addr in &opt.admin_addr {
+        server = server.admin_addr(*addr);
+    }
+    if let Some(root) = &opt.backup_root {
+        server = server.backup_root(root);
+    }
+    *served_stats.write().unwrap() = Some(server.latency_stats());
+    server.run_on(&opt.addr)
+}
+
+/// Serves the latency histograms over HTTP in a background thread. Requests to
+/// /ready are answered with 200 once the server is ready and 503 before. Every
+/// other request, whatever its path, is answered with the Prometheus text
+/// exposition format.
+fn serve_metrics(addr: SocketAddr, served_stats: ServedStats) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving metrics on {}", addr);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.and_then(|stream| respond_metrics(stream, &served_stats));
+            if let Err(e) = res {
+                warn!("Error on serving metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond_metrics(stream: TcpStream, served_stats: &ServedStats) -> io::Result<()> {
+    // Keep the path of the request line and skip the headers.
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+
+    let served_stats = served_stats.read().unwrap();
+    let (status, content_type, body) = match (path, &*served_stats) {
+        ("/ready", Some(_)) => ("200 OK", "text/plain", "ready\n".to_owned()),
+        ("/ready", None) => (
+            "503 Service Unavailable",
+            "text/plain",
+            "not ready\n".to_owned(),
+        ),
+        (_, stats) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            stats
+                .as_ref()
+                .map(LatencyStats::to_prometheus)
+                .unwrap_or_default(),
+        ),
+    };
+    let mut writer = &stream;
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    writer.flush()
 }
 
 fn current_engine() -> Result<Option<Engine>> {
@@ -105,7 +497,7 @@ fn current_engine() -> Result<Option<Engine>> {
         return Ok(None);
     }
 
-    match fs::read_to_string(engine)?.parse() {
+    match fs::read_to_string(engine)?.trim().parse() {
         Ok(engine) => Ok(Some(engine)),
         Err(err) => {
             warn!("The content of engine file is invalid: {}", err);
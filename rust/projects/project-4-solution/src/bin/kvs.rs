@@ -0,0 +1,122 @@
+use std::path::PathBuf;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use kvs::debug::{dump_log, segments};
+use kvs::Result;
+
+// A struct to hold command line arguments parsed.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs")]
+pub struct Options {
+    #[structopt(subcommand)]
+    pub cmd: SubCommand,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum SubCommand {
+    /// Tools to inspect the files of a data directory
+    Debug {
+        #[structopt(subcommand)]
+        cmd: DebugCommand,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+pub enum DebugCommand {
+    /// Print every record of a log file with its offset, length, checksum and validity
+    DumpLog {
+        #[structopt(name = "FILE", required = true, parse(from_os_str))]
+        /// A log file, e.g. 1.log
+        file: PathBuf,
+    },
+    /// Print the key count and key range of the compacted log files of a data directory
+    Segments {
+        #[structopt(name = "DIR", required = true, parse(from_os_str))]
+        /// A data directory
+        dir: PathBuf,
+        /// Also print whether each log file may have this key
+        #[structopt(long)]
+        key: Option<String>,
+    },
+}
+
+fn main() {
+    let opt = Options::from_args();
+    match run(opt) {
+        Ok(true) => {}
+        Ok(false) => exit(2),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+/// Returns whether the inspected data is valid.
+fn run(opt: Options) -> Result<bool> {
+    match opt.cmd {
+        SubCommand::Debug {
+            cmd: DebugCommand::DumpLog { file },
+        } => {
+            let records = dump_log(&file)?;
+            println!(
+                "{:>10} {:>8} {:>10} {:>10} {:<8} {:>9} {:<8} KEY",
+                "OFFSET", "LEN", "CRC32", "LSN", "OP", "VALUE-LEN", "STATUS"
+            );
+            for record in &records {
+                println!(
+                    "{:>10} {:>8} {:>10} {:>10} {:<8} {:>9} {:<8} {}",
+                    record.offset,
+                    record.len,
+                    format!("{:08x}", record.checksum),
+                    record.lsn,
+                    record.op,
+                    record
+                        .value_len
+                        .map_or_else(|| "-".to_owned(), |len| len.to_string()),
+                    if record.is_valid() { "ok" } else { "invalid" },
+                    match (&record.key, &record.error) {
+                        (Some(key), _) => format!("{:?}", key),
+                        (None, Some(error)) => error.clone(),
+                        (None, None) => String::new(),
+                    }
+                );
+            }
+            let invalid = records.iter().filter(|record| !record.is_valid()).count();
+            println!("{} records, {} invalid", records.len(), invalid);
+            Ok(invalid == 0)
+        }
+        SubCommand::Debug {
+            cmd: DebugCommand::Segments { dir, key },
+        } => {
+            let segments = segments(&dir)?;
+            println!(
+                "{:>8} {:>10} {:<5} MIN-KEY .. MAX-KEY",
+                "GEN", "KEYS", "HAS"
+            );
+            for segment in &segments {
+                println!(
+                    "{:>8} {:>10} {:<5} {:?} .. {:?}",
+                    segment.gen,
+                    segment.keys,
+                    match &key {
+                        Some(key) if segment.may_contain(key) => "maybe",
+                        Some(_) => "no",
+                        None => "-",
+                    },
+                    segment.min_key.as_deref().unwrap_or(""),
+                    segment.max_key.as_deref().unwrap_or("")
+                );
+            }
+            let keys: u64 = segments.iter().map(|segment| segment.keys).sum();
+            println!(
+                "{} compacted log files, about {} keys",
+                segments.len(),
+                keys
+            );
+            Ok(true)
+        }
+    }
+}
@@ -1,62 +1,317 @@
+use std::collections::HashMap;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-use serde::Deserialize;
-use serde_json::de::{Deserializer, IoRead};
+use serde::de::DeserializeOwned;
 
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
-use crate::{KvsError, Result};
+use crate::common::{
+    read_frame, remote_error, write_frame, ClientKillResponse, ClientListResponse, CompactResponse,
+    ExpireResponse, GetBytesResponse, GetResponse, RemoveIfExistsResponse, RemoveResponse, Request,
+    RestoreResponse, SetIfResponse, SetReadOnlyResponse, SetResponse, StatsResponse, TaggedRequest,
+    TaggedResponse, TransactionOp, TransactionResponse,
+};
+use crate::{ClientInfo, KvsError, OpLatency, Result};
 
 /// The client of a key value store.
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
+    ///
+    /// `TCP_NODELAY` is set on the connection, see `set_nodelay`.
     pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
         let tcp_reader = TcpStream::connect(addr)?;
+        tcp_reader.set_nodelay(true)?;
         let tcp_writer = tcp_reader.try_clone()?;
 
         Ok(Self {
-            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
+            reader: BufReader::new(tcp_reader),
             writer: BufWriter::new(tcp_writer),
         })
     }
 
+    /// Sets `TCP_NODELAY` on the connection. With `false`, Nagle's algorithm
+    /// may delay small requests to coalesce them.
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        self.writer.get_ref().set_nodelay(nodelay)?;
+        Ok(())
+    }
+
     /// Get a value from the server using a key String.
     ///
     /// Returns `None` if the given key does not exist.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Request::Get { key })?;
-        self.writer.flush()?;
-        let resp = GetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        match self.call(Request::Get { key })? {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            GetResponse::Err(err) => Err(remote_error(err)),
         }
     }
 
     /// Set a given key and value Strings in the server.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Set { key, value })?;
-        self.writer.flush()?;
-        let resp = SetResponse::deserialize(&mut self.reader)?;
-        match resp {
+        match self.call(Request::Set { key, value })? {
             SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            SetResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Set a given key in the server only if it does not exist.
+    ///
+    /// Returns whether the value was set.
+    pub fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        match self.call(Request::SetNx { key, value })? {
+            SetIfResponse::Ok(set) => Ok(set),
+            SetIfResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Set a given key in the server only if it already exists.
+    ///
+    /// Returns whether the value was set.
+    pub fn set_xx(&mut self, key: String, value: String) -> Result<bool> {
+        match self.call(Request::SetXx { key, value })? {
+            SetIfResponse::Ok(set) => Ok(set),
+            SetIfResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Make a given key in the server expire after `ttl`.
+    ///
+    /// Returns `false` if the key does not exist.
+    pub fn expire(&mut self, key: String, ttl: Duration) -> Result<bool> {
+        let ttl_millis = ttl.as_millis() as u64;
+        match self.call(Request::Expire { key, ttl_millis })? {
+            ExpireResponse::Ok(set) => Ok(set),
+            ExpireResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Remove the expiry of a given key in the server.
+    ///
+    /// Returns `false` if the key does not exist or has no expiry.
+    pub fn persist(&mut self, key: String) -> Result<bool> {
+        match self.call(Request::Persist { key })? {
+            ExpireResponse::Ok(persisted) => Ok(persisted),
+            ExpireResponse::Err(err) => Err(remote_error(err)),
         }
     }
 
     /// Remove a given key from the server.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Request::Remove { key })?;
-        self.writer.flush()?;
-        let resp = RemoveResponse::deserialize(&mut self.reader)?;
-        match resp {
+        match self.call(Request::Remove { key })? {
             RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+            RemoveResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Remove a given key from the server if it exists.
+    ///
+    /// Returns whether the key was removed.
+    pub fn remove_if_exists(&mut self, key: String) -> Result<bool> {
+        match self.call(Request::RemoveIfExists { key })? {
+            RemoveIfExistsResponse::Ok(removed) => Ok(removed),
+            RemoveIfExistsResponse::Err(err) => Err(remote_error(err)),
         }
     }
+
+    /// Get a binary value from the server using a binary key.
+    ///
+    /// Returns `None` if the given key does not exist. A key set with `set` is
+    /// read back as its UTF-8 bytes.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.call(Request::GetBytes { key })? {
+            GetBytesResponse::Ok(value) => Ok(value.map(|value| value.into_vec())),
+            GetBytesResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Set a given binary key and value in the server.
+    ///
+    /// The bytes are sent as they are, without any text encoding. The server
+    /// rejects keys that are not valid UTF-8, and values the engine cannot
+    /// store, i.e. invalid UTF-8 for engines storing strings.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match self.call(Request::SetBytes { key, value })? {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Remove a given binary key from the server.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        match self.call(Request::RemoveBytes { key })? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Replace the whole data set of the server with the backup in directory
+    /// `from`, e.g. a snapshot directory. The path is relative to the backup
+    /// root of the server, see `KvsServer::backup_root`, and the client must
+    /// be connected to an admin address of the server.
+    ///
+    /// The server serves the restored data set once it returns.
+    pub fn restore(&mut self, from: String) -> Result<()> {
+        match self.call(Request::Restore { from })? {
+            RestoreResponse::Ok(_) => Ok(()),
+            RestoreResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Compact the data set of the server now, rather than when its engine
+    /// would on its own.
+    pub fn compact(&mut self) -> Result<()> {
+        match self.call(Request::Compact)? {
+            CompactResponse::Ok(_) => Ok(()),
+            CompactResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Get the latency percentiles of the operations served by the server.
+    pub fn stats(&mut self) -> Result<Vec<OpLatency>> {
+        match self.call(Request::Stats)? {
+            StatsResponse::Ok(stats) => Ok(stats),
+            StatsResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// List the connections served by the server, this one included. The
+    /// client must be connected to an admin address of the server.
+    pub fn client_list(&mut self) -> Result<Vec<ClientInfo>> {
+        match self.call(Request::ClientList)? {
+            ClientListResponse::Ok(clients) => Ok(clients),
+            ClientListResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Close the connection of the server with the given ID, as listed by
+    /// `client_list`. The client must be connected to an admin address of the
+    /// server.
+    ///
+    /// Returns `false` if there is no such connection.
+    pub fn client_kill(&mut self, id: u64) -> Result<bool> {
+        match self.call(Request::ClientKill { id })? {
+            ClientKillResponse::Ok(killed) => Ok(killed),
+            ClientKillResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Get the values of `keys` from the server, in the order of `keys`.
+    ///
+    /// All the requests are sent before the responses are read, and the
+    /// server serves them concurrently and responds as each completes, so a
+    /// slow read does not hold back the others.
+    pub fn get_pipelined(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let span = debug_span!("pipeline", len = keys.len());
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let mut pending = HashMap::with_capacity(keys.len());
+        for (i, key) in keys.into_iter().enumerate() {
+            let id = next_request_id();
+            let request = TaggedRequest {
+                id,
+                request: Request::Get { key },
+                out_of_order: true,
+            };
+            write_frame(&mut self.writer, &request, None)?;
+            pending.insert(id, i);
+        }
+        self.writer.flush()?;
+
+        let mut values = vec![None; pending.len()];
+        while !pending.is_empty() {
+            let resp: TaggedResponse<GetResponse> = read_frame(&mut self.reader, None)?
+                .ok_or_else(|| {
+                    KvsError::Protocol("connection closed before the response".to_owned())
+                })?;
+            let i = pending.remove(&resp.id).ok_or_else(|| {
+                KvsError::Protocol(format!("response to unknown request {}", resp.id))
+            })?;
+            match resp.response {
+                GetResponse::Ok(value) => values[i] = value,
+                GetResponse::Err(err) => return Err(remote_error(err)),
+            }
+        }
+        debug!(
+            duration_us = start.elapsed().as_micros() as u64,
+            "Responses received"
+        );
+        Ok(values)
+    }
+
+    /// Make the server reject writes with `KvsError::ReadOnly`, or accept
+    /// them again. The client must be connected to an admin address of the
+    /// server, see `KvsServer::admin_addr`.
+    pub fn set_read_only(&mut self, read_only: bool) -> Result<()> {
+        match self.call(Request::SetReadOnly { read_only })? {
+            SetReadOnlyResponse::Ok(_) => Ok(()),
+            SetReadOnlyResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Begins a transaction on the connection, see `Transaction`. Until it is
+    /// committed or rolled back, the sets and removes of the connection are
+    /// buffered, and its gets read them and record the versions read.
+    ///
+    /// Removes in a transaction do not fail on missing keys.
+    pub fn begin(&mut self) -> Result<()> {
+        self.transaction(TransactionOp::Begin)
+    }
+
+    /// Commits the transaction of the connection. It fails with
+    /// `KvsError::TransactionConflict` if a key it read has changed since.
+    pub fn commit(&mut self) -> Result<()> {
+        self.transaction(TransactionOp::Commit)
+    }
+
+    /// Discards the transaction of the connection.
+    pub fn rollback(&mut self) -> Result<()> {
+        self.transaction(TransactionOp::Rollback)
+    }
+
+    fn transaction(&mut self, op: TransactionOp) -> Result<()> {
+        match self.call(Request::Transaction { op })? {
+            TransactionResponse::Ok(_) => Ok(()),
+            TransactionResponse::Err(err) => Err(remote_error(err)),
+        }
+    }
+
+    /// Send a request tagged with a fresh request ID and wait for its response.
+    fn call<R: DeserializeOwned>(&mut self, request: Request) -> Result<R> {
+        let id = next_request_id();
+        let span = debug_span!("request", id, op = request.op());
+        let _enter = span.enter();
+
+        let start = Instant::now();
+        let request = TaggedRequest {
+            id,
+            request,
+            out_of_order: false,
+        };
+        write_frame(&mut self.writer, &request, None)?;
+        self.writer.flush()?;
+        let resp = read_frame(&mut self.reader, None)?.ok_or_else(|| {
+            KvsError::Protocol("connection closed before the response".to_owned())
+        })?;
+        debug!(
+            duration_us = start.elapsed().as_micros() as u64,
+            "Response received"
+        );
+        Ok(resp)
+    }
+}
+
+/// Request IDs are unique per host: the high half is the process ID and the
+/// low half a per-process counter.
+fn next_request_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let seq = NEXT_ID.fetch_add(1, Ordering::Relaxed) & 0xffff_ffff;
+    (u64::from(process::id()) << 32) | seq
 }
@@ -1,26 +1,491 @@
+//! The wire protocol shared by `KvsClient` and `KvsServer`.
+//!
+//! Every message is a JSON document sent in a frame with a 12-byte header:
+//!
+//! | bytes | field                                          |
+//! |-------|------------------------------------------------|
+//! | 0..2  | magic, `b"KV"`                                 |
+//! | 2     | protocol version, currently 1                  |
+//! | 3     | flags, see below                               |
+//! | 4..8  | length of the payload, big endian              |
+//! | 8..12 | CRC32 of the payload, big endian               |
+//!
+//! The only flag is `FLAG_COMPRESSED` (bit 0): the payload is zlib-compressed
+//! JSON. Peers always accept compressed frames, so a sender may compress any
+//! frame without negotiation; the server does it for large responses.
+//!
+//! The header is checked before the payload is decoded, so talking to
+//! something else than a kvs peer, or a damaged frame, fails with
+//! `KvsError::Protocol`.
+
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{ClientInfo, ErrorKind, KvsError, OpLatency, Result};
+
+const MAGIC: [u8; 2] = *b"KV";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 12;
+const FLAG_COMPRESSED: u8 = 0x01;
+
+/// Serializes `message` and writes it in a frame. It does not flush `writer`.
+///
+/// The payload is compressed if it is larger than `compress_above` bytes and
+/// compression makes it smaller.
+pub fn write_frame<W: Write, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+    compress_above: Option<usize>,
+) -> Result<()> {
+    let mut payload = serde_json::to_vec(message)?;
+    let mut flags = 0;
+    if matches!(compress_above, Some(threshold) if payload.len() > threshold) {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&payload)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < payload.len() {
+            payload = compressed;
+            flags |= FLAG_COMPRESSED;
+        }
+    }
+    let len = u32::try_from(payload.len())
+        .map_err(|_| KvsError::Protocol(format!("message of {} bytes", payload.len())))?;
+    let mut header = [0; HEADER_LEN];
+    header[0..2].copy_from_slice(&MAGIC);
+    header[2] = VERSION;
+    header[3] = flags;
+    header[4..8].copy_from_slice(&len.to_be_bytes());
+    header[8..12].copy_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads a frame and deserializes its payload.
+///
+/// Returns `None` if `reader` is at its end before the frame starts. Frames
+/// whose payload is larger than `max_len` bytes, before or after
+/// decompression, are rejected before the payload is read.
+pub fn read_frame<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    max_len: Option<usize>,
+) -> Result<Option<T>> {
+    let mut header = [0; HEADER_LEN];
+    let mut read = 0;
+    while read < HEADER_LEN {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(KvsError::Protocol("truncated frame header".to_owned())),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if header[0..2] != MAGIC {
+        return Err(KvsError::Protocol(format!(
+            "bad magic {:02x?}, the peer is not a kvs client or server",
+            &header[0..2]
+        )));
+    }
+    if header[2] != VERSION {
+        return Err(KvsError::Protocol(format!(
+            "unsupported protocol version {}, expected {}",
+            header[2], VERSION
+        )));
+    }
+    let flags = header[3];
+    if flags & !FLAG_COMPRESSED != 0 {
+        return Err(KvsError::Protocol(format!(
+            "unsupported flags {:#04x}",
+            flags
+        )));
+    }
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    let crc = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    if let Some(max_len) = max_len.filter(|&max_len| len as usize > max_len) {
+        return Err(KvsError::Protocol(format!(
+            "frame of {} bytes exceeds the limit of {} bytes",
+            len, max_len
+        )));
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            KvsError::Protocol(format!("truncated frame of {} bytes", len))
+        } else {
+            e.into()
+        }
+    })?;
+    if crc32fast::hash(&payload) != crc {
+        return Err(KvsError::Protocol("frame checksum mismatch".to_owned()));
+    }
+    if flags & FLAG_COMPRESSED != 0 {
+        let mut decompressed = Vec::new();
+        let limit = max_len.map_or(u64::MAX, |max_len| max_len as u64 + 1);
+        ZlibDecoder::new(&payload[..])
+            .take(limit)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| KvsError::Protocol(format!("bad compressed frame: {}", e)))?;
+        if let Some(max_len) = max_len.filter(|&max_len| decompressed.len() > max_len) {
+            return Err(KvsError::Protocol(format!(
+                "frame exceeds the limit of {} bytes once decompressed",
+                max_len
+            )));
+        }
+        payload = decompressed;
+    }
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+/// A request tagged with an ID chosen by the client, so that the logs of
+/// both sides can be attributed to the same request.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaggedRequest {
+    pub id: u64,
+    pub request: Request,
+    /// Whether the server may serve the request concurrently with the next
+    /// ones of the connection. Its response is then a `TaggedResponse`, which
+    /// may come before the responses to earlier requests.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub out_of_order: bool,
+}
+
+/// A response tagged with the ID of its request, sent to the requests which
+/// may be answered out of order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaggedResponse<R> {
+    pub id: u64,
+    pub response: R,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Set { key: String, value: String },
-    Get { key: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Remove {
+        key: String,
+    },
+    RemoveIfExists {
+        key: String,
+    },
+    Stats,
+    SetNx {
+        key: String,
+        value: String,
+    },
+    SetXx {
+        key: String,
+        value: String,
+    },
+    Expire {
+        key: String,
+        ttl_millis: u64,
+    },
+    Persist {
+        key: String,
+    },
+    SetBytes {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    GetBytes {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    RemoveBytes {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Replaces the data set with the backup in directory `from`, relative to
+    /// the backup root of the server.
+    Restore {
+        from: String,
+    },
+    /// Compacts the data set now.
+    Compact,
+    /// Lists the connections served by the server.
+    ClientList,
+    /// Closes the connection with the given ID.
+    ClientKill {
+        id: u64,
+    },
+    /// Makes the server reject writes, or accept them again.
+    SetReadOnly {
+        read_only: bool,
+    },
+    /// Begins, commits or rolls back the transaction of the connection. The
+    /// get, set and remove requests of a connection with a transaction go
+    /// through it.
+    Transaction {
+        op: TransactionOp,
+    },
+}
+
+/// An operation on the transaction of a connection.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum TransactionOp {
+    Begin,
+    Commit,
+    Rollback,
+}
+
+impl Request {
+    /// Name of the operation, used in logs.
+    pub fn op(&self) -> &'static str {
+        match self {
+            Request::Set { .. } => "set",
+            Request::Get { .. } => "get",
+            Request::Remove { .. } => "remove",
+            Request::RemoveIfExists { .. } => "remove_if_exists",
+            Request::Stats => "stats",
+            Request::SetNx { .. } => "set_nx",
+            Request::SetXx { .. } => "set_xx",
+            Request::Expire { .. } => "expire",
+            Request::Persist { .. } => "persist",
+            Request::SetBytes { .. } => "set_bytes",
+            Request::GetBytes { .. } => "get_bytes",
+            Request::RemoveBytes { .. } => "remove_bytes",
+            Request::Restore { .. } => "restore",
+            Request::Compact => "compact",
+            Request::ClientList => "client_list",
+            Request::ClientKill { .. } => "client_kill",
+            Request::SetReadOnly { .. } => "set_read_only",
+            Request::Transaction { .. } => "transaction",
+        }
+    }
+
+    /// The key the request operates on, if any. Binary keys are converted
+    /// lossily.
+    pub fn key(&self) -> Option<Cow<'_, str>> {
+        match self {
+            Request::Set { key, .. }
+            | Request::Get { key }
+            | Request::Remove { key }
+            | Request::RemoveIfExists { key }
+            | Request::SetNx { key, .. }
+            | Request::SetXx { key, .. }
+            | Request::Expire { key, .. }
+            | Request::Persist { key } => Some(Cow::Borrowed(key)),
+            Request::SetBytes { key, .. }
+            | Request::GetBytes { key }
+            | Request::RemoveBytes { key } => Some(String::from_utf8_lossy(key)),
+            Request::Stats
+            | Request::Restore { .. }
+            | Request::Compact
+            | Request::ClientList
+            | Request::ClientKill { .. }
+            | Request::SetReadOnly { .. }
+            | Request::Transaction { .. } => None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetIfResponse {
+    Ok(bool),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExpireResponse {
+    Ok(bool),
+    Err(RemoteError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum GetBytesResponse {
+    Ok(Option<ByteBuf>),
+    Err(RemoteError),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum RemoveResponse {
     Ok(()),
-    Err(String),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RemoveIfExistsResponse {
+    Ok(bool),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RestoreResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum CompactResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse {
+    Ok(Vec<OpLatency>),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientListResponse {
+    Ok(Vec<ClientInfo>),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientKillResponse {
+    Ok(bool),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SetReadOnlyResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransactionResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+/// The error of a response, with its kind so that clients tell transient
+/// errors from permanent ones without parsing the message.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RemoteError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl From<KvsError> for RemoteError {
+    fn from(err: KvsError) -> Self {
+        RemoteError {
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Converts the error of a response back to an error, restoring the errors
+/// callers are expected to match on. Errors of no specific kind come back as
+/// `KvsError::StringError`; the others keep their kind.
+pub fn remote_error(err: RemoteError) -> KvsError {
+    match err.kind {
+        ErrorKind::KeyNotFound => KvsError::KeyNotFound,
+        ErrorKind::DiskFull => KvsError::DiskFull,
+        ErrorKind::ReadOnly => KvsError::ReadOnly,
+        ErrorKind::Conflict => KvsError::TransactionConflict,
+        ErrorKind::Busy => KvsError::ServerBusy,
+        ErrorKind::PermissionDenied => KvsError::PermissionDenied(err.message),
+        ErrorKind::Other => KvsError::StringError(err.message),
+        kind => KvsError::Remote {
+            kind,
+            message: err.message,
+        },
+    }
 }
+
+/// A response to a request.
+pub trait Response {
+    /// Returns `true` if the request failed.
+    fn is_err(&self) -> bool;
+}
+
+/// Any of the responses, serialized as the response it holds.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum AnyResponse {
+    Set(SetResponse),
+    SetIf(SetIfResponse),
+    Expire(ExpireResponse),
+    Get(GetResponse),
+    GetBytes(GetBytesResponse),
+    Remove(RemoveResponse),
+    RemoveIfExists(RemoveIfExistsResponse),
+    Restore(RestoreResponse),
+    Compact(CompactResponse),
+    Stats(StatsResponse),
+    ClientList(ClientListResponse),
+    ClientKill(ClientKillResponse),
+    SetReadOnly(SetReadOnlyResponse),
+    Transaction(TransactionResponse),
+}
+
+macro_rules! impl_response {
+    ($($variant:ident($response:ident)),*) => {
+        $(
+            impl Response for $response {
+                fn is_err(&self) -> bool {
+                    matches!(self, $response::Err(_))
+                }
+            }
+
+            impl From<$response> for AnyResponse {
+                fn from(response: $response) -> Self {
+                    AnyResponse::$variant(response)
+                }
+            }
+        )*
+
+        impl Response for AnyResponse {
+            fn is_err(&self) -> bool {
+                match self {
+                    $(AnyResponse::$variant(response) => response.is_err(),)*
+                }
+            }
+        }
+    };
+}
+
+impl_response!(
+    Set(SetResponse),
+    SetIf(SetIfResponse),
+    Expire(ExpireResponse),
+    Get(GetResponse),
+    GetBytes(GetBytesResponse),
+    Remove(RemoveResponse),
+    RemoveIfExists(RemoveIfExistsResponse),
+    Restore(RestoreResponse),
+    Compact(CompactResponse),
+    Stats(StatsResponse),
+    ClientList(ClientListResponse),
+    ClientKill(ClientKillResponse),
+    SetReadOnly(SetReadOnlyResponse),
+    Transaction(TransactionResponse)
+);
@@ -0,0 +1,192 @@
+//! This module provides tools to inspect the files of a `KvStore`, to diagnose
+//! corrupted data directories.
+
+use std::fs;
+use std::path::Path;
+
+use crate::engines::{decompress_lossy, Command, Keyring, Manifest, RawRecord, SegmentSummary};
+use crate::vfs::OsFs;
+use crate::Result;
+
+/// A record of a log file, as found by `dump_log`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    /// Offset of the record in the file.
+    pub offset: u64,
+    /// Length of the record in bytes.
+    pub len: u64,
+    /// CRC32 of the bytes of the record.
+    pub checksum: u32,
+    /// The command of the record: `set`, `remove`, `expire`, `remove-range`
+    /// or `append`, the `begin` or `commit` marker of a batch, `encrypted` if
+    /// the record is encrypted, or `invalid` if the record cannot be decoded.
+    pub op: &'static str,
+    /// The key of the command, if the record is a valid command.
+    pub key: Option<String>,
+    /// Length of the value of a `set` command.
+    pub value_len: Option<usize>,
+    /// The log sequence number of the command, 0 if it has none.
+    pub lsn: u64,
+    /// Why the record cannot be decoded.
+    pub error: Option<String>,
+}
+
+impl LogRecord {
+    /// Returns whether the record could be decoded.
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The summary of a compacted log file, as found by `segments`.
+#[derive(Debug)]
+pub struct SegmentInfo {
+    /// Generation number of the log file, e.g. 3 for 3.log.
+    pub gen: u64,
+    /// Number of keys in the log file.
+    pub keys: u64,
+    /// The smallest key of the log file.
+    pub min_key: Option<String>,
+    /// The largest key of the log file.
+    pub max_key: Option<String>,
+    summary: SegmentSummary,
+}
+
+impl SegmentInfo {
+    /// Returns `false` if the log file does not have `key`, from its key range
+    /// and bloom filter. `true` means it probably has it.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.summary.may_contain(key)
+    }
+}
+
+/// Reads the summaries of the compacted log files of the data directory `dir`
+/// from its manifest, without replaying the log.
+///
+/// The log files written since the last compaction are not summarized, so the
+/// number of keys is an estimate.
+///
+/// # Errors
+///
+/// It propagates I/O or deserialization errors from reading the manifest. A
+/// directory that was never compacted has no summaries.
+pub fn segments(dir: impl AsRef<Path>) -> Result<Vec<SegmentInfo>> {
+    let dir = dir.as_ref();
+    let manifest = Manifest::read(&OsFs, dir)?;
+    Ok(manifest
+        .segments
+        .into_iter()
+        // The manifest may be older than the last compaction if it failed to be written.
+        .filter(|summary| dir.join(format!("{}.log", summary.gen)).exists())
+        .map(|summary| SegmentInfo {
+            gen: summary.gen,
+            keys: summary.keys,
+            min_key: summary.min_key.clone(),
+            max_key: summary.max_key.clone(),
+            summary,
+        })
+        .collect())
+}
+
+/// Decodes every record of the log file at `path`.
+///
+/// Decoding stops at the first invalid record, which spans the rest of the
+/// file, since the following records cannot be located. The records of a
+/// compressed log file are decoded from its decompressed bytes, up to its
+/// first damaged block, and located in them.
+///
+/// # Errors
+///
+/// It propagates I/O errors from reading the file. Invalid records are not
+/// errors.
+pub fn dump_log(path: impl AsRef<Path>) -> Result<Vec<LogRecord>> {
+    let path = path.as_ref();
+    // The generation is only reported in errors, e.g. 0 for a file not named
+    // like a log file.
+    let gen = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse().ok())
+        .unwrap_or(0);
+    let (bytes, _, _) = decompress_lossy(fs::read(path)?);
+    let mut records = Vec::new();
+    let mut reader = &bytes[..];
+
+    loop {
+        let pos = bytes.len() - reader.len();
+        let record = match read_record(&mut reader, gen, pos as u64) {
+            Ok(None) => break,
+            Ok(Some((None, lsn))) => {
+                let new_pos = bytes.len() - reader.len();
+                LogRecord {
+                    offset: pos as u64,
+                    len: (new_pos - pos) as u64,
+                    checksum: crc32fast::hash(&bytes[pos..new_pos]),
+                    op: "encrypted",
+                    key: None,
+                    value_len: None,
+                    lsn,
+                    error: None,
+                }
+            }
+            Ok(Some((Some(cmd), lsn))) => {
+                let new_pos = bytes.len() - reader.len();
+                let (op, key, value_len) = match cmd {
+                    Command::Set { key, value, .. } => ("set", Some(key), Some(value.len())),
+                    Command::Remove { key, .. } => ("remove", Some(key), None),
+                    Command::Expire { key, .. } => ("expire", Some(key), None),
+                    Command::Begin { .. } => ("begin", None, None),
+                    Command::Commit { .. } => ("commit", None, None),
+                    Command::RemoveRange { .. } => ("remove-range", None, None),
+                    Command::Append { key, suffix, .. } => {
+                        ("append", Some(key), Some(suffix.len()))
+                    }
+                };
+                LogRecord {
+                    offset: pos as u64,
+                    len: (new_pos - pos) as u64,
+                    checksum: crc32fast::hash(&bytes[pos..new_pos]),
+                    op,
+                    key,
+                    value_len,
+                    lsn,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                records.push(LogRecord {
+                    offset: pos as u64,
+                    len: (bytes.len() - pos) as u64,
+                    checksum: crc32fast::hash(&bytes[pos..]),
+                    op: "invalid",
+                    key: None,
+                    value_len: None,
+                    lsn: 0,
+                    error: Some(e.to_string()),
+                });
+                break;
+            }
+        };
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Reads the next record of `reader` like `Command::read_record`, without the
+/// command of an encrypted record, as the keys are not known.
+fn read_record(
+    reader: &mut &[u8],
+    gen: u64,
+    offset: u64,
+) -> Result<Option<(Option<Command>, u64)>> {
+    let record = match RawRecord::read(reader, gen, offset)? {
+        Some(record) => record,
+        None => return Ok(None),
+    };
+    if record.key_version().is_some() {
+        return Ok(Some((None, record.lsn())));
+    }
+    let (command, lsn) = record.decode(gen, offset, &Keyring::default())?;
+    Ok(Some((Some(command), lsn)))
+}
@@ -0,0 +1,73 @@
+/// A write of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BatchOp {
+    Set { key: String, value: Vec<u8> },
+    Remove { key: String },
+}
+
+impl BatchOp {
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+        }
+    }
+}
+
+/// Sets and removes applied together with `KvsEngine::write_batch`.
+///
+/// The writes are applied in the order they were added, so a later write of
+/// a key wins. Removing a missing key is not an error.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// # fn main() -> kvs::Result<()> {
+/// let store = KvStore::open("data")?;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("from".to_owned(), "90".to_owned())
+///     .set("to".to_owned(), "110".to_owned())
+///     .remove("pending".to_owned());
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the value of `key` to a string.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key, value.into_bytes())
+    }
+
+    /// Sets the value of `key` to bytes, like `KvsEngine::set_bytes`.
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    /// Removes `key`, if it exists.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
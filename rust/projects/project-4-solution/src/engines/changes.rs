@@ -0,0 +1,281 @@
+//! This module provides the change feed of a `KvStore`, read from its log
+//! files by `KvStore::changes_since`.
+
+use std::collections::VecDeque;
+use std::ops::Bound;
+
+use super::kvs::KvStore;
+use super::record::Command;
+use super::segment::{self, log_path, sorted_gen_list, BufReaderWithPos, LogFile};
+use crate::{KvsError, Result};
+
+/// A change of a `KvStore`, yielded by `KvStore::changes_since`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// `key` was set to `value`.
+    Set {
+        /// The sequence number of the write.
+        lsn: u64,
+        /// The key set.
+        key: String,
+        /// The value, as bytes.
+        value: Vec<u8>,
+    },
+    /// `key` was removed.
+    Remove {
+        /// The sequence number of the write.
+        lsn: u64,
+        /// The key removed.
+        key: String,
+    },
+    /// `suffix` was appended to the value of `key`, see `KvStore::append_with_lsn`.
+    Append {
+        /// The sequence number of the write.
+        lsn: u64,
+        /// The key appended to.
+        key: String,
+        /// The bytes appended.
+        suffix: Vec<u8>,
+    },
+    /// Every key in `start..end` was removed, see `KvStore::delete_range`.
+    RemoveRange {
+        /// The sequence number of the write.
+        lsn: u64,
+        /// The start of the range removed.
+        start: Bound<String>,
+        /// The end of the range removed.
+        end: Bound<String>,
+    },
+}
+
+impl Change {
+    /// Returns the log sequence number of the write.
+    pub fn lsn(&self) -> u64 {
+        match self {
+            Change::Set { lsn, .. }
+            | Change::Remove { lsn, .. }
+            | Change::Append { lsn, .. }
+            | Change::RemoveRange { lsn, .. } => *lsn,
+        }
+    }
+
+    /// Returns the key changed, or `None` for a range removed.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            Change::Set { key, .. } | Change::Remove { key, .. } | Change::Append { key, .. } => {
+                Some(key)
+            }
+            Change::RemoveRange { .. } => None,
+        }
+    }
+
+    /// Returns the change made by `command`, if it is a set or a remove
+    /// stamped after `lsn`.
+    fn from_command(command: Command, lsn: u64) -> Option<Change> {
+        match command {
+            Command::Set {
+                key,
+                value,
+                stamp: Some(stamp),
+                ..
+            } if stamp.seq > lsn => Some(Change::Set {
+                lsn: stamp.seq,
+                key,
+                value,
+            }),
+            Command::Remove {
+                key,
+                stamp: Some(stamp),
+                ..
+            } if stamp.seq > lsn => Some(Change::Remove {
+                lsn: stamp.seq,
+                key,
+            }),
+            Command::RemoveRange {
+                start,
+                end,
+                stamp: Some(stamp),
+            } if stamp.seq > lsn => Some(Change::RemoveRange {
+                lsn: stamp.seq,
+                start,
+                end,
+            }),
+            Command::Append {
+                key,
+                suffix,
+                stamp: Some(stamp),
+                ..
+            } if stamp.seq > lsn => Some(Change::Append {
+                lsn: stamp.seq,
+                key,
+                suffix,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// An iterator over the changes of a `KvStore` after a log sequence number,
+/// returned by `KvStore::changes_since`.
+pub struct Changes<'a> {
+    store: &'a KvStore,
+    /// The sequence number of the last change yielded, or the one given.
+    lsn: u64,
+    /// The log file read, none before the first one.
+    file: Option<ChangesFile>,
+    /// The changes read but not yielded yet.
+    ready: VecDeque<Change>,
+}
+
+struct ChangesFile {
+    gen: u64,
+    reader: BufReaderWithPos<LogFile>,
+    /// The end of the records flushed, if it is the active log file.
+    end: Option<u64>,
+}
+
+impl<'a> Changes<'a> {
+    /// Returns the changes of `store` after `lsn`.
+    pub(crate) fn new(store: &'a KvStore, lsn: u64) -> Self {
+        Changes {
+            store,
+            lsn,
+            file: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Reads the log files until changes are ready. Returns `false` once the
+    /// records flushed are all read.
+    fn fill(&mut self) -> Result<bool> {
+        // The number of records of the batch read and their changes.
+        let mut batch: Option<(u32, Vec<Option<Change>>)> = None;
+        loop {
+            let command = match &mut self.file {
+                Some(file) if file.end.is_none_or(|end| file.reader.pos < end) => {
+                    let pos = file.reader.pos;
+                    Command::read_from(&mut file.reader, file.gen, pos, &self.store.reader.keyring)?
+                }
+                _ => None,
+            };
+            let command = match command {
+                Some(command) => command,
+                None => match self.advance()? {
+                    Advance::Same => continue,
+                    Advance::Next => {
+                        // It was cut short by a crash.
+                        batch = None;
+                        continue;
+                    }
+                    Advance::End => return Ok(false),
+                },
+            };
+
+            if let Some((count, changes)) = &mut batch {
+                match command {
+                    Command::Set { .. } | Command::Remove { .. }
+                        if changes.len() < *count as usize =>
+                    {
+                        changes.push(Change::from_command(command, self.lsn));
+                        continue;
+                    }
+                    Command::Commit { count: committed }
+                        if committed == *count && changes.len() == *count as usize =>
+                    {
+                        self.ready.extend(changes.drain(..).flatten());
+                        batch = None;
+                        if !self.ready.is_empty() {
+                            return Ok(true);
+                        }
+                        continue;
+                    }
+                    // It was cut short by a crash.
+                    _ => batch = None,
+                }
+            }
+            match command {
+                Command::Begin { count } => batch = Some((count, Vec::new())),
+                command => {
+                    if let Some(change) = Change::from_command(command, self.lsn) {
+                        self.ready.push_back(change);
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves past the records read: to the records flushed since to the
+    /// active log file, or to the next log file.
+    fn advance(&mut self) -> Result<Advance> {
+        let writer = self.store.writer.lock().unwrap();
+        if let Some(file) = &mut self.file {
+            if file.end.is_some() {
+                if file.gen != writer.current_gen {
+                    // It was rotated since: its records are all flushed.
+                    file.end = None;
+                    return Ok(Advance::Same);
+                }
+                if writer.writer.pos > file.reader.pos {
+                    file.end = Some(writer.writer.pos);
+                    return Ok(Advance::Same);
+                }
+                return Ok(Advance::End);
+            }
+        }
+        // The log files between the last one read and the last compaction
+        // file were deleted by the compaction.
+        let after = self.file.as_ref().map(|file| file.gen);
+        let dropped = match after {
+            Some(after) => writer.compacted_gen > after + 1,
+            None => writer.compacted_seq > self.lsn,
+        };
+        if dropped {
+            return Err(KvsError::ChangesUnavailable);
+        }
+        let compacting = writer.compaction.as_ref().map(|compaction| compaction.gen);
+        let next = sorted_gen_list(&*writer.vfs, &writer.path)?
+            .into_iter()
+            .find(|&gen| after.is_none_or(|after| gen > after) && Some(gen) != compacting);
+        let gen = match next {
+            Some(gen) => gen,
+            None => return Ok(Advance::End),
+        };
+        let reader =
+            BufReaderWithPos::new(segment::open(&*writer.vfs, &log_path(&writer.path, gen))?)?;
+        let end = if gen == writer.current_gen {
+            Some(writer.writer.pos)
+        } else {
+            None
+        };
+        self.file = Some(ChangesFile { gen, reader, end });
+        Ok(Advance::Next)
+    }
+}
+
+/// Where `Changes::advance` moved to.
+enum Advance {
+    /// Further into the same log file.
+    Same,
+    /// To the start of the next log file.
+    Next,
+    /// Nowhere: every record flushed was read.
+    End,
+}
+
+impl Iterator for Changes<'_> {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ready.is_empty() {
+            match self.fill() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let change = self.ready.pop_front()?;
+        self.lsn = change.lsn();
+        Some(Ok(change))
+    }
+}
@@ -0,0 +1,80 @@
+//! This module provides the checkpoint file of a `KvStore` data directory,
+//! which holds the index and the rest of the in-memory state of the store as
+//! of a position in its log, so that opening the store only replays the log
+//! written after it.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::vfs::Vfs;
+use crate::Result;
+
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// The first byte of the checkpoint file. It is followed by the CRC32C of the
+/// state as a little-endian `u32`, then by the state encoded with bincode.
+const CHECKPOINT_FORMAT_V1: u8 = 1;
+const HEADER_LEN: usize = 5;
+
+pub(crate) fn checkpoint_path(dir: &Path) -> PathBuf {
+    dir.join(CHECKPOINT_FILE)
+}
+
+/// Reads the checkpoint of `dir`.
+///
+/// Returns `None` if there is none, or if it is corrupted.
+pub(crate) fn read<T: DeserializeOwned>(vfs: &dyn Vfs, dir: &Path) -> Result<Option<T>> {
+    let path = checkpoint_path(dir);
+    let mut bytes = Vec::new();
+    match vfs.open(&path) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.len() < HEADER_LEN || bytes[0] != CHECKPOINT_FORMAT_V1 {
+        warn!("Ignoring {:?}: unknown format", path);
+        return Ok(None);
+    }
+    let checksum = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    let payload = &bytes[HEADER_LEN..];
+    if crc32c::crc32c(payload) != checksum {
+        warn!("Ignoring {:?}: checksum mismatch", path);
+        return Ok(None);
+    }
+    match bincode::DefaultOptions::new().deserialize(payload) {
+        Ok(state) => Ok(Some(state)),
+        Err(e) => {
+            warn!("Ignoring {:?}: {}", path, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Replaces the checkpoint of `dir` with `state`, through a temporary file
+/// renamed once synced.
+pub(crate) fn write<T: Serialize>(vfs: &dyn Vfs, dir: &Path, state: &T) -> Result<()> {
+    let payload = bincode::DefaultOptions::new().serialize(state)?;
+    let path = checkpoint_path(dir);
+    let tmp_path = path.with_extension("tmp");
+    let _ = vfs.remove_file(&tmp_path);
+    let mut file = vfs.open_append(&tmp_path)?;
+    file.write_all(&[CHECKPOINT_FORMAT_V1])?;
+    file.write_all(&crc32c::crc32c(&payload).to_le_bytes())?;
+    file.write_all(&payload)?;
+    file.sync_data()?;
+    io_fail_point!("kvs::checkpoint::rename");
+    vfs.rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Removes the checkpoint of `dir`, if any.
+pub(crate) fn remove(vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+    match vfs.remove_file(&checkpoint_path(dir)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
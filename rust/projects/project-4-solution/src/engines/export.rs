@@ -0,0 +1,205 @@
+//! This module provides the formats of `KvStore::export` and
+//! `KvStore::import`, which write and read one key-value pair per record.
+
+use std::io::{self, BufRead, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+const CSV_HEADER: [&str; 2] = ["key", "value"];
+
+/// The format of the pairs written by `KvStore::export` and read by
+/// `KvStore::import`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A JSON object per line, e.g. `{"key":"a","value":"1"}`.
+    JsonLines,
+    /// Comma-separated values as in RFC 4180, with a `key,value` header.
+    /// Fields with a comma, a quote or a line break are quoted.
+    Csv,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    key: String,
+    value: String,
+}
+
+/// Writes the key-value pairs of `pairs` to `writer`, and returns their number.
+pub(crate) fn export_pairs<W: Write>(
+    mut writer: W,
+    format: ExportFormat,
+    pairs: impl Iterator<Item = Result<(String, String)>>,
+) -> Result<u64> {
+    if format == ExportFormat::Csv {
+        write_csv_record(&mut writer, CSV_HEADER[0], CSV_HEADER[1])?;
+    }
+    let mut count = 0;
+    for pair in pairs {
+        let (key, value) = pair?;
+        match format {
+            ExportFormat::JsonLines => {
+                let pair = Pair { key, value };
+                serde_json::to_writer(&mut writer, &pair)?;
+                writer.write_all(b"\n")?;
+            }
+            ExportFormat::Csv => write_csv_record(&mut writer, &key, &value)?,
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+fn write_csv_record<W: Write>(writer: &mut W, key: &str, value: &str) -> io::Result<()> {
+    write_csv_field(writer, key)?;
+    writer.write_all(b",")?;
+    write_csv_field(writer, value)?;
+    writer.write_all(b"\r\n")
+}
+
+fn write_csv_field<W: Write>(writer: &mut W, field: &str) -> io::Result<()> {
+    if field.contains([',', '"', '\r', '\n']) {
+        write!(writer, "\"{}\"", field.replace('"', "\"\""))
+    } else {
+        writer.write_all(field.as_bytes())
+    }
+}
+
+/// An iterator over the key-value pairs read from a reader in an
+/// `ExportFormat`.
+pub(crate) struct ImportPairs<R> {
+    reader: R,
+    format: ExportFormat,
+    line: u64,
+    started: bool,
+}
+
+impl<R: BufRead> ImportPairs<R> {
+    pub(crate) fn new(reader: R, format: ExportFormat) -> Self {
+        ImportPairs {
+            reader,
+            format,
+            line: 0,
+            started: false,
+        }
+    }
+
+    fn next_json(&mut self) -> Result<Option<(String, String)>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            // Blank lines, e.g. a trailing one, are skipped.
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        let pair: Pair = serde_json::from_str(&line)
+            .map_err(|e| invalid(self.line, &format!("invalid JSON pair: {}", e)))?;
+        Ok(Some((pair.key, pair.value)))
+    }
+
+    fn next_csv(&mut self) -> Result<Option<(String, String)>> {
+        if !self.started {
+            self.started = true;
+            match self.read_csv_record()? {
+                Some(header) if header == CSV_HEADER => {}
+                _ => return Err(invalid(1, "missing `key,value` header")),
+            }
+        }
+        let line = self.line + 1;
+        match self.read_csv_record()? {
+            None => Ok(None),
+            Some(mut fields) if fields.len() == 2 => {
+                let value = fields.pop().unwrap();
+                let key = fields.pop().unwrap();
+                Ok(Some((key, value)))
+            }
+            Some(fields) => Err(invalid(
+                line,
+                &format!("expected 2 fields, found {}", fields.len()),
+            )),
+        }
+    }
+
+    /// Reads the fields of the next record, which spans several lines if a
+    /// quoted field has line breaks. Blank lines are skipped.
+    fn read_csv_record(&mut self) -> Result<Option<Vec<String>>> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                if in_quotes {
+                    return Err(invalid(self.line, "unterminated quoted field"));
+                }
+                return Ok(None);
+            }
+            self.line += 1;
+            if !in_quotes && fields.is_empty() && line.trim_end_matches(['\r', '\n']).is_empty() {
+                continue;
+            }
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if in_quotes => {
+                        if chars.peek() == Some(&'"') {
+                            chars.next();
+                            field.push('"');
+                        } else {
+                            in_quotes = false;
+                        }
+                    }
+                    '"' if field.is_empty() && !quoted => {
+                        in_quotes = true;
+                        quoted = true;
+                    }
+                    _ if in_quotes => field.push(c),
+                    ',' => {
+                        fields.push(std::mem::take(&mut field));
+                        quoted = false;
+                    }
+                    '\r' if chars.peek() == Some(&'\n') => {}
+                    '\n' => {
+                        fields.push(field);
+                        return Ok(Some(fields));
+                    }
+                    _ if quoted => return Err(invalid(self.line, "text after a quoted field")),
+                    _ => field.push(c),
+                }
+            }
+            // The last line may lack a line break.
+            if !in_quotes {
+                fields.push(field);
+                return Ok(Some(fields));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for ImportPairs<R> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            ExportFormat::JsonLines => self.next_json(),
+            ExportFormat::Csv => self.next_csv(),
+        }
+        .transpose()
+    }
+}
+
+fn invalid(line: u64, message: &str) -> KvsError {
+    KvsError::InvalidImport {
+        line,
+        message: message.to_owned(),
+    }
+}
@@ -0,0 +1,479 @@
+//! This module provides the hint files of a `KvStore` data directory. Every
+//! compaction file `<gen>.log` gets a `<gen>.hint` file listing the key,
+//! position and length of each of its records without the values, so that
+//! opening the store rebuilds the index from the hints rather than by
+//! deserializing every command of the log file.
+//!
+//! The hints are sorted by key and stored in blocks, with an index of the
+//! blocks at the end of the file, so that the index of a store with a memory
+//! budget looks up the keys of the compaction file in its hint file.
+
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use super::history::Stamp;
+use super::manifest::BloomFilter;
+use crate::vfs::{Vfs, VfsFile};
+use crate::Result;
+
+/// The first byte of a hint file in the first format. It is followed by the
+/// length of the log file it describes and the CRC32C of the hints, both
+/// little-endian, then by the hints in log order encoded with bincode.
+const HINT_FORMAT_V1: u8 = 1;
+const HEADER_LEN: usize = 13;
+/// The first byte of a hint file in the current format. It is followed by the
+/// length of the log file it describes as a little-endian `u64`, then by the
+/// blocks of hints sorted by key, each a bincode list, then by the footer
+/// encoded with bincode, its length and its CRC32C as little-endian `u32`s.
+const HINT_FORMAT_V2: u8 = 2;
+const V2_HEADER_LEN: usize = 9;
+const TRAILER_LEN: usize = 8;
+/// The number of hints after which a block ends, at the next key.
+const BLOCK_HINTS: usize = 128;
+
+/// A record of a log file, without its value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Hint {
+    pub(crate) key: String,
+    pub(crate) pos: u64,
+    pub(crate) len: u64,
+    pub(crate) kind: HintKind,
+}
+
+/// The command of a record, with the fields of `Command` but the value. An
+/// expiry also has the log sequence number of its record, as it has no stamp.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum HintKind {
+    Set {
+        expires_at: Option<u64>,
+        stamp: Option<Stamp>,
+    },
+    Remove {
+        removed_at: Option<u64>,
+        stamp: Option<Stamp>,
+    },
+    Expire {
+        expires_at: Option<u64>,
+        lsn: u64,
+    },
+    /// The start of a batch of `count` records. Markers have an empty key.
+    Begin {
+        count: u32,
+    },
+    /// The commit marker of a batch.
+    Commit {
+        count: u32,
+    },
+    /// The removal of a range of keys. Like markers, it has an empty key.
+    RemoveRange {
+        start: Bound<String>,
+        end: Bound<String>,
+        stamp: Option<Stamp>,
+    },
+    /// An "append" command, which only the active log files have.
+    Append {
+        expires_at: Option<u64>,
+        stamp: Option<Stamp>,
+    },
+}
+
+/// The end of a hint file, which describes its blocks.
+#[derive(Serialize, Deserialize)]
+struct Footer {
+    /// Whether every key has a single hint, of a "set" command without a
+    /// stamp, so that the log file alone has no trash or history to replay.
+    plain: bool,
+    /// The number of keys whose last hint is of a "set" command.
+    live_keys: u64,
+    /// A bloom filter of those keys.
+    bloom: BloomFilter,
+    blocks: Vec<BlockRef>,
+}
+
+/// A block of a hint file. The hints of a key are never split across blocks.
+#[derive(Serialize, Deserialize)]
+struct BlockRef {
+    first_key: String,
+    offset: u64,
+    len: u32,
+    checksum: u32,
+}
+
+pub(crate) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// Reads the hints of the log file `gen` of `dir`, which is `log_len` bytes
+/// long.
+///
+/// Returns `None` if there is no hint file, or if it cannot be trusted: it is
+/// corrupted, or describes a log file of another length.
+pub(crate) fn read(vfs: &dyn Vfs, dir: &Path, gen: u64, log_len: u64) -> Result<Option<Vec<Hint>>> {
+    let path = hint_path(dir, gen);
+    let mut bytes = Vec::new();
+    match vfs.open(&path) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.first() == Some(&HINT_FORMAT_V2) {
+        return Ok(read_v2(&path, &bytes, log_len));
+    }
+    if bytes.len() < HEADER_LEN || bytes[0] != HINT_FORMAT_V1 {
+        warn!("Ignoring {:?}: unknown format", path);
+        return Ok(None);
+    }
+    let mut field = [0; 8];
+    field.copy_from_slice(&bytes[1..9]);
+    if u64::from_le_bytes(field) != log_len {
+        warn!("Ignoring {:?}: it describes another log file", path);
+        return Ok(None);
+    }
+    let checksum = u32::from_le_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]);
+    let payload = &bytes[HEADER_LEN..];
+    if crc32c::crc32c(payload) != checksum {
+        warn!("Ignoring {:?}: checksum mismatch", path);
+        return Ok(None);
+    }
+    match bincode::DefaultOptions::new().deserialize(payload) {
+        Ok(hints) => Ok(Some(hints)),
+        Err(e) => {
+            warn!("Ignoring {:?}: {}", path, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Returns the hints of a hint file in the current format read whole into
+/// `bytes`, or `None` if it cannot be trusted.
+fn read_v2(path: &Path, bytes: &[u8], log_len: u64) -> Option<Vec<Hint>> {
+    let parsed = if bytes.len() < V2_HEADER_LEN + TRAILER_LEN {
+        Err("truncated")
+    } else {
+        let trailer = &bytes[bytes.len() - TRAILER_LEN..];
+        let footer_end = bytes.len() - TRAILER_LEN;
+        footer_start(footer_end as u64, trailer)
+            .ok_or("truncated")
+            .and_then(|start| {
+                parse_footer(
+                    &bytes[..V2_HEADER_LEN],
+                    &bytes[start as usize..footer_end],
+                    trailer,
+                    log_len,
+                )
+            })
+    };
+    let footer = match parsed {
+        Ok(footer) => footer,
+        Err(reason) => {
+            warn!("Ignoring {:?}: {}", path, reason);
+            return None;
+        }
+    };
+    let mut hints = Vec::new();
+    for block in &footer.blocks {
+        let start = block.offset as usize;
+        match bytes
+            .get(start..start + block.len as usize)
+            .and_then(|payload| decode_block(block, payload))
+        {
+            Some(block_hints) => hints.extend(block_hints),
+            None => {
+                warn!("Ignoring {:?}: corrupted block", path);
+                return None;
+            }
+        }
+    }
+    Some(hints)
+}
+
+/// Returns the position of the footer of a hint file in the current format,
+/// from its end and its trailer, or `None` if it is truncated.
+fn footer_start(footer_end: u64, trailer: &[u8]) -> Option<u64> {
+    let footer_len = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    footer_end
+        .checked_sub(u64::from(footer_len))
+        .filter(|&start| start >= V2_HEADER_LEN as u64)
+}
+
+/// Returns the footer of a hint file in the current format from its header,
+/// footer and trailer bytes, checking that it describes a log file `log_len`
+/// bytes long.
+fn parse_footer(
+    header: &[u8],
+    footer: &[u8],
+    trailer: &[u8],
+    log_len: u64,
+) -> std::result::Result<Footer, &'static str> {
+    let mut field = [0; 8];
+    field.copy_from_slice(&header[1..9]);
+    if u64::from_le_bytes(field) != log_len {
+        return Err("it describes another log file");
+    }
+    let checksum = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc32c::crc32c(footer) != checksum {
+        return Err("checksum mismatch");
+    }
+    bincode::DefaultOptions::new()
+        .deserialize(footer)
+        .map_err(|_| "corrupted footer")
+}
+
+/// Returns the hints of `block` from its bytes, or `None` if they are
+/// corrupted.
+fn decode_block(block: &BlockRef, payload: &[u8]) -> Option<Vec<Hint>> {
+    if crc32c::crc32c(payload) != block.checksum {
+        return None;
+    }
+    bincode::DefaultOptions::new().deserialize(payload).ok()
+}
+
+/// Writes the hints of the log file `gen` of `dir`, which is `log_len` bytes
+/// long, through a temporary file renamed once synced.
+///
+/// The hints are given in log order, and written sorted by key. The hints of
+/// a key stay in log order, which is all the replay needs from a compaction
+/// file: it has no batches.
+pub(crate) fn write(
+    vfs: &dyn Vfs,
+    dir: &Path,
+    gen: u64,
+    log_len: u64,
+    mut hints: Vec<Hint>,
+) -> Result<()> {
+    hints.sort_by(|a, b| a.key.cmp(&b.key));
+    let path = hint_path(dir, gen);
+    let tmp_path = path.with_extension("hint.tmp");
+    let _ = vfs.remove_file(&tmp_path);
+    let mut file = BufWriter::new(vfs.open_append(&tmp_path)?);
+    file.write_all(&[HINT_FORMAT_V2])?;
+    file.write_all(&log_len.to_le_bytes())?;
+
+    let mut footer = Footer {
+        plain: true,
+        live_keys: 0,
+        bloom: BloomFilter::new(hints.len() as u64),
+        blocks: Vec::new(),
+    };
+    let mut offset = V2_HEADER_LEN as u64;
+    let mut start = 0;
+    while start < hints.len() {
+        // Extend the block to the last hint of the key it ends with.
+        let mut end = (start + BLOCK_HINTS).min(hints.len());
+        while end < hints.len() && hints[end].key == hints[end - 1].key {
+            end += 1;
+        }
+        let block = &hints[start..end];
+        for (i, hint) in block.iter().enumerate() {
+            let last_of_key = block.get(i + 1).is_none_or(|next| next.key != hint.key);
+            if !last_of_key || !matches!(hint.kind, HintKind::Set { stamp: None, .. }) {
+                footer.plain = false;
+            }
+            if last_of_key && matches!(hint.kind, HintKind::Set { .. }) {
+                footer.live_keys += 1;
+                footer.bloom.insert(&hint.key);
+            }
+        }
+        let payload = bincode::DefaultOptions::new().serialize(block)?;
+        file.write_all(&payload)?;
+        footer.blocks.push(BlockRef {
+            first_key: block[0].key.clone(),
+            offset,
+            len: payload.len() as u32,
+            checksum: crc32c::crc32c(&payload),
+        });
+        offset += payload.len() as u64;
+        start = end;
+    }
+    let payload = bincode::DefaultOptions::new().serialize(&footer)?;
+    file.write_all(&payload)?;
+    file.write_all(&(payload.len() as u32).to_le_bytes())?;
+    file.write_all(&crc32c::crc32c(&payload).to_le_bytes())?;
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_data()?;
+    io_fail_point!("kvs::hint::rename");
+    vfs.rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// A hint file opened to look up keys, which keeps its footer in memory and
+/// reads its blocks on demand.
+pub(crate) struct SortedHints {
+    gen: u64,
+    footer: Footer,
+    blocks: Mutex<BlockReader>,
+}
+
+struct BlockReader {
+    file: Box<dyn VfsFile>,
+    /// The index and the hints of the last block read.
+    cached: Option<(usize, Arc<Vec<Hint>>)>,
+}
+
+impl SortedHints {
+    /// Opens the hint file of the log file `gen` of `dir`, which is `log_len`
+    /// bytes long.
+    ///
+    /// Returns `None` if there is no hint file in the current format, or if
+    /// it cannot be trusted.
+    pub(crate) fn open(vfs: &dyn Vfs, dir: &Path, gen: u64, log_len: u64) -> Result<Option<Self>> {
+        let path = hint_path(dir, gen);
+        let mut file = match vfs.open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let file_len = vfs.file_len(&path)?;
+        let mut header = [0; V2_HEADER_LEN];
+        let mut trailer = [0; TRAILER_LEN];
+        if file_len < (V2_HEADER_LEN + TRAILER_LEN) as u64 {
+            return Ok(None);
+        }
+        file.read_exact(&mut header)?;
+        if header[0] != HINT_FORMAT_V2 {
+            return Ok(None);
+        }
+        let footer_end = file_len - TRAILER_LEN as u64;
+        file.seek(SeekFrom::Start(footer_end))?;
+        file.read_exact(&mut trailer)?;
+        let start = match footer_start(footer_end, &trailer) {
+            Some(start) => start,
+            None => {
+                warn!("Ignoring {:?}: truncated", path);
+                return Ok(None);
+            }
+        };
+        let mut footer = vec![0; (footer_end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut footer)?;
+        match parse_footer(&header, &footer, &trailer, log_len) {
+            Ok(footer) => Ok(Some(SortedHints {
+                gen,
+                footer,
+                blocks: Mutex::new(BlockReader { file, cached: None }),
+            })),
+            Err(reason) => {
+                warn!("Ignoring {:?}: {}", path, reason);
+                Ok(None)
+            }
+        }
+    }
+
+    /// The generation of the log file described.
+    pub(crate) fn gen(&self) -> u64 {
+        self.gen
+    }
+
+    /// Whether every key has a single hint, of a "set" command without a
+    /// stamp.
+    pub(crate) fn is_plain(&self) -> bool {
+        self.footer.plain
+    }
+
+    /// The number of keys whose last hint is of a "set" command.
+    pub(crate) fn live_keys(&self) -> u64 {
+        self.footer.live_keys
+    }
+
+    /// Returns the bytes of memory used by the footer.
+    pub(crate) fn memory(&self) -> u64 {
+        let blocks: u64 = self
+            .footer
+            .blocks
+            .iter()
+            .map(|block| (std::mem::size_of::<BlockRef>() + block.first_key.len()) as u64)
+            .sum();
+        blocks + self.footer.bloom.memory()
+    }
+
+    /// Returns the last hint of `key` if it is of a "set" command.
+    pub(crate) fn get(&self, key: &str) -> Result<Option<Hint>> {
+        if !self.footer.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let index = match self
+            .footer
+            .blocks
+            .partition_point(|block| block.first_key.as_str() <= key)
+        {
+            0 => return Ok(None),
+            index => index - 1,
+        };
+        let block = self.block(index)?;
+        let end = block.partition_point(|hint| hint.key.as_str() <= key);
+        Ok(match end.checked_sub(1).map(|last| &block[last]) {
+            Some(hint) if hint.key == key && matches!(hint.kind, HintKind::Set { .. }) => {
+                Some(hint.clone())
+            }
+            _ => None,
+        })
+    }
+
+    /// Returns the last hint of the first key in `start..` whose last hint is
+    /// of a "set" command.
+    pub(crate) fn next(&self, start: Bound<&str>) -> Result<Option<Hint>> {
+        let blocks = &self.footer.blocks;
+        let mut index = match start {
+            Bound::Included(key) | Bound::Excluded(key) => blocks
+                .partition_point(|block| block.first_key.as_str() <= key)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        while index < blocks.len() {
+            let block = self.block(index)?;
+            let mut i = match start {
+                Bound::Included(key) => block.partition_point(|hint| hint.key.as_str() < key),
+                Bound::Excluded(key) => block.partition_point(|hint| hint.key.as_str() <= key),
+                Bound::Unbounded => 0,
+            };
+            while i < block.len() {
+                let key = &block[i].key;
+                let end = i + block[i..]
+                    .iter()
+                    .take_while(|hint| hint.key == *key)
+                    .count();
+                if let HintKind::Set { .. } = block[end - 1].kind {
+                    return Ok(Some(block[end - 1].clone()));
+                }
+                i = end;
+            }
+            index += 1;
+        }
+        Ok(None)
+    }
+
+    fn block(&self, index: usize) -> Result<Arc<Vec<Hint>>> {
+        let mut reader = self.blocks.lock().unwrap();
+        if let Some((cached, hints)) = &reader.cached {
+            if *cached == index {
+                return Ok(Arc::clone(hints));
+            }
+        }
+        let block = &self.footer.blocks[index];
+        let mut payload = vec![0; block.len as usize];
+        reader.file.seek(SeekFrom::Start(block.offset))?;
+        reader.file.read_exact(&mut payload)?;
+        let hints = decode_block(block, &payload).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted hint block at offset {}", block.offset),
+            )
+        })?;
+        let hints = Arc::new(hints);
+        reader.cached = Some((index, Arc::clone(&hints)));
+        Ok(hints)
+    }
+}
+
+/// Removes the hint file of the log file `gen` of `dir`, if any.
+pub(crate) fn remove(vfs: &dyn Vfs, dir: &Path, gen: u64) -> Result<()> {
+    match vfs.remove_file(&hint_path(dir, gen)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
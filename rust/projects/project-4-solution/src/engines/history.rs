@@ -0,0 +1,193 @@
+//! This module provides the stamps and the versions of the writes kept for
+//! time-travel reads, and the file recording the retention of the history of a `KvStore` data
+//! directory and since when it is complete.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::keydir::{CommandPos, Keydir};
+use crate::vfs::Vfs;
+use crate::Result;
+
+const HISTORY_FILE: &str = "history.json";
+
+/// A point in the history of a store, read with `KvStore::get_as_of`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    /// Right after the write with the given sequence number, as returned by
+    /// `KvStore::last_seq`.
+    Seq(u64),
+    /// At the given time, after every write made until then.
+    Time(SystemTime),
+}
+
+impl AsOf {
+    /// Returns `true` if the write stamped with `stamp` was made by this point.
+    pub(crate) fn includes(self, stamp: Stamp) -> bool {
+        match self {
+            AsOf::Seq(seq) => stamp.seq <= seq,
+            AsOf::Time(time) => stamp.at <= millis(time),
+        }
+    }
+}
+
+/// The sequence number and the time in milliseconds since the Unix epoch of
+/// a write.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    pub(crate) seq: u64,
+    pub(crate) at: u64,
+}
+
+pub(crate) fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub(crate) fn history_path(dir: &Path) -> PathBuf {
+    dir.join(HISTORY_FILE)
+}
+
+/// The retention of the history of a data directory, and since when the
+/// history is complete.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct HistoryState {
+    pub(crate) start: Stamp,
+    pub(crate) retention_millis: u64,
+}
+
+impl HistoryState {
+    /// Reads the state of `dir`, if its history is retained.
+    pub(crate) fn read(vfs: &dyn Vfs, dir: &Path) -> Result<Option<HistoryState>> {
+        let mut bytes = Vec::new();
+        match vfs.open(&history_path(dir)) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    /// Replaces the state of `dir` with `state`, through a temporary file
+    /// renamed once synced, or removes it with `None`.
+    pub(crate) fn write(vfs: &dyn Vfs, dir: &Path, state: Option<HistoryState>) -> Result<()> {
+        let path = history_path(dir);
+        let state = match state {
+            Some(state) => state,
+            None => {
+                return match vfs.remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+                    _ => Ok(()),
+                }
+            }
+        };
+        let tmp_path = path.with_extension("json.tmp");
+        let _ = vfs.remove_file(&tmp_path);
+        let mut file = vfs.open_append(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(&state)?)?;
+        file.sync_data()?;
+        vfs.rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// A version of a key kept for `KvStore::get_as_of`.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub(crate) struct Version {
+    pub(crate) stamp: Stamp,
+    /// The "set" command of the value, `None` if the key did not exist.
+    pub(crate) pos: Option<CommandPos>,
+}
+
+/// The versions of the keys kept for `KvStore::get_as_of`.
+#[derive(Default)]
+pub(crate) struct History {
+    /// The retention and the start of the history, `None` if it is not retained.
+    pub(crate) state: Option<HistoryState>,
+    /// The versions of every key written since the start, each list preceded
+    /// by the version of the key at the start.
+    pub(crate) versions: BTreeMap<String, Vec<Version>>,
+    /// The sequence number of the last stamped write.
+    pub(crate) last_seq: u64,
+}
+
+impl History {
+    /// Adds a version of `key` stamped with `stamp`, or with the start of the
+    /// history for `None`. The first version of a key is preceded by its
+    /// version at the start, taken from `index`, which must not have the
+    /// version yet.
+    pub(crate) fn push(
+        &mut self,
+        index: &Keydir,
+        key: &str,
+        stamp: Option<Stamp>,
+        pos: Option<CommandPos>,
+    ) -> Result<()> {
+        let start = match self.state {
+            Some(state) => state.start,
+            None => return Ok(()),
+        };
+        let stamp = stamp.unwrap_or(start);
+        let versions = self.versions.entry(key.to_owned()).or_default();
+        if versions.is_empty() && stamp.seq > start.seq {
+            versions.push(Version {
+                stamp: start,
+                pos: index.get(key)?,
+            });
+        }
+        versions.push(Version { stamp, pos });
+        Ok(())
+    }
+}
+
+/// The last versions of the keys kept for `KvStore::get_at_version`.
+pub(crate) struct Versions {
+    /// The number of versions kept per key, 0 if none.
+    pub(crate) max: usize,
+    /// The versions of every key written since the last compaction, oldest first.
+    keys: HashMap<String, VecDeque<Version>>,
+}
+
+impl Versions {
+    pub(crate) fn new(max: usize) -> Self {
+        Versions {
+            max,
+            keys: HashMap::new(),
+        }
+    }
+
+    /// Adds a version of `key`, dropping its oldest one beyond `max`.
+    pub(crate) fn push(&mut self, key: &str, stamp: Stamp, pos: Option<CommandPos>) {
+        if self.max == 0 {
+            return;
+        }
+        let versions = self.keys.entry(key.to_owned()).or_default();
+        if versions.len() == self.max {
+            versions.pop_front();
+        }
+        versions.push_back(Version { stamp, pos });
+    }
+
+    /// Returns the version `seq` of `key`, `None` if it is not kept.
+    pub(crate) fn get(&self, key: &str, seq: u64) -> Option<Version> {
+        self.keys
+            .get(key)?
+            .iter()
+            .find(|version| version.stamp.seq == seq)
+            .copied()
+    }
+
+    /// Drops the versions but those of the values in the log files after
+    /// `gen`.
+    pub(crate) fn retain_after(&mut self, gen: u64) {
+        self.keys.retain(|_, versions| {
+            versions.retain(|version| matches!(version.pos, Some(pos) if pos.gen > gen));
+            !versions.is_empty()
+        });
+    }
+}
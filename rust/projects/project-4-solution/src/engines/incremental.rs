@@ -0,0 +1,171 @@
+//! This module provides the chains of incremental backups of a `KvStore`,
+//! taken with `KvStore::backup_incremental`. A chain is a directory holding a
+//! directory per increment, and a `chain.json` file listing the increments in
+//! order, along with the data files of the store when each was taken.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::vfs::Vfs;
+use crate::Result;
+
+const CHAIN_FILE: &str = "chain.json";
+const INCREMENT_PREFIX: &str = "increment-";
+const RESTORE_DIR: &str = "restore.tmp";
+
+/// The increments of a chain, oldest first.
+#[derive(Serialize, Deserialize, Default)]
+struct Chain {
+    increments: Vec<Increment>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Increment {
+    /// The name of the directory of the increment in the chain.
+    name: String,
+    /// The length of every data file of the store, by name.
+    files: BTreeMap<String, u64>,
+    /// The files in the directory of the increment. The others are in the
+    /// directories of earlier increments.
+    put: Vec<String>,
+}
+
+impl Chain {
+    fn read(dir: &Path) -> Result<Chain> {
+        match fs::read(dir.join(CHAIN_FILE)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Chain::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the `chain.json` file of `dir`, through a temporary file
+    /// renamed once synced.
+    fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(CHAIN_FILE);
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
+
+/// Adds an increment with the data files `files` of a store to the chain
+/// `dir`, which is created if needed. Returns the name of the increment.
+///
+/// The log files are named after their generation, which is never reused,
+/// and only ever grow, so those with the length they had in the previous
+/// increment are left out. The other data files are always put.
+pub(crate) fn put_increment(vfs: &dyn Vfs, files: &[(PathBuf, u64)], dir: &Path) -> Result<String> {
+    fs::create_dir_all(dir)?;
+    let mut chain = Chain::read(dir)?;
+    let previous = chain.increments.last().map(|increment| &increment.files);
+    let mut increment = Increment {
+        name: format!("{}{}", INCREMENT_PREFIX, chain.increments.len() + 1),
+        files: BTreeMap::new(),
+        put: Vec::new(),
+    };
+    let increment_dir = dir.join(&increment.name);
+    let tmp_dir = dir.join(format!("{}.tmp", increment.name));
+    // Left by a backup that failed before updating `chain.json`.
+    remove_dir_if_exists(&increment_dir)?;
+    remove_dir_if_exists(&tmp_dir)?;
+
+    let res = (|| -> Result<()> {
+        fs::create_dir(&tmp_dir)?;
+        for (path, len) in files {
+            let name = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .expect("data files have UTF-8 names");
+            increment.files.insert(name.to_owned(), *len);
+            let is_log = path.extension() == Some("log".as_ref());
+            if is_log && previous.and_then(|files| files.get(name)) == Some(len) {
+                continue;
+            }
+            let mut file = File::create(tmp_dir.join(name))?;
+            io::copy(&mut vfs.open(path)?.take(*len), &mut file)?;
+            file.sync_all()?;
+            increment.put.push(name.to_owned());
+        }
+        fs::rename(&tmp_dir, &increment_dir)?;
+        Ok(())
+    })();
+    if let Err(e) = res {
+        if let Err(e) = remove_dir_if_exists(&tmp_dir) {
+            warn!("Failed to remove {}: {}", tmp_dir.display(), e);
+        }
+        return Err(e);
+    }
+
+    let name = increment.name.clone();
+    chain.increments.push(increment);
+    chain.write(dir)?;
+    Ok(name)
+}
+
+/// Rebuilds the data directory of the store as of the last increment of the
+/// chain `dir`, in a temporary directory of the chain, and returns its path.
+/// The increments are applied in order, each replacing the files it has, then
+/// the files the store no longer had, e.g. deleted by a compaction, are
+/// removed.
+///
+/// The files are hard-linked rather than copied where possible, so the
+/// directory must only be read, then removed with `remove_rebuilt`.
+pub(crate) fn rebuild(dir: &Path) -> Result<PathBuf> {
+    let chain = Chain::read(dir)?;
+    let last = chain.increments.last().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no incremental backup in {}", dir.display()),
+        )
+    })?;
+    let rebuilt = dir.join(RESTORE_DIR);
+    remove_dir_if_exists(&rebuilt)?;
+    fs::create_dir(&rebuilt)?;
+
+    for increment in &chain.increments {
+        for name in &increment.put {
+            let src = dir.join(&increment.name).join(name);
+            let dest = rebuilt.join(name);
+            if dest.exists() {
+                fs::remove_file(&dest)?;
+            }
+            if fs::hard_link(&src, &dest).is_err() {
+                fs::copy(&src, &dest)?;
+            }
+        }
+    }
+    for entry in fs::read_dir(&rebuilt)? {
+        let path = entry?.path();
+        let kept = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| last.files.contains_key(name));
+        if !kept {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(rebuilt)
+}
+
+/// Removes the directory returned by `rebuild`.
+pub(crate) fn remove_rebuilt(rebuilt: &Path) {
+    if let Err(e) = remove_dir_if_exists(rebuilt) {
+        warn!("Failed to remove {}: {}", rebuilt.display(), e);
+    }
+}
+
+fn remove_dir_if_exists(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,195 @@
+//! This module provides the iterators over the key-value pairs of a
+//! `KvStore`, and the entry API of `KvStore::entry`.
+
+use std::ops::Bound;
+use std::sync::MutexGuard;
+
+use super::kvs::{now_millis, KvStore, KvStoreWriter, ALL_KEYS};
+use crate::Result;
+
+/// An iterator over the key-value pairs of a `KvStore`, returned by
+/// `KvStore::iter`.
+pub struct Iter<'a> {
+    pub(crate) store: &'a KvStore,
+    pub(crate) last_key: Option<String>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.store.next_pair(&mut self.last_key, &ALL_KEYS)
+    }
+}
+
+impl<'a> IntoIterator for &'a KvStore {
+    type Item = Result<(String, String)>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the keys of a `KvStore`, returned by `KvStore::keys`.
+pub struct Keys<'a> {
+    pub(crate) store: &'a KvStore,
+    pub(crate) last_key: Option<String>,
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        let index = &self.store.index;
+        let now = now_millis(&*self.store.clock);
+        loop {
+            let next = match &self.last_key {
+                None => index.next(Bound::Unbounded),
+                Some(last_key) => index.next(Bound::Excluded(last_key.as_str())),
+            };
+            let (key, cmd_pos) = match next {
+                Ok(next) => next?,
+                Err(e) => {
+                    error!("Failed to read the index: {}", e);
+                    return None;
+                }
+            };
+            let key = key.to_string();
+            self.last_key = Some(key.clone());
+            if !cmd_pos.is_expired(now) {
+                return Some(key);
+            }
+        }
+    }
+}
+
+/// An iterator over the key-value pairs of a `KvStore` with a key in a range,
+/// returned by `KvStore::scan`.
+pub struct Scan<'a> {
+    pub(crate) store: &'a KvStore,
+    pub(crate) range: (Bound<String>, Bound<String>),
+    pub(crate) last_key: Option<String>,
+}
+
+impl<'a> Iterator for Scan<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.store.next_pair(&mut self.last_key, &self.range)
+    }
+}
+
+/// An owning iterator over the key-value pairs of a `KvStore`.
+///
+/// Consuming a `KvStore` handle does not remove anything from the store; use
+/// `KvStore::drain` for that.
+pub struct IntoIter {
+    store: KvStore,
+    last_key: Option<String>,
+}
+
+impl Iterator for IntoIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.store.next_pair(&mut self.last_key, &ALL_KEYS)
+    }
+}
+
+impl IntoIterator for KvStore {
+    type Item = Result<(String, String)>;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> IntoIter {
+        IntoIter {
+            store: self,
+            last_key: None,
+        }
+    }
+}
+
+/// A draining iterator over the key-value pairs of a `KvStore`, returned by
+/// `KvStore::drain`.
+pub struct Drain<'a> {
+    pub(crate) store: &'a KvStore,
+    pub(crate) last_key: Option<String>,
+}
+
+impl<'a> Iterator for Drain<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let next = match &self.last_key {
+                None => self.store.index.next(Bound::Unbounded),
+                Some(last_key) => self.store.index.next(Bound::Excluded(last_key.as_str())),
+            };
+            let key = match next {
+                Ok(next) => next?.0.to_string(),
+                Err(e) => return Some(Err(e)),
+            };
+            self.last_key = Some(key.clone());
+            // The value is read and removed under the write lock, so a write
+            // made in between is never removed without being yielded.
+            match self.store.take(key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // Removed since we found it.
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Extend<(String, String)> for KvStore {
+    /// Sets every pair, writing them in batches.
+    ///
+    /// # Panics
+    ///
+    /// Panics on I/O or serialization errors, as `Extend` cannot report them.
+    /// Use `KvStore::open_from_iter` to handle them.
+    fn extend<I: IntoIterator<Item = (String, String)>>(&mut self, pairs: I) {
+        self.set_all(pairs).expect("unable to write to the store");
+    }
+}
+
+/// An entry of a `KvStore`, returned by `KvStore::entry`.
+pub struct Entry<'a> {
+    pub(crate) writer: MutexGuard<'a, KvStoreWriter>,
+    pub(crate) key: String,
+}
+
+impl<'a> Entry<'a> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Sets the value to `default` if the key is missing, and returns the value.
+    pub fn or_insert(self, default: String) -> Result<String> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Sets the value to the result of `default` if the key is missing, and
+    /// returns the value.
+    pub fn or_insert_with<F: FnOnce() -> String>(mut self, default: F) -> Result<String> {
+        match self.writer.get(&self.key)? {
+            Some(value) => Ok(value),
+            None => {
+                let value = default();
+                self.writer.set(self.key, value.clone())?;
+                Ok(value)
+            }
+        }
+    }
+
+    /// Updates the value with `f` if the key is present.
+    pub fn and_modify<F: FnOnce(&mut String)>(mut self, f: F) -> Result<Self> {
+        if let Some(mut value) = self.writer.get(&self.key)? {
+            f(&mut value);
+            self.writer.set(self.key.clone(), value)?;
+        }
+        Ok(self)
+    }
+}
@@ -0,0 +1,583 @@
+//! This module provides the index of a `KvStore`, from every key to the
+//! position of its live record.
+//!
+//! Without a memory budget, every entry is resident, in a skip list. With
+//! `KvStoreBuilder::index_budget`, the entries of the compaction file are
+//! left in its hint file, sorted by key, and looked up there. Only the entries
+//! written since the compaction, and the ones read while the index is under
+//! its budget, stay resident; the latter are evicted again when writes take
+//! the index over its budget.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::{Bound, Range};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockWriteGuard};
+
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+
+use super::hint::{Hint, HintKind, SortedHints};
+use crate::Result;
+
+/// Estimated bytes of memory used by a resident entry, besides its key: the
+/// skip-list node header, the boxed key, the slot, and a tower of two links
+/// on average.
+const ENTRY_OVERHEAD: u64 =
+    (3 * mem::size_of::<usize>() + mem::size_of::<Box<str>>() + mem::size_of::<Slot>()) as u64;
+
+/// A resident entry of the index.
+#[derive(Copy, Clone, PartialEq)]
+enum Slot {
+    Live(CommandPos),
+    /// A key removed since the compaction, which hides its entry in the
+    /// hint file.
+    Removed,
+}
+
+pub(crate) struct Keydir {
+    resident: SkipMap<Box<str>, Slot>,
+    /// The hint file of the compaction file, with a budget.
+    cold: RwLock<Option<SortedHints>>,
+    budget: Option<u64>,
+    /// The total length of the resident keys.
+    key_bytes: AtomicU64,
+    /// The number of keys of the hint file which are not resident.
+    cold_keys: AtomicU64,
+    /// The number of `Slot::Removed` entries.
+    removed: AtomicU64,
+    /// The number of keys expiring at each time, resident or not, so that
+    /// the expired keys are counted without reading the hint file.
+    expiries: Mutex<BTreeMap<u64, u64>>,
+    /// Held while the resident entries change, so that a reader only adds
+    /// an entry of the hint file if the writer did not add a newer one.
+    update: Mutex<()>,
+    /// The key after which the next eviction resumes.
+    hand: Mutex<Option<Box<str>>>,
+}
+
+impl Keydir {
+    /// Creates an empty index, kept under `budget` bytes of memory if any.
+    pub(crate) fn new(budget: Option<u64>) -> Self {
+        Keydir {
+            resident: SkipMap::new(),
+            cold: RwLock::new(None),
+            budget,
+            key_bytes: AtomicU64::new(0),
+            cold_keys: AtomicU64::new(0),
+            removed: AtomicU64::new(0),
+            expiries: Mutex::new(BTreeMap::new()),
+            update: Mutex::new(()),
+            hand: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn budget(&self) -> Option<u64> {
+        self.budget
+    }
+
+    /// Returns the number of keys.
+    pub(crate) fn len(&self) -> usize {
+        let resident =
+            (self.resident.len() as u64).saturating_sub(self.removed.load(Ordering::SeqCst));
+        (resident + self.cold_keys.load(Ordering::SeqCst)) as usize
+    }
+
+    /// Returns the number of keys expired at `now`, from memory alone.
+    pub(crate) fn expired(&self, now: u64) -> usize {
+        let expiries = self.expiries.lock().unwrap();
+        expiries.range(..=now).map(|(_, &keys)| keys).sum::<u64>() as usize
+    }
+
+    /// Returns the estimated bytes of memory used by the index, keys
+    /// included.
+    pub(crate) fn memory(&self) -> u64 {
+        let cold = self.cold.read().unwrap();
+        self.resident_memory() + cold.as_ref().map_or(0, SortedHints::memory)
+    }
+
+    fn resident_memory(&self) -> u64 {
+        self.key_bytes.load(Ordering::SeqCst) + self.resident.len() as u64 * ENTRY_OVERHEAD
+    }
+
+    /// Returns the position of `key`.
+    pub(crate) fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        if let Some(entry) = self.resident.get(key) {
+            return Ok(entry.value().live());
+        }
+        let cold = self.cold.read().unwrap();
+        lookup_cold(cold.as_ref(), key)
+    }
+
+    /// Returns the position of `key` like `get`, and keeps it resident if it
+    /// is read from the hint file while the index is under its budget.
+    pub(crate) fn get_hot(&self, key: &str) -> Result<Option<CommandPos>> {
+        if let Some(entry) = self.resident.get(key) {
+            return Ok(entry.value().live());
+        }
+        let cold = self.cold.read().unwrap();
+        let cmd_pos = lookup_cold(cold.as_ref(), key)?;
+        if let (Some(cmd_pos), Some(budget)) = (cmd_pos, self.budget) {
+            let under_budget =
+                self.resident_memory() + cold.as_ref().map_or(0, SortedHints::memory) < budget;
+            let _update = self.update.lock().unwrap();
+            if under_budget && self.resident.get(key).is_none() {
+                self.resident.insert(key.into(), Slot::Live(cmd_pos));
+                self.key_bytes.fetch_add(key.len() as u64, Ordering::SeqCst);
+                self.cold_keys.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        Ok(cmd_pos)
+    }
+
+    /// Points `key` to `cmd_pos`, and returns its previous position.
+    pub(crate) fn insert(&self, key: Box<str>, cmd_pos: CommandPos) -> Result<Option<CommandPos>> {
+        let cold = self.cold.read().unwrap();
+        let _update = self.update.lock().unwrap();
+        let previous = match self.resident.get(&*key).map(|entry| *entry.value()) {
+            Some(Slot::Live(previous)) => Some(previous),
+            Some(Slot::Removed) => {
+                self.removed.fetch_sub(1, Ordering::SeqCst);
+                None
+            }
+            None => {
+                self.key_bytes.fetch_add(key.len() as u64, Ordering::SeqCst);
+                let previous = lookup_cold(cold.as_ref(), &key)?;
+                if previous.is_some() {
+                    self.cold_keys.fetch_sub(1, Ordering::SeqCst);
+                }
+                previous
+            }
+        };
+        if let Some(previous) = &previous {
+            self.forget_expiry(previous);
+        }
+        self.note_expiry(&cmd_pos);
+        self.resident.insert(key, Slot::Live(cmd_pos));
+        Ok(previous)
+    }
+
+    /// Removes `key`, and returns its previous position.
+    pub(crate) fn remove(&self, key: &str) -> Result<Option<CommandPos>> {
+        let cold = self.cold.read().unwrap();
+        let _update = self.update.lock().unwrap();
+        match self.resident.get(key).map(|entry| *entry.value()) {
+            Some(Slot::Live(previous)) => {
+                self.forget_expiry(&previous);
+                if lookup_cold(cold.as_ref(), key)?.is_some() {
+                    self.resident.insert(key.into(), Slot::Removed);
+                    self.removed.fetch_add(1, Ordering::SeqCst);
+                } else {
+                    self.resident.remove(key);
+                    self.key_bytes.fetch_sub(key.len() as u64, Ordering::SeqCst);
+                }
+                Ok(Some(previous))
+            }
+            Some(Slot::Removed) => Ok(None),
+            None => {
+                let previous = lookup_cold(cold.as_ref(), key)?;
+                if let Some(previous) = &previous {
+                    self.forget_expiry(previous);
+                    self.resident.insert(key.into(), Slot::Removed);
+                    self.key_bytes.fetch_add(key.len() as u64, Ordering::SeqCst);
+                    self.removed.fetch_add(1, Ordering::SeqCst);
+                    self.cold_keys.fetch_sub(1, Ordering::SeqCst);
+                }
+                Ok(previous)
+            }
+        }
+    }
+
+    /// Returns the first key in `start..`, with its position.
+    pub(crate) fn next(&self, start: Bound<&str>) -> Result<Option<(Box<str>, CommandPos)>> {
+        let cold = self.cold.read().unwrap();
+        let mut start = start.map(Box::<str>::from);
+        loop {
+            let bound = start.as_ref().map(|key| &**key);
+            let resident = self.resident.lower_bound(bound);
+            let cold_hint = match cold.as_ref() {
+                Some(cold) => cold.next(bound)?.map(|hint| (cold.gen(), hint)),
+                None => None,
+            };
+            match (resident, cold_hint) {
+                (Some(entry), Some((_, hint))) if hint.key.as_str() >= entry.key().as_ref() => {
+                    match entry.value() {
+                        Slot::Live(cmd_pos) => return Ok(Some((entry.key().clone(), *cmd_pos))),
+                        Slot::Removed => start = Bound::Excluded(entry.key().clone()),
+                    }
+                }
+                (_, Some((gen, hint))) => {
+                    return Ok(Some((
+                        hint.key.as_str().into(),
+                        CommandPos::from_hint(gen, &hint),
+                    )))
+                }
+                (Some(entry), None) => match entry.value() {
+                    Slot::Live(cmd_pos) => return Ok(Some((entry.key().clone(), *cmd_pos))),
+                    Slot::Removed => start = Bound::Excluded(entry.key().clone()),
+                },
+                (None, None) => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns an iterator over the keys in `start..`, in key order, with
+    /// their positions. It does not see a snapshot.
+    pub(crate) fn iter_from(&self, start: Bound<&str>) -> Iter<'_> {
+        Iter {
+            keydir: self,
+            start: Some(start.map(Box::from)),
+        }
+    }
+
+    /// Evicts resident entries of the hint file until the index is back
+    /// under its budget, or none is left.
+    pub(crate) fn evict(&self) -> Result<()> {
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        let cold = self.cold.read().unwrap();
+        let cold = match cold.as_ref() {
+            Some(cold) => cold,
+            None => return Ok(()),
+        };
+        if self.resident_memory() + cold.memory() <= budget {
+            return Ok(());
+        }
+        // Leave some room, so that the next writes do not evict right away.
+        let target = (budget - budget / 10).saturating_sub(cold.memory());
+        self.evict_to(cold, target)
+    }
+
+    /// Evicts every resident entry of the hint file, whatever the budget.
+    pub(crate) fn shrink(&self) -> Result<()> {
+        let cold = self.cold.read().unwrap();
+        match cold.as_ref() {
+            Some(cold) => self.evict_to(cold, 0),
+            None => Ok(()),
+        }
+    }
+
+    /// Evicts resident entries of the hint file `cold`, from where the last
+    /// eviction stopped, until the resident entries take at most `target`
+    /// bytes or none is left.
+    fn evict_to(&self, cold: &SortedHints, target: u64) -> Result<()> {
+        let _update = self.update.lock().unwrap();
+        let mut hand = self.hand.lock().unwrap();
+        let mut visited = 0;
+        let total = self.resident.len();
+        while self.resident_memory() > target && visited < total {
+            let entry = match &*hand {
+                Some(key) => self.resident.lower_bound(Bound::Excluded(&**key)),
+                None => self.resident.front(),
+            };
+            let entry = match entry {
+                Some(entry) => entry,
+                None if hand.is_some() => {
+                    *hand = None;
+                    continue;
+                }
+                None => break,
+            };
+            visited += 1;
+            *hand = Some(entry.key().clone());
+            if let Slot::Live(cmd_pos) = *entry.value() {
+                if lookup_cold(Some(cold), entry.key())? == Some(cmd_pos) {
+                    self.resident.remove(entry.key());
+                    self.key_bytes
+                        .fetch_sub(entry.key().len() as u64, Ordering::SeqCst);
+                    self.cold_keys.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves the entries of the hint file `cold` in it, the index being
+    /// empty. The hints are read once for the expiries of the keys.
+    pub(crate) fn open_cold(&self, cold: SortedHints) -> Result<()> {
+        let mut current = self.cold.write().unwrap();
+        let mut start: Option<String> = None;
+        while let Some(hint) = cold.next(match &start {
+            Some(key) => Bound::Excluded(key.as_str()),
+            None => Bound::Unbounded,
+        })? {
+            self.note_expiry(&CommandPos::from_hint(cold.gen(), &hint));
+            start = Some(hint.key);
+        }
+        self.cold_keys.store(cold.live_keys(), Ordering::SeqCst);
+        *current = Some(cold);
+        Ok(())
+    }
+
+    /// Leaves the entries of the hint file `cold` in it, the index holding
+    /// every entry: those pointing where the hint file does are evicted, and
+    /// the keys of the hint file missing from the index are marked removed.
+    pub(crate) fn attach(&self, cold: SortedHints) -> Result<()> {
+        let mut current = self.cold.write().unwrap();
+        let _update = self.update.lock().unwrap();
+        let mut start: Option<String> = None;
+        while let Some(hint) = cold.next(match &start {
+            Some(key) => Bound::Excluded(key.as_str()),
+            None => Bound::Unbounded,
+        })? {
+            let cmd_pos = CommandPos::from_hint(cold.gen(), &hint);
+            match self
+                .resident
+                .get(hint.key.as_str())
+                .map(|entry| *entry.value())
+            {
+                Some(Slot::Live(resident)) if resident == cmd_pos => {
+                    self.resident.remove(hint.key.as_str());
+                    self.key_bytes
+                        .fetch_sub(hint.key.len() as u64, Ordering::SeqCst);
+                    self.cold_keys.fetch_add(1, Ordering::SeqCst);
+                }
+                Some(_) => {}
+                None => {
+                    self.resident
+                        .insert(hint.key.as_str().into(), Slot::Removed);
+                    self.key_bytes
+                        .fetch_add(hint.key.len() as u64, Ordering::SeqCst);
+                    self.removed.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            start = Some(hint.key);
+        }
+        *current = Some(cold);
+        Ok(())
+    }
+
+    /// Replaces every entry with the ones of `other`, which has no hint file.
+    pub(crate) fn replace(&self, other: Keydir) {
+        let mut cold = self.cold.write().unwrap();
+        let _update = self.update.lock().unwrap();
+        *cold = None;
+        self.resident.clear();
+        for entry in other.resident.iter() {
+            self.resident.insert(entry.key().clone(), *entry.value());
+        }
+        self.key_bytes
+            .store(other.key_bytes.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.cold_keys.store(0, Ordering::SeqCst);
+        self.removed.store(0, Ordering::SeqCst);
+        *self.expiries.lock().unwrap() = other.expiries.into_inner().unwrap();
+        *self.hand.lock().unwrap() = None;
+    }
+
+    /// Starts pointing the index to a compaction file, whose hint file is
+    /// `next` with a budget. Lookups of the hint file wait for it to finish.
+    pub(crate) fn swap(&self, next: Option<SortedHints>) -> Swap<'_> {
+        Swap {
+            keydir: self,
+            cold: self.cold.write().unwrap(),
+            _update: self.update.lock().unwrap(),
+            next,
+            removed: Vec::new(),
+            cold_keys: 0,
+        }
+    }
+
+    /// Counts the expiry of a key now at `cmd_pos`, if it has one.
+    fn note_expiry(&self, cmd_pos: &CommandPos) {
+        if let Some(expires_at) = cmd_pos.expires_at() {
+            *self.expiries.lock().unwrap().entry(expires_at).or_insert(0) += 1;
+        }
+    }
+
+    /// Stops counting the expiry of a key no longer at `cmd_pos`.
+    fn forget_expiry(&self, cmd_pos: &CommandPos) {
+        if let Some(expires_at) = cmd_pos.expires_at() {
+            let mut expiries = self.expiries.lock().unwrap();
+            if let Some(keys) = expiries.get_mut(&expires_at) {
+                *keys -= 1;
+                if *keys == 0 {
+                    expiries.remove(&expires_at);
+                }
+            }
+        }
+    }
+}
+
+/// The pointing of the index to a compaction file in progress, returned by
+/// `Keydir::swap`.
+pub(crate) struct Swap<'a> {
+    keydir: &'a Keydir,
+    cold: RwLockWriteGuard<'a, Option<SortedHints>>,
+    _update: MutexGuard<'a, ()>,
+    next: Option<SortedHints>,
+    /// The keys of the compaction file removed since they were copied.
+    removed: Vec<Box<str>>,
+    /// The number of keys left to the hint file of the compaction file.
+    cold_keys: u64,
+}
+
+impl<'a> Swap<'a> {
+    /// Returns the position of `key` before the compaction file.
+    pub(crate) fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        match self.keydir.resident.get(key) {
+            Some(entry) => Ok(entry.value().live()),
+            None => lookup_cold(self.cold.as_ref(), key),
+        }
+    }
+
+    /// Points `key` to its copy at `cmd_pos`, unless the hint file of the
+    /// compaction file can have it.
+    pub(crate) fn repoint(&mut self, key: Box<str>, cmd_pos: CommandPos) -> Result<()> {
+        let resident = self.keydir.resident.get(&*key).is_some();
+        if !resident && lookup_cold(self.next.as_ref(), &key)? == Some(cmd_pos) {
+            self.cold_keys += 1;
+            return Ok(());
+        }
+        if !resident {
+            self.keydir
+                .key_bytes
+                .fetch_add(key.len() as u64, Ordering::SeqCst);
+        }
+        self.keydir.resident.insert(key, Slot::Live(cmd_pos));
+        Ok(())
+    }
+
+    /// Marks `key` removed since it was copied.
+    pub(crate) fn removed(&mut self, key: Box<str>) {
+        if self.next.is_some() {
+            self.removed.push(key);
+        }
+    }
+
+    /// Removes `key`, which is not in the compaction file.
+    pub(crate) fn remove(&mut self, key: &str) -> Result<()> {
+        if let Some(cmd_pos) = self.get(key)? {
+            self.keydir.forget_expiry(&cmd_pos);
+        }
+        if let Some(entry) = self.keydir.resident.remove(key) {
+            self.keydir
+                .key_bytes
+                .fetch_sub(key.len() as u64, Ordering::SeqCst);
+            if *entry.value() == Slot::Removed {
+                self.keydir.removed.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the hint file of the index with the one of the compaction
+    /// file.
+    pub(crate) fn finish(mut self) {
+        let keydir = self.keydir;
+        // The removed keys hide the entries of the previous hint file.
+        for entry in keydir.resident.iter() {
+            if *entry.value() == Slot::Removed {
+                keydir.resident.remove(entry.key());
+                keydir
+                    .key_bytes
+                    .fetch_sub(entry.key().len() as u64, Ordering::SeqCst);
+            }
+        }
+        keydir
+            .removed
+            .store(self.removed.len() as u64, Ordering::SeqCst);
+        for key in self.removed.drain(..) {
+            keydir
+                .key_bytes
+                .fetch_add(key.len() as u64, Ordering::SeqCst);
+            keydir.resident.insert(key, Slot::Removed);
+        }
+        keydir.cold_keys.store(self.cold_keys, Ordering::SeqCst);
+        *self.cold = self.next.take();
+    }
+}
+
+/// An iterator over the keys of a `Keydir`, returned by `Keydir::iter_from`.
+pub(crate) struct Iter<'a> {
+    keydir: &'a Keydir,
+    /// The bound of the next key, `None` once the iterator is done.
+    start: Option<Bound<Box<str>>>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(Box<str>, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.start.take()?;
+        match self.keydir.next(start.as_ref().map(|key| &**key)) {
+            Ok(Some((key, cmd_pos))) => {
+                self.start = Some(Bound::Excluded(key.clone()));
+                Some(Ok((key, cmd_pos)))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Slot {
+    fn live(&self) -> Option<CommandPos> {
+        match self {
+            Slot::Live(cmd_pos) => Some(*cmd_pos),
+            Slot::Removed => None,
+        }
+    }
+}
+
+/// Returns the position of `key` in the hint file `cold`.
+fn lookup_cold(cold: Option<&SortedHints>, key: &str) -> Result<Option<CommandPos>> {
+    match cold {
+        Some(cold) => Ok(cold
+            .get(key)?
+            .map(|hint| CommandPos::from_hint(cold.gen(), &hint))),
+        None => Ok(None),
+    }
+}
+
+/// Represents the record of a command in the log.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct CommandPos {
+    /// Log files are named after a generation number.
+    /// `gen` gives us the log filename the command was stored.
+    pub(crate) gen: u64,
+    /// Position.
+    pub(crate) pos: u64,
+    /// Length.
+    pub(crate) len: u64,
+    /// Milliseconds since the Unix epoch after which the key is expired.
+    pub(crate) expires_at: Option<u64>,
+    /// Whether `expires_at` comes from an expiry record rather than from the
+    /// "set" command itself, or the command is an "append" command, so the
+    /// command must be rewritten on compaction.
+    pub(crate) expiry_changed: bool,
+}
+
+impl CommandPos {
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    /// Milliseconds since the Unix epoch after which the key is expired.
+    pub(crate) fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Returns the position of the record of the log file `gen` described by
+    /// `hint`, of a "set" command.
+    pub(crate) fn from_hint(gen: u64, hint: &Hint) -> Self {
+        let mut cmd_pos = CommandPos::from((gen, hint.pos..hint.pos + hint.len));
+        if let HintKind::Set { expires_at, .. } = hint.kind {
+            cmd_pos.expires_at = expires_at;
+        }
+        cmd_pos
+    }
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        Self {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            expires_at: None,
+            expiry_changed: false,
+        }
+    }
+}
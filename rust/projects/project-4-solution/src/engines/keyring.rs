@@ -0,0 +1,121 @@
+//! This module provides the encryption keys of a `KvStore`, which encrypt the
+//! payloads of its log records with XChaCha20-Poly1305. Every key has a
+//! version, written in the header of the records it encrypts, so that the
+//! records of older keys are still read after `KvStore::rotate_key`, until
+//! compactions encrypt them again with the new key.
+//!
+//! The keys themselves are never written to the data directory.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::{KvsError, Result};
+
+/// The length of an encryption key, in bytes.
+pub(crate) const KEY_LEN: usize = 32;
+/// The length of the random nonce starting an encrypted payload.
+const NONCE_LEN: usize = 24;
+
+/// The encryption keys of a store, by version. It is shared by the readers,
+/// the writer and the namespaces of the store, so that a rotation applies to
+/// all of them at once.
+#[derive(Default)]
+pub(crate) struct Keyring {
+    /// The last key encrypts the new records.
+    ciphers: RwLock<BTreeMap<u32, XChaCha20Poly1305>>,
+}
+
+impl Keyring {
+    pub(crate) fn new(keys: &BTreeMap<u32, [u8; KEY_LEN]>) -> Self {
+        let ciphers = keys
+            .iter()
+            .map(|(&version, key)| (version, XChaCha20Poly1305::new(key.into())))
+            .collect();
+        Keyring {
+            ciphers: RwLock::new(ciphers),
+        }
+    }
+
+    /// Returns the version of the key encrypting the new records, or `None`
+    /// if they are not encrypted.
+    pub(crate) fn current(&self) -> Option<u32> {
+        self.ciphers.read().unwrap().keys().next_back().copied()
+    }
+
+    /// Adds `key` with the version after the current one, and makes it
+    /// encrypt the new records. Returns its version.
+    pub(crate) fn rotate(&self, key: [u8; KEY_LEN]) -> Result<u32> {
+        let mut ciphers = self.ciphers.write().unwrap();
+        let version = match ciphers.keys().next_back() {
+            Some(&last) => last
+                .checked_add(1)
+                .ok_or_else(|| KvsError::StringError("no key version left".to_owned()))?,
+            None => 1,
+        };
+        ciphers.insert(version, XChaCha20Poly1305::new(&key.into()));
+        Ok(version)
+    }
+
+    /// Encrypts `payload` with the current key, authenticating `header` with
+    /// it. Returns the version of the key and the encrypted payload, starting
+    /// with its nonce, or `None` without a key.
+    pub(crate) fn seal(&self, header: &[u8], payload: &[u8]) -> Result<Option<(u32, Vec<u8>)>> {
+        let ciphers = self.ciphers.read().unwrap();
+        let (&version, cipher) = match ciphers.iter().next_back() {
+            Some(last) => last,
+            None => return Ok(None),
+        };
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: payload,
+                    aad: header,
+                },
+            )
+            .map_err(|_| KvsError::StringError("failed to encrypt a record".to_owned()))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(ciphertext);
+        Ok(Some((version, sealed)))
+    }
+
+    /// Decrypts `sealed`, a payload encrypted by `seal` with the key of
+    /// `version` along with `header`. `gen` and `offset` locate its record in
+    /// the log for `KvsError::Corruption`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyUnavailable` if the store has no key of
+    /// `version`, and `KvsError::Corruption` if the payload does not decrypt.
+    pub(crate) fn open(
+        &self,
+        version: u32,
+        header: &[u8],
+        sealed: &[u8],
+        gen: u64,
+        offset: u64,
+    ) -> Result<Vec<u8>> {
+        let ciphers = self.ciphers.read().unwrap();
+        let cipher = ciphers
+            .get(&version)
+            .ok_or(KvsError::KeyUnavailable { version })?;
+        if sealed.len() < NONCE_LEN {
+            return Err(KvsError::Corruption { gen, offset });
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| KvsError::Corruption { gen, offset })
+    }
+}
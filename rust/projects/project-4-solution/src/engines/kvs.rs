@@ -1,29 +1,666 @@
-use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::fmt;
+#[cfg(feature = "fs")]
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Range, RangeBounds};
 use std::path::{Path, PathBuf};
+#[cfg(feature = "mmap")]
+use std::sync::atomic::AtomicBool;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crossbeam_skiplist::SkipMap;
+use metrics::{counter, gauge};
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 
+use super::batch::{BatchOp, WriteBatch};
+use super::changes::Changes;
+use super::checkpoint;
+use super::export::{self, ExportFormat, ImportPairs};
+use super::hint::{self, SortedHints};
+use super::history::{history_path, millis, AsOf, History, HistoryState, Stamp, Version, Versions};
+#[cfg(feature = "fs")]
+use super::incremental;
+use super::iter::{Drain, Entry, Iter, Keys, Scan};
+use super::keydir::{CommandPos, Keydir};
+use super::keyring::{Keyring, KEY_LEN};
+use super::manifest::{manifest_path, Manifest, SegmentSummary};
+use super::marker::{check_engine, engine_path, KVS_ENGINE};
+use super::record::{record_key_version, Command};
+#[cfg(feature = "fs")]
+use super::repair::repair_logs;
+use super::replay::{read_hints, replay_all, replay_to};
+use super::restore::{
+    clear_staging, finish_restore, install_restored, remove_replaced, staging_dir, RestoreMarker,
+};
+use super::secondary::{indexes_path, SecondaryIndexes};
+use super::segment::{
+    self, log_gen, log_path, new_log_file, sorted_gen_list, BufReaderWithPos, BufWriterWithPos,
+    LogFile,
+};
+use super::snapshot::SNAPSHOT_PREFIX;
+#[cfg(feature = "fs")]
+use super::snapshot::{self, SnapshotRetention, SnapshotSchedule};
 use super::KvsEngine;
+use crate::backup::BackupTarget;
+#[cfg(feature = "fs")]
+use crate::backup::DirTarget;
+use crate::clock::Clock;
+#[cfg(feature = "fs")]
+use crate::clock::SystemClock;
+#[cfg(feature = "fs")]
+use crate::vfs::OsFs;
+use crate::vfs::Vfs;
 use crate::{KvsError, Result};
 
+/// The default of `KvStoreBuilder::compaction_threshold`.
 const COMPACTION_THRESHOLD: u64 = 1024;
+/// The longest chain of "append" commands a read follows, see
+/// `KvStore::append_with_lsn`.
+const MAX_APPEND_DEPTH: u32 = 32;
 
-/// The `KvStore` stores string key/value pairs.
+const METRIC_SETS: &str = "kvs_sets_total";
+const METRIC_GETS: &str = "kvs_gets_total";
+const METRIC_REMOVES: &str = "kvs_removes_total";
+const METRIC_COMPACTIONS: &str = "kvs_compactions_total";
+const METRIC_BYTES_WRITTEN: &str = "kvs_bytes_written_total";
+const METRIC_UNCOMPACTED_BYTES: &str = "kvs_uncompacted_bytes";
+const METRIC_COMPACTION_RECLAIMED_BYTES: &str = "kvs_compaction_reclaimed_bytes_total";
+
+/// How long the free disk space measured before a write is trusted.
+const FREE_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of pairs written per batch by `Extend`, `KvStore::open_from_iter`
+/// and `KvStore::import`.
+const BULK_BATCH_SIZE: usize = 1024;
+
+/// Progress of a compaction is reported every time this many more percent of
+/// the live entries have been copied.
+const COMPACTION_PROGRESS_STEP: u8 = 10;
+
+/// The subdirectory of a data directory holding the data directories of its
+/// namespaces, see `KvStore::namespace`.
+const NAMESPACES_DIR: &str = "namespaces";
+
+/// An event emitted while the log is being compacted.
+///
+/// Register a listener with `KvStore::on_compaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompactionEvent {
+    /// A compaction started.
+    Started {
+        /// Bytes of stale commands that triggered the compaction.
+        uncompacted_bytes: u64,
+        /// Number of live entries to copy.
+        entries: u64,
+    },
+    /// Some of the live entries have been copied to the compaction file.
+    Progress {
+        /// Percentage of the live entries copied so far.
+        percent: u8,
+        /// Bytes written to the compaction file so far.
+        bytes_copied: u64,
+    },
+    /// A compaction finished.
+    Finished {
+        /// Bytes freed on disk by deleting the stale log files.
+        bytes_reclaimed: u64,
+        /// Size of the compaction file.
+        bytes_written: u64,
+        /// Time spent compacting.
+        duration: Duration,
+    },
+    /// A compaction failed. The store remains usable and the stale log files
+    /// are compacted again next time.
+    Failed {
+        /// Description of the error.
+        error: String,
+    },
+}
+
+/// When `KvStore` syncs its writes to durable storage.
+///
+/// Every write is flushed to the OS before it is acknowledged, so it survives
+/// a crash of the process. The policy decides what survives a crash of the
+/// machine or a power loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are only synced by `KvsEngine::flush`. This is the default.
+    Manual,
+    /// Every write is synced before it is acknowledged.
+    Always,
+    /// A background thread syncs the writes every given number of
+    /// milliseconds, and when the store is closed. At most the writes of the
+    /// last interval can be lost.
+    EveryNMillis(u64),
+}
+
+/// The codec compressing the records of large values, set by
+/// `KvStoreBuilder::compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// LZ4, the faster one.
+    Lz4,
+    /// Snappy.
+    Snappy,
+}
+
+/// Where compactions put the stale log files rather than deleting them, set
+/// by `KvStore::set_segment_archive`, e.g. to keep them for point-in-time
+/// recovery or auditing.
+#[derive(Clone)]
+pub enum SegmentArchive {
+    /// Moves them to this directory, created if needed, under their name,
+    /// e.g. `3.log`. `KvStore::recover_to` replays them.
+    Dir(PathBuf),
+    /// Calls this with the generation and the path of every stale log file,
+    /// e.g. to upload it. The file is deleted once it returns `Ok`, unless it
+    /// moved it. On error, it is kept and archived again by the next
+    /// compaction.
+    Callback(ArchiveCallback),
+}
+
+impl SegmentArchive {
+    /// Archives the stale log files with `archive`, see `SegmentArchive::Callback`.
+    pub fn callback<F>(archive: F) -> Self
+    where
+        F: Fn(u64, &Path) -> io::Result<()> + Send + Sync + 'static,
+    {
+        SegmentArchive::Callback(Arc::new(archive))
+    }
+}
+
+impl fmt::Debug for SegmentArchive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SegmentArchive::Dir(dir) => f.debug_tuple("Dir").field(dir).finish(),
+            SegmentArchive::Callback(_) => f.write_str("Callback(..)"),
+        }
+    }
+}
+
+/// The entries read by `KvStore::warm_up`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WarmUp {
+    /// The entries of the given keys. Missing keys are ignored.
+    Keys(Vec<String>),
+    /// The entries whose key starts with the given prefix. An empty prefix
+    /// selects every entry.
+    Prefix(String),
+}
+
+/// How far `KvStore::open_with_progress` is in replaying the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct OpenProgress {
+    /// Number of log files replayed so far.
+    pub segments_replayed: u64,
+    /// Number of log files to replay.
+    pub segments_total: u64,
+    /// Bytes of the log files replayed so far.
+    pub bytes_replayed: u64,
+    /// Bytes of all the log files to replay.
+    pub bytes_total: u64,
+}
+
+/// Statistics of a `KvStore`, returned by `KvStore::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct StoreStats {
+    /// Number of keys in the index, including expired keys not yet compacted.
+    pub keys: u64,
+    /// Number of keys which have not expired, as counted by `KvStore::len`.
+    pub live_keys: u64,
+    /// Estimated bytes of memory used by the index, keys included.
+    pub index_bytes: u64,
+    /// Number of log files, the active one included.
+    pub segments: u64,
+    /// Bytes of all the files in the data directory.
+    pub disk_bytes: u64,
+    /// Bytes of stale commands the next compaction can delete.
+    pub uncompacted_bytes: u64,
+}
+
+/// What `KvStore::open_with_repair` salvaged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RepairReport {
+    /// Number of log files scanned.
+    pub segments_scanned: u64,
+    /// Number of log files rewritten without their damaged regions.
+    pub segments_repaired: u64,
+    /// Number of damaged regions dropped. A region may have held several
+    /// records, so it is the least number of entries lost.
+    pub entries_dropped: u64,
+    /// Bytes of the damaged regions dropped.
+    pub bytes_dropped: u64,
+}
+
+/// The compactions of a `KvStore` since it was opened, returned by
+/// `KvStore::compaction_stats`.
+///
+/// The bytes written by the compactions against the bytes written by the
+/// writes give the write amplification.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CompactionStats {
+    /// Number of compactions finished.
+    pub compactions: u64,
+    /// Number of compactions failed.
+    pub failures: u64,
+    /// Bytes freed on disk by all the compactions.
+    pub bytes_reclaimed: u64,
+    /// Bytes written to compaction files by all the compactions.
+    pub bytes_written: u64,
+    /// Number of expired entries dropped by all the compactions.
+    pub entries_expired: u64,
+    /// The last compaction finished, if any.
+    pub last: Option<CompactionReport>,
+}
+
+/// What a finished compaction did, see `CompactionStats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CompactionReport {
+    /// Bytes freed on disk by deleting the stale log files.
+    pub bytes_reclaimed: u64,
+    /// Size of the compaction file.
+    pub bytes_written: u64,
+    /// Time spent compacting, slices and writes in between included.
+    pub duration: Duration,
+    /// Number of live entries copied to the compaction file.
+    pub entries_copied: u64,
+    /// Number of expired entries dropped rather than copied.
+    pub entries_expired: u64,
+    /// Bytes of the expired entries dropped, part of `bytes_reclaimed`.
+    pub bytes_expired: u64,
+    /// Number of stale log files deleted, or archived with
+    /// `KvStore::set_segment_archive`.
+    pub segments_deleted: u64,
+}
+
+type CompactionListener = Box<dyn Fn(&CompactionEvent) + Send>;
+
+type ArchiveCallback = Arc<dyn Fn(u64, &Path) -> io::Result<()> + Send + Sync>;
+
+pub(crate) type ProgressCallback<'a> = dyn FnMut(&OpenProgress) + Send + 'a;
+
+/// Opens a `KvStore` with options, returned by `KvStore::builder`.
+///
+/// Every option defaults to what `KvStore::open` uses, and the ones which can
+/// be changed on an open store can also be set with the matching `KvStore`
+/// method.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{Durability, KvStore};
+/// # fn main() -> kvs::Result<()> {
+/// let store = KvStore::builder()
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .durability(Durability::EveryNMillis(100))
+///     .open("data")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default, Clone)]
+pub struct KvStoreBuilder {
+    vfs: Option<Arc<dyn Vfs>>,
+    clock: Option<Arc<dyn Clock>>,
+    compaction_threshold: Option<u64>,
+    compaction_slice_keys: Option<u64>,
+    compaction_slice_bytes: Option<u64>,
+    segment_size: Option<u64>,
+    compression: Option<(Compression, usize)>,
+    segment_compression: Option<Compression>,
+    #[cfg(feature = "mmap")]
+    mmap_reads: Option<bool>,
+    index_budget: Option<u64>,
+    keep_versions: usize,
+    durability: Option<Durability>,
+    min_free_space: u64,
+    trash_retention: Option<Duration>,
+    max_tombstone_age: Option<Duration>,
+    expiry_sweep_interval: Option<Duration>,
+    checkpoint_interval: Option<Duration>,
+    segment_archive: Option<SegmentArchive>,
+    encryption_keys: BTreeMap<u32, [u8; KEY_LEN]>,
+    /// The keys of the store opening a namespace or a recovered store, which
+    /// share its rotations, in place of `encryption_keys`.
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl KvStoreBuilder {
+    /// Sets the file system of the store. Defaults to the OS file system,
+    /// which is required without the `fs` feature.
+    pub fn vfs(mut self, vfs: Arc<dyn Vfs>) -> Self {
+        self.vfs = Some(vfs);
+        self
+    }
+
+    /// Sets the clock against which expiries are checked. Defaults to the
+    /// system clock, which is required without the `fs` feature.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Compacts the log once its stale commands take more than `bytes`.
+    /// Defaults to 1024, which keeps small test stores compact but rewrites
+    /// large stores very often.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = Some(bytes);
+        self
+    }
+
+    /// Compacts in slices of at most `keys` entries, one after every write,
+    /// rather than in one pass, so that a write waits for a slice rather than
+    /// for the whole compaction. A slice ends once either limit is reached.
+    /// Defaults to no limit.
+    ///
+    /// With a trash or a history retention, compactions still run in one
+    /// pass.
+    pub fn compaction_slice_keys(mut self, keys: u64) -> Self {
+        self.compaction_slice_keys = Some(keys);
+        self
+    }
+
+    /// Compacts in slices of about `bytes` copied, like
+    /// `KvStoreBuilder::compaction_slice_keys`. Defaults to no limit.
+    pub fn compaction_slice_bytes(mut self, bytes: u64) -> Self {
+        self.compaction_slice_bytes = Some(bytes);
+        self
+    }
+
+    /// Rolls over to a new log file once the active one takes `bytes`,
+    /// whether or not a compaction is due. A write is not split, so a file
+    /// may end past the limit by one write. Defaults to no limit.
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = Some(bytes);
+        self
+    }
+
+    /// Compresses the records setting values of at least `min_size` bytes
+    /// with `codec`, when it makes them smaller. The records are read whether
+    /// they are compressed or not, whatever the setting. Defaults to no
+    /// compression.
+    pub fn compression(mut self, codec: Compression, min_size: usize) -> Self {
+        self.compression = Some((codec, min_size));
+        self
+    }
+
+    /// Compresses the files written by compactions with `codec`, in blocks
+    /// read on demand, trading CPU on reads of compacted entries for disk
+    /// space. The active log file is never compressed. Compressed files are
+    /// read whatever the setting. Defaults to no compression.
+    pub fn segment_compression(mut self, codec: Compression) -> Self {
+        self.segment_compression = Some(codec);
+        self
+    }
+
+    /// Sets whether values are read through memory maps of the log files,
+    /// rather than through file handles with a seek and a read per value.
+    /// Compressed log files are always read through file handles. Defaults to
+    /// `true`.
+    #[cfg(feature = "mmap")]
+    pub fn mmap_reads(mut self, enabled: bool) -> Self {
+        self.mmap_reads = Some(enabled);
+        self
+    }
+
+    /// Keeps the memory of the index under about `bytes`, for stores with
+    /// more keys than fit in memory. Defaults to no budget: every entry of
+    /// the index is in memory.
+    ///
+    /// With a budget, the entries of the compaction file are looked up in its
+    /// hint file, which costs a read of a block of hints per lookup missing
+    /// the OS page cache. The entries written since the last compaction stay
+    /// in memory whatever the budget, as do the ones read while the index is
+    /// under it, until writes take it over. Opening the store holds every
+    /// entry in memory for a while, unless the compaction file kept neither
+    /// trash nor history and there is no checkpoint; so does restoring a
+    /// backup until the next compaction.
+    pub fn index_budget(mut self, bytes: u64) -> Self {
+        self.index_budget = Some(bytes);
+        self
+    }
+
+    /// Keeps the last `n` versions of every key until the next compaction,
+    /// for `KvStore::get_at_version`. Defaults to 0: only the current version
+    /// of a key is readable.
+    ///
+    /// The version of a key is the sequence number of the write which set or
+    /// removed it.
+    pub fn keep_versions(mut self, n: usize) -> Self {
+        self.keep_versions = n;
+        self
+    }
+
+    /// Sets when writes are synced, like `KvStore::set_durability`.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = Some(durability);
+        self
+    }
+
+    /// Sets the free disk space below which writes fail, like
+    /// `KvStore::set_min_free_space`.
+    pub fn min_free_space(mut self, bytes: u64) -> Self {
+        self.min_free_space = bytes;
+        self
+    }
+
+    /// Keeps removed values for `retention`, like
+    /// `KvStore::set_trash_retention`.
+    pub fn trash_retention(mut self, retention: Duration) -> Self {
+        self.trash_retention = Some(retention);
+        self
+    }
+
+    /// Archives the stale log files of compactions, like
+    /// `KvStore::set_segment_archive`.
+    pub fn segment_archive(mut self, archive: SegmentArchive) -> Self {
+        self.segment_archive = Some(archive);
+        self
+    }
+
+    /// Compacts the log once a tombstone is older than `max_age`, like
+    /// `KvStore::set_max_tombstone_age`.
+    pub fn max_tombstone_age(mut self, max_age: Duration) -> Self {
+        self.max_tombstone_age = Some(max_age);
+        self
+    }
+
+    /// Checks for expired entries to purge every `interval`, like
+    /// `KvStore::set_expiry_sweep_interval`.
+    pub fn expiry_sweep_interval(mut self, interval: Duration) -> Self {
+        self.expiry_sweep_interval = Some(interval);
+        self
+    }
+
+    /// Writes a checkpoint every `interval`, like
+    /// `KvStore::set_checkpoint_interval`.
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = Some(interval);
+        self
+    }
+
+    /// Adds the 256-bit encryption key `key` of version `version`.
+    ///
+    /// With keys, the payloads of the log records, with their keys and
+    /// values, are encrypted with XChaCha20-Poly1305 under the key of the
+    /// highest version, written in the record header. The records encrypted
+    /// under the other keys are still read, so every key rotated with
+    /// `KvStore::rotate_key` must be given again until no record uses it, and
+    /// reading a record of a missing version fails with
+    /// `KvsError::KeyUnavailable`. The records written without keys stay
+    /// readable too. A store with keys must be opened with the builder, e.g.
+    /// with `KvStoreBuilder::open_with_repair` rather than
+    /// `KvStore::open_with_repair`.
+    ///
+    /// Only the values are protected. The keys are written in the clear to
+    /// the hint files, the checkpoints and the manifest, whose minimum and
+    /// maximum keys and bloom filters of every log file reveal them too, so
+    /// keys must not hold secrets.
+    pub fn encryption_key(mut self, version: u32, key: [u8; 32]) -> Self {
+        self.encryption_keys.insert(version, key);
+        self
+    }
+
+    /// Opens the store with the given path, creating the directory if it does
+    /// not exist.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay, and
+    /// I/O errors if a background thread cannot be spawned.
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        self.open_with_progress(path, |_| {})
+    }
+
+    /// Opens the store like `KvStoreBuilder::open`, after salvaging its
+    /// damaged log files like `KvStore::open_with_repair`. The encrypted
+    /// records are checked against their checksum only.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the repair, and the errors of
+    /// `KvStoreBuilder::open`.
+    #[cfg(feature = "fs")]
+    pub fn open_with_repair(self, path: impl Into<PathBuf>) -> Result<(KvStore, RepairReport)> {
+        let path = path.into();
+        let vfs: Arc<dyn Vfs> = match &self.vfs {
+            Some(vfs) => Arc::clone(vfs),
+            None => Arc::new(OsFs),
+        };
+        vfs.create_dir_all(&path)?;
+        // The restored log files are repaired too.
+        finish_restore(&*vfs, &path)?;
+        let report = repair_logs(&*vfs, &path)?;
+        let store = self.open(path)?;
+        Ok((store, report))
+    }
+
+    /// Opens the store like `KvStoreBuilder::open`, reporting the progress of
+    /// the log replay like `KvStore::open_with_progress`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay, and
+    /// I/O errors if a background thread cannot be spawned.
+    pub fn open_with_progress<F>(self, path: impl Into<PathBuf>, mut progress: F) -> Result<KvStore>
+    where
+        F: FnMut(&OpenProgress) + Send,
+    {
+        let options = self.clone();
+        let vfs = match self.vfs {
+            Some(vfs) => vfs,
+            #[cfg(feature = "fs")]
+            None => Arc::new(OsFs),
+            #[cfg(not(feature = "fs"))]
+            None => return Err(KvsError::StringError("no file system to open".to_owned())),
+        };
+        let clock = match self.clock {
+            Some(clock) => clock,
+            #[cfg(feature = "fs")]
+            None => Arc::new(SystemClock),
+            #[cfg(not(feature = "fs"))]
+            None => return Err(KvsError::StringError("no clock to open".to_owned())),
+        };
+        let keyring = match self.keyring {
+            Some(keyring) => keyring,
+            None => Arc::new(Keyring::new(&self.encryption_keys)),
+        };
+        let mut store = KvStore::open_impl(
+            path.into(),
+            vfs,
+            clock,
+            self.index_budget,
+            self.keep_versions,
+            keyring,
+            &mut progress,
+        )?;
+        #[cfg(feature = "mmap")]
+        if let Some(enabled) = self.mmap_reads {
+            store.reader.maps.enabled.store(enabled, Ordering::SeqCst);
+        }
+        {
+            let mut writer = store.writer.lock().unwrap();
+            writer.compaction_threshold = self.compaction_threshold.unwrap_or(COMPACTION_THRESHOLD);
+            writer.compaction_slice_keys = self.compaction_slice_keys;
+            writer.compaction_slice_bytes = self.compaction_slice_bytes;
+            writer.segment_size = self.segment_size;
+            writer.compression = self.compression;
+            writer.segment_compression = self.segment_compression;
+            writer.min_free_space = self.min_free_space;
+            writer.trash_retention = self.trash_retention;
+            writer.segment_archive = self.segment_archive;
+        }
+        if let Some(durability) = self.durability {
+            store.set_durability(durability)?;
+        }
+        if self.max_tombstone_age.is_some() {
+            store.set_max_tombstone_age(self.max_tombstone_age)?;
+        }
+        if self.expiry_sweep_interval.is_some() {
+            store.set_expiry_sweep_interval(self.expiry_sweep_interval)?;
+        }
+        if self.checkpoint_interval.is_some() {
+            store.set_checkpoint_interval(self.checkpoint_interval)?;
+        }
+        store.options = Arc::new(options);
+        Ok(store)
+    }
+}
+
+/// The `KvStore` stores string keys with string or binary values.
 ///
 /// Key/value pairs are stored in memory and also persisted to disk in a log.
 /// Log files are named after monotonically increasing generation numbers with
 /// a `log` extension name. Index as a skip list in memory stores the keys and
 /// the value positions for fast query.
 ///
+/// `KvStore` is `Send` and `Sync`, so one instance can be shared between threads behind an
+/// `Arc`, e.g. as the state of a web server. Cloning it is cheap too, and the clones share the
+/// same index and log writer.
+///
+/// The store reports the following metrics through the `metrics` facade, so they
+/// are picked up by whatever exporter the application installs:
+///
+/// - `kvs_sets_total`, `kvs_gets_total`, `kvs_removes_total` (counters)
+/// - `kvs_compactions_total` (counter)
+/// - `kvs_bytes_written_total` (counter): bytes appended to the log, excluding compaction
+/// - `kvs_uncompacted_bytes` (gauge): bytes of stale commands a compaction would reclaim
+/// - `kvs_compaction_reclaimed_bytes_total` (counter): disk space freed by compactions
+///
+/// With the `failpoints` feature, failures and delays can be injected with the `fail` crate
+/// at the following points:
+///
+/// - `kvs::write`: before a command is appended to the log
+/// - `kvs::flush`: after a command is written to the buffer, before it is flushed
+/// - `kvs::write::after`: after a command is flushed, before the index is updated
+/// - `kvs::compaction::copy`: after the compaction file is created, before entries are copied
+/// - `kvs::compaction::remove_stale`: after the compaction file is flushed, before the stale
+///   log files are removed
+/// - `kvs::restore::install`: after the restored log files are staged, before they replace
+///   the current ones
+/// - `kvs::sync`, `kvs::sync::after`: before and after the log is synced
+/// - `kvs::compaction::sync`, `kvs::compaction::sync::after`: before and after the
+///   compaction file is synced
+/// - `kvs::bulk_load::sync`, `kvs::bulk_load::sync::after`: before and after the file of a
+///   bulk load is synced
+/// - `kvs::bulk_load::rename`: after the file of a bulk load is synced, before it is renamed
+///   into place
+/// - `kvs::repair::rename`, `kvs::restore::rename`: before a repaired or restored log file is
+///   renamed into place
+/// - `kvs::hint::rename`, `kvs::manifest::rename`, `kvs::checkpoint::rename`: after a hint
+///   file, the manifest or a checkpoint is synced, before it is renamed into place
+///
 /// Example:
 ///
 /// ```rust
@@ -40,14 +677,27 @@ pub struct KvStore {
     /// Directory for the log and other data
     path: Arc<PathBuf>,
     /// The log reader
-    reader: KvStoreReader,
-    /// The in-memory index from key to log pointer
-    index: Arc<SkipMap<String, CommandPos>>,
+    pub(crate) reader: KvStoreReader,
+    /// The index from key to log pointer
+    pub(crate) index: Arc<Keydir>,
+    /// Held exclusively while a batch updates the index, so lookups see all or none of it
+    batch_gate: Arc<RwLock<()>>,
+    /// Tells the time against which expiries are checked
+    pub(crate) clock: Arc<dyn Clock>,
     /// The log writer
-    writer: Arc<Mutex<KvStoreWriter>>,
+    pub(crate) writer: Arc<Mutex<KvStoreWriter>>,
+    /// The options the store was opened with, which its namespaces take
+    options: Arc<KvStoreBuilder>,
+    /// The namespaces opened so far, by name
+    namespaces: Arc<Mutex<HashMap<String, KvStore>>>,
 }
 
 impl KvStore {
+    /// Returns a builder to open a store with options.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
     /// Opens the store with the given path.
     ///
     /// This will create a new directory if the given one does not exist.
@@ -55,290 +705,3616 @@ impl KvStore {
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during the log replay.
+    #[cfg(feature = "fs")]
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+        Self::open_with_vfs(path, Arc::new(OsFs), Arc::new(SystemClock))
+    }
+
+    /// Opens the store with the given path like `KvStore::open`, calling
+    /// `progress` before the log is replayed and after every log file.
+    ///
+    /// Log files may be replayed concurrently, but `progress` is never called
+    /// concurrently with itself.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    #[cfg(feature = "fs")]
+    pub fn open_with_progress<F>(path: impl Into<PathBuf>, mut progress: F) -> Result<Self>
+    where
+        F: FnMut(&OpenProgress) + Send,
+    {
+        Self::open_impl(
+            path.into(),
+            Arc::new(OsFs),
+            Arc::new(SystemClock),
+            None,
+            0,
+            Arc::default(),
+            &mut progress,
+        )
+    }
+
+    /// Opens the store with the given path like `KvStore::open`, after
+    /// salvaging its damaged log files, e.g. after disk errors.
+    ///
+    /// Every log file is scanned record by record, ignoring the hint files.
+    /// A region which does not decode or fails its checksum is skipped up to
+    /// the next valid record, and a log file with such regions is rewritten
+    /// with its valid records only. A batch which lost a record is discarded
+    /// as a whole by the replay.
+    ///
+    /// The store is opened without encryption keys, see
+    /// `KvStoreBuilder::open_with_repair` for the others.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the repair, and the errors of
+    /// `KvStore::open`.
+    #[cfg(feature = "fs")]
+    pub fn open_with_repair(path: impl Into<PathBuf>) -> Result<(Self, RepairReport)> {
+        KvStore::builder().open_with_repair(path)
+    }
+
+    /// Opens the store with the given path on the given file system, using the
+    /// given clock.
+    ///
+    /// `KvStore::open` uses the OS file system and the system clock. An in-memory
+    /// file system and a manual clock make tests fast and deterministic.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_vfs(
+        path: impl Into<PathBuf>,
+        vfs: Arc<dyn Vfs>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        Self::open_impl(
+            path.into(),
+            vfs,
+            clock,
+            None,
+            0,
+            Arc::default(),
+            &mut |_| {},
+        )
+    }
+
+    fn open_impl(
+        path: PathBuf,
+        vfs: Arc<dyn Vfs>,
+        clock: Arc<dyn Clock>,
+        index_budget: Option<u64>,
+        keep_versions: usize,
+        keyring: Arc<Keyring>,
+        progress: &mut ProgressCallback<'_>,
+    ) -> Result<Self> {
+        let path = Arc::new(path);
+        vfs.create_dir_all(&path)?;
+        finish_restore(&*vfs, &path)?;
+        check_engine(&*vfs, &path, KVS_ENGINE)?;
 
         // A list of log file names. The file names looks like a sequence of generated numbers.
-        let gen_list = sorted_gen_list(&path)?;
+        let gen_list = sorted_gen_list(&*vfs, &path)?;
         let mut uncompacted = 0;
 
-        // Initialized index and log readers.
-        let index = Arc::new(SkipMap::new());
-        let mut readers = BTreeMap::new(); // one reader for one log file
+        // Replay the log files independently, then apply them to the index in
+        // generation order, so that later commands win.
+        let index = Arc::new(Keydir::new(index_budget));
+        let mut trash = BTreeMap::new();
+        // The age of the tombstones is unknown, so they are aged from now.
+        let mut tombstones = BTreeMap::new();
+        let opened_at = now_millis(&*clock);
+        let mut history = History {
+            state: HistoryState::read(&*vfs, &path)?,
+            ..History::default()
+        };
+        let mut versions = Versions::new(keep_versions);
 
-        // Loop over multiple log files if any in a directory
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
-            readers.insert(gen, reader);
+        // Start from the checkpoint, if any, and only replay the log written since.
+        let mut segments: Vec<(u64, u64)> = gen_list.iter().map(|&gen| (gen, 0)).collect();
+        let checkpoint = Checkpoint::read(&*vfs, &path, &gen_list)?;
+        let from_checkpoint = checkpoint.is_some();
+        if let Some(state) = checkpoint {
+            let (last_gen, last_pos) = state.logs[state.logs.len() - 1];
+            segments.retain(|&(gen, _)| gen >= last_gen);
+            segments[0].1 = last_pos;
+            for (key, cmd_pos) in state.index {
+                index.insert(key.into(), cmd_pos)?;
+            }
+            trash = state.trash;
+            for gen in state.tombstone_gens {
+                tombstones.insert(gen, opened_at);
+            }
+            // The retention may have been turned off since.
+            if history.state.is_some() {
+                history.versions = state.versions;
+            }
+            history.last_seq = state.last_seq;
+            uncompacted = state.uncompacted;
+        }
+        // With a budget, the entries of the compaction file, the oldest log
+        // file, are left in its hint file. It need not be replayed if the
+        // hints have nothing else.
+        let mut cold = match (index_budget, gen_list.first()) {
+            (Some(_), Some(&gen)) => {
+                let log_len = vfs.file_len(&log_path(&path, gen))?;
+                SortedHints::open(&*vfs, &path, gen, log_len)?
+            }
+            _ => None,
+        };
+        if let Some(plain) = cold.take_if(|cold| cold.is_plain() && !from_checkpoint) {
+            segments.retain(|&(gen, _)| gen != plain.gen());
+            index.open_cold(plain)?;
+        }
+        // The last log file was the active one, written to until a crash maybe.
+        let replays = replay_all(&*vfs, &path, &segments, true, &keyring, progress)?;
+        for (&(gen, _), replay) in segments.iter().zip(replays) {
+            if replay.has_tombstones {
+                tombstones.insert(gen, opened_at);
+            }
+            uncompacted += replay.apply(&index, &mut trash, &mut history, &mut versions)?;
         }
+        // Compactions drop the removals, whose sequence numbers must not be
+        // used again.
+        let mut compacted_seq = 0;
+        match Manifest::read(&*vfs, &path) {
+            Ok(manifest) => {
+                history.last_seq = history.last_seq.max(manifest.last_seq);
+                compacted_seq = manifest.compacted_seq;
+            }
+            Err(e) => warn!("Failed to read the manifest: {}", e),
+        }
+        if let Some(cold) = cold {
+            index.attach(cold)?;
+        }
+        index.evict()?;
 
         // Increment log file name from the last generated number and create new log file with it.
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
+        let writer = new_log_file(&*vfs, &path, current_gen)?;
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(uncompacted as f64);
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
-            readers: RefCell::new(BTreeMap::new()),
+            vfs: Arc::clone(&vfs),
+            readers: Mutex::new(Vec::new()),
             safe_point: Arc::new(AtomicU64::new(0)),
+            keyring,
+            #[cfg(feature = "mmap")]
+            maps: Arc::new(MapSet {
+                enabled: AtomicBool::new(true),
+                maps: RwLock::new(BTreeMap::new()),
+            }),
         };
 
-        let writer = KvStoreWriter {
+        let indexes = SecondaryIndexes::read(&*vfs, &path)?;
+        let batch_gate = Arc::new(RwLock::new(()));
+        let mut writer = KvStoreWriter {
             path: Arc::clone(&path),
+            vfs,
+            clock: Arc::clone(&clock),
             writer,
             reader: reader.clone(),
             uncompacted,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            compaction_slice_keys: None,
+            compaction_slice_bytes: None,
+            compaction: None,
+            segment_size: None,
+            compression: None,
+            segment_compression: None,
+            compaction_stats: CompactionStats::default(),
             current_gen,
             index: Arc::clone(&index),
+            batch_gate: Arc::clone(&batch_gate),
+            compaction_listeners: Vec::new(),
+            durability: Durability::Manual,
+            compaction_paused: false,
+            backups_running: 0,
+            trash_retention: None,
+            trash,
+            segment_archive: None,
+            min_free_space: 0,
+            free_space_check: None,
+            flusher: None,
+            max_tombstone_age: None,
+            tombstones,
+            tombstone_sweeper: None,
+            expiry_sweeper: None,
+            checkpoint_interval: None,
+            checkpointer: None,
+            history,
+            versions,
+            compacted_gen: 0,
+            compacted_seq,
+            indexes,
+            #[cfg(feature = "fs")]
+            snapshot_schedule: None,
+            #[cfg(feature = "fs")]
+            snapshotter: None,
         };
+        writer.index_values(None)?;
 
         Ok(Self {
             path,
             reader,
             index,
+            batch_gate,
+            clock,
             writer: Arc::new(Mutex::new(writer)),
+            options: Arc::new(KvStoreBuilder::default()),
+            namespaces: Arc::new(Mutex::new(HashMap::new())),
         })
     }
-}
 
-impl KvsEngine for KvStore {
-    /// Set a given key and value Strings in the store.
+    /// Opens the store with the given path and sets every pair of `pairs`,
+    /// writing them in batches.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// # Errors
+    ///
+    /// It propagates the errors of `KvStore::open` and I/O or serialization
+    /// errors during writing the log.
+    #[cfg(feature = "fs")]
+    pub fn open_from_iter<I>(path: impl Into<PathBuf>, pairs: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let store = Self::open(path)?;
+        store.set_all(pairs)?;
+        Ok(store)
+    }
+
+    /// Sets every pair, writing them in batches of `BULK_BATCH_SIZE`.
+    pub(crate) fn set_all<I>(&self, pairs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut batch = Vec::with_capacity(BULK_BATCH_SIZE);
+        for (key, value) in pairs {
+            batch.push(Command::set(key, value.into_bytes()));
+            if batch.len() == BULK_BATCH_SIZE {
+                self.writer.lock().unwrap().write_batch(batch)?;
+                batch = Vec::with_capacity(BULK_BATCH_SIZE);
+            }
+        }
+        if !batch.is_empty() {
+            self.writer.lock().unwrap().write_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Loads `pairs`, sorted by key, much faster than setting them one by one.
+    ///
+    /// The pairs are written to a new log file, with a single sync and no
+    /// compaction, which is installed at once when they have all been
+    /// written: either all of them are loaded, or none is. They override the
+    /// existing values of the same keys. Other writers wait until the load
+    /// is done.
+    ///
+    /// Returns the number of pairs loaded.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log.
+    /// It returns `KvsError::StringError` if the keys are not strictly
+    /// increasing, and propagates I/O or serialization errors during writing
+    /// the log.
+    pub fn bulk_load<I>(&self, pairs: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.writer.lock().unwrap().bulk_load(pairs.into_iter())
+    }
+
+    /// Registers a listener called with every `CompactionEvent`.
     ///
-    /// # Example
+    /// Listeners are called synchronously on the thread running the compaction
+    /// while it holds the write lock, so they must be quick and must not write
+    /// to the store themselves.
+    pub fn on_compaction<F>(&self, listener: F)
+    where
+        F: Fn(&CompactionEvent) + Send + 'static,
+    {
+        self.writer
+            .lock()
+            .unwrap()
+            .compaction_listeners
+            .push(Box::new(listener));
+    }
+
+    /// Makes compactions archive the stale log files with `archive` rather
+    /// than delete them, or delete them with `None`, the default.
     ///
-    /// ```
-    /// use std::env::current_dir;
-    /// use kvs::KvStore;
+    /// Like the deletions, archiving runs on the thread running the
+    /// compaction while it holds the write lock. The hint files of the stale
+    /// log files are still deleted, as they can be rebuilt.
+    pub fn set_segment_archive(&self, archive: Option<SegmentArchive>) {
+        self.writer.lock().unwrap().segment_archive = archive;
+    }
+
+    /// Adds the 256-bit encryption key `new_key` with the version after the
+    /// highest one, see `KvStoreBuilder::encryption_key`, and returns its
+    /// version. It encrypts the records written from now on, in the store and
+    /// its namespaces, and the store is encrypted from now on if it was not.
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
-    /// store.set(String::from("my_key"), String::from("my_value")).unwrap();
-    /// ```
-    fn set(&self, key: String, value: String) -> Result<()> {
+    /// The records of the older keys are encrypted again lazily, as
+    /// compactions copy them: once a compaction started after the rotation
+    /// is done, the log files only hold records of the new key, besides the
+    /// archived ones. Until then, the store must be opened with every key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the highest version is
+    /// `u32::MAX`.
+    pub fn rotate_key(&self, new_key: [u8; 32]) -> Result<u32> {
+        // The writer lock orders the rotation with the writes.
+        let _writer = self.writer.lock().unwrap();
+        let version = self.reader.keyring.rotate(new_key)?;
+        info!(version, "Rotated the encryption key");
+        Ok(version)
+    }
+
+    /// Keeps the values removed by `KvsEngine::remove` for `retention`, so
+    /// that `KvStore::undelete` can restore them, or removes them for good
+    /// with `None`, the default.
+    ///
+    /// Only the last removed value of a key is kept, and the retention is
+    /// counted from its removal. Kept values take disk space until they are
+    /// dropped by the first compaction after the retention.
+    pub fn set_trash_retention(&self, retention: Option<Duration>) {
+        let mut writer = self.writer.lock().unwrap();
+        // Its slices would miss the values removed with the retention.
+        writer.abandon_compaction();
+        writer.trash_retention = retention;
+    }
+
+    /// Restores the last value of `key` removed within the trash retention,
+    /// with its expiry if any. Returns `false` if the key exists, or if it
+    /// has no such value.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading or writing
+    /// the log.
+    pub fn undelete(&self, key: String) -> Result<bool> {
+        self.writer.lock().unwrap().undelete(key)
+    }
+
+    /// Sets `key` to `value` like `KvsEngine::set`, and returns the log
+    /// sequence number of the write.
+    ///
+    /// Every command appended to the log takes the next log sequence number,
+    /// and keeps it in its record. It is the version of the value, see
+    /// `KvsEngine::get_with_version`, and the one of its `Change`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    #[instrument(level = "debug", skip(self, value))]
+    pub fn set_with_lsn(&self, key: String, value: String) -> Result<u64> {
         self.writer.lock().unwrap().set(key, value)
     }
 
-    /// Get a value from the store using a key String.
-    ///
-    /// Returns `None` if the given key does not exist.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use std::env::current_dir;
-    /// use kvs::KvStore;
-    ///
-    /// let store = KvStore::open(current_dir().unwrap()).unwrap();
-    /// match store.get(String::from("my_key")).unwrap() {
-    ///     Some(value) => println!("Value: {}", value),
-    ///     None => println!("Key not found"),
-    /// }
-    /// ```
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
+    /// Sets `key` to a binary value like `KvsEngine::set_bytes`, and returns
+    /// the log sequence number of the write, see `KvStore::set_with_lsn`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    #[instrument(level = "debug", skip(self, value))]
+    pub fn set_bytes_with_lsn(&self, key: String, value: Vec<u8>) -> Result<u64> {
+        self.writer
+            .lock()
+            .unwrap()
+            .write_set(Command::set(key, value))
+    }
+
+    /// Applies the writes of `batch` atomically like `KvsEngine::write_batch`,
+    /// and returns the log sequence number of its last write, or the last one
+    /// of the store if the batch is empty. The writes of the batch have
+    /// consecutive numbers.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    #[instrument(level = "debug", skip(self, batch), fields(len = batch.len()))]
+    pub fn write_batch_with_lsn(&self, batch: WriteBatch) -> Result<u64> {
+        self.writer
+            .lock()
+            .unwrap()
+            .write_batch(batch_commands(batch))
+    }
+
+    /// Appends `suffix` to the value of `key` like `KvsEngine::append`, and
+    /// returns the log sequence number of the write.
+    ///
+    /// Only the suffix is written to the log, in a record pointing to the one
+    /// of the value it extends, so that growing a long value does not rewrite
+    /// it. Reads follow the chain of records, which the next compaction folds
+    /// into a single value. The expiry of the key is kept.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing
+    /// the log.
+    #[instrument(level = "debug", skip(self, suffix))]
+    pub fn append_with_lsn(&self, key: String, suffix: String) -> Result<u64> {
+        self.writer.lock().unwrap().append(key, suffix.into_bytes())
+    }
+
+    /// Removes `key` like `KvsEngine::remove`, and returns the log sequence
+    /// number of the write, see `KvStore::set_with_lsn`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key does not exist, and
+    /// propagates I/O or serialization errors during writing the log.
+    #[instrument(level = "debug", skip(self))]
+    pub fn remove_with_lsn(&self, key: String) -> Result<u64> {
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    /// Removes every key in `range`, and returns the number of keys removed.
+    ///
+    /// A single record is appended to the log rather than one per key, and
+    /// the keys are removed from the index in one pass, so `get` sees either
+    /// all of them or none. The values removed are not kept in the trash. The
+    /// next compaction drops them, along with the record.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn delete_range<R: RangeBounds<String>>(&self, range: R) -> Result<u64> {
+        self.writer
+            .lock()
+            .unwrap()
+            .remove_range(range.start_bound().cloned(), range.end_bound().cloned())
+    }
+
+    /// Removes every key starting with `prefix` like `KvStore::delete_range`,
+    /// and returns the number of keys removed. An empty prefix removes every
+    /// key.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn remove_prefix(&self, prefix: &str) -> Result<u64> {
+        let (start, end) = prefix_range(prefix);
+        self.writer.lock().unwrap().remove_range(start, end)
+    }
+
+    /// Sets the value of `key`, which expires `ttl` from now: it then reads
+    /// as absent, and is purged from the log by the next compaction unless
+    /// the history is retained. The expiry is part of the "set" command, so
+    /// it takes a single record.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_with_ttl_lsn(key, value, ttl)?;
+        Ok(())
+    }
+
+    /// Sets the value of `key` like `KvStore::set_with_ttl`, and returns the
+    /// log sequence number of the write, see `KvStore::set_with_lsn`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn set_with_ttl_lsn(&self, key: String, value: String, ttl: Duration) -> Result<u64> {
+        let mut writer = self.writer.lock().unwrap();
+        let expires_at = now_millis(&*writer.clock).saturating_add(ttl.as_millis() as u64);
+        writer.write_set(Command::Set {
+            key,
+            value: value.into_bytes(),
+            expires_at: Some(expires_at),
+            stamp: None,
+        })
+    }
+
+    /// Returns how long `key` has left before it expires, from the index
+    /// alone. Returns `None` if the key does not exist, has expired, or has
+    /// no expiry.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let expires_at = self.index_get(key)?.expires_at?;
+        let now = now_millis(&*self.clock);
+        if expires_at <= now {
+            return None;
+        }
+        Some(Duration::from_millis(expires_at - now))
+    }
+
+    /// Makes writes fail with `KvsError::DiskFull` while the disk of the store
+    /// has less than `bytes` of free space, rather than risking to fail in the
+    /// middle of a command. Reads and compactions, which free space, still
+    /// work. 0, the default, disables the check.
+    ///
+    /// The free space is measured at most once a second.
+    pub fn set_min_free_space(&self, bytes: u64) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.min_free_space = bytes;
+        writer.free_space_check = None;
+    }
+
+    /// Compacts the log once a tombstone in it is older than `max_age`, even if
+    /// the log has too few stale commands to be compacted otherwise, so that
+    /// removed values are purged from the disk within a bounded time. `None`,
+    /// the default, only compacts on the amount of stale commands.
+    ///
+    /// A background thread checks the age of the tombstones every tenth of
+    /// `max_age`, and at least every second. The tombstones found when the store
+    /// is opened are aged from then. Paused compactions are not run.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if the background thread cannot be spawned.
+    pub fn set_max_tombstone_age(&self, max_age: Option<Duration>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Dropping the sender stops the running sweeper, if any.
+        writer.tombstone_sweeper = None;
+        if let Some(max_age) = max_age {
+            let interval = (max_age / 10).clamp(Duration::from_millis(1), Duration::from_secs(1));
+            writer.tombstone_sweeper = Some(spawn_periodic(
+                "kvs-tombstone-sweeper",
+                Arc::downgrade(&self.writer),
+                interval,
+                |writer| writer.lock().unwrap().purge_old_tombstones(),
+            )?);
+        }
+        writer.max_tombstone_age = max_age;
+        Ok(())
+    }
+
+    /// Checks every `interval`, from a background thread, whether the expired
+    /// entries and the stale commands add up to the compaction threshold, and
+    /// compacts the log if they do. Compactions drop the expired entries, but
+    /// otherwise only run on the amount of stale commands, so that expired
+    /// values could stay on the disk for good. `None`, the default, stops the
+    /// checks.
+    ///
+    /// Every check visits the whole index. Paused compactions are not run,
+    /// and nothing is purged while the history is retained, as it may still
+    /// read the expired values.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if the background thread cannot be spawned.
+    pub fn set_expiry_sweep_interval(&self, interval: Option<Duration>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Dropping the sender stops the running sweeper, if any.
+        writer.expiry_sweeper = None;
+        if let Some(interval) = interval {
+            writer.expiry_sweeper = Some(spawn_periodic(
+                "kvs-expiry-sweeper",
+                Arc::downgrade(&self.writer),
+                interval,
+                |writer| writer.lock().unwrap().sweep_expired(),
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Writes a checkpoint of the index, along with the trash and the history,
+    /// to the data directory. Opening the store then loads the checkpoint and
+    /// only replays the log written after it, instead of the whole log.
+    ///
+    /// A checkpoint is ignored once a compaction or a restore replaced the
+    /// log files it was taken from, so that the store is replayed from the
+    /// log again.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during syncing the log or writing the
+    /// checkpoint.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.writer.lock().unwrap().checkpoint()
+    }
+
+    /// Writes a checkpoint every `interval`, and when the store is closed.
+    /// `None`, the default, only writes them with `KvStore::checkpoint`.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if the background thread cannot be spawned.
+    pub fn set_checkpoint_interval(&self, interval: Option<Duration>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Dropping the sender stops the running checkpoints, if any.
+        writer.checkpointer = None;
+        if let Some(interval) = interval {
+            writer.checkpointer = Some(spawn_periodic(
+                "kvs-checkpointer",
+                Arc::downgrade(&self.writer),
+                interval,
+                |writer| writer.lock().unwrap().checkpoint(),
+            )?);
+        }
+        writer.checkpoint_interval = interval;
+        Ok(())
+    }
+
+    /// Keeps the writes of the last `retention` in the log, stamped with a
+    /// sequence number and their time, so that `KvStore::get_as_of` can read
+    /// the values as of any point since. `None`, the default, drops the
+    /// history; the writes are then only kept until they are overwritten.
+    ///
+    /// The history starts when it is first retained, and compactions drop
+    /// the writes older than the retention. The retention is stored in the
+    /// data directory, so it applies again when the store is reopened.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the retention.
+    pub fn set_history_retention(&self, retention: Option<Duration>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Its slices would miss the versions written with the retention.
+        writer.abandon_compaction();
+        let state = retention.map(|retention| {
+            let start = match writer.history.state {
+                Some(state) => state.start,
+                None => Stamp {
+                    seq: writer.history.last_seq,
+                    at: now_millis(&*writer.clock),
+                },
+            };
+            HistoryState {
+                start,
+                retention_millis: retention.as_millis() as u64,
+            }
+        });
+        HistoryState::write(&*writer.vfs, &writer.path, state)?;
+        if state.is_none() {
+            writer.history.versions.clear();
+        }
+        writer.history.state = state;
+        Ok(())
+    }
+
+    /// Returns the sequence number of the last write, 0 if there is none.
+    pub fn last_seq(&self) -> u64 {
+        self.writer.lock().unwrap().history.last_seq
+    }
+
+    /// Gets the value of `key` as of a past point, from the history kept with
+    /// `KvStore::set_history_retention`. Returns `None` if the key did not
+    /// exist then.
+    ///
+    /// Expiries are those set by the writes of the values. A value is expired
+    /// as of the given time, or as of now for a sequence number.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::HistoryUnavailable` if the history is not
+    /// retained, or does not go back to `as_of`, and propagates I/O or
+    /// deserialization errors during reading the log.
+    pub fn get_as_of(&self, key: String, as_of: AsOf) -> Result<Option<String>> {
+        let writer = self.writer.lock().unwrap();
+        match writer.history.state {
+            Some(state) if as_of.includes(state.start) => {}
+            _ => return Err(KvsError::HistoryUnavailable),
+        }
+        let cmd_pos = match writer.history.versions.get(&key) {
+            Some(versions) => versions
+                .iter()
+                .filter(|version| as_of.includes(version.stamp))
+                .max_by_key(|version| version.stamp.seq)
+                .and_then(|version| version.pos),
+            // Not written since the start of the history.
+            None => self.index.get(key.as_str())?,
+        };
+        let now = match as_of {
+            AsOf::Seq(_) => now_millis(&*writer.clock),
+            AsOf::Time(time) => millis(time),
+        };
+        match cmd_pos {
+            Some(cmd_pos) if !cmd_pos.is_expired(now) => {
+                match self.reader.read_command(cmd_pos)? {
+                    Command::Set { value, .. } => Ok(Some(String::from_utf8(value)?)),
+                    _ => Err(KvsError::UnexpectedCommandType),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over the changes of the store after the log
+    /// sequence number `lsn`, in log order: every set and remove, with the
+    /// sequence number of its write. `KvStore::last_seq` is the one of the
+    /// last write, so that a consumer can catch up from there.
+    ///
+    /// The iterator returns `None` once it has caught up with the writes;
+    /// calling `next` again yields the changes written since. The changes of
+    /// a batch are yielded once it is committed.
+    ///
+    /// Compactions drop the changes overwritten since, but keep the sequence
+    /// number of the last write before them, from which the changes are
+    /// complete again.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::ChangesUnavailable` if a compaction dropped
+    /// changes after `lsn`. So does the iterator, if one does while it reads
+    /// the log files.
+    pub fn changes_since(&self, lsn: u64) -> Result<Changes<'_>> {
+        if self.writer.lock().unwrap().compacted_seq > lsn {
+            return Err(KvsError::ChangesUnavailable);
+        }
+        Ok(Changes::new(self, lsn))
+    }
+
+    /// Returns the namespace `name` of the store, opening it if needed.
+    ///
+    /// A namespace is a store of its own, with its own keys and log files,
+    /// in a subdirectory of the data directory. It is opened with the options
+    /// of the builder the store was opened with. Its writes do not make the
+    /// store compact, nor the other namespaces, and it can be dropped with
+    /// `KvStore::drop_namespace` without rewriting anything. Snapshots and
+    /// backups of the store do not include its namespaces.
+    ///
+    /// Names are made of ASCII letters, digits, `-` and `_`. The store hands
+    /// out handles to the same namespace until it is dropped.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the name is invalid, and
+    /// propagates the errors of `KvStoreBuilder::open`.
+    pub fn namespace(&self, name: &str) -> Result<KvStore> {
+        check_namespace_name(name)?;
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if let Some(namespace) = namespaces.get(name) {
+            return Ok(namespace.clone());
+        }
+        let vfs = Arc::clone(&self.writer.lock().unwrap().vfs);
+        let namespace = KvStoreBuilder {
+            keyring: Some(Arc::clone(&self.reader.keyring)),
+            ..(*self.options).clone()
+        }
+        .vfs(vfs)
+        .clock(Arc::clone(&self.clock))
+        .open(namespace_path(&self.path, name))?;
+        namespaces.insert(name.to_owned(), namespace.clone());
+        Ok(namespace)
+    }
+
+    /// Drops the namespace `name` of the store: its log files and the other
+    /// files of its data directory are removed. Returns `false` if it has
+    /// none.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the name is invalid, or if
+    /// handles to the namespace are still alive, and propagates I/O errors
+    /// during removing the files.
+    pub fn drop_namespace(&self, name: &str) -> Result<bool> {
+        check_namespace_name(name)?;
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if let Some(namespace) = namespaces.get(name) {
+            // Background threads only hold weak references.
+            if Arc::strong_count(&namespace.writer) > 1 {
+                return Err(KvsError::StringError(format!(
+                    "namespace {:?} is in use",
+                    name
+                )));
+            }
+            // Closing it may write a checkpoint, which is removed below.
+            namespaces.remove(name);
+        }
+        let vfs = Arc::clone(&self.writer.lock().unwrap().vfs);
+        let files = match vfs.list_files(&namespace_path(&self.path, name)) {
+            Ok(files) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        for file in &files {
+            vfs.remove_file(file)?;
+        }
+        Ok(!files.is_empty())
+    }
+
+    /// Declares the secondary index `name` on the field of the values at the
+    /// JSON `pointer`, e.g. `/user/email`, and indexes the values stored.
+    ///
+    /// Values which are JSON documents with a string, a number or a boolean
+    /// at `pointer` are indexed under it, a number or a boolean as JSON
+    /// text, see `KvStore::get_by_index`. Other values are not indexed. The
+    /// entries are added under the lock of the writer, along with the write
+    /// of the value, so lookups never see one without the other.
+    ///
+    /// The definition is kept in the data directory, but the entries are
+    /// kept in memory only, and rebuilt by reading every value when the store
+    /// is opened.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the index exists or the pointer
+    /// is invalid, and propagates I/O or deserialization errors during
+    /// reading the log or writing the definition.
+    pub fn create_index(&self, name: &str, pointer: &str) -> Result<()> {
+        if !pointer.is_empty() && !pointer.starts_with('/') {
+            return Err(KvsError::StringError(format!(
+                "invalid JSON pointer: {:?}",
+                pointer
+            )));
+        }
+        let mut writer = self.writer.lock().unwrap();
+        if writer.indexes.contains(name) {
+            return Err(KvsError::StringError(format!(
+                "index {:?} already exists",
+                name
+            )));
+        }
+        writer.indexes.create(name, pointer);
+        let res = writer
+            .index_values(Some(name))
+            .and_then(|()| writer.indexes.write(&*writer.vfs, &writer.path));
+        if res.is_err() {
+            writer.indexes.drop_index(name);
+        }
+        res
+    }
+
+    /// Removes the secondary index `name`. Returns `false` if there is none.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during writing the definitions.
+    pub fn drop_index(&self, name: &str) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        if !writer.indexes.drop_index(name) {
+            return Ok(false);
+        }
+        writer.indexes.write(&*writer.vfs, &writer.path)?;
+        Ok(true)
+    }
+
+    /// Returns the keys whose value has `value` as the field of the secondary
+    /// index `index`, in key order, with their values.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// # fn main() -> kvs::Result<()> {
+    /// let store = KvStore::open("data")?;
+    /// store.create_index("by-city", "/address/city")?;
+    /// store.set(
+    ///     "user:1".to_owned(),
+    ///     r#"{"name":"Ada","address":{"city":"London"}}"#.to_owned(),
+    /// )?;
+    /// let londoners = store.get_by_index("by-city", "London")?;
+    /// assert_eq!(londoners[0].0, "user:1");
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::IndexNotFound` if there is no such index, and
+    /// propagates I/O or deserialization errors during reading the log.
+    pub fn get_by_index(&self, index: &str, value: &str) -> Result<Vec<(String, String)>> {
+        let mut writer = self.writer.lock().unwrap();
+        let keys = writer
+            .indexes
+            .candidates(index, value)
+            .ok_or(KvsError::IndexNotFound)?;
+        let mut found = Vec::new();
+        for key in keys {
+            match writer.get_bytes(&key)? {
+                Some(bytes) if writer.indexes.matches(index, value, &bytes) => {
+                    found.push((key, String::from_utf8(bytes)?));
+                }
+                // The key was overwritten, removed or expired since.
+                _ => writer.indexes.prune(index, value, &key),
+            }
+        }
+        Ok(found)
+    }
+
+    /// Gets the value of `key` at `version`, as returned by
+    /// `KvsEngine::get_with_version`. Returns `None` if the version removed the
+    /// key, or if the value has expired since.
+    ///
+    /// The current version of a key is always readable; the earlier ones
+    /// are while kept with `KvStoreBuilder::keep_versions`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::VersionUnavailable` if the version is not kept,
+    /// and propagates I/O or deserialization errors during reading the log.
+    pub fn get_at_version(&self, key: String, version: u64) -> Result<Option<String>> {
+        // Compactions wait for the lock, so the positions stay valid.
+        let writer = self.writer.lock().unwrap();
+        let cmd_pos = match writer.versions.get(&key, version) {
+            Some(kept) => kept.pos,
+            None => match self.index.get(key.as_str())? {
+                Some(cmd_pos) => Some(cmd_pos),
+                None => return Err(KvsError::VersionUnavailable),
+            },
+        };
+        let cmd_pos = match cmd_pos {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        match self.reader.read_command(cmd_pos)? {
+            Command::Set { value, stamp, .. } => {
+                if stamp.map_or(0, |stamp| stamp.seq) != version {
+                    Err(KvsError::VersionUnavailable)
+                } else if cmd_pos.is_expired(now_millis(&*writer.clock)) {
+                    Ok(None)
+                } else {
+                    Ok(Some(String::from_utf8(value)?))
+                }
+            }
+            _ => Err(KvsError::UnexpectedCommandType),
+        }
+    }
+
+    /// Backs up the log files and the manifest to `target`, as a backup named
+    /// after the current time, e.g. `snapshot-1700000000000`. Returns its name.
+    ///
+    /// The store stays open: reads and writes go on during the backup, which
+    /// holds the log as it was when it started. Compactions wait for it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors from reading the log or writing to `target`,
+    /// after aborting the backup.
+    pub fn backup_to(&self, target: &dyn BackupTarget) -> Result<String> {
+        backup_to(&self.writer, target)
+    }
+
+    /// Backs up the store to the new directory `dest`, which `KvStore::open`
+    /// can load as is, and `KvsEngine::restore` can restore from.
+    ///
+    /// Like `KvStore::backup_to`, the store stays open and the backup holds
+    /// the log as it was when it started. The log files other than the active
+    /// one never change, so they are hard-linked rather than copied when they
+    /// are on the same file system as `dest`. The files are written to a
+    /// temporary directory next to `dest`, renamed once they are all synced.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if `dest` already exists, and propagates I/O
+    /// errors during the copy, after removing the temporary directory.
+    #[cfg(feature = "fs")]
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<()> {
+        backup_dir(&self.writer, dest.as_ref())
+    }
+
+    /// Adds an incremental backup of the store to the chain of backups in
+    /// directory `chain`, which is created if needed, and returns its name,
+    /// e.g. `increment-3`.
+    ///
+    /// Only the log files created or grown since the previous backup of the
+    /// chain are copied, along with the manifest and the other small files,
+    /// so the first backup of a chain is a full one. The chain records the
+    /// data files of the store at every backup in `chain.json`, and
+    /// `KvStore::restore_incremental` restores from it. Like
+    /// `KvStore::backup`, the store stays open during the backup.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors from reading the log or
+    /// writing to `chain`. A failed backup is not added to the chain.
+    #[cfg(feature = "fs")]
+    pub fn backup_incremental(&self, chain: impl AsRef<Path>) -> Result<String> {
+        let (vfs, files) = {
+            let mut writer = self.writer.lock().unwrap();
+            let files = writer.start_backup()?;
+            (Arc::clone(&writer.vfs), files)
+        };
+
+        let res = incremental::put_increment(&*vfs, &files, chain.as_ref());
+
+        let mut writer = self.writer.lock().unwrap();
+        writer.backups_running -= 1;
+        let increment = res?;
+        info!(
+            "Backed up the store to {} in {}",
+            increment,
+            chain.as_ref().display()
+        );
+        writer.maybe_compact()?;
+        Ok(increment)
+    }
+
+    /// Replaces the whole data set with the one of the last backup of the
+    /// chain in directory `chain`, as written by `KvStore::backup_incremental`,
+    /// like `KvsEngine::restore` does with a full backup.
+    ///
+    /// The data directory is rebuilt in `chain` by applying the backups of
+    /// the chain in order, then restored from, then removed.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if the chain has no backup, and propagates the
+    /// errors of `KvsEngine::restore`.
+    #[cfg(feature = "fs")]
+    pub fn restore_incremental(&self, chain: impl AsRef<Path>) -> Result<()> {
+        let rebuilt = incremental::rebuild(chain.as_ref())?;
+        let res = self.restore(&rebuilt);
+        incremental::remove_rebuilt(&rebuilt);
+        res
+    }
+
+    /// Backs up the store to a new directory in `dest`, as `KvStore::backup_to`
+    /// does with a `DirTarget`. `KvStore::open` can load it as is. Returns its
+    /// path.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during the copy. It returns an I/O error if
+    /// a snapshot was already taken in the same millisecond.
+    #[cfg(feature = "fs")]
+    pub fn snapshot(&self, dest: impl AsRef<Path>) -> Result<PathBuf> {
+        let target = DirTarget::new(dest.as_ref());
+        let backup = self.backup_to(&target)?;
+        Ok(target.backup_dir(&backup))
+    }
+
+    /// Recovers the data set as it was at `point` into a new store in the
+    /// directory `dest`, on the file system of the store, and returns the
+    /// number of keys recovered.
+    ///
+    /// The writes are replayed from the log files archived by compactions to
+    /// the `SegmentArchive::Dir` of the store, if any, and from its log files,
+    /// in generation order. The records copied by compactions are replayed
+    /// once, and a batch is recovered whole or not at all. The store stays
+    /// open during the recovery, which holds the log as it was when it
+    /// started. Compactions wait for it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::HistoryUnavailable` if writes made by `point` are
+    /// missing from the log files, e.g. deleted by a compaction before the
+    /// archive was set, and an I/O error if `dest` already has files. It
+    /// propagates I/O or deserialization errors during reading the log files
+    /// or writing the new store.
+    pub fn recover_to(&self, dest: impl Into<PathBuf>, point: AsOf) -> Result<u64> {
+        let dest = dest.into();
+        let (vfs, clock, files) = {
+            let mut writer = self.writer.lock().unwrap();
+            if writer
+                .vfs
+                .list_files(&dest)
+                .is_ok_and(|files| !files.is_empty())
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already has files", dest.display()),
+                )
+                .into());
+            }
+            let archive = match &writer.segment_archive {
+                Some(SegmentArchive::Dir(dir)) => Some(dir.clone()),
+                _ => None,
+            };
+            let files = writer.start_backup()?;
+            (
+                Arc::clone(&writer.vfs),
+                Arc::clone(&writer.clock),
+                (archive, files),
+            )
+        };
+
+        let res = (|| {
+            let (archive, files) = files;
+            let mut logs = BTreeMap::new();
+            if let Some(dir) = archive {
+                match sorted_gen_list(&*vfs, &dir) {
+                    Ok(gens) => {
+                        for gen in gens {
+                            logs.insert(gen, (log_path(&dir, gen), None));
+                        }
+                    }
+                    Err(KvsError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            for (path, len) in files {
+                if let Some(gen) = log_gen(&path) {
+                    logs.insert(gen, (path, Some(len)));
+                }
+            }
+            replay_to(&*vfs, logs, point, &self.reader.keyring)
+        })();
+
+        let recovered = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.backups_running -= 1;
+            let recovered = res?;
+            writer.maybe_compact()?;
+            recovered
+        };
+
+        let store = KvStoreBuilder {
+            keyring: Some(Arc::clone(&self.reader.keyring)),
+            ..KvStore::builder()
+        }
+        .vfs(vfs)
+        .clock(clock)
+        .open(&dest)?;
+        let keys = recovered.len() as u64;
+        let mut batch = Vec::with_capacity(BULK_BATCH_SIZE);
+        for (key, (value, expires_at)) in recovered {
+            batch.push(Command::Set {
+                key,
+                value,
+                expires_at,
+                stamp: None,
+            });
+            if batch.len() == BULK_BATCH_SIZE {
+                store.writer.lock().unwrap().write_batch(batch)?;
+                batch = Vec::with_capacity(BULK_BATCH_SIZE);
+            }
+        }
+        if !batch.is_empty() {
+            store.writer.lock().unwrap().write_batch(batch)?;
+        }
+        store.flush()?;
+        info!("Recovered {} keys to {}", keys, dest.display());
+        Ok(keys)
+    }
+
+    /// Takes a snapshot in `dest` every `interval` from a background thread,
+    /// as `KvStore::snapshot` does, then deletes the snapshots of `dest` that
+    /// `retention` does not keep. It replaces the previous schedule, if any.
+    ///
+    /// Failed snapshots are logged and retried at the next interval.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if the background thread cannot be spawned.
+    #[cfg(feature = "fs")]
+    pub fn snapshot_every(
+        &self,
+        interval: Duration,
+        dest: impl Into<PathBuf>,
+        retention: SnapshotRetention,
+    ) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Dropping the sender stops the running snapshotter, if any.
+        writer.snapshotter = None;
+        writer.snapshot_schedule = Some(SnapshotSchedule {
+            dest: dest.into(),
+            retention,
+        });
+        writer.snapshotter = Some(spawn_periodic(
+            "kvs-snapshotter",
+            Arc::downgrade(&self.writer),
+            interval,
+            take_scheduled_snapshot,
+        )?);
+        Ok(())
+    }
+
+    /// Stops the snapshots started by `KvStore::snapshot_every`. The snapshots
+    /// already taken are kept.
+    #[cfg(feature = "fs")]
+    pub fn stop_snapshots(&self) {
+        let mut writer = self.writer.lock().unwrap();
+        writer.snapshotter = None;
+        writer.snapshot_schedule = None;
+    }
+
+    /// Defers compactions until `KvStore::resume_compaction`, e.g. during a
+    /// latency-critical window. The log grows with stale commands meanwhile.
+    ///
+    /// The pause applies to every clone of the store. Pausing an already
+    /// paused compaction does nothing.
+    pub fn pause_compaction(&self) {
+        self.writer.lock().unwrap().compaction_paused = true;
+    }
+
+    /// Resumes the compactions paused by `KvStore::pause_compaction`, and
+    /// compacts the log right away if it has grown enough stale commands in
+    /// the meantime.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the compaction.
+    pub fn resume_compaction(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.compaction_paused = false;
+        writer.maybe_compact()
+    }
+
+    /// Returns statistics of the store.
+    ///
+    /// Keys are stored in the index without spare capacity, so its memory is
+    /// about the length of the keys plus a fixed overhead per key, for the
+    /// keys in memory with `KvStoreBuilder::index_budget`.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during listing the data directory.
+    pub fn stats(&self) -> Result<StoreStats> {
+        let writer = self.writer.lock().unwrap();
+        // The files do not change while the writer is locked.
+        let segments = sorted_gen_list(&*writer.vfs, &writer.path)?.len() as u64;
+        let mut disk_bytes = 0;
+        for path in writer.vfs.list_files(&writer.path)? {
+            disk_bytes += writer.vfs.file_len(&path)?;
+        }
+        Ok(StoreStats {
+            keys: self.index.len() as u64,
+            live_keys: self.len() as u64,
+            index_bytes: self.index.memory(),
+            segments,
+            disk_bytes,
+            uncompacted_bytes: writer.uncompacted,
+        })
+    }
+
+    /// Evicts from memory every entry of the index which the hint file of the
+    /// compaction file holds, whatever the budget of the index: the ones
+    /// written before the compaction, and the ones read since. Returns the
+    /// bytes of memory released, estimated like `KvStore::stats`.
+    ///
+    /// Only a store with `KvStoreBuilder::index_budget` looks entries up in
+    /// the hint file: without a budget, every entry stays in memory and
+    /// nothing is released.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading the hint file.
+    pub fn shrink_index(&self) -> Result<u64> {
+        let before = self.index.memory();
+        self.index.shrink()?;
+        Ok(before.saturating_sub(self.index.memory()))
+    }
+
+    /// Returns statistics of the compactions run since the store was opened.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.writer.lock().unwrap().compaction_stats
+    }
+
+    /// Sets when writes are synced to durable storage. The policy applies to
+    /// every clone of the store.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error if the background thread of
+    /// `Durability::EveryNMillis` cannot be spawned.
+    pub fn set_durability(&self, durability: Durability) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        // Dropping the sender stops the running flusher, if any.
+        writer.flusher = None;
+        if let Durability::EveryNMillis(millis) = durability {
+            writer.flusher = Some(spawn_periodic(
+                "kvs-flusher",
+                Arc::downgrade(&self.writer),
+                Duration::from_millis(millis.max(1)),
+                |writer| writer.lock().unwrap().sync(),
+            )?);
+        }
+        writer.durability = durability;
+        Ok(())
+    }
+
+    /// Gets the entry of a key for an in-place read-modify-write.
+    ///
+    /// The entry holds the write lock until it is consumed or dropped, so the
+    /// read and the write are atomic with respect to other writers. Don't use
+    /// the store from the same thread while holding an entry.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// # fn main() -> kvs::Result<()> {
+    /// let store = KvStore::open("data")?;
+    /// store
+    ///     .entry("visits".to_owned())
+    ///     .and_modify(|visits| *visits = (visits.parse::<u64>().unwrap() + 1).to_string())?
+    ///     .or_insert("1".to_owned())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn entry(&self, key: String) -> Entry<'_> {
+        Entry {
+            writer: self.writer.lock().unwrap(),
+            key,
+        }
+    }
+
+    /// Replaces the value of `key` with the result of `f`, called with the
+    /// current value, and returns the new value. Returning `None` removes the
+    /// key.
+    ///
+    /// `f` runs under the write lock, so no other write can slip between the
+    /// read and the write. It must not use the store itself.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading or writing
+    /// the log.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// # fn main() -> kvs::Result<()> {
+    /// let store = KvStore::open("data")?;
+    /// let visits = store.update("visits".to_owned(), |visits| {
+    ///     let visits: u64 = visits.map_or(0, |visits| visits.parse().unwrap());
+    ///     Some((visits + 1).to_string())
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn update<F>(&self, key: String, f: F) -> Result<Option<String>>
+    where
+        F: FnOnce(Option<String>) -> Option<String>,
+    {
+        let mut writer = self.writer.lock().unwrap();
+        let old = writer.get(&key)?;
+        let existed = old.is_some();
+        let new = f(old);
+        match &new {
+            Some(value) => {
+                writer.set(key, value.clone())?;
+            }
+            None if existed => {
+                writer.remove(key)?;
+            }
+            None => {}
+        }
+        Ok(new)
+    }
+
+    /// Sets the value of `key` and returns its previous value, or `None` if
+    /// it did not exist.
+    ///
+    /// The read and the write happen under the write lock, so when several
+    /// threads insert into the same key, each value is returned to exactly one
+    /// of them.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading or writing
+    /// the log.
+    pub fn insert(&self, key: String, value: String) -> Result<Option<String>> {
+        let mut writer = self.writer.lock().unwrap();
+        let old = writer.get(&key)?;
+        writer.set(key, value)?;
+        Ok(old)
+    }
+
+    /// Removes `key` and returns its value, or `None` if it does not exist.
+    ///
+    /// The read and the removal happen under the write lock, so when several
+    /// threads take the same key, only one of them gets the value.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading or writing
+    /// the log.
+    pub fn take(&self, key: String) -> Result<Option<String>> {
+        let mut writer = self.writer.lock().unwrap();
+        let value = writer.get(&key)?;
+        if value.is_some() {
+            writer.remove(key)?;
+        }
+        Ok(value)
+    }
+
+    /// Exchanges the values of `key_a` and `key_b`. If only one of them
+    /// exists, its value moves to the other key.
+    ///
+    /// Both keys are written in a single batch, and `get` never sees one key
+    /// swapped and not the other.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading or writing
+    /// the log.
+    pub fn swap(&self, key_a: String, key_b: String) -> Result<()> {
+        if key_a == key_b {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        let value_a = writer.get_bytes(&key_a)?;
+        let value_b = writer.get_bytes(&key_b)?;
+        let mut batch = Vec::with_capacity(2);
+        let mut push = |key, old: &Option<Vec<u8>>, new: &Option<Vec<u8>>| match new {
+            Some(value) => batch.push(Command::set(key, value.clone())),
+            None if old.is_some() => batch.push(Command::remove(key)),
+            None => {}
+        };
+        push(key_a, &value_a, &value_b);
+        push(key_b, &value_b, &value_a);
+        if !batch.is_empty() {
+            writer.write_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the key-value pairs of the store, in key order.
+    ///
+    /// The iterator does not see a snapshot: keys set or removed concurrently
+    /// may or may not be visited.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            store: self,
+            last_key: None,
+        }
+    }
+
+    /// Returns the number of keys in the store, from the counts the index
+    /// keeps in memory: it reads neither the log nor the hint files. Expired
+    /// keys are not counted.
+    pub fn len(&self) -> usize {
+        let now = now_millis(&*self.clock);
+        self.index.len().saturating_sub(self.index.expired(now))
+    }
+
+    /// Gets the values of `keys`, in the same order, with `None` for the
+    /// missing ones.
+    ///
+    /// The values are read in generation and position order, which is mostly
+    /// sequential on disk, so it seeks less than a `get` per key. A batch
+    /// written with `write_batch` is seen either completely or not at all.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during reading the log.
+    pub fn get_many(&self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        counter!(METRIC_GETS).increment(keys.len() as u64);
+        let now = now_millis(&*self.clock);
+        let mut positions: Vec<(usize, CommandPos)> = Vec::new();
+        {
+            let _gate = self.batch_gate.read().unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                match self.index.get_hot(key)? {
+                    Some(cmd_pos) if !cmd_pos.is_expired(now) => positions.push((i, cmd_pos)),
+                    _ => {}
+                }
+            }
+        }
+        positions.sort_by_key(|&(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+
+        let commands = self
+            .reader
+            .read_commands(positions.iter().map(|&(_, cmd_pos)| cmd_pos));
+        let mut values = vec![None; keys.len()];
+        for ((i, cmd_pos), command) in positions.into_iter().zip(commands) {
+            values[i] = match command {
+                Ok(Command::Set { value, .. }) => Some(String::from_utf8(value)?),
+                Ok(_) => return Err(KvsError::UnexpectedCommandType),
+                // A concurrent compaction may have moved the entry and deleted the
+                // file it was in. Read it again from its new position in that case.
+                Err(e) => {
+                    let key = keys[i].as_str();
+                    if self.index.get(key)? == Some(cmd_pos) {
+                        return Err(e);
+                    }
+                    self.lookup(key)?
+                }
+            };
+        }
+        Ok(values)
+    }
+
+    /// Returns `true` if `key` exists and has not expired, from the index
+    /// alone: unlike `get`, it does not read the value from the log.
+    pub fn contains_key(&self, key: &str) -> bool {
+        match self.index_get(key) {
+            Some(cmd_pos) => !cmd_pos.is_expired(now_millis(&*self.clock)),
+            None => false,
+        }
+    }
+
+    /// Returns `true` if the store has no keys, from the index alone.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the keys of the store, in key order, from the
+    /// index alone. Expired keys are skipped.
+    ///
+    /// Like `iter`, the iterator does not see a snapshot.
+    pub fn keys(&self) -> Keys<'_> {
+        Keys {
+            store: self,
+            last_key: None,
+        }
+    }
+
+    /// Returns an iterator over the key-value pairs of the store with a key in
+    /// `range`, in key order. The keys are found in the index, and each value
+    /// is read from the log as its pair is yielded.
+    ///
+    /// Like `iter`, the iterator does not see a snapshot.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use kvs::KvStore;
+    /// # fn main() -> kvs::Result<()> {
+    /// let store = KvStore::open("data")?;
+    /// for pair in store.scan("user:".to_owned().."user;".to_owned()) {
+    ///     let (key, value) = pair?;
+    ///     println!("{} = {}", key, value);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Scan<'_> {
+        Scan {
+            store: self,
+            range: (range.start_bound().cloned(), range.end_bound().cloned()),
+            last_key: None,
+        }
+    }
+
+    /// Writes every live key-value pair to `writer` in `format`, in key
+    /// order, and returns the number of pairs written.
+    ///
+    /// Like `iter`, the export does not see a snapshot.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors from writing to `writer`, and I/O or
+    /// deserialization errors during reading the log. A value that is not
+    /// valid UTF-8 fails with `KvsError::Utf8`.
+    pub fn export<W: Write>(&self, writer: W, format: ExportFormat) -> Result<u64> {
+        export::export_pairs(writer, format, self.iter())
+    }
+
+    /// Sets the key-value pairs read from `reader` in `format`, e.g. written
+    /// by `KvStore::export`, and returns their number.
+    ///
+    /// The pairs are streamed and written in batches of `BULK_BATCH_SIZE`,
+    /// each atomically, so the import as a whole is not atomic.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidImport` for a malformed record, after setting the pairs of the previous
+    /// batches. It propagates I/O errors from reading `reader` or writing the
+    /// log.
+    pub fn import<R: Read>(&self, reader: R, format: ExportFormat) -> Result<u64> {
+        let mut count = 0;
+        let mut batch = Vec::with_capacity(BULK_BATCH_SIZE);
+        for pair in ImportPairs::new(BufReader::new(reader), format) {
+            let (key, value) = pair?;
+            batch.push(Command::set(key, value.into_bytes()));
+            count += 1;
+            if batch.len() == BULK_BATCH_SIZE {
+                self.writer.lock().unwrap().write_batch(batch)?;
+                batch = Vec::with_capacity(BULK_BATCH_SIZE);
+            }
+        }
+        if !batch.is_empty() {
+            self.writer.lock().unwrap().write_batch(batch)?;
+        }
+        Ok(count)
+    }
+
+    /// Reads the log entries of the selected keys, so that they are in the OS
+    /// page cache before the first `get`.
+    ///
+    /// The entries are read in generation and position order, which is mostly
+    /// sequential on disk, e.g. to avoid cold-read latency spikes after startup.
+    ///
+    /// Returns the number of entries read.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during reading the log.
+    pub fn warm_up(&self, keys: WarmUp) -> Result<usize> {
+        let mut positions: Vec<(String, CommandPos)> = Vec::new();
+        match keys {
+            WarmUp::Keys(keys) => {
+                for key in keys {
+                    if let Some(cmd_pos) = self.index.get(key.as_str())? {
+                        positions.push((key, cmd_pos));
+                    }
+                }
+            }
+            WarmUp::Prefix(prefix) => {
+                for entry in self.index.iter_from(Bound::Included(prefix.as_str())) {
+                    let (key, cmd_pos) = entry?;
+                    if !key.starts_with(&prefix) {
+                        break;
+                    }
+                    positions.push((key.into(), cmd_pos));
+                }
+            }
+        }
+        positions.sort_by_key(|(_, cmd_pos)| (cmd_pos.gen, cmd_pos.pos));
+
+        let mut warmed = 0;
+        for (key, cmd_pos) in positions {
+            let res = self.reader.build_cmd_reader(cmd_pos, |mut cmd_reader| {
+                Ok(io::copy(&mut cmd_reader, &mut io::sink())?)
+            });
+            match res {
+                Ok(_) => warmed += 1,
+                // A concurrent compaction may have moved the entry and deleted the
+                // file it was in. Its new copy was just written, so skip it.
+                Err(e) => {
+                    if self.index.get(key.as_str())? == Some(cmd_pos) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(warmed)
+    }
+
+    /// Returns an iterator removing the key-value pairs from the store as it
+    /// yields them, in key order. Pairs not yet yielded when the iterator is
+    /// dropped are kept.
+    pub fn drain(&self) -> Drain<'_> {
+        Drain {
+            store: self,
+            last_key: None,
+        }
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.lookup_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    fn lookup_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.lookup_versioned(key)?.map(|(value, _)| value))
+    }
+
+    /// Returns the value of `key` with its version, 0 if it was written
+    /// without a sequence number.
+    fn lookup_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>> {
+        loop {
+            let cmd_pos = {
+                let _gate = self.batch_gate.read().unwrap();
+                match self.index.get_hot(key)? {
+                    Some(cmd_pos) => cmd_pos,
+                    None => return Ok(None),
+                }
+            };
+            if cmd_pos.is_expired(now_millis(&*self.clock)) {
+                return Ok(None);
+            }
+            match self.reader.read_command(cmd_pos) {
+                Ok(Command::Set { value, stamp, .. }) => {
+                    return Ok(Some((value, stamp.map_or(0, |stamp| stamp.seq))))
+                }
+                Ok(_) => return Err(KvsError::UnexpectedCommandType),
+                // A concurrent compaction may have moved the entry and deleted the
+                // file it was in. Retry with the new position in that case.
+                Err(e) => {
+                    if self.index.get(key)? == Some(cmd_pos) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the version of the value of `key`, `None` if it does not exist.
+    /// The writer must be locked, so that compactions do not move the value.
+    fn current_version(&self, key: &str) -> Result<Option<u64>> {
+        match self.index.get(key)? {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_millis(&*self.clock)) => {
+                match self.reader.read_command(cmd_pos)? {
+                    Command::Set { stamp, .. } => Ok(Some(stamp.map_or(0, |stamp| stamp.seq))),
+                    _ => Err(KvsError::UnexpectedCommandType),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the position of `key` in the index, for the methods which
+    /// cannot fail: a failed read of the hint file is logged, and the key
+    /// taken as missing.
+    fn index_get(&self, key: &str) -> Option<CommandPos> {
+        self.index.get(key).unwrap_or_else(|e| {
+            error!("Failed to look up {:?} in the index: {}", key, e);
+            None
+        })
+    }
+
+    /// Returns the first pair of `range` with a key greater than `last_key`,
+    /// and moves `last_key` to it.
+    pub(crate) fn next_pair(
+        &self,
+        last_key: &mut Option<String>,
+        range: &(Bound<String>, Bound<String>),
+    ) -> Option<Result<(String, String)>> {
+        loop {
+            let next = match last_key {
+                None => self.index.next(str_bound(&range.0)),
+                Some(last_key) => self.index.next(Bound::Excluded(last_key.as_str())),
+            };
+            let key = match next {
+                Ok(next) => next?.0.to_string(),
+                Err(e) => return Some(Err(e)),
+            };
+            if !range.contains(&key) {
+                return None;
+            }
+            *last_key = Some(key.clone());
+            match self.lookup(&key) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // Removed since we found it.
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Set a given key and value Strings in the store.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::env::current_dir;
+    /// use kvs::KvStore;
+    ///
+    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// store.set(String::from("my_key"), String::from("my_value")).unwrap();
+    /// ```
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_with_lsn(key, value)?;
+        Ok(())
+    }
+
+    /// Get a value from the store using a key String.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::env::current_dir;
+    /// use kvs::KvStore;
+    ///
+    /// let store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// match store.get(String::from("my_key")).unwrap() {
+    ///     Some(value) => println!("Value: {}", value),
+    ///     None => println!("Key not found"),
+    /// }
+    /// ```
+    #[instrument(level = "debug", skip(self))]
+    fn get(&self, key: String) -> Result<Option<String>> {
+        counter!(METRIC_GETS).increment(1);
+        self.lookup(&key)
+    }
+
+    /// Sets `key` to a binary value, stored as it is.
+    #[instrument(level = "debug", skip(self, value))]
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set_bytes_with_lsn(key, value)?;
+        Ok(())
+    }
+
+    /// Gets the value of `key` as bytes, whether it was set as a string or
+    /// as bytes.
+    #[instrument(level = "debug", skip(self))]
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        counter!(METRIC_GETS).increment(1);
+        self.lookup_bytes(&key)
+    }
+
+    /// Applies the writes of `batch` atomically: they are logged between
+    /// batch markers with a single flush, then applied to the index at once,
+    /// so `get` sees either none or all of them. After a crash, the batch is
+    /// discarded on open unless it was logged completely.
+    #[instrument(level = "debug", skip(self, batch), fields(len = batch.len()))]
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.write_batch_with_lsn(batch)?;
+        Ok(())
+    }
+
+    /// Gets the value of `key` with its version, the sequence number of the
+    /// write that set it. Values written by older versions of kvs, without a
+    /// sequence number, have version 0.
+    #[instrument(level = "debug", skip(self))]
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        counter!(METRIC_GETS).increment(1);
+        match self.lookup_versioned(&key)? {
+            Some((value, version)) => Ok(Some((String::from_utf8(value)?, version))),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies the writes of `batch` atomically like `write_batch`, if the
+    /// keys of `reads` still have the versions given. The versions are
+    /// checked and the batch written under the lock of the writer.
+    #[instrument(level = "debug", skip(self, reads, batch), fields(len = batch.len()))]
+    fn write_batch_if_unchanged(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for (key, version) in reads {
+            if self.current_version(&key)? != version {
+                return Err(KvsError::TransactionConflict);
+            }
+        }
+        writer.write_batch(batch_commands(batch))?;
+        Ok(())
+    }
+
+    /// Remove a given key from the store.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::env::current_dir;
+    /// use kvs::KvStore;
+    ///
+    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// store.remove(String::from("my_key")).unwrap();
+    /// ```
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_with_lsn(key)?;
+        Ok(())
+    }
+
+    /// Appends `suffix` to the value of `key` atomically, logging the suffix
+    /// only, see `KvStore::append_with_lsn`.
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        self.append_with_lsn(key, suffix)?;
+        Ok(())
+    }
+
+    /// Sets the value of `key` only if it does not exist, atomically with
+    /// respect to other writes.
+    #[instrument(level = "debug", skip(self, value))]
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.is_live(&key)? {
+            return Ok(false);
+        }
+        writer.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Sets the value of `key` only if it already exists, atomically with
+    /// respect to other writes.
+    #[instrument(level = "debug", skip(self, value))]
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        if !writer.is_live(&key)? {
+            return Ok(false);
+        }
+        writer.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Makes `key` expire `ttl` from now, replacing its previous expiry if any.
+    /// Returns `false` if the key does not exist.
+    ///
+    /// Only a small record with the new expiry is appended to the log; the
+    /// value is not rewritten. Expired keys read as absent.
+    #[instrument(level = "debug", skip(self))]
+    fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        let expires_at = now_millis(&*writer.clock).saturating_add(ttl.as_millis() as u64);
+        writer.set_expiry(key, Some(expires_at))
+    }
+
+    /// Removes the expiry of `key` by appending a small record to the log.
+    /// Returns `false` if the key does not exist or has no expiry.
+    #[instrument(level = "debug", skip(self))]
+    fn persist(&self, key: String) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        match writer.live_pos(&key)? {
+            Some(cmd_pos) if cmd_pos.expires_at.is_some() => writer.set_expiry(key, None),
+            _ => Ok(false),
+        }
+    }
+
+    /// Flushes the buffered writes and fsyncs the active log file.
+    ///
+    /// Writes are flushed to the OS on every call, but only synced to the disk by
+    /// `flush`, so a power loss may lose the writes acknowledged since the last one.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors during flushing or syncing the log.
+    #[instrument(level = "debug", skip(self))]
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+
+    /// Copies the log files of the backup in `from` next to the current ones,
+    /// then swaps them in: the index is rebuilt from them and replaces the
+    /// current one at once, and the replaced log files are deleted.
+    ///
+    /// The writer is locked for the whole restore, so writes wait for it.
+    /// A restore interrupted by a crash is finished by `KvStore::open` if the
+    /// restored log files were all copied, and rolled back otherwise.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if `from` has no log files or a
+    /// backup is running, and propagates I/O or deserialization errors
+    /// during the copy. The current data set is kept on errors.
+    #[instrument(level = "debug", skip(self))]
+    fn restore(&self, from: &Path) -> Result<()> {
+        self.writer.lock().unwrap().restore(from)
+    }
+
+    /// Compacts the log now, whatever its stale commands take, finishing the
+    /// compaction in progress if any. It runs even if compactions are paused,
+    /// and writes wait for it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if a backup is running, and
+    /// propagates I/O or deserialization errors during the compaction.
+    #[instrument(level = "debug", skip(self))]
+    fn compact(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.backups_running > 0 {
+            return Err(KvsError::StringError(
+                "cannot compact while a backup is running".to_owned(),
+            ));
+        }
+        writer.compact()
+    }
+}
+
+/// Map generation number to the file reader.
+type ReaderSet = BTreeMap<u64, BufReaderWithPos<LogFile>>;
+
+/// A log reader that can be shared between threads.
+///
+/// A read takes an idle `ReaderSet` out of the pool, or starts an empty one if every set is in
+/// use, and puts it back afterwards. So concurrent reads through one `KvStore` use separate file
+/// handles and don't wait for each other, except to take and return a set.
+pub(crate) struct KvStoreReader {
+    path: Arc<PathBuf>,
+    vfs: Arc<dyn Vfs>,
+    // Idle reader sets
+    readers: Mutex<Vec<ReaderSet>>,
+    // Generation of the latest compaction file.
+    // Readers with a generation before safe_point can be closed.
+    safe_point: Arc<AtomicU64>,
+    /// The encryption keys, shared by the clones and the namespaces.
+    pub(crate) keyring: Arc<Keyring>,
+    /// The memory maps shared by the clones.
+    #[cfg(feature = "mmap")]
+    maps: Arc<MapSet>,
+}
+
+/// The memory maps of the log files, see `KvStoreBuilder::mmap_reads`.
+#[cfg(feature = "mmap")]
+struct MapSet {
+    enabled: AtomicBool,
+    /// Maps generation number to the map of the file, or to `None` if it
+    /// cannot be mapped. The map of the active log file is replaced when a
+    /// record past its end is read.
+    maps: RwLock<BTreeMap<u64, Option<Arc<memmap2::Mmap>>>>,
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> Self {
+        Self {
+            path: Arc::clone(&self.path),
+            vfs: Arc::clone(&self.vfs),
+            // Don't use other KvStoreReader's readers
+            readers: Mutex::new(Vec::new()),
+            safe_point: Arc::clone(&self.safe_point),
+            keyring: Arc::clone(&self.keyring),
+            #[cfg(feature = "mmap")]
+            maps: Arc::clone(&self.maps),
+        }
+    }
+}
+
+impl KvStoreReader {
+    /// Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    ///
+    /// An "append" command is resolved into the "set" command of the value
+    /// it makes.
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        let command = self.read_record(cmd_pos)?;
+        self.resolve(command, cmd_pos)
+    }
+
+    /// Reads the command at `cmd_pos` as it is in the log.
+    fn read_record(&self, cmd_pos: CommandPos) -> Result<Command> {
+        #[cfg(feature = "mmap")]
+        if let Some(command) = self.read_mapped(cmd_pos)? {
+            return Ok(command);
+        }
+        self.build_cmd_reader(cmd_pos, |cmd_reader| {
+            decode_command(cmd_reader, cmd_pos, &self.keyring)
+        })
+    }
+
+    /// Returns the "set" command of the value made by `command`, read at
+    /// `cmd_pos`, from the records of its chain if it is an "append" command.
+    /// The key, the expiry and the stamp are the ones of `command`.
+    fn resolve(&self, command: Command, cmd_pos: CommandPos) -> Result<Command> {
+        let (key, expires_at, stamp) = match &command {
+            Command::Append {
+                key,
+                expires_at,
+                stamp,
+                ..
+            } => (key.clone(), *expires_at, *stamp),
+            _ => return Ok(command),
+        };
+        let mut suffixes = Vec::new();
+        let mut next = command;
+        let mut base_pos = cmd_pos;
+        loop {
+            match next {
+                Command::Append {
+                    key: base_key,
+                    suffix,
+                    base_gen,
+                    base_range,
+                    ..
+                } if base_key == key => {
+                    suffixes.push(suffix);
+                    base_pos = CommandPos::from((base_gen, base_range));
+                    next = self.read_record(base_pos)?;
+                }
+                Command::Set {
+                    key: base_key,
+                    mut value,
+                    ..
+                } if base_key == key => {
+                    for suffix in suffixes.into_iter().rev() {
+                        value.extend(suffix);
+                    }
+                    return Ok(Command::Set {
+                        key,
+                        value,
+                        expires_at,
+                        stamp,
+                    });
+                }
+                // The chain does not lead to a value of the key.
+                _ => {
+                    return Err(KvsError::Corruption {
+                        gen: base_pos.gen,
+                        offset: base_pos.pos,
+                    })
+                }
+            }
+        }
+    }
+
+    /// Reads the command at `cmd_pos` from the memory map of its log file, or
+    /// returns `None` if it is not mapped.
+    #[cfg(feature = "mmap")]
+    fn read_mapped(&self, cmd_pos: CommandPos) -> Result<Option<Command>> {
+        let map = match self.map(cmd_pos.gen, cmd_pos.pos + cmd_pos.len)? {
+            Some(map) => map,
+            None => return Ok(None),
+        };
+        let mut record = match map.get(cmd_pos.pos as usize..(cmd_pos.pos + cmd_pos.len) as usize) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        Command::read_from(&mut record, cmd_pos.gen, cmd_pos.pos, &self.keyring)?
+            .ok_or(KvsError::UnexpectedCommandType)
+            .map(Some)
+    }
+
+    /// Returns the map of the log file `gen`, mapping it if it is not mapped
+    /// up to `end`.
+    #[cfg(feature = "mmap")]
+    fn map(&self, gen: u64, end: u64) -> Result<Option<Arc<memmap2::Mmap>>> {
+        if !self.maps.enabled.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        match self.maps.maps.read().unwrap().get(&gen) {
+            Some(None) => return Ok(None),
+            Some(Some(map)) if map.len() as u64 >= end => return Ok(Some(Arc::clone(map))),
+            _ => {}
+        }
+        let mut maps = self.maps.maps.write().unwrap();
+        // Unmap the stale log files, like `close_stale_handles`.
+        *maps = maps.split_off(&self.safe_point.load(Ordering::SeqCst));
+        let map = self
+            .vfs
+            .map(&log_path(&self.path, gen))?
+            .filter(|map| !segment::is_compressed(map))
+            .map(Arc::new);
+        maps.insert(gen, map.clone());
+        Ok(map)
+    }
+
+    /// Reads the commands at `positions` in the given order, with the same
+    /// file handles.
+    fn read_commands(&self, positions: impl Iterator<Item = CommandPos>) -> Vec<Result<Command>> {
+        let mut readers = self.readers.lock().unwrap().pop().unwrap_or_default();
+        self.close_stale_handles(&mut readers);
+        let commands = positions
+            .map(|cmd_pos| {
+                let command = self.read_with(&mut readers, cmd_pos, |cmd_reader| {
+                    decode_command(cmd_reader, cmd_pos, &self.keyring)
+                })?;
+                self.resolve(command, cmd_pos)
+            })
+            .collect();
+        self.readers.lock().unwrap().push(readers);
+        commands
+    }
+
+    /// Build command reader from reader and `CommandPos`.
+    fn build_cmd_reader<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReaderWithPos<LogFile>>) -> Result<R>,
+    {
+        let mut readers = self.readers.lock().unwrap().pop().unwrap_or_default();
+        self.close_stale_handles(&mut readers);
+        let res = self.read_with(&mut readers, cmd_pos, f);
+        self.readers.lock().unwrap().push(readers);
+        res
+    }
+
+    fn read_with<F, R>(&self, readers: &mut ReaderSet, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReaderWithPos<LogFile>>) -> Result<R>,
+    {
+        // Open the file if we haven't opened it in this `ReaderSet`.
+        // We don't use entry API here because we want the errors to be propogated.
+        if !readers.contains_key(&cmd_pos.gen) {
+            let file = segment::open(&*self.vfs, &log_path(&self.path, cmd_pos.gen))?;
+            let reader = BufReaderWithPos::new(file)?;
+            readers.insert(cmd_pos.gen, reader);
+        }
+
+        let reader = readers
+            .get_mut(&cmd_pos.gen)
+            .expect("Cannot find log reader");
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+
+        let cmd_reader = reader.take(cmd_pos.len);
+        f(cmd_reader)
+    }
+
+    /// Close the file handles of `readers` with generation number less than safe_point.
+    ///
+    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
+    /// The compaction generation contains the sum of all operations before it and the
+    /// in-memory index contains no entries with generation number less than safe_point.
+    /// So we can safely close those file handles and the stale files can be deleted.
+    fn close_stale_handles(&self, readers: &mut ReaderSet) {
+        while !readers.is_empty() {
+            let first_gen = *readers.keys().next().unwrap();
+            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
+                break;
+            }
+            readers.remove(&first_gen);
+        }
+    }
+}
+
+fn decode_command(
+    mut cmd_reader: io::Take<&mut BufReaderWithPos<LogFile>>,
+    cmd_pos: CommandPos,
+    keyring: &Keyring,
+) -> Result<Command> {
+    Command::read_from(&mut cmd_reader, cmd_pos.gen, cmd_pos.pos, keyring)?
+        .ok_or(KvsError::UnexpectedCommandType)
+}
+
+pub(crate) struct KvStoreWriter {
+    pub(crate) path: Arc<PathBuf>,
+    pub(crate) vfs: Arc<dyn Vfs>,
+    clock: Arc<dyn Clock>,
+    pub(crate) writer: BufWriterWithPos<LogFile>,
+    reader: KvStoreReader,
+    /// The number of bytes representing "stale" commands
+    /// that could be deleted during a compaction.
+    uncompacted: u64,
+    /// Set by `KvStoreBuilder::compaction_threshold`.
+    compaction_threshold: u64,
+    /// Set by `KvStoreBuilder::compaction_slice_keys`.
+    compaction_slice_keys: Option<u64>,
+    /// Set by `KvStoreBuilder::compaction_slice_bytes`.
+    compaction_slice_bytes: Option<u64>,
+    /// The compaction run in slices, if one is in progress.
+    pub(crate) compaction: Option<Compaction>,
+    /// Set by `KvStoreBuilder::segment_size`.
+    segment_size: Option<u64>,
+    /// Set by `KvStoreBuilder::compression`.
+    compression: Option<(Compression, usize)>,
+    /// Set by `KvStoreBuilder::segment_compression`.
+    segment_compression: Option<Compression>,
+    compaction_stats: CompactionStats,
+    /// Current generation number
+    pub(crate) current_gen: u64,
+    index: Arc<Keydir>,
+    batch_gate: Arc<RwLock<()>>,
+    compaction_listeners: Vec<CompactionListener>,
+    durability: Durability,
+    /// Set by `KvStore::pause_compaction`.
+    compaction_paused: bool,
+    /// The number of running backups, during which compactions are deferred.
+    backups_running: usize,
+    /// How long removed values can be undeleted, `None` if they can't.
+    trash_retention: Option<Duration>,
+    /// The last removed value of every key removed with a retention.
+    trash: BTreeMap<String, Trashed>,
+    /// Set by `KvStore::set_segment_archive`.
+    segment_archive: Option<SegmentArchive>,
+    /// Below this many bytes of free disk space, writes fail. 0 disables the check.
+    min_free_space: u64,
+    /// When the free disk space was last measured, and whether it was below
+    /// `min_free_space`.
+    free_space_check: Option<(SystemTime, bool)>,
+    /// Stops the background flusher of `Durability::EveryNMillis` when dropped.
+    flusher: Option<Sender<()>>,
+    /// Set by `KvStore::set_max_tombstone_age`.
+    max_tombstone_age: Option<Duration>,
+    /// The time of the oldest tombstone of every log file with tombstones, in
+    /// milliseconds since the Unix epoch.
+    tombstones: BTreeMap<u64, u64>,
+    /// Stops the background sweeper of `max_tombstone_age` when dropped.
+    tombstone_sweeper: Option<Sender<()>>,
+    /// Stops the background sweeper of `KvStore::set_expiry_sweep_interval`
+    /// when dropped.
+    expiry_sweeper: Option<Sender<()>>,
+    /// Set by `KvStore::set_checkpoint_interval`.
+    checkpoint_interval: Option<Duration>,
+    /// Stops the background checkpoints of `checkpoint_interval` when dropped.
+    checkpointer: Option<Sender<()>>,
+    /// Set by `KvStore::set_history_retention`.
+    history: History,
+    /// Set by `KvStoreBuilder::keep_versions`.
+    versions: Versions,
+    /// The generation of the last compaction file, or of the first restored
+    /// log file, and the sequence number of the last write before it. The
+    /// changes after it are all in the log files.
+    pub(crate) compacted_gen: u64,
+    pub(crate) compacted_seq: u64,
+    /// Set by `KvStore::create_index`.
+    indexes: SecondaryIndexes,
+    /// Set by `KvStore::snapshot_every`.
+    #[cfg(feature = "fs")]
+    snapshot_schedule: Option<SnapshotSchedule>,
+    /// Stops the background snapshots of `snapshot_schedule` when dropped.
+    #[cfg(feature = "fs")]
+    snapshotter: Option<Sender<()>>,
+}
+
+impl KvStoreWriter {
+    /// Reads the value of a key. No compaction can run concurrently, as it
+    /// needs the write lock too.
+    pub(crate) fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// Reads the value of a key as bytes.
+    fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.live_pos(key)? {
+            Some(cmd_pos) => match self.reader.read_command(cmd_pos)? {
+                Command::Set { value, .. } => Ok(Some(value)),
+                _ => Err(KvsError::UnexpectedCommandType),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the log pointer of `key`, unless it does not exist or has expired.
+    fn live_pos(&self, key: &str) -> Result<Option<CommandPos>> {
+        let now = now_millis(&*self.clock);
+        Ok(self
+            .index
+            .get(key)?
+            .filter(|cmd_pos| !cmd_pos.is_expired(now)))
+    }
+
+    fn is_live(&self, key: &str) -> Result<bool> {
+        Ok(self.live_pos(key)?.is_some())
+    }
+
+    /// Logs a new expiry of `key`, or its removal with `None`, and points the
+    /// index to it. Returns `false` if the key does not exist.
+    fn set_expiry(&mut self, key: String, expires_at: Option<u64>) -> Result<bool> {
+        self.check_free_space()?;
+        let mut cmd_pos = match self.live_pos(&key)? {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(false),
+        };
+        let command = Command::Expire { key, expires_at };
+        let lsn = self.next_stamp().seq;
+        let pos = self.writer.pos;
+        io_fail_point!("kvs::write");
+        command.write_to(&mut self.writer, lsn, &self.reader.keyring)?;
+        io_fail_point!("kvs::flush");
+        self.writer.flush()?;
+        self.sync_if_always()?;
+        io_fail_point!("kvs::write::after");
+        counter!(METRIC_BYTES_WRITTEN).increment(self.writer.pos - pos);
+
+        if let Command::Expire { key, expires_at } = command {
+            cmd_pos.expires_at = expires_at;
+            cmd_pos.expiry_changed = true;
+            self.index.insert(key.into(), cmd_pos)?;
+            // Like a "remove" command, the record is stale after the next compaction.
+            self.uncompacted += self.writer.pos - pos;
+        }
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
+
+        self.maybe_rotate()?;
+        self.maybe_compact()?;
+
+        Ok(true)
+    }
+
+    /// Restores the value of `key` from the trash. Returns `false` if the key
+    /// exists or has no value in the trash.
+    fn undelete(&mut self, key: String) -> Result<bool> {
+        if self.is_live(&key)? {
+            return Ok(false);
+        }
+        let now = now_millis(&*self.clock);
+        let trashed = match self.trash.get(&key) {
+            Some(trashed) if trashed.is_retained(now, self.trash_retention) => *trashed,
+            _ => return Ok(false),
+        };
+        let value = match self.reader.read_command(trashed.pos)? {
+            Command::Set { value, .. } => value,
+            _ => return Err(KvsError::UnexpectedCommandType),
+        };
+        self.write_set(Command::Set {
+            key: key.clone(),
+            value,
+            expires_at: trashed.pos.expires_at,
+            stamp: None,
+        })?;
+        self.trash.remove(&key);
+        Ok(true)
+    }
+
+    pub(crate) fn set(&mut self, key: String, value: String) -> Result<u64> {
+        self.write_set(Command::set(key, value.into_bytes()))
+    }
+
+    /// Appends a "set" command to the log and points the index to it.
+    /// Returns its log sequence number.
+    fn write_set(&mut self, mut command: Command) -> Result<u64> {
+        self.check_free_space()?;
+        command.set_stamp(Some(self.next_stamp()));
+        let lsn = command.lsn();
+        let pos = self.writer.pos;
+        io_fail_point!("kvs::write");
+        command.write_compressed(
+            &mut self.writer,
+            lsn,
+            self.compression,
+            &self.reader.keyring,
+        )?;
+        io_fail_point!("kvs::flush");
+        self.writer.flush()?;
+        self.sync_if_always()?;
+        io_fail_point!("kvs::write::after");
+        counter!(METRIC_SETS).increment(1);
+        counter!(METRIC_BYTES_WRITTEN).increment(self.writer.pos - pos);
+        if let Command::Set {
+            key,
+            value,
+            expires_at,
+            stamp,
+        } = command
+        {
+            self.indexes.insert(&key, &value, None);
+            // Storing log pointers in the index. Log pointers is of type CommandPos.
+            let mut cmd_pos = CommandPos::from((self.current_gen, pos..self.writer.pos));
+            cmd_pos.expires_at = expires_at;
+            if let Some(stamp) = stamp {
+                self.history
+                    .push(&self.index, &key, Some(stamp), Some(cmd_pos))?;
+                self.versions.push(&key, stamp, Some(cmd_pos));
+            }
+            if let Some(old_cmd) = self.index.insert(key.into(), cmd_pos)? {
+                self.uncompacted += old_cmd.len;
+            }
+        }
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
+
+        self.maybe_rotate()?;
+        self.maybe_compact()?;
+
+        Ok(lsn)
+    }
+
+    /// Appends `suffix` to the value of `key`, or sets `key` to `suffix` if it
+    /// is not live. Returns the log sequence number of the write.
+    ///
+    /// Only the suffix is logged, in an "append" command pointing to the
+    /// record of the value it extends, unless the chain is already
+    /// `MAX_APPEND_DEPTH` commands long or a compaction is in progress: the
+    /// log files of the chain may be deleted by the compaction, so the whole
+    /// value is written in a "set" command then.
+    fn append(&mut self, key: String, suffix: Vec<u8>) -> Result<u64> {
+        let now = now_millis(&*self.clock);
+        let base = match self.index.get(&key)? {
+            Some(cmd_pos) if !cmd_pos.is_expired(now) => cmd_pos,
+            _ => return self.write_set(Command::set(key, suffix)),
+        };
+        // Only the records rewritten on compaction may be "append" commands.
+        let depth = if base.expiry_changed {
+            match self.reader.read_record(base)? {
+                Command::Append { depth, .. } => depth + 1,
+                _ => 1,
+            }
+        } else {
+            1
+        };
+        if depth > MAX_APPEND_DEPTH || self.compaction.is_some() {
+            let mut value = match self.reader.read_command(base)? {
+                Command::Set { value, .. } => value,
+                _ => return Err(KvsError::UnexpectedCommandType),
+            };
+            value.extend(suffix);
+            return self.write_set(Command::Set {
+                key,
+                value,
+                expires_at: base.expires_at,
+                stamp: None,
+            });
+        }
+
+        self.check_free_space()?;
+        let stamp = self.next_stamp();
+        let command = Command::Append {
+            key: key.clone(),
+            suffix,
+            base_gen: base.gen,
+            base_range: base.pos..base.pos + base.len,
+            depth,
+            expires_at: base.expires_at,
+            stamp: Some(stamp),
+        };
+        let pos = self.writer.pos;
+        io_fail_point!("kvs::write");
+        command.write_compressed(
+            &mut self.writer,
+            stamp.seq,
+            self.compression,
+            &self.reader.keyring,
+        )?;
+        io_fail_point!("kvs::flush");
+        self.writer.flush()?;
+        self.sync_if_always()?;
+        io_fail_point!("kvs::write::after");
+        counter!(METRIC_SETS).increment(1);
+        counter!(METRIC_BYTES_WRITTEN).increment(self.writer.pos - pos);
+
+        let mut cmd_pos = CommandPos::from((self.current_gen, pos..self.writer.pos));
+        cmd_pos.expires_at = base.expires_at;
+        cmd_pos.expiry_changed = true;
+        if !self.indexes.is_empty() {
+            if let Command::Set { value, .. } = self.reader.read_command(cmd_pos)? {
+                self.indexes.insert(&key, &value, None);
+            }
+        }
+        self.history
+            .push(&self.index, &key, Some(stamp), Some(cmd_pos))?;
+        self.versions.push(&key, stamp, Some(cmd_pos));
+        // The records of the chain are stale once the next compaction folds it.
+        if let Some(old_cmd) = self.index.insert(key.into(), cmd_pos)? {
+            self.uncompacted += old_cmd.len;
+        }
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
+
+        self.maybe_rotate()?;
+        self.maybe_compact()?;
+
+        Ok(stamp.seq)
+    }
+
+    /// Writes the commands between batch markers with a single flush, then
+    /// updates the index. On replay, the commands are discarded unless the
+    /// commit marker follows them. The markers have the log sequence number
+    /// of the last command, which is returned.
+    fn write_batch(&mut self, mut commands: Vec<Command>) -> Result<u64> {
+        if commands.is_empty() {
+            return Ok(self.history.last_seq);
+        }
+        self.check_free_space()?;
+        for command in &mut commands {
+            command.set_stamp(Some(self.next_stamp()));
+        }
+        let count = commands.len() as u32;
+        let last_lsn = self.history.last_seq;
+        let start = self.writer.pos;
+        let mut ranges = Vec::with_capacity(commands.len());
+        io_fail_point!("kvs::write");
+        Command::Begin { count }.write_to(&mut self.writer, last_lsn, &self.reader.keyring)?;
+        for command in &commands {
+            let pos = self.writer.pos;
+            command.write_compressed(
+                &mut self.writer,
+                command.lsn(),
+                self.compression,
+                &self.reader.keyring,
+            )?;
+            ranges.push(pos..self.writer.pos);
+        }
+        Command::Commit { count }.write_to(&mut self.writer, last_lsn, &self.reader.keyring)?;
+        io_fail_point!("kvs::flush");
+        self.writer.flush()?;
+        self.sync_if_always()?;
+        io_fail_point!("kvs::write::after");
+        counter!(METRIC_BYTES_WRITTEN).increment(self.writer.pos - start);
+
+        if commands
+            .iter()
+            .any(|command| matches!(command, Command::Remove { .. }))
+        {
+            self.track_tombstone();
+        }
+
+        let records_len: u64 = ranges.iter().map(|range| range.end - range.start).sum();
+        let gate = self.batch_gate.write().unwrap();
+        for (command, range) in commands.into_iter().zip(ranges) {
+            match command {
+                Command::Set {
+                    key, value, stamp, ..
+                } => {
+                    counter!(METRIC_SETS).increment(1);
+                    self.indexes.insert(&key, &value, None);
+                    let cmd_pos = CommandPos::from((self.current_gen, range));
+                    if let Some(stamp) = stamp {
+                        self.history
+                            .push(&self.index, &key, Some(stamp), Some(cmd_pos))?;
+                        self.versions.push(&key, stamp, Some(cmd_pos));
+                    }
+                    if let Some(old_cmd) = self.index.insert(key.into(), cmd_pos)? {
+                        self.uncompacted += old_cmd.len;
+                    }
+                }
+                Command::Remove { key, stamp, .. } => {
+                    counter!(METRIC_REMOVES).increment(1);
+                    if let Some(stamp) = stamp {
+                        self.history.push(&self.index, &key, Some(stamp), None)?;
+                        self.versions.push(&key, stamp, None);
+                    }
+                    if let Some(old_cmd) = self.index.remove(key.as_str())? {
+                        self.uncompacted += old_cmd.len;
+                    }
+                    self.uncompacted += range.end - range.start;
+                }
+                Command::Expire { .. }
+                | Command::Begin { .. }
+                | Command::Commit { .. }
+                | Command::RemoveRange { .. }
+                | Command::Append { .. } => {
+                    unreachable!("only sets and removes are batched")
+                }
+            }
+        }
+        // The markers are stale once the batch is compacted.
+        self.uncompacted += self.writer.pos - start - records_len;
+        drop(gate);
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
+
+        self.maybe_rotate()?;
+        self.maybe_compact()?;
+
+        Ok(last_lsn)
+    }
+
+    /// Writes `pairs` to the log file after the active one, through a temporary
+    /// file renamed once synced, then moves the active log past it.
+    fn bulk_load(&mut self, pairs: impl Iterator<Item = (String, String)>) -> Result<u64> {
+        self.check_free_space()?;
+        let bulk_gen = self.current_gen + 1;
+        let tmp_path = log_path(&self.path, bulk_gen).with_extension("log.tmp");
+        // It may have been left over by a load interrupted by a crash.
+        let _ = self.vfs.remove_file(&tmp_path);
+
+        // The pairs are stamped with consecutive sequence numbers.
+        let stamp = self.next_stamp();
+        let positions = match self.write_bulk_file(&tmp_path, pairs, stamp) {
+            Ok(positions) => positions,
+            Err(e) => {
+                let _ = self.vfs.remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+        // Writes acknowledged before the load must not be lost once it is installed.
+        self.sync()?;
+        io_fail_point!("kvs::bulk_load::rename");
+        self.vfs
+            .rename(&tmp_path, &log_path(&self.path, bulk_gen))?;
+        self.current_gen += 2;
+        self.writer = new_log_file(&*self.vfs, &self.path, self.current_gen)?;
+
+        let loaded = positions.len() as u64;
+        let gate = self.batch_gate.write().unwrap();
+        for (i, (key, range)) in positions.into_iter().enumerate() {
+            let cmd_pos = CommandPos::from((bulk_gen, range));
+            let stamp = bulk_stamp(stamp, i);
+            self.history
+                .push(&self.index, &key, Some(stamp), Some(cmd_pos))?;
+            self.versions.push(&key, stamp, Some(cmd_pos));
+            if !self.indexes.is_empty() {
+                if let Command::Set { value, .. } = self.reader.read_command(cmd_pos)? {
+                    self.indexes.insert(&key, &value, None);
+                }
+            }
+            if let Some(old_cmd) = self.index.insert(key.into(), cmd_pos)? {
+                self.uncompacted += old_cmd.len;
+            }
+        }
+        self.history.last_seq = bulk_stamp(stamp, loaded.saturating_sub(1) as usize).seq;
+        drop(gate);
+        counter!(METRIC_SETS).increment(loaded);
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
+
+        self.maybe_rotate()?;
+        self.maybe_compact()?;
+
+        Ok(loaded)
+    }
+
+    fn write_bulk_file(
+        &self,
+        path: &Path,
+        pairs: impl Iterator<Item = (String, String)>,
+        stamp: Stamp,
+    ) -> Result<Vec<(String, Range<u64>)>> {
+        let mut writer = BufWriterWithPos::new(self.vfs.open_append(path)?)?;
+        let mut positions: Vec<(String, Range<u64>)> = Vec::new();
+        for (key, value) in pairs {
+            if let Some((last_key, _)) = positions.last() {
+                if key <= *last_key {
+                    return Err(KvsError::StringError(format!(
+                        "bulk load keys are not strictly increasing: {:?} after {:?}",
+                        key, last_key
+                    )));
+                }
+            }
+            let mut command = Command::set(key.clone(), value.into_bytes());
+            command.set_stamp(Some(bulk_stamp(stamp, positions.len())));
+            let pos = writer.pos;
+            command.write_compressed(
+                &mut writer,
+                command.lsn(),
+                self.compression,
+                &self.reader.keyring,
+            )?;
+            positions.push((key, pos..writer.pos));
+        }
+        writer.flush()?;
+        io_fail_point!("kvs::bulk_load::sync");
+        writer.writer.get_ref().sync_data()?;
+        io_fail_point!("kvs::bulk_load::sync::after");
+        counter!(METRIC_BYTES_WRITTEN).increment(writer.pos);
+        Ok(positions)
+    }
+
+    /// Fails with `KvsError::DiskFull` if the free disk space, measured at most
+    /// every `FREE_SPACE_CHECK_INTERVAL`, is below `min_free_space`.
+    fn check_free_space(&mut self) -> Result<()> {
+        if self.min_free_space == 0 {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let fresh = match self.free_space_check {
+            Some((checked_at, _)) => matches!(
+                now.duration_since(checked_at),
+                Ok(age) if age < FREE_SPACE_CHECK_INTERVAL
+            ),
+            None => false,
+        };
+        if !fresh {
+            let full = match self.vfs.available_space(&self.path)? {
+                Some(available) => available < self.min_free_space,
+                None => false,
+            };
+            self.free_space_check = Some((now, full));
+        }
+        match self.free_space_check {
+            Some((_, true)) => Err(KvsError::DiskFull),
+            _ => Ok(()),
+        }
+    }
+
+    /// Compacts the log if it has enough stale commands, or runs the next
+    /// slice of the compaction in progress, unless compaction is paused.
+    fn maybe_compact(&mut self) -> Result<()> {
+        self.index.evict()?;
+        let due = self.compaction.is_some() || self.uncompacted > self.compaction_threshold;
+        if due && !self.compaction_deferred() {
+            self.compact_step(true)?;
+        }
+        Ok(())
+    }
+
+    /// Rolls over to a new active log file once the active one reaches
+    /// `segment_size`.
+    fn maybe_rotate(&mut self) -> Result<()> {
+        match self.segment_size {
+            Some(size) if self.writer.pos >= size => {}
+            _ => return Ok(()),
+        }
+        // Only the active log file is synced by `sync`, so sync the one being replaced.
+        self.sync()?;
+        self.current_gen += 1;
+        self.writer = new_log_file(&*self.vfs, &self.path, self.current_gen)?;
+        Ok(())
+    }
+
+    /// Returns `true` if compactions are paused, or deferred by a running backup.
+    fn compaction_deferred(&self) -> bool {
+        self.compaction_paused || self.backups_running > 0
+    }
+
+    /// Records a tombstone written to the active log file now.
+    fn track_tombstone(&mut self) {
+        let now = now_millis(&*self.clock);
+        self.tombstones.entry(self.current_gen).or_insert(now);
+    }
+
+    /// Compacts the log if a tombstone is older than `max_tombstone_age`,
+    /// unless compaction is paused.
+    fn purge_old_tombstones(&mut self) -> Result<()> {
+        let max_age = match self.max_tombstone_age {
+            Some(max_age) => max_age.as_millis() as u64,
+            None => return Ok(()),
+        };
+        let now = now_millis(&*self.clock);
+        let too_old = self
+            .tombstones
+            .values()
+            .any(|&oldest| oldest.saturating_add(max_age) <= now);
+        if too_old && !self.compaction_deferred() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Archives the stale log file of generation `gen` at `path` with the
+    /// segment archive, or deletes it without one.
+    fn retire_segment(&self, gen: u64, path: &Path) -> io::Result<()> {
+        match &self.segment_archive {
+            None => self.vfs.remove_file(path),
+            Some(SegmentArchive::Dir(dir)) => {
+                self.vfs.create_dir_all(dir)?;
+                self.vfs.rename(path, &log_path(dir, gen))
+            }
+            Some(SegmentArchive::Callback(archive)) => {
+                archive(gen, path)?;
+                match self.vfs.remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Syncs the log and marks a backup as running, so that compactions and
+    /// restores wait for it. Returns the data files to back up.
+    fn start_backup(&mut self) -> Result<Vec<(PathBuf, u64)>> {
+        self.sync()?;
+        let files = self.data_files()?;
+        self.backups_running += 1;
+        Ok(files)
+    }
+
+    /// Compacts the log if the expired entries and the stale commands add up
+    /// to the compaction threshold, unless compaction is deferred or the
+    /// history is retained.
+    fn sweep_expired(&mut self) -> Result<()> {
+        if self.compaction_deferred() || self.history.state.is_some() {
+            return Ok(());
+        }
+        let now = now_millis(&*self.clock);
+        let mut expired = 0;
+        for entry in self.index.iter_from(Bound::Unbounded) {
+            let (_, cmd_pos) = entry?;
+            if cmd_pos.is_expired(now) {
+                expired += cmd_pos.len;
+            }
+        }
+        if expired > 0 && self.uncompacted + expired >= self.compaction_threshold {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the paths and lengths of the log files, and of those of the
+    /// manifest, the history state, the definitions of the secondary indexes
+    /// and the `ENGINE` file that exist. They do not change until the next
+    /// compaction, but the active log file may grow past its length.
+    fn data_files(&self) -> Result<Vec<(PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for gen in sorted_gen_list(&*self.vfs, &self.path)? {
+            let path = log_path(&self.path, gen);
+            let len = self.vfs.file_len(&path)?;
+            files.push((path, len));
+        }
+        for path in [
+            manifest_path(&self.path),
+            history_path(&self.path),
+            indexes_path(&self.path),
+            engine_path(&self.path),
+        ] {
+            match self.vfs.file_len(&path) {
+                Ok(len) => files.push((path, len)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
             }
-        } else {
-            Ok(None)
         }
+        Ok(files)
     }
 
-    /// Remove a given key from the store.
-    ///
-    /// # Example
-    ///
-    /// ```
-    /// use std::env::current_dir;
-    /// use kvs::KvStore;
-    ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
-    /// store.remove(String::from("my_key")).unwrap();
-    /// ```
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    /// Adds the live values to the secondary indexes, or only to the index
+    /// `only`.
+    fn index_values(&mut self, only: Option<&str>) -> Result<()> {
+        if self.indexes.is_empty() {
+            return Ok(());
+        }
+        let now = now_millis(&*self.clock);
+        let index = Arc::clone(&self.index);
+        for entry in index.iter_from(Bound::Unbounded) {
+            let (key, cmd_pos) = entry?;
+            if cmd_pos.is_expired(now) {
+                continue;
+            }
+            if let Command::Set { value, .. } = self.reader.read_command(cmd_pos)? {
+                self.indexes.insert(&key, &value, only);
+            }
+        }
+        Ok(())
     }
-}
 
-/// A single thread reader.
-///
-/// Each `KvStore` instance has its own `KvStoreReader` and `KvStoreReader`s open the same files
-/// separately. So the user can read concurrently through multiple `KvStore`s in different threads.
-struct KvStoreReader {
-    path: Arc<PathBuf>,
-    // Map generation number to the file reader
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
-    // Generation of the latest compaction file.
-    // Readers with a generation before safe_point can be closed.
-    safe_point: Arc<AtomicU64>,
-}
+    /// Returns the stamp of a new write.
+    fn next_stamp(&mut self) -> Stamp {
+        self.history.last_seq += 1;
+        Stamp {
+            seq: self.history.last_seq,
+            at: now_millis(&*self.clock),
+        }
+    }
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> Self {
-        Self {
-            path: Arc::clone(&self.path),
-            // Don't use other KvStoreReader's readers
-            readers: RefCell::new(BTreeMap::new()),
-            safe_point: Arc::clone(&self.safe_point),
+    /// Writes the checkpoint of the store, see `KvStore::checkpoint`.
+    fn checkpoint(&mut self) -> Result<()> {
+        // The checkpoint must not cover writes lost in a crash.
+        self.sync()?;
+        let mut logs = Vec::new();
+        for gen in sorted_gen_list(&*self.vfs, &self.path)? {
+            if gen < self.current_gen {
+                logs.push((gen, self.vfs.file_len(&log_path(&self.path, gen))?));
+            }
         }
+        logs.push((self.current_gen, self.writer.pos));
+        let state = Checkpoint {
+            logs,
+            index: self
+                .index
+                .iter_from(Bound::Unbounded)
+                .map(|entry| entry.map(|(key, cmd_pos)| (key.into(), cmd_pos)))
+                .collect::<Result<_>>()?,
+            trash: self.trash.clone(),
+            versions: self.history.versions.clone(),
+            last_seq: self.history.last_seq,
+            tombstone_gens: self.tombstones.keys().copied().collect(),
+            uncompacted: self.uncompacted,
+        };
+        checkpoint::write(&*self.vfs, &self.path, &state)
     }
-}
 
-impl KvStoreReader {
-    /// Read the log file at the given `CommandPos` and deserialize it to `Command`.
-    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.build_cmd_reader(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
-        })
+    /// Flushes the active log file and syncs it to durable storage. Log files
+    /// are synced before they are replaced, so older ones need nothing more.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        io_fail_point!("kvs::sync");
+        self.writer.writer.get_ref().sync_data()?;
+        io_fail_point!("kvs::sync::after");
+        Ok(())
     }
 
-    /// Build command reader from reader and `CommandPos`.
-    fn build_cmd_reader<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
-    where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
-    {
-        self.close_stale_handles();
+    fn sync_if_always(&mut self) -> Result<()> {
+        if self.durability == Durability::Always {
+            io_fail_point!("kvs::sync");
+            self.writer.writer.get_ref().sync_data()?;
+            io_fail_point!("kvs::sync::after");
+        }
+        Ok(())
+    }
 
-        let mut readers = self.readers.borrow_mut();
+    /// Replaces the data set with the backup in `from`, see
+    /// `KvsEngine::restore`.
+    fn restore(&mut self, from: &Path) -> Result<()> {
+        if self.backups_running > 0 {
+            return Err(KvsError::StringError(
+                "cannot restore while a backup is running".to_owned(),
+            ));
+        }
+        let source_gens = sorted_gen_list(&*self.vfs, from)?;
+        if source_gens.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "no log files in {}",
+                from.display()
+            )));
+        }
+        self.sync()?;
+        // Its compaction file is replaced with the rest of the data set.
+        self.abandon_compaction();
 
-        // Open the file if we haven't opened it in this `KvStoreReader`.
-        // We don't use entry API here because we want the errors to be propogated.
-        if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
+        // Stage copies of the restored log files, numbered after the active
+        // one so that they replace every current log file.
+        let staging = staging_dir(&self.path);
+        clear_staging(&*self.vfs, &self.path)?;
+        self.vfs.create_dir_all(&staging)?;
+        let marker = RestoreMarker {
+            first_gen: self.current_gen + 1,
+            last_gen: self.current_gen + source_gens.len() as u64,
+        };
+        let gens: Vec<u64> = (marker.first_gen..=marker.last_gen).collect();
+        for (&gen, &source_gen) in gens.iter().zip(&source_gens) {
+            let mut source = self.vfs.open(&log_path(from, source_gen))?;
+            let mut file = self.vfs.open_append(&log_path(&staging, gen))?;
+            io::copy(&mut source, &mut file)?;
+            file.sync_data()?;
         }
 
-        let reader = readers
-            .get_mut(&cmd_pos.gen)
-            .expect("Cannot find log reader");
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        // Replaying the staged files checks them before anything is replaced.
+        let index = Keydir::new(None);
+        let mut trash = BTreeMap::new();
+        let mut tombstones = BTreeMap::new();
+        let mut uncompacted = 0;
+        let restored_at = now_millis(&*self.clock);
+        // The history starts over from the restored data set.
+        let mut history = History {
+            last_seq: self.history.last_seq,
+            ..History::default()
+        };
+        let mut versions = Versions::new(self.versions.max);
+        let segments: Vec<(u64, u64)> = gens.iter().map(|&gen| (gen, 0)).collect();
+        let replays = replay_all(
+            &*self.vfs,
+            &staging,
+            &segments,
+            false,
+            &self.reader.keyring,
+            &mut |_| {},
+        )?;
+        for (&gen, replay) in gens.iter().zip(replays) {
+            if replay.has_tombstones {
+                tombstones.insert(gen, restored_at);
+            }
+            uncompacted += replay.apply(&index, &mut trash, &mut history, &mut versions)?;
+        }
 
-        let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
-    }
+        // From here on, the restore is finished on open if it is interrupted.
+        marker.write(&*self.vfs, &self.path)?;
+        io_fail_point!("kvs::restore::install");
+        install_restored(&*self.vfs, &self.path, &marker)?;
+        self.current_gen = marker.last_gen + 1;
+        self.writer = new_log_file(&*self.vfs, &self.path, self.current_gen)?;
 
-    /// Close file handles with generation number less than safe_point.
-    ///
-    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
-    /// The compaction generation contains the sum of all operations before it and the
-    /// in-memory index contains no entries with generation number less than safe_point.
-    /// So we can safely close those file handles and the stale files can be deleted.
-    fn close_stale_handles(&self) {
-        let mut readers = self.readers.borrow_mut();
+        {
+            let _gate = self.batch_gate.write().unwrap();
+            self.index.replace(index);
+        }
+        self.uncompacted = uncompacted;
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(uncompacted as f64);
+        self.trash = trash;
+        self.tombstones = tombstones;
+        history.state = self.history.state.map(|state| HistoryState {
+            start: Stamp {
+                seq: history.last_seq,
+                at: restored_at,
+            },
+            ..state
+        });
+        self.history = history;
+        self.versions = versions;
+        // The changes of the data set replaced are lost.
+        self.compacted_gen = marker.first_gen;
+        self.compacted_seq = self.history.last_seq;
 
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
-                break;
-            }
-            readers.remove(&first_gen);
+        self.reader
+            .safe_point
+            .store(marker.first_gen, Ordering::SeqCst);
+        for readers in self.reader.readers.lock().unwrap().iter_mut() {
+            self.reader.close_stale_handles(readers);
         }
+        // Opening the store removes them if this fails.
+        if let Err(e) = remove_replaced(&*self.vfs, &self.path, marker.first_gen) {
+            error!("Failed to remove the replaced log files: {}", e);
+        }
+        HistoryState::write(&*self.vfs, &self.path, self.history.state)?;
+        self.indexes.clear();
+        self.index_values(None)?;
+        info!("Restored the store from {}", from.display());
+        Ok(())
     }
-}
-
-struct KvStoreWriter {
-    path: Arc<PathBuf>,
-    writer: BufWriterWithPos<File>,
-    reader: KvStoreReader,
-    /// The number of bytes representing "stale" commands
-    /// that could be deleted during a compaction.
-    uncompacted: u64,
-    /// Current generation number
-    current_gen: u64,
-    index: Arc<SkipMap<String, CommandPos>>,
-}
 
-impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::set(key, value);
+    /// Logs the removal of the keys in `start..end` as a single record, then
+    /// removes them from the index at once. Returns the number of live keys
+    /// removed.
+    fn remove_range(&mut self, start: Bound<String>, end: Bound<String>) -> Result<u64> {
+        self.check_free_space()?;
+        let now = now_millis(&*self.clock);
+        let entries = index_range(&self.index, &start, &end)?;
+        let live = entries
+            .iter()
+            .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now))
+            .count() as u64;
+        if live == 0 {
+            return Ok(0);
+        }
+        let stamp = self.next_stamp();
+        let command = Command::RemoveRange {
+            start,
+            end,
+            stamp: Some(stamp),
+        };
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &command)?;
+        io_fail_point!("kvs::write");
+        command.write_to(&mut self.writer, stamp.seq, &self.reader.keyring)?;
+        io_fail_point!("kvs::flush");
         self.writer.flush()?;
-        if let Command::Set { key, .. } = command {
-            // Storing log pointers in the index. Log pointers is of type CommandPos.
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+        self.sync_if_always()?;
+        io_fail_point!("kvs::write::after");
+        counter!(METRIC_REMOVES).increment(live);
+        counter!(METRIC_BYTES_WRITTEN).increment(self.writer.pos - pos);
+
+        self.track_tombstone();
+        let gate = self.batch_gate.write().unwrap();
+        for (key, _) in entries {
+            self.history.push(&self.index, &key, Some(stamp), None)?;
+            self.versions.push(&key, stamp, None);
+            if let Some(old_cmd) = self.index.remove(&key)? {
+                self.uncompacted += old_cmd.len;
             }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
         }
+        drop(gate);
+        // Like a "remove" command, the record is stale after the next compaction.
+        self.uncompacted += self.writer.pos - pos;
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
+        self.maybe_rotate()?;
+        self.maybe_compact()?;
 
-        Ok(())
+        Ok(live)
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let command = Command::remove(key);
+    fn remove(&mut self, key: String) -> Result<u64> {
+        self.check_free_space()?;
+        if self.is_live(&key)? {
+            let command = Command::Remove {
+                key,
+                removed_at: self.trash_retention.map(|_| now_millis(&*self.clock)),
+                stamp: Some(self.next_stamp()),
+            };
+            let lsn = command.lsn();
             let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &command)?;
+            io_fail_point!("kvs::write");
+            command.write_to(&mut self.writer, lsn, &self.reader.keyring)?;
+            io_fail_point!("kvs::flush");
             self.writer.flush()?;
+            self.sync_if_always()?;
+            io_fail_point!("kvs::write::after");
+            counter!(METRIC_REMOVES).increment(1);
+            counter!(METRIC_BYTES_WRITTEN).increment(self.writer.pos - pos);
 
-            if let Command::Remove { key } = command {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
+            self.track_tombstone();
+            if let Command::Remove {
+                key,
+                removed_at,
+                stamp,
+            } = command
+            {
+                if let Some(stamp) = stamp {
+                    self.history.push(&self.index, &key, Some(stamp), None)?;
+                    self.versions.push(&key, stamp, None);
+                }
+                let old_cmd = self.index.remove(key.as_str())?.expect("key not found");
+                self.uncompacted += old_cmd.len;
+                if let Some(removed_at) = removed_at {
+                    self.trash.insert(
+                        key,
+                        Trashed {
+                            pos: old_cmd,
+                            removed_at,
+                        },
+                    );
+                }
 
                 // The "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`.
                 self.uncompacted += self.writer.pos - pos;
             }
+            gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
+            self.maybe_rotate()?;
+            self.maybe_compact()?;
 
-            Ok(())
+            Ok(lsn)
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
-    /// Save space by clearing stale entries in the log.
+    /// Save space by clearing stale entries in the log, finishing the
+    /// compaction in progress if any.
     fn compact(&mut self) -> Result<()> {
+        while !self.compact_step(false)? {}
+        Ok(())
+    }
+
+    /// Runs the next slice of the compaction in progress, starting one if
+    /// there is none, or all of it unless `sliced`. Returns `true` once the
+    /// compaction finished.
+    #[instrument(level = "info", skip(self), fields(uncompacted = self.uncompacted))]
+    fn compact_step(&mut self, sliced: bool) -> Result<bool> {
+        match self.try_compact_step(sliced) {
+            Ok(finished) => Ok(finished),
+            Err(e) => {
+                error!("Compaction failed: {}", e);
+                self.abandon_compaction();
+                self.compaction_stats.failures += 1;
+                self.emit(&CompactionEvent::Failed {
+                    error: e.to_string(),
+                });
+                Err(e)
+            }
+        }
+    }
+
+    fn try_compact_step(&mut self, sliced: bool) -> Result<bool> {
+        let mut compaction = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => self.start_compaction()?,
+        };
+        // The trash and the history are copied at the start, so they must not
+        // change until the end.
+        let sliced = sliced && self.trash_retention.is_none() && self.history.state.is_none();
+        let limits = if sliced {
+            (self.compaction_slice_keys, self.compaction_slice_bytes)
+        } else {
+            (None, None)
+        };
+        if !self.copy_slice(&mut compaction, limits)? {
+            self.compaction = Some(compaction);
+            return Ok(false);
+        }
+
+        let report = self.finish_compaction(compaction)?;
+        info!(
+            bytes_written = report.bytes_written,
+            bytes_reclaimed = report.bytes_reclaimed,
+            entries_copied = report.entries_copied,
+            entries_expired = report.entries_expired,
+            segments_deleted = report.segments_deleted,
+            "Compaction finished"
+        );
+        counter!(METRIC_COMPACTIONS).increment(1);
+        counter!(METRIC_COMPACTION_RECLAIMED_BYTES).increment(report.bytes_reclaimed);
+        let stats = &mut self.compaction_stats;
+        stats.compactions += 1;
+        stats.bytes_reclaimed += report.bytes_reclaimed;
+        stats.bytes_written += report.bytes_written;
+        stats.entries_expired += report.entries_expired;
+        stats.last = Some(report);
+        self.emit(&CompactionEvent::Finished {
+            bytes_reclaimed: report.bytes_reclaimed,
+            bytes_written: report.bytes_written,
+            duration: report.duration,
+        });
+        Ok(true)
+    }
+
+    /// Drops the compaction in progress, if any. Its compaction file is not
+    /// referenced by the index yet, and is removed with the stale log files
+    /// of the next compaction.
+    fn abandon_compaction(&mut self) {
+        if let Some(compaction) = self.compaction.take() {
+            self.uncompacted += compaction.writer.pos;
+            gauge!(METRIC_UNCOMPACTED_BYTES).set(self.uncompacted as f64);
+        }
+    }
+
+    fn emit(&self, event: &CompactionEvent) {
+        for listener in &self.compaction_listeners {
+            listener(event);
+        }
+    }
+
+    /// Writes the hint file of the compaction file `gen`, `len` bytes long,
+    /// from its records read back.
+    fn write_hints(&self, gen: u64, len: u64) -> Result<()> {
+        let hints = read_hints(&*self.vfs, &self.path, gen, 0, &self.reader.keyring)?
+            .collect::<Result<Vec<_>>>()?;
+        hint::write(&*self.vfs, &self.path, gen, len, hints)
+    }
+
+    /// Copies the "set" command at `cmd_pos` to the end of `writer`, and returns
+    /// its new length.
+    ///
+    /// A record which is not encrypted with the current key is encrypted
+    /// again, so that compactions rotate the keys of the copied records.
+    fn copy_entry(
+        &self,
+        cmd_pos: CommandPos,
+        writer: &mut BufWriterWithPos<LogFile>,
+    ) -> Result<u64> {
+        if cmd_pos.expiry_changed {
+            // Fold the expiry records into the copied "set" command.
+            let command = match self.reader.read_command(cmd_pos)? {
+                Command::Set {
+                    key, value, stamp, ..
+                } => Command::Set {
+                    key,
+                    value,
+                    expires_at: cmd_pos.expires_at,
+                    stamp,
+                },
+                _ => return Err(KvsError::UnexpectedCommandType),
+            };
+            let start = writer.pos;
+            command.write_compressed(
+                &mut *writer,
+                command.lsn(),
+                self.compression,
+                &self.reader.keyring,
+            )?;
+            Ok(writer.pos - start)
+        } else {
+            let mut record = Vec::with_capacity(cmd_pos.len as usize);
+            self.reader.build_cmd_reader(cmd_pos, |mut entry_reader| {
+                Ok(entry_reader.read_to_end(&mut record)?)
+            })?;
+            let keyring = &self.reader.keyring;
+            if record_key_version(&record) == keyring.current() {
+                writer.write_all(&record)?;
+                return Ok(record.len() as u64);
+            }
+            let (command, lsn) =
+                Command::read_record(&mut &record[..], cmd_pos.gen, cmd_pos.pos, keyring)?
+                    .ok_or(KvsError::UnexpectedCommandType)?;
+            let start = writer.pos;
+            command.write_compressed(&mut *writer, lsn, self.compression, keyring)?;
+            Ok(writer.pos - start)
+        }
+    }
+
+    /// Copies the versions kept in the history to the end of `writer`, the
+    /// compaction file `gen`, each as a command stamped like the write it
+    /// comes from. Returns the history of the copies.
+    ///
+    /// The versions older than the retention are dropped, except the last one
+    /// of every key, which becomes its version at the new start of the history.
+    fn copy_history(
+        &self,
+        now: u64,
+        gen: u64,
+        writer: &mut BufWriterWithPos<LogFile>,
+    ) -> Result<History> {
+        let mut history = History {
+            last_seq: self.history.last_seq,
+            ..History::default()
+        };
+        let mut state = match self.history.state {
+            Some(state) => state,
+            None => return Ok(history),
+        };
+        let cutoff = now.saturating_sub(state.retention_millis);
+        for version in self.history.versions.values().flatten() {
+            if version.stamp.at < cutoff && version.stamp.seq > state.start.seq {
+                state.start.seq = version.stamp.seq;
+            }
+        }
+        state.start.at = state.start.at.max(cutoff);
+        history.state = Some(state);
+
+        for (key, versions) in &self.history.versions {
+            let mut versions = versions.clone();
+            versions.sort_by_key(|version| version.stamp.seq);
+            let first = versions
+                .iter()
+                .rposition(|version| version.stamp.seq <= state.start.seq)
+                .unwrap_or(0);
+            let mut kept = versions.split_off(first);
+            if kept[0].stamp.seq <= state.start.seq {
+                kept[0].stamp = state.start;
+            }
+            // Unchanged since the start: the index has its only version.
+            let live = self.index.get(key.as_str())?;
+            if kept.len() == 1 && kept[0].pos == live {
+                continue;
+            }
+
+            let mut copied = Vec::with_capacity(kept.len());
+            for (i, version) in kept.into_iter().enumerate() {
+                let command = match version.pos {
+                    Some(cmd_pos) => match self.reader.read_command(cmd_pos)? {
+                        Command::Set { value, .. } => Command::Set {
+                            key: key.clone(),
+                            value,
+                            expires_at: cmd_pos.expires_at,
+                            stamp: Some(version.stamp),
+                        },
+                        _ => return Err(KvsError::UnexpectedCommandType),
+                    },
+                    // A key missing at the start needs no command.
+                    None if i == 0 => {
+                        copied.push(version);
+                        continue;
+                    }
+                    None => Command::Remove {
+                        key: key.clone(),
+                        removed_at: None,
+                        stamp: Some(version.stamp),
+                    },
+                };
+                let pos = writer.pos;
+                command.write_compressed(
+                    &mut *writer,
+                    command.lsn(),
+                    self.compression,
+                    &self.reader.keyring,
+                )?;
+                let moved = version.pos.map(|cmd_pos| {
+                    let mut moved = CommandPos::from((gen, pos..writer.pos));
+                    moved.expires_at = cmd_pos.expires_at;
+                    moved
+                });
+                copied.push(Version {
+                    stamp: version.stamp,
+                    pos: moved,
+                });
+            }
+            history.versions.insert(key.clone(), copied);
+        }
+        Ok(history)
+    }
+
+    /// Starts a compaction: the writes go on to a new active log file, and
+    /// the trash and the history are copied to the compaction file before
+    /// it.
+    fn start_compaction(&mut self) -> Result<Compaction> {
+        let started_at = self.clock.now();
+        let entries = self.index.len() as u64;
+        self.emit(&CompactionEvent::Started {
+            uncompacted_bytes: self.uncompacted,
+            entries,
+        });
+
+        // Only the active log file is synced by `sync`, so sync the one being replaced.
+        self.sync()?;
+
         // Increase current gen number by 2. current_gen + 1 is for the compaction file.
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
 
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+        self.writer = new_log_file(&*self.vfs, &self.path, self.current_gen)?;
+
+        let mut compaction_writer = new_log_file(&*self.vfs, &self.path, compaction_gen)?;
+        io_fail_point!("kvs::compaction::copy");
+
+        // Copy the values still in the trash, each followed by its tombstone. They
+        // go first, so that a later value of the same key is replayed after them.
+        let now = now_millis(&*self.clock);
+        let mut new_trash = BTreeMap::new();
+        for (key, trashed) in &self.trash {
+            if !trashed.is_retained(now, self.trash_retention) {
+                continue;
+            }
+            let new_pos = compaction_writer.pos;
+            let len = self.copy_entry(trashed.pos, &mut compaction_writer)?;
+            let mut moved = CommandPos::from((compaction_gen, new_pos..new_pos + len));
+            moved.expires_at = trashed.pos.expires_at;
+            let tombstone = Command::Remove {
+                key: key.clone(),
+                removed_at: Some(trashed.removed_at),
+                stamp: None,
+            };
+            tombstone.write_to(&mut compaction_writer, 0, &self.reader.keyring)?;
+            new_trash.insert(
+                key.clone(),
+                Trashed {
+                    pos: moved,
+                    removed_at: trashed.removed_at,
+                },
+            );
+        }
+
+        // The history goes next, for the same reason.
+        let history = self.copy_history(now, compaction_gen, &mut compaction_writer)?;
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+        Ok(Compaction {
+            gen: compaction_gen,
+            writer: compaction_writer,
+            started_at,
+            now,
+            uncompacted_at_start: self.uncompacted,
+            last_key: None,
+            entries,
+            visited: 0,
+            reported_percent: 0,
+            new_positions: Vec::with_capacity(entries as usize),
+            expired: Vec::new(),
+            trash: new_trash,
+            history,
+            last_seq: self.history.last_seq,
+            summary: SegmentSummary::new(compaction_gen, entries),
+        })
+    }
 
+    /// Copies the live entries after the last one visited to the compaction
+    /// file, until `limits` of entries visited and bytes copied are reached.
+    /// Returns `true` once every entry was visited.
+    fn copy_slice(
+        &self,
+        compaction: &mut Compaction,
+        (max_keys, max_bytes): (Option<u64>, Option<u64>),
+    ) -> Result<bool> {
         // Compact the log by key order.
         // Mostly read sequentially; with a sorted index like a b-tree,
         // there would be no copying of the index.
-        let mut new_pos = 0; // pos in the new log file
-        for entry in &mut self.index.iter() {
-            let len = self
-                .reader
-                .build_cmd_reader(*entry.value(), |mut entry_reader| {
-                    Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-                })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+        let last_key = compaction.last_key.take();
+        let start = match &last_key {
+            Some(key) => Bound::Excluded(&**key),
+            None => Bound::Unbounded,
+        };
+        let (visited_start, slice_start) = (compaction.visited, compaction.writer.pos);
+
+        // Expired entries are dropped, unless the history may still read them.
+        let purge_expired = self.history.state.is_none();
+        for entry in self.index.iter_from(start) {
+            let (key, cmd_pos) = entry?;
+            // The entries written since the start are in the log files kept.
+            if cmd_pos.gen < compaction.gen {
+                if purge_expired && cmd_pos.is_expired(compaction.now) {
+                    compaction.expired.push((key.clone(), cmd_pos));
+                } else {
+                    let new_pos = compaction.writer.pos;
+                    let len = self.copy_entry(cmd_pos, &mut compaction.writer)?;
+                    compaction.summary.add(&key);
+                    let mut moved = CommandPos::from((compaction.gen, new_pos..new_pos + len));
+                    moved.expires_at = cmd_pos.expires_at;
+                    compaction.new_positions.push((key.clone(), cmd_pos, moved));
+                }
+            }
+
+            compaction.visited += 1;
+            let percent = (compaction.visited * 100 / compaction.entries.max(1)).min(100) as u8;
+            if percent >= compaction.reported_percent + COMPACTION_PROGRESS_STEP {
+                compaction.reported_percent = percent - percent % COMPACTION_PROGRESS_STEP;
+                self.emit(&CompactionEvent::Progress {
+                    percent: compaction.reported_percent,
+                    bytes_copied: compaction.writer.pos,
+                });
+            }
+
+            let keys = compaction.visited - visited_start;
+            let copied = compaction.writer.pos - slice_start;
+            if matches!(max_keys, Some(max) if keys >= max)
+                || matches!(max_bytes, Some(max) if copied >= max)
+            {
+                compaction.last_key = Some(key);
+                return Ok(false);
+            }
         }
+        Ok(true)
+    }
+
+    /// Points the index to the compaction file and deletes the stale log files.
+    fn finish_compaction(&mut self, compaction: Compaction) -> Result<CompactionReport> {
+        let Compaction {
+            gen: compaction_gen,
+            writer: mut compaction_writer,
+            started_at,
+            uncompacted_at_start,
+            new_positions,
+            expired,
+            trash: new_trash,
+            mut history,
+            last_seq,
+            summary,
+            ..
+        } = compaction;
 
         // Explicit flush and close before dropping the writer. We would not rely the destructor
         // to do it, particularly in a case where data must not be lost.
         compaction_writer.flush()?;
+        // Sync it too, as the stale log files it replaces are deleted below.
+        io_fail_point!("kvs::compaction::sync");
+        compaction_writer.writer.get_ref().sync_data()?;
+        io_fail_point!("kvs::compaction::sync::after");
+        let mut bytes_written = compaction_writer.pos;
+        if let Some(codec) = self.segment_compression {
+            let path = log_path(&self.path, compaction_gen);
+            bytes_written = segment::compress_file(&*self.vfs, &path, codec)?;
+        }
+        // Without a hint file, the compaction file is replayed from its records.
+        let mut cold = None;
+        match self.write_hints(compaction_gen, bytes_written) {
+            Ok(()) if self.index.budget().is_some() => {
+                cold = SortedHints::open(&*self.vfs, &self.path, compaction_gen, bytes_written)?;
+            }
+            Ok(()) => {}
+            Err(e) => error!("Failed to write the hint file: {}", e),
+        }
+
+        // Only point the index to the compaction file once its content is flushed,
+        // otherwise concurrent readers could read past its end.
+        //
+        // The writes made between slices are in the log files kept: the
+        // copies of the entries they replaced are stale.
+        let mut uncompacted = self.uncompacted.saturating_sub(uncompacted_at_start);
+        let entries_copied = new_positions.len() as u64;
+        // With a budget, the entries left as copied are looked up in the hint
+        // file from now on.
+        let mut swap = self.index.swap(cold);
+        for (key, old_pos, mut new_pos) in new_positions {
+            match swap.get(&key)? {
+                Some(cmd_pos) if cmd_pos.gen == old_pos.gen && cmd_pos.pos == old_pos.pos => {
+                    // Only the expiry may have changed, by a record replayed after the copy.
+                    new_pos.expires_at = cmd_pos.expires_at;
+                    new_pos.expiry_changed = cmd_pos.expires_at != old_pos.expires_at;
+                    swap.repoint(key, new_pos)?;
+                }
+                current => {
+                    // The replaced command is deleted, its copy is stale instead.
+                    uncompacted = uncompacted.saturating_sub(old_pos.len) + new_pos.len;
+                    if current.is_none() {
+                        swap.removed(key);
+                    }
+                }
+            }
+        }
+        let (mut entries_expired, mut bytes_expired) = (0, 0);
+        for (key, cmd_pos) in expired {
+            if swap.get(&key)? == Some(cmd_pos) {
+                swap.remove(&key)?;
+                entries_expired += 1;
+                bytes_expired += cmd_pos.len;
+            }
+        }
+        swap.finish();
+        self.index.evict()?;
+        self.trash = new_trash;
+        let history_changed = history.state != self.history.state;
+        // Slices may have been written since the start.
+        history.last_seq = self.history.last_seq;
+        self.history = history;
+        if history_changed {
+            HistoryState::write(&*self.vfs, &self.path, self.history.state)?;
+        }
+        // Only the versions written since the start are left.
+        self.versions.retain_after(compaction_gen);
+        self.compacted_gen = compaction_gen;
+        self.compacted_seq = last_seq;
+
+        // The compaction file is the only compacted log file left.
+        let manifest = Manifest {
+            segments: vec![summary],
+            last_seq: self.history.last_seq,
+            compacted_seq: last_seq,
+        };
+        if let Err(e) = manifest.write(&*self.vfs, &self.path) {
+            error!("Failed to write the manifest: {}", e);
+        }
+        io_fail_point!("kvs::compaction::remove_stale");
 
         self.reader
             .safe_point
             .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
+        for readers in self.reader.readers.lock().unwrap().iter_mut() {
+            self.reader.close_stale_handles(readers);
+        }
 
         // Remove stale log files.
         //
@@ -347,204 +4323,401 @@ impl KvStoreWriter {
         // its stale file handles. On Unix, the files will be deleted after all the handles
         // are closed. On Windows, the deletions below will fail and stale files are expected
         // to be deleted in the next compaction.
-        let stale_gens = sorted_gen_list(&self.path)?
+        let stale_gens = sorted_gen_list(&*self.vfs, &self.path)?
             .into_iter()
             .filter(|&gen| gen < compaction_gen);
+        let mut stale_bytes = 0;
+        let mut segments_deleted = 0;
         for stale_gen in stale_gens {
             let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
+            stale_bytes += self.vfs.file_len(&file_path).unwrap_or(0);
+            match self.retire_segment(stale_gen, &file_path) {
+                Ok(()) => segments_deleted += 1,
+                Err(e) => error!("{:?} cannot be deleted or archived: {}", file_path, e),
+            }
+            if let Err(e) = hint::remove(&*self.vfs, &self.path, stale_gen) {
+                error!("The hint file of {:?} cannot be deleted: {}", file_path, e);
             }
         }
 
-        // Reset uncompacted after compaction
-        self.uncompacted = 0;
+        // The checkpoint refers to the stale log files.
+        if let Err(e) = checkpoint::remove(&*self.vfs, &self.path) {
+            error!("Failed to remove the checkpoint: {}", e);
+        }
 
-        Ok(())
-    }
-}
+        // The tombstones of the stale log files are gone. The compaction file
+        // only has the tombstones of the values kept in the trash.
+        self.tombstones = self.tombstones.split_off(&compaction_gen);
 
-/// Enum representing a command
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
-}
+        // Only the writes made between slices left stale commands.
+        self.uncompacted = uncompacted;
+        gauge!(METRIC_UNCOMPACTED_BYTES).set(uncompacted as f64);
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+        Ok(CompactionReport {
+            bytes_reclaimed: stale_bytes.saturating_sub(bytes_written),
+            bytes_written,
+            duration: self
+                .clock
+                .now()
+                .duration_since(started_at)
+                .unwrap_or_default(),
+            entries_copied,
+            entries_expired,
+            bytes_expired,
+            segments_deleted,
+        })
     }
+}
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
-    }
+/// A compaction in progress, run in slices by `KvStoreWriter::compact_step`.
+/// The writes made between slices go to the log files after the compaction
+/// file.
+pub(crate) struct Compaction {
+    pub(crate) gen: u64,
+    writer: BufWriterWithPos<LogFile>,
+    started_at: SystemTime,
+    /// The time the expiries and the retentions are checked against.
+    now: u64,
+    uncompacted_at_start: u64,
+    /// The last key visited, after which the next slice resumes.
+    last_key: Option<Box<str>>,
+    /// The number of entries in the index at the start.
+    entries: u64,
+    visited: u64,
+    reported_percent: u8,
+    /// The entries copied, with their position before and after the copy.
+    new_positions: Vec<(Box<str>, CommandPos, CommandPos)>,
+    /// The expired entries dropped, with their position.
+    expired: Vec<(Box<str>, CommandPos)>,
+    trash: BTreeMap<String, Trashed>,
+    history: History,
+    /// The sequence number of the last write before the start.
+    last_seq: u64,
+    summary: SegmentSummary,
 }
 
-/// Represents the JSON-serialized command in the log.
-#[derive(Copy, Clone)]
-struct CommandPos {
-    /// Log files are named after a generation number.
-    /// `gen` gives us the log filename the command was stored.
-    gen: u64,
-    /// Position.
-    pos: u64,
-    /// Length.
-    len: u64,
+/// The state of a store saved by `KvStore::checkpoint`.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    /// The generation and the length of every log file when the checkpoint
+    /// was taken, the active one last.
+    logs: Vec<(u64, u64)>,
+    index: Vec<(String, CommandPos)>,
+    trash: BTreeMap<String, Trashed>,
+    versions: BTreeMap<String, Vec<Version>>,
+    last_seq: u64,
+    /// The log files with tombstones.
+    tombstone_gens: Vec<u64>,
+    uncompacted: u64,
 }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
-        Self {
-            gen,
-            pos: range.start,
-            len: range.end - range.start,
+impl Checkpoint {
+    /// Reads the checkpoint of `dir`, if it was taken from the log files of
+    /// `gen_list`: they still have the same lengths, the active one then
+    /// possibly longer, and there are only newer log files next to them.
+    fn read(vfs: &dyn Vfs, dir: &Path, gen_list: &[u64]) -> Result<Option<Checkpoint>> {
+        let state: Checkpoint = match checkpoint::read(vfs, dir)? {
+            Some(state) => state,
+            None => return Ok(None),
+        };
+        let (last_gen, last_pos) = match state.logs.last() {
+            Some(&last) => last,
+            None => return Ok(None),
+        };
+        let old_gens = gen_list.iter().take_while(|&&gen| gen <= last_gen);
+        if !old_gens.copied().eq(state.logs.iter().map(|&(gen, _)| gen)) {
+            return Ok(None);
+        }
+        for &(gen, len) in &state.logs {
+            let actual_len = vfs.file_len(&log_path(dir, gen))?;
+            if actual_len != len && !(gen == last_gen && actual_len > last_pos) {
+                return Ok(None);
+            }
         }
+        Ok(Some(state))
     }
 }
 
-/// A wrapper of BufReader of the log file
-struct BufReaderWithPos<R: Read + Seek> {
-    reader: BufReader<R>,
-    pos: u64,
+/// A removed value kept for `KvStore::undelete`.
+#[derive(Serialize, Deserialize, Copy, Clone)]
+pub(crate) struct Trashed {
+    pub(crate) pos: CommandPos,
+    /// Milliseconds since the Unix epoch at which the key was removed.
+    pub(crate) removed_at: u64,
 }
 
-impl<R: Read + Seek> BufReaderWithPos<R> {
-    fn new(mut inner: R) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
-        Ok(BufReaderWithPos {
-            reader: BufReader::new(inner),
-            pos,
-        })
+impl Trashed {
+    fn is_retained(&self, now: u64, retention: Option<Duration>) -> bool {
+        matches!(retention, Some(retention)
+            if self.removed_at.saturating_add(retention.as_millis() as u64) > now)
     }
 }
 
-impl<R: Read + Seek> Read for BufReaderWithPos<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let len = self.reader.read(buf)?;
-        self.pos += len as u64;
+/// Returns the commands of the writes of `batch`.
+fn batch_commands(batch: WriteBatch) -> Vec<Command> {
+    batch
+        .ops
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value } => Command::set(key, value),
+            BatchOp::Remove { key } => Command::remove(key),
+        })
+        .collect()
+}
 
-        Ok(len)
+/// Returns the stamp of the `i`th pair of a bulk load stamped with `first`.
+fn bulk_stamp(first: Stamp, i: usize) -> Stamp {
+    Stamp {
+        seq: first.seq + i as u64,
+        at: first.at,
     }
 }
 
-impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.reader.seek(pos)?;
-        Ok(self.pos)
-    }
+/// Returns the time of `clock` in milliseconds since the Unix epoch.
+pub(crate) fn now_millis(clock: &dyn Clock) -> u64 {
+    clock
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
 }
 
-/// A wrapper of BufWriter of the log file
-struct BufWriterWithPos<W: Write + Seek> {
-    writer: BufWriter<W>,
-    pos: u64,
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        if self.checkpoint_interval.is_some() {
+            if let Err(e) = self.checkpoint() {
+                error!("Failed to write the checkpoint on close: {}", e);
+            }
+        } else if let Durability::EveryNMillis(_) = self.durability {
+            if let Err(e) = self.sync() {
+                error!("Failed to sync the log on close: {}", e);
+            }
+        }
+    }
 }
 
-impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
-        Ok(BufWriterWithPos {
-            writer: BufWriter::new(inner),
-            pos,
-        })
+/// Backs up the data files to `target`, see `KvStore::backup_to`. The writer
+/// is only locked at the start and the end, compactions are deferred in
+/// between so that the files are not deleted.
+fn backup_to(writer: &Mutex<KvStoreWriter>, target: &dyn BackupTarget) -> Result<String> {
+    let (vfs, backup, files) = {
+        let mut writer = writer.lock().unwrap();
+        let files = writer.start_backup()?;
+        let backup = format!("{}{}", SNAPSHOT_PREFIX, now_millis(&*writer.clock));
+        (Arc::clone(&writer.vfs), backup, files)
+    };
+
+    let res = backup_files(&*vfs, &files, target, &backup);
+    if res.is_err() {
+        if let Err(e) = target.abort(&backup) {
+            warn!("Failed to abort backup {}: {}", backup, e);
+        }
     }
+
+    let mut writer = writer.lock().unwrap();
+    writer.backups_running -= 1;
+    res?;
+    info!("Backed up the store to {}", backup);
+    writer.maybe_compact()?;
+    Ok(backup)
 }
 
-impl<W: Write + Seek> Write for BufWriterWithPos<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let len = self.writer.write(buf)?;
-        self.pos += len as u64;
-        Ok(len)
+#[cfg(feature = "fs")]
+fn backup_dir(writer: &Mutex<KvStoreWriter>, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("backup {} already exists", dest.display()),
+        )
+        .into());
     }
+    let (vfs, active_gen, files) = {
+        let mut writer = writer.lock().unwrap();
+        let files = writer.start_backup()?;
+        (Arc::clone(&writer.vfs), writer.current_gen, files)
+    };
 
-    fn flush(&mut self) -> io::Result<()> {
-        self.writer.flush()
+    let mut tmp_dir = dest.as_os_str().to_owned();
+    tmp_dir.push(".tmp");
+    let tmp_dir = PathBuf::from(tmp_dir);
+    let res = link_or_copy_files(&*vfs, &files, active_gen, &tmp_dir)
+        .and_then(|()| Ok(fs::rename(&tmp_dir, dest)?));
+    if res.is_err() {
+        if let Err(e) = fs::remove_dir_all(&tmp_dir) {
+            warn!("Failed to remove {}: {}", tmp_dir.display(), e);
+        }
     }
+
+    let mut writer = writer.lock().unwrap();
+    writer.backups_running -= 1;
+    res?;
+    info!("Backed up the store to {}", dest.display());
+    writer.maybe_compact()
 }
 
-impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
-        Ok(self.pos)
+/// Puts `files` in the new directory `dir`: the log files before `active_gen`
+/// as hard links if possible, the others as copies of their first `len`
+/// bytes.
+#[cfg(feature = "fs")]
+fn link_or_copy_files(
+    vfs: &dyn Vfs,
+    files: &[(PathBuf, u64)],
+    active_gen: u64,
+    dir: &Path,
+) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    for (path, len) in files {
+        let name = path.file_name().expect("data files have names");
+        let dest = dir.join(name);
+        let immutable = path.extension() == Some("log".as_ref())
+            && matches!(
+                path.file_stem().and_then(OsStr::to_str).map(str::parse::<u64>),
+                Some(Ok(gen)) if gen < active_gen
+            );
+        // Hard links fail across file systems, or for files of another `Vfs`.
+        if immutable && fs::hard_link(path, &dest).is_ok() {
+            continue;
+        }
+        let mut file = File::create(&dest)?;
+        io::copy(&mut vfs.open(path)?.take(*len), &mut file)?;
+        file.sync_all()?;
     }
+    Ok(())
 }
 
-/// Log files are named after a generation number with a "log" extension name.
-///
-/// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-        .flat_map(|path| {
-            path.file_name()
-                .and_then(OsStr::to_str)
-                .map(|s| s.trim_end_matches(".log"))
-                .map(str::parse::<u64>)
-        })
-        .flatten()
-        .collect();
-
-    gen_list.sort_unstable();
-    Ok(gen_list)
+fn backup_files(
+    vfs: &dyn Vfs,
+    files: &[(PathBuf, u64)],
+    target: &dyn BackupTarget,
+    backup: &str,
+) -> Result<()> {
+    for (path, len) in files {
+        let mut file = vfs.open(path)?;
+        let name = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .expect("data files have UTF-8 names");
+        target.put(backup, name, &mut file, *len)?;
+    }
+    target.commit(backup)?;
+    Ok(())
 }
 
-fn log_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.log", gen))
+/// Takes a snapshot as scheduled by `KvStore::snapshot_every`, then prunes
+/// the old ones.
+#[cfg(feature = "fs")]
+fn take_scheduled_snapshot(writer: &Mutex<KvStoreWriter>) -> Result<()> {
+    let (schedule, clock) = {
+        let writer = writer.lock().unwrap();
+        match &writer.snapshot_schedule {
+            Some(schedule) => (schedule.clone(), Arc::clone(&writer.clock)),
+            None => return Ok(()),
+        }
+    };
+    backup_to(writer, &DirTarget::new(&schedule.dest))?;
+    let now = now_millis(&*clock);
+    let pruned = snapshot::prune_snapshots(&schedule.dest, schedule.retention, now)?;
+    if pruned > 0 {
+        info!("Deleted {} old snapshots", pruned);
+    }
+    Ok(())
 }
 
-/// Create a new log file with given generation number.
-///
-/// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
-    Ok(writer)
-}
-
-/// Load the whole log file and store value positions in the index map.
-///
-/// Returns `uncompacted`, which is number of bytes that can be saved after a compaction.
-fn load(
-    gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
-) -> Result<u64> {
-    let mut uncompacted = 0;
-
-    // To make sure we read from the beginning of the file.
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
+/// Spawns a background thread named `name`, which runs `task` on `writer`
+/// every `interval` until the returned sender is dropped or the store is
+/// closed. It runs the syncs of `Durability::EveryNMillis`, the sweeps of
+/// `KvStore::set_max_tombstone_age` and the snapshots of
+/// `KvStore::snapshot_every`.
+fn spawn_periodic(
+    name: &'static str,
+    writer: Weak<Mutex<KvStoreWriter>>,
+    interval: Duration,
+    task: fn(&Mutex<KvStoreWriter>) -> Result<()>,
+) -> Result<Sender<()>> {
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                let writer = match writer.upgrade() {
+                    Some(writer) => writer,
+                    None => return,
+                };
+                let res = task(&writer);
+                if let Err(e) = res {
+                    error!("Background task {} failed: {}", name, e);
                 }
-                index.insert(key, (gen, pos..new_pos).into());
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
+        })?;
+    Ok(stop_tx)
+}
 
-                // The "remove" command itself can be deleted in the next compaction so we add
-                // its length to `uncompacted`.
-                uncompacted += new_pos - pos;
-            }
+/// The range of every key, for `KvStore::next_pair`.
+pub(crate) const ALL_KEYS: (Bound<String>, Bound<String>) = (Bound::Unbounded, Bound::Unbounded);
+
+fn str_bound(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Returns whether `key` is in `start..end`.
+pub(crate) fn range_contains(start: &Bound<String>, end: &Bound<String>, key: &str) -> bool {
+    (
+        start.as_ref().map(String::as_str),
+        end.as_ref().map(String::as_str),
+    )
+        .contains(&key)
+}
+
+/// Returns the entries of `index` with a key in `start..end`.
+pub(crate) fn index_range(
+    index: &Keydir,
+    start: &Bound<String>,
+    end: &Bound<String>,
+) -> Result<Vec<(Box<str>, CommandPos)>> {
+    let mut entries = Vec::new();
+    for entry in index.iter_from(start.as_ref().map(String::as_str)) {
+        let (key, cmd_pos) = entry?;
+        if !range_contains(start, end, &key) {
+            break;
         }
+        entries.push((key, cmd_pos));
+    }
+    Ok(entries)
+}
 
-        pos = new_pos;
+/// Returns the range of the keys starting with `prefix`: up to `prefix` with
+/// its last char incremented, the chars which cannot be dropped first.
+fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let mut end = prefix.to_owned();
+    while let Some(c) = end.pop() {
+        if let Some(next) = (c as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return (Bound::Included(prefix.to_owned()), Bound::Excluded(end));
+        }
     }
+    (Bound::Included(prefix.to_owned()), Bound::Unbounded)
+}
+
+fn namespace_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(NAMESPACES_DIR).join(name)
+}
 
-    Ok(uncompacted)
+/// Returns an error unless `name` is a valid name of a namespace, which
+/// cannot escape the directory of the namespaces.
+fn check_namespace_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(KvsError::StringError(format!(
+            "invalid namespace name: {:?}",
+            name
+        )));
+    }
+    Ok(())
 }
@@ -0,0 +1,154 @@
+//! This module provides the manifest of a `KvStore` data directory, which
+//! describes its compacted log files: how many keys they hold, their key range
+//! and a bloom filter of their keys. It is rewritten by every compaction.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::vfs::Vfs;
+use crate::Result;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// False positive rate of the bloom filters.
+const BLOOM_FALSE_POSITIVE_RATE: f64 = 0.01;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Manifest {
+    pub(crate) segments: Vec<SegmentSummary>,
+    /// The last sequence number stamped when it was written.
+    #[serde(default)]
+    pub(crate) last_seq: u64,
+    /// The last sequence number stamped before the compaction started.
+    #[serde(default)]
+    pub(crate) compacted_seq: u64,
+}
+
+/// The summary of the keys of a compacted log file.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct SegmentSummary {
+    pub(crate) gen: u64,
+    pub(crate) keys: u64,
+    pub(crate) min_key: Option<String>,
+    pub(crate) max_key: Option<String>,
+    pub(crate) bloom: BloomFilter,
+}
+
+impl SegmentSummary {
+    /// Starts the summary of log file `gen`, to which about `expected_keys`
+    /// keys are added in increasing order.
+    pub(crate) fn new(gen: u64, expected_keys: u64) -> Self {
+        SegmentSummary {
+            gen,
+            keys: 0,
+            min_key: None,
+            max_key: None,
+            bloom: BloomFilter::new(expected_keys),
+        }
+    }
+
+    pub(crate) fn add(&mut self, key: &str) {
+        self.keys += 1;
+        if self.min_key.is_none() {
+            self.min_key = Some(key.to_owned());
+        }
+        self.max_key = Some(key.to_owned());
+        self.bloom.insert(key);
+    }
+
+    /// Returns `false` if the log file does not have `key`. `true` means it
+    /// probably has it.
+    #[cfg(feature = "fs")]
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        match (&self.min_key, &self.max_key) {
+            (Some(min_key), Some(max_key)) => {
+                min_key.as_str() <= key && key <= max_key.as_str() && self.bloom.may_contain(key)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A bloom filter of strings, with double hashing over two FNV-1a hashes so
+/// that it reads the same across builds.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    pub(crate) fn new(expected_keys: u64) -> Self {
+        let keys = expected_keys.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-keys * BLOOM_FALSE_POSITIVE_RATE.ln() / (ln2 * ln2)).ceil() as u64;
+        let hashes = ((bits as f64 / keys) * ln2).round().max(1.0) as u32;
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(64) as usize],
+            hashes,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Returns the bytes of memory used by its bits.
+    pub(crate) fn memory(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = u64> {
+        let len = self.bits.len() as u64 * 64;
+        let h1 = fnv1a(key.as_bytes(), 0xcbf2_9ce4_8422_2325);
+        let h2 = fnv1a(key.as_bytes(), 0x8422_2325_cbf2_9ce4) | 1;
+        (0..u64::from(self.hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+}
+
+fn fnv1a(bytes: &[u8], basis: u64) -> u64 {
+    bytes.iter().fold(basis, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+pub(crate) fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE)
+}
+
+impl Manifest {
+    /// Reads the manifest of `dir`. A directory without one has an empty
+    /// manifest.
+    pub(crate) fn read(vfs: &dyn Vfs, dir: &Path) -> Result<Manifest> {
+        let mut bytes = Vec::new();
+        match vfs.open(&manifest_path(dir)) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Manifest::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Replaces the manifest of `dir` with this one, through a temporary file
+    /// renamed once synced.
+    pub(crate) fn write(&self, vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        let path = manifest_path(dir);
+        let tmp_path = path.with_extension("json.tmp");
+        let _ = vfs.remove_file(&tmp_path);
+        let mut file = vfs.open_append(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_data()?;
+        io_fail_point!("kvs::manifest::rename");
+        vfs.rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+//! This module provides the `ENGINE` file of a data directory, which names
+//! the engine that created it, so that the other engine refuses to open it
+//! rather than seeing an empty store.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::vfs::Vfs;
+use crate::{KvsError, Result};
+
+const ENGINE_FILE: &str = "ENGINE";
+/// The name of the engine of `KvStore`.
+pub(crate) const KVS_ENGINE: &str = "kvs";
+/// The name of the engine of `SledKvsEngine`.
+pub(crate) const SLED_ENGINE: &str = "sled";
+/// The name of the engine of `ShardedKvStore`.
+pub(crate) const SHARDED_ENGINE: &str = "kvs-sharded";
+
+pub(crate) fn engine_path(dir: &Path) -> PathBuf {
+    dir.join(ENGINE_FILE)
+}
+
+/// Checks that `dir` belongs to `engine`, and writes the `ENGINE` file if it
+/// has none yet.
+///
+/// A directory without the file predates it: it belongs to the other engine
+/// if it holds its files, and to `engine` otherwise.
+pub(crate) fn check_engine(vfs: &dyn Vfs, dir: &Path, engine: &str) -> Result<()> {
+    let found = match read_engine(vfs, dir)? {
+        Some(found) => found,
+        None => {
+            let files = vfs.list_files(dir)?;
+            let has_file = |name: &str| files.iter().any(|path| path.ends_with(name));
+            let has_log = files
+                .iter()
+                .any(|path| path.extension().is_some_and(|ext| ext == "log"));
+            if engine != SLED_ENGINE && has_file("conf") && has_file("db") {
+                SLED_ENGINE.to_owned()
+            } else if engine != KVS_ENGINE && has_log {
+                KVS_ENGINE.to_owned()
+            } else {
+                write_engine(vfs, dir, engine)?;
+                return Ok(());
+            }
+        }
+    };
+    if found != engine {
+        return Err(KvsError::WrongEngine {
+            expected: engine.to_owned(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+fn read_engine(vfs: &dyn Vfs, dir: &Path) -> Result<Option<String>> {
+    let mut engine = String::new();
+    match vfs.open(&engine_path(dir)) {
+        Ok(mut file) => file.read_to_string(&mut engine)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(engine.trim().to_owned()))
+}
+
+/// Writes the `ENGINE` file of `dir`, through a temporary file renamed once
+/// synced.
+fn write_engine(vfs: &dyn Vfs, dir: &Path, engine: &str) -> Result<()> {
+    let path = engine_path(dir);
+    let tmp_path = path.with_extension("tmp");
+    let _ = vfs.remove_file(&tmp_path);
+    let mut file = vfs.open_append(&tmp_path)?;
+    writeln!(file, "{}", engine)?;
+    file.sync_data()?;
+    vfs.rename(&tmp_path, &path)?;
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::KvsEngine;
+use crate::testing::Op;
+use crate::{KvsError, Result};
+
+/// A `KvsEngine` for unit tests, which never touches the disk.
+///
+/// It records every call, and answers from an in-memory map unless a
+/// response was scripted for the operation. Scripted responses are consumed
+/// in order. Cloning gives another handle to the same engine, so a test can
+/// keep one while a `KvsServer` owns the other.
+///
+/// ```no_run
+/// # use kvs::{KvsEngine, KvsError, MockKvsEngine};
+/// let engine = MockKvsEngine::new();
+/// engine.script_set(Err(KvsError::StringError("disk full".to_owned())));
+/// assert!(engine.set("key".to_owned(), "value".to_owned()).is_err());
+/// assert_eq!(engine.get("key".to_owned()).unwrap(), None);
+/// assert_eq!(engine.calls().len(), 2);
+/// ```
+#[derive(Clone, Default)]
+pub struct MockKvsEngine {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<Op>,
+    data: HashMap<String, String>,
+    set_responses: VecDeque<Result<()>>,
+    get_responses: VecDeque<Result<Option<String>>>,
+    remove_responses: VecDeque<Result<()>>,
+    delay: Duration,
+}
+
+impl MockKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the calls received so far, in order.
+    pub fn calls(&self) -> Vec<Op> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Forgets the calls received so far.
+    pub fn clear_calls(&self) {
+        self.state.lock().unwrap().calls.clear();
+    }
+
+    /// Queues the response of a future `set`.
+    pub fn script_set(&self, response: Result<()>) {
+        self.state.lock().unwrap().set_responses.push_back(response);
+    }
+
+    /// Queues the response of a future `get`.
+    pub fn script_get(&self, response: Result<Option<String>>) {
+        self.state.lock().unwrap().get_responses.push_back(response);
+    }
+
+    /// Queues the response of a future `remove`.
+    pub fn script_remove(&self, response: Result<()>) {
+        self.state
+            .lock()
+            .unwrap()
+            .remove_responses
+            .push_back(response);
+    }
+
+    /// Makes every call sleep for `delay` before answering, e.g. to test
+    /// timeouts.
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    /// Records the call and returns the delay to apply, outside of the lock.
+    fn record(&self, op: Op) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(op);
+        state.delay
+    }
+}
+
+impl KvsEngine for MockKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        thread::sleep(self.record(Op::Set {
+            key: key.clone(),
+            value: value.clone(),
+        }));
+        let mut state = self.state.lock().unwrap();
+        match state.set_responses.pop_front() {
+            Some(response) => response,
+            None => {
+                state.data.insert(key, value);
+                Ok(())
+            }
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        thread::sleep(self.record(Op::Get { key: key.clone() }));
+        let mut state = self.state.lock().unwrap();
+        match state.get_responses.pop_front() {
+            Some(response) => response,
+            None => Ok(state.data.get(&key).cloned()),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        thread::sleep(self.record(Op::Remove { key: key.clone() }));
+        let mut state = self.state.lock().unwrap();
+        match state.remove_responses.pop_front() {
+            Some(response) => response,
+            None => state
+                .data
+                .remove(&key)
+                .map(|_| ())
+                .ok_or(KvsError::KeyNotFound),
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use crate::Result;
+use std::path::Path;
+use std::time::Duration;
+
+use self::batch::BatchOp;
+use crate::{KvsError, Result};
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine: Clone + Send + 'static {
@@ -14,15 +18,252 @@ pub trait KvsEngine: Clone + Send + 'static {
     /// Returns an error if the value is not read successfully.
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Sets the value of a string key to bytes.
+    ///
+    /// The default implementation stores the value with `set`, and fails with
+    /// `KvsError::Utf8` if it is not valid UTF-8.
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set(key, String::from_utf8(value)?)
+    }
+
+    /// Gets the value of a string key as bytes.
+    ///
+    /// Values set with `set` read as their UTF-8 bytes. Reading a value set
+    /// with `set_bytes` with `get` fails with `KvsError::Utf8` if it is not
+    /// valid UTF-8.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key)?.map(String::into_bytes))
+    }
+
     /// Remove a given string key.
     ///
     /// Returns `KvsError::KeyNotFound` error if the given key does not exit
     /// or value is not read successfully.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Remove a given string key if it exists.
+    ///
+    /// Returns whether the key was removed. Unlike `remove`, a missing key
+    /// is not an error, which makes deletes idempotent.
+    fn remove_if_exists(&self, key: String) -> Result<bool> {
+        match self.remove(key) {
+            Ok(()) => Ok(true),
+            Err(KvsError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Appends `suffix` to the value of `key`, which is set to `suffix` if it
+    /// does not exist.
+    ///
+    /// The default implementation is a `get` followed by a `set`, which is
+    /// not atomic; engines should override it with an atomic version.
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let value = self.get(key.clone())?.unwrap_or_default();
+        self.set(key, value + &suffix)
+    }
+
+    /// Sets the value of `key` only if it does not exist. Returns whether the
+    /// value was set.
+    ///
+    /// The default implementation is a `get` followed by a `set`, which is
+    /// not atomic; engines should override it with an atomic version.
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        if self.get(key.clone())?.is_some() {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Sets the value of `key` only if it already exists. Returns whether the
+    /// value was set.
+    ///
+    /// The default implementation is a `get` followed by a `set`, which is
+    /// not atomic; engines should override it with an atomic version.
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        if self.get(key.clone())?.is_none() {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Applies the writes of `batch` in order.
+    ///
+    /// The default implementation applies them one by one, which is not
+    /// atomic: a failure or a crash may leave only some of them applied, and
+    /// concurrent reads may see them partially applied. Engines should
+    /// override it with an atomic version.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => self.set_bytes(key, value)?,
+                BatchOp::Remove { key } => {
+                    self.remove_if_exists(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the value of `key` with its version, which changes with every
+    /// write of the key. Returns `None` if the key does not exist.
+    ///
+    /// The default implementation fails, for engines without versions.
+    fn get_with_version(&self, _key: String) -> Result<Option<(String, u64)>> {
+        Err(transactions_unsupported())
+    }
+
+    /// Applies the writes of `batch` atomically, like `write_batch`, if every
+    /// key of `reads` still has the version given, as returned by
+    /// `get_with_version`; `None` is for a missing key. Fails with
+    /// `KvsError::TransactionConflict` otherwise.
+    ///
+    /// The default implementation fails, for engines without versions.
+    fn write_batch_if_unchanged(
+        &self,
+        _reads: Vec<(String, Option<u64>)>,
+        _batch: WriteBatch,
+    ) -> Result<()> {
+        Err(transactions_unsupported())
+    }
+
+    /// Starts an optimistic transaction, see `Transaction`. It needs
+    /// `get_with_version` and `write_batch_if_unchanged`.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Makes `key` expire `ttl` from now, replacing its previous expiry if
+    /// any. Returns `false` if the key does not exist.
+    ///
+    /// The default implementation fails, for engines without TTL support.
+    fn expire(&self, _key: String, _ttl: Duration) -> Result<bool> {
+        Err(ttl_unsupported())
+    }
+
+    /// Removes the expiry of `key`, so that it is kept until removed.
+    /// Returns `false` if the key does not exist or has no expiry.
+    ///
+    /// The default implementation fails, for engines without TTL support.
+    fn persist(&self, _key: String) -> Result<bool> {
+        Err(ttl_unsupported())
+    }
+
+    /// Flushes buffered writes and makes every acknowledged write durable,
+    /// so that it survives a crash or a power loss.
+    ///
+    /// Engines that persist every write before acknowledging it can keep the
+    /// default implementation, which does nothing.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Replaces the whole data set with the backup in directory `from`, as
+    /// written by `KvStore::snapshot`. Writes wait for the restore, and reads
+    /// see either the data set before it or the restored one.
+    ///
+    /// The default implementation fails, for engines without restore support.
+    fn restore(&self, _from: &Path) -> Result<()> {
+        Err(KvsError::StringError(
+            "restores are not supported by this engine".to_owned(),
+        ))
+    }
+
+    /// Reclaims the space of the stale data now, e.g. during off-peak hours,
+    /// rather than when the engine would on its own.
+    ///
+    /// The default implementation fails, for engines without manual
+    /// compaction.
+    fn compact(&self) -> Result<()> {
+        Err(KvsError::StringError(
+            "compactions are not supported by this engine".to_owned(),
+        ))
+    }
+}
+
+fn ttl_unsupported() -> KvsError {
+    KvsError::StringError("TTLs are not supported by this engine".to_owned())
+}
+
+fn transactions_unsupported() -> KvsError {
+    KvsError::StringError("transactions are not supported by this engine".to_owned())
+}
+
+/// Defines a fault-injection point returning an I/O error from the enclosing function
+/// when configured with the `return` action. Other actions (`panic`, `sleep`, `pause`,
+/// ...) behave as documented by the `fail` crate.
+///
+/// The points are compiled out unless the `failpoints` feature is enabled. It is defined
+/// before the modules below, so that all of them can use it.
+#[cfg(feature = "failpoints")]
+macro_rules! io_fail_point {
+    ($name:expr) => {
+        fail_point!($name, |msg: Option<String>| {
+            let msg = msg.unwrap_or_else(|| format!("failpoint {} triggered", $name));
+            Err(std::io::Error::new(std::io::ErrorKind::Other, msg).into())
+        });
+    };
+}
+
+#[cfg(not(feature = "failpoints"))]
+macro_rules! io_fail_point {
+    ($name:expr) => {};
 }
 
+mod batch;
+mod changes;
+mod checkpoint;
+mod export;
+mod hint;
+mod history;
+#[cfg(feature = "fs")]
+mod incremental;
+mod iter;
+mod keydir;
+mod keyring;
 mod kvs;
+mod manifest;
+mod marker;
+#[cfg(feature = "test-util")]
+mod mock;
+mod record;
+#[cfg(feature = "fs")]
+mod repair;
+mod replay;
+mod restore;
+mod secondary;
+mod segment;
+mod shadow;
+mod sharded;
+#[cfg(feature = "sled")]
 mod sled;
+mod snapshot;
+mod transaction;
 
-pub use self::kvs::KvStore;
+pub use self::batch::WriteBatch;
+pub use self::changes::{Change, Changes};
+pub use self::export::ExportFormat;
+pub use self::history::AsOf;
+pub use self::iter::{Drain, Entry, IntoIter, Iter, Keys, Scan};
+#[cfg(feature = "fs")]
+pub(crate) use self::keyring::Keyring;
+pub use self::kvs::{
+    CompactionEvent, CompactionReport, CompactionStats, Compression, Durability, KvStore,
+    KvStoreBuilder, OpenProgress, RepairReport, SegmentArchive, StoreStats, WarmUp,
+};
+#[cfg(feature = "fs")]
+pub(crate) use self::manifest::{Manifest, SegmentSummary};
+#[cfg(feature = "test-util")]
+pub use self::mock::MockKvsEngine;
+#[cfg(feature = "fs")]
+pub(crate) use self::record::{Command, RawRecord};
+#[cfg(feature = "fs")]
+pub(crate) use self::segment::decompress_lossy;
+pub use self::shadow::{ShadowEngine, ShadowReport};
+pub use self::sharded::ShardedKvStore;
+#[cfg(feature = "sled")]
 pub use self::sled::SledKvsEngine;
+pub use self::snapshot::SnapshotRetention;
+pub use self::transaction::Transaction;
@@ -0,0 +1,458 @@
+//! This module provides the commands a `KvStore` writes to its log files,
+//! and the format of their records.
+
+use std::io::{self, BufRead, Read, Write};
+use std::ops::{Bound, Range};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use super::hint::{Hint, HintKind};
+use super::history::Stamp;
+use super::keyring::Keyring;
+use super::kvs::Compression;
+use crate::{KvsError, Result};
+
+/// The first byte of a binary log record, which gives its format version.
+/// It is followed by the length of the payload as a little-endian `u32` and
+/// the command encoded with bincode. Logs written by older versions hold JSON
+/// records, which start with `{` and are still read.
+const RECORD_FORMAT_V1: u8 = 1;
+/// Like `RECORD_FORMAT_V1`, with the CRC32C of the length and the payload as a
+/// little-endian `u32` between them.
+pub(crate) const RECORD_FORMAT_V2: u8 = 2;
+/// Like `RECORD_FORMAT_V2`, with the log sequence number of the command as a
+/// little-endian `u64` between the checksum and the payload. The checksum
+/// covers it too.
+pub(crate) const RECORD_FORMAT_V3: u8 = 3;
+/// Set in the format byte of a `RECORD_FORMAT_V3` record whose payload is
+/// compressed. The payload then starts with `CODEC_LZ4` or `CODEC_SNAPPY`, and
+/// the checksum covers the compressed bytes.
+pub(crate) const RECORD_COMPRESSED: u8 = 0x80;
+/// Set in the format byte of a `RECORD_FORMAT_V3` record whose payload is
+/// encrypted, see `KvStoreBuilder::encryption_key`. The version of the key
+/// follows the log sequence number as a little-endian `u32`, which the
+/// checksum covers too, and the payload, compressed or not, is encrypted
+/// along with the log sequence number.
+pub(crate) const RECORD_ENCRYPTED: u8 = 0x40;
+const CODEC_LZ4: u8 = 1;
+const CODEC_SNAPPY: u8 = 2;
+
+/// Enum representing a command
+///
+/// The optional fields are always serialized, as bincode does not support
+/// skipping them, and default to `None` when missing from a JSON record.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Command {
+    Set {
+        key: String,
+        /// Written like a string, so that records of string values written
+        /// before binary values were supported still read.
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        /// Milliseconds since the Unix epoch after which the key is expired.
+        #[serde(default)]
+        expires_at: Option<u64>,
+        /// Set if the write is kept in the history.
+        #[serde(default)]
+        stamp: Option<Stamp>,
+    },
+    Remove {
+        key: String,
+        /// Milliseconds since the Unix epoch at which the key was removed, if
+        /// its value is kept in the trash.
+        #[serde(default)]
+        removed_at: Option<u64>,
+        /// Set if the write is kept in the history.
+        #[serde(default)]
+        stamp: Option<Stamp>,
+    },
+    /// Replaces the expiry of an existing key, or removes it with `None`.
+    Expire {
+        key: String,
+        expires_at: Option<u64>,
+    },
+    /// Starts a batch of `count` "set" and "remove" commands.
+    Begin { count: u32 },
+    /// Commits the batch of `count` commands started right before it.
+    Commit { count: u32 },
+    /// Removes every key in the range, in a single record.
+    RemoveRange {
+        start: Bound<String>,
+        end: Bound<String>,
+        stamp: Option<Stamp>,
+    },
+    /// Appends `suffix` to the value of the record `base_range` of the log
+    /// file `base_gen`, a "set" or "append" command of the same key. `depth`
+    /// is the number of "append" commands of the chain, this one included.
+    Append {
+        key: String,
+        #[serde(with = "serde_bytes")]
+        suffix: Vec<u8>,
+        base_gen: u64,
+        base_range: Range<u64>,
+        depth: u32,
+        expires_at: Option<u64>,
+        stamp: Option<Stamp>,
+    },
+}
+
+impl Command {
+    pub(crate) fn set(key: String, value: Vec<u8>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at: None,
+            stamp: None,
+        }
+    }
+
+    pub(crate) fn remove(key: String) -> Command {
+        Command::Remove {
+            key,
+            removed_at: None,
+            stamp: None,
+        }
+    }
+
+    /// Sets the stamp of a "set" or "remove" command.
+    pub(crate) fn set_stamp(&mut self, new_stamp: Option<Stamp>) {
+        match self {
+            Command::Set { stamp, .. }
+            | Command::Remove { stamp, .. }
+            | Command::RemoveRange { stamp, .. }
+            | Command::Append { stamp, .. } => *stamp = new_stamp,
+            Command::Expire { .. } | Command::Begin { .. } | Command::Commit { .. } => {}
+        }
+    }
+
+    /// Returns the hint of the command, whose record spans `range` of its
+    /// log file and has the log sequence number `lsn`.
+    pub(crate) fn into_hint(self, range: Range<u64>, lsn: u64) -> Hint {
+        let (key, kind) = match self {
+            Command::Set {
+                key,
+                expires_at,
+                stamp,
+                ..
+            } => (key, HintKind::Set { expires_at, stamp }),
+            Command::Remove {
+                key,
+                removed_at,
+                stamp,
+            } => (key, HintKind::Remove { removed_at, stamp }),
+            Command::Expire { key, expires_at } => (key, HintKind::Expire { expires_at, lsn }),
+            Command::Begin { count } => (String::new(), HintKind::Begin { count }),
+            Command::Commit { count } => (String::new(), HintKind::Commit { count }),
+            Command::RemoveRange { start, end, stamp } => {
+                (String::new(), HintKind::RemoveRange { start, end, stamp })
+            }
+            Command::Append {
+                key,
+                expires_at,
+                stamp,
+                ..
+            } => (key, HintKind::Append { expires_at, stamp }),
+        };
+        Hint {
+            key,
+            pos: range.start,
+            len: range.end - range.start,
+            kind,
+        }
+    }
+
+    /// Returns the stamp of a "set", "remove", "remove range" or "append"
+    /// command.
+    pub(crate) fn stamp(&self) -> Option<Stamp> {
+        match self {
+            Command::Set { stamp, .. }
+            | Command::Remove { stamp, .. }
+            | Command::RemoveRange { stamp, .. }
+            | Command::Append { stamp, .. } => *stamp,
+            Command::Expire { .. } | Command::Begin { .. } | Command::Commit { .. } => None,
+        }
+    }
+
+    /// Returns the log sequence number of a "set", "remove", "remove range"
+    /// or "append" command, the one of its stamp, or 0 if it has none.
+    pub(crate) fn lsn(&self) -> u64 {
+        match self {
+            Command::Set { stamp, .. }
+            | Command::Remove { stamp, .. }
+            | Command::RemoveRange { stamp, .. }
+            | Command::Append { stamp, .. } => stamp.map_or(0, |stamp| stamp.seq),
+            Command::Expire { .. } | Command::Begin { .. } | Command::Commit { .. } => 0,
+        }
+    }
+
+    /// Appends the command to `writer` as a binary record with the log
+    /// sequence number `lsn`, encrypted with the current key of `keyring` if
+    /// it has one.
+    pub(crate) fn write_to<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+        lsn: u64,
+        keyring: &Keyring,
+    ) -> Result<()> {
+        self.write_compressed(writer, lsn, None, keyring)
+    }
+
+    /// Like `write_to`, compressing the record with the given codec if it
+    /// sets a value of at least the given size and gets smaller.
+    pub(crate) fn write_compressed<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+        lsn: u64,
+        compression: Option<(Compression, usize)>,
+        keyring: &Keyring,
+    ) -> Result<()> {
+        let mut payload = bincode::DefaultOptions::new().serialize(self)?;
+        let mut format = RECORD_FORMAT_V3;
+        if let (Some((codec, min_size)), Command::Set { value, .. }) = (compression, self) {
+            if value.len() >= min_size {
+                let compressed = compress(codec, &payload)?;
+                if compressed.len() < payload.len() {
+                    payload = compressed;
+                    format |= RECORD_COMPRESSED;
+                }
+            }
+        }
+        let lsn = lsn.to_le_bytes();
+        let mut key_version = None;
+        if let Some((version, sealed)) = keyring.seal(&lsn, &payload)? {
+            payload = sealed;
+            format |= RECORD_ENCRYPTED;
+            key_version = Some(version.to_le_bytes());
+        }
+        let len = (payload.len() as u32).to_le_bytes();
+        let mut checksum = crc32c::crc32c_append(crc32c::crc32c(&len), &lsn);
+        if let Some(version) = &key_version {
+            checksum = crc32c::crc32c_append(checksum, version);
+        }
+        let checksum = crc32c::crc32c_append(checksum, &payload);
+        writer.write_all(&[format])?;
+        writer.write_all(&len)?;
+        writer.write_all(&checksum.to_le_bytes())?;
+        writer.write_all(&lsn)?;
+        if let Some(version) = &key_version {
+            writer.write_all(version)?;
+        }
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+
+    /// Reads the next record of `reader`, binary or JSON, or returns `None`
+    /// at the end of the log. `gen` and `offset` locate the record in the log
+    /// for `KvsError::Corruption`. Encrypted records are decrypted with the
+    /// keys of `keyring`.
+    pub(crate) fn read_from<R: BufRead>(
+        reader: &mut R,
+        gen: u64,
+        offset: u64,
+        keyring: &Keyring,
+    ) -> Result<Option<Command>> {
+        Ok(Command::read_record(reader, gen, offset, keyring)?.map(|(command, _)| command))
+    }
+
+    /// Like `read_from`, with the log sequence number of the command. The
+    /// records written before it was in the header have the one of their
+    /// stamp, if any.
+    pub(crate) fn read_record<R: BufRead>(
+        reader: &mut R,
+        gen: u64,
+        offset: u64,
+        keyring: &Keyring,
+    ) -> Result<Option<(Command, u64)>> {
+        match RawRecord::read(reader, gen, offset)? {
+            Some(record) => record.decode(gen, offset, keyring).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A record read from the log and checked against its checksum, but whose
+/// binary payload is not decoded yet, see `Command::read_record`.
+pub(crate) enum RawRecord {
+    /// A JSON record, which is decoded to be read.
+    Json(Command),
+    Binary {
+        /// Whether the payload is compressed.
+        compressed: bool,
+        /// The log sequence number in the header, if any.
+        lsn: Option<[u8; 8]>,
+        /// The version of the key the payload is encrypted with, if any.
+        key_version: Option<u32>,
+        /// The payload, encrypted or compressed as the header tells.
+        payload: Vec<u8>,
+    },
+}
+
+impl RawRecord {
+    /// Reads the next record of `reader`, or returns `None` at the end of the
+    /// log.
+    pub(crate) fn read<R: BufRead>(reader: &mut R, gen: u64, offset: u64) -> Result<Option<Self>> {
+        let format = loop {
+            match reader.fill_buf()?.first() {
+                None => return Ok(None),
+                // JSON records may be separated by whitespace.
+                Some(byte) if byte.is_ascii_whitespace() => reader.consume(1),
+                Some(&byte) => break byte,
+            }
+        };
+        if !is_record_format(format) {
+            let mut de = serde_json::Deserializer::from_reader(reader);
+            return Ok(Some(RawRecord::Json(Command::deserialize(&mut de)?)));
+        }
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        let len = [header[1], header[2], header[3], header[4]];
+        let checksum = if format != RECORD_FORMAT_V1 {
+            let mut checksum = [0; 4];
+            reader.read_exact(&mut checksum)?;
+            Some(u32::from_le_bytes(checksum))
+        } else {
+            None
+        };
+        let lsn = if format & !(RECORD_COMPRESSED | RECORD_ENCRYPTED) == RECORD_FORMAT_V3 {
+            let mut lsn = [0; 8];
+            reader.read_exact(&mut lsn)?;
+            Some(lsn)
+        } else {
+            None
+        };
+        let key_version = if format & RECORD_ENCRYPTED != 0 {
+            let mut version = [0; 4];
+            reader.read_exact(&mut version)?;
+            Some(version)
+        } else {
+            None
+        };
+        // A corrupted header may give a huge length, so it is not allocated
+        // upfront.
+        let mut payload = Vec::new();
+        reader
+            .take(u32::from_le_bytes(len) as u64)
+            .read_to_end(&mut payload)?;
+        if payload.len() < u32::from_le_bytes(len) as usize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if let Some(checksum) = checksum {
+            let mut actual = crc32c::crc32c(&len);
+            if let Some(lsn) = &lsn {
+                actual = crc32c::crc32c_append(actual, lsn);
+            }
+            if let Some(version) = &key_version {
+                actual = crc32c::crc32c_append(actual, version);
+            }
+            if crc32c::crc32c_append(actual, &payload) != checksum {
+                return Err(KvsError::Corruption { gen, offset });
+            }
+        }
+        Ok(Some(RawRecord::Binary {
+            compressed: format & RECORD_COMPRESSED != 0,
+            lsn,
+            key_version: key_version.map(u32::from_le_bytes),
+            payload,
+        }))
+    }
+
+    /// Returns the version of the key the record is encrypted with, if any.
+    #[cfg(feature = "fs")]
+    pub(crate) fn key_version(&self) -> Option<u32> {
+        match self {
+            RawRecord::Json(_) => None,
+            RawRecord::Binary { key_version, .. } => *key_version,
+        }
+    }
+
+    /// Returns the log sequence number in the header of the record, or 0 if
+    /// it has none.
+    #[cfg(feature = "fs")]
+    pub(crate) fn lsn(&self) -> u64 {
+        match self {
+            RawRecord::Binary { lsn: Some(lsn), .. } => u64::from_le_bytes(*lsn),
+            _ => 0,
+        }
+    }
+
+    /// Decodes the command of the record, decrypting it with the keys of
+    /// `keyring`, along with its log sequence number.
+    pub(crate) fn decode(self, gen: u64, offset: u64, keyring: &Keyring) -> Result<(Command, u64)> {
+        let (compressed, lsn, key_version, mut payload) = match self {
+            RawRecord::Json(command) => {
+                let lsn = command.lsn();
+                return Ok((command, lsn));
+            }
+            RawRecord::Binary {
+                compressed,
+                lsn,
+                key_version,
+                payload,
+            } => (compressed, lsn, key_version, payload),
+        };
+        if let (Some(version), Some(lsn)) = (key_version, &lsn) {
+            payload = keyring.open(version, lsn, &payload, gen, offset)?;
+        }
+        if compressed {
+            payload = decompress(&payload).ok_or(KvsError::Corruption { gen, offset })?;
+        }
+        let command: Command = bincode::DefaultOptions::new().deserialize(&payload)?;
+        let lsn = lsn.map_or_else(|| command.lsn(), u64::from_le_bytes);
+        Ok((command, lsn))
+    }
+}
+
+/// Returns the version of the key the record starting `record` is encrypted
+/// with, if any, from its header only.
+pub(crate) fn record_key_version(record: &[u8]) -> Option<u32> {
+    match record.first() {
+        Some(&format) if format & RECORD_ENCRYPTED != 0 && is_record_format(format) => {
+            let version = record.get(17..21)?;
+            Some(u32::from_le_bytes([
+                version[0], version[1], version[2], version[3],
+            ]))
+        }
+        _ => None,
+    }
+}
+
+/// Returns whether `format`, the first byte of a record, starts a binary
+/// record rather than a JSON one.
+fn is_record_format(format: u8) -> bool {
+    match format & !(RECORD_COMPRESSED | RECORD_ENCRYPTED) {
+        RECORD_FORMAT_V1 => format == RECORD_FORMAT_V1,
+        RECORD_FORMAT_V2 => format & RECORD_ENCRYPTED == 0,
+        RECORD_FORMAT_V3 => true,
+        _ => false,
+    }
+}
+
+/// Compresses the payload of a record with `codec`, after the byte naming it.
+pub(super) fn compress(codec: Compression, payload: &[u8]) -> Result<Vec<u8>> {
+    Ok(match codec {
+        Compression::Lz4 => {
+            let mut compressed = vec![CODEC_LZ4];
+            compressed.extend(lz4_flex::compress_prepend_size(payload));
+            compressed
+        }
+        Compression::Snappy => {
+            let mut compressed = vec![CODEC_SNAPPY; 1 + snap::raw::max_compress_len(payload.len())];
+            let len = snap::raw::Encoder::new()
+                .compress(payload, &mut compressed[1..])
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            compressed.truncate(1 + len);
+            compressed
+        }
+    })
+}
+
+/// Decompresses the payload of a record written by `compress`, or returns
+/// `None` if it does not decompress.
+pub(super) fn decompress(payload: &[u8]) -> Option<Vec<u8>> {
+    match payload.split_first()? {
+        (&CODEC_LZ4, compressed) => lz4_flex::decompress_size_prepended(compressed).ok(),
+        (&CODEC_SNAPPY, compressed) => snap::raw::Decoder::new().decompress_vec(compressed).ok(),
+        _ => None,
+    }
+}
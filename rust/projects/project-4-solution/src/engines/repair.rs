@@ -0,0 +1,110 @@
+//! This module provides the repair of the log files of a `KvStore` data
+//! directory damaged by a crash or a bad disk, run by
+//! `KvStore::open_with_repair`.
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+use super::checkpoint;
+use super::hint;
+use super::keyring::Keyring;
+use super::kvs::RepairReport;
+use super::record::{
+    RawRecord, RECORD_COMPRESSED, RECORD_ENCRYPTED, RECORD_FORMAT_V2, RECORD_FORMAT_V3,
+};
+use super::segment::{self, log_path, sorted_gen_list};
+use crate::vfs::Vfs;
+use crate::Result;
+
+/// Rewrites the log files of `dir` without the regions which do not decode,
+/// see `KvStore::open_with_repair`.
+pub(crate) fn repair_logs(vfs: &dyn Vfs, dir: &Path) -> Result<RepairReport> {
+    let mut report = RepairReport::default();
+    for gen in sorted_gen_list(vfs, dir)? {
+        report.segments_scanned += 1;
+        let file_path = log_path(dir, gen);
+        let mut bytes = Vec::new();
+        vfs.open(&file_path)?.read_to_end(&mut bytes)?;
+        // The blocks after a damaged one of a compressed log file are lost.
+        let (bytes, blocks_dropped, block_bytes_dropped) = segment::decompress_lossy(bytes);
+        if blocks_dropped > 0 {
+            warn!(
+                "Dropping {} damaged compressed bytes of {:?}",
+                block_bytes_dropped, file_path
+            );
+            report.entries_dropped += blocks_dropped;
+            report.bytes_dropped += block_bytes_dropped;
+        }
+
+        let mut kept = Vec::with_capacity(bytes.len());
+        let mut pos = 0;
+        while pos < bytes.len() {
+            if let Some(len) = record_len(&bytes[pos..], gen, pos as u64) {
+                kept.extend_from_slice(&bytes[pos..pos + len]);
+                pos += len;
+                continue;
+            }
+            // Resume at the next record which decodes. Only binary records
+            // with a checksum and JSON records are looked for, as any byte
+            // may start a record without a checksum.
+            let next = (pos + 1..bytes.len())
+                .find(|&at| {
+                    (matches!(
+                        bytes[at] & !(RECORD_COMPRESSED | RECORD_ENCRYPTED),
+                        RECORD_FORMAT_V2 | RECORD_FORMAT_V3
+                    ) || bytes[at] == b'{')
+                        && record_len(&bytes[at..], gen, at as u64).is_some()
+                })
+                .unwrap_or(bytes.len());
+            warn!(
+                "Dropping {} damaged bytes at offset {} of {:?}",
+                next - pos,
+                pos,
+                file_path
+            );
+            report.entries_dropped += 1;
+            report.bytes_dropped += (next - pos) as u64;
+            pos = next;
+        }
+        if kept.len() == bytes.len() && blocks_dropped == 0 {
+            continue;
+        }
+
+        let tmp_path = file_path.with_extension("log.tmp");
+        let _ = vfs.remove_file(&tmp_path);
+        let mut file = vfs.open_append(&tmp_path)?;
+        file.write_all(&kept)?;
+        file.sync_data()?;
+        io_fail_point!("kvs::repair::rename");
+        vfs.rename(&tmp_path, &file_path)?;
+        hint::remove(vfs, dir, gen)?;
+        report.segments_repaired += 1;
+    }
+    if report.segments_repaired > 0 {
+        // The checkpoint refers to the positions before the repair.
+        checkpoint::remove(vfs, dir)?;
+        info!(
+            segments_repaired = report.segments_repaired,
+            entries_dropped = report.entries_dropped,
+            "Repaired the log"
+        );
+    }
+    Ok(report)
+}
+
+/// Returns the length of the record at the start of `bytes`, with the
+/// whitespace before it, or `None` if it does not decode. Whitespace up to
+/// the end counts as a record.
+fn record_len(mut bytes: &[u8], gen: u64, offset: u64) -> Option<usize> {
+    let len = bytes.len();
+    // Encrypted records are checked against their checksum only, as the
+    // keys are not known.
+    let valid = match RawRecord::read(&mut bytes, gen, offset) {
+        Ok(Some(record)) if record.key_version().is_none() => {
+            record.decode(gen, offset, &Keyring::default()).is_ok()
+        }
+        Ok(_) => true,
+        Err(_) => false,
+    };
+    valid.then(|| len - bytes.len())
+}
@@ -0,0 +1,641 @@
+//! This module provides the replay of the log files of a `KvStore` data
+//! directory, which rebuilds the index when the store is opened.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Seek, SeekFrom};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use super::hint::{self, Hint, HintKind};
+use super::history::{AsOf, History, Stamp, Versions};
+use super::keydir::{CommandPos, Keydir};
+use super::keyring::Keyring;
+use super::kvs::{index_range, range_contains, OpenProgress, ProgressCallback, Trashed};
+use super::record::Command;
+use super::segment::{self, log_path, BufReaderWithPos};
+use crate::vfs::Vfs;
+use crate::{KvsError, Result};
+
+/// The value and the expiry of every key, replayed by `KvStore::recover_to`.
+pub(crate) type Recovered = BTreeMap<String, (Vec<u8>, Option<u64>)>;
+
+/// Replays the writes of the log files `logs`, by generation, up to `point`.
+/// A log file is read up to its length if it has one.
+pub(crate) fn replay_to(
+    vfs: &dyn Vfs,
+    logs: BTreeMap<u64, (PathBuf, Option<u64>)>,
+    point: AsOf,
+    keyring: &Keyring,
+) -> Result<Recovered> {
+    let mut recovered = Recovered::new();
+    let mut last_seq = 0;
+    for (gen, (path, len)) in logs {
+        let mut reader = BufReaderWithPos::new(segment::open(vfs, &path)?)?;
+        let mut batch: Option<(u32, Vec<(Command, u64)>)> = None;
+        while len.is_none_or(|len| reader.pos < len) {
+            let pos = reader.pos;
+            let (command, lsn) = match Command::read_record(&mut reader, gen, pos, keyring) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                // An archived log file may end with a record cut short by a
+                // crash before it was archived. The live ones are read up to
+                // their length, synced when the recovery started.
+                Err(ref e) if len.is_none() && is_torn(e) => break,
+                Err(e) => return Err(e),
+            };
+            let writes = match (command, &mut batch) {
+                (Command::Begin { count }, _) => {
+                    batch = Some((count, Vec::new()));
+                    continue;
+                }
+                (Command::Commit { count }, Some((begun, writes)))
+                    if count == *begun && writes.len() == count as usize =>
+                {
+                    std::mem::take(writes)
+                }
+                (Command::Commit { .. }, _) => {
+                    // It was cut short by a crash.
+                    batch = None;
+                    continue;
+                }
+                (command, Some((_, writes))) => {
+                    writes.push((command, lsn));
+                    continue;
+                }
+                (command, None) => vec![(command, lsn)],
+            };
+            batch = None;
+            if !replay_writes(&mut recovered, &mut last_seq, writes, point)? {
+                return Ok(recovered);
+            }
+        }
+    }
+    Ok(recovered)
+}
+
+/// Applies `writes`, a write or the writes of a batch, to `recovered` unless
+/// they were already applied, from a log file older than the compaction file
+/// they were copied to. Returns `false` if they were made after `point`.
+fn replay_writes(
+    recovered: &mut Recovered,
+    last_seq: &mut u64,
+    writes: Vec<(Command, u64)>,
+    point: AsOf,
+) -> Result<bool> {
+    let mut seqs = writes.iter().map(|(_, lsn)| *lsn).filter(|&lsn| lsn > 0);
+    // Writes made before sequence numbers are replayed in log order.
+    if let Some(first) = seqs.next() {
+        let last = seqs.next_back().unwrap_or(first);
+        if first <= *last_seq {
+            return Ok(true);
+        }
+        if matches!(point, AsOf::Seq(seq) if last > seq) {
+            return Ok(false);
+        }
+        if first != *last_seq + 1 {
+            return Err(KvsError::HistoryUnavailable);
+        }
+        let after_point = writes
+            .iter()
+            .filter_map(|(command, _)| command.stamp())
+            .any(|stamp| !point.includes(stamp));
+        if after_point {
+            return Ok(false);
+        }
+        *last_seq = last;
+    }
+
+    for (command, _) in writes {
+        match command {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                ..
+            } => {
+                recovered.insert(key, (value, expires_at));
+            }
+            Command::Remove { key, .. } => {
+                recovered.remove(&key);
+            }
+            Command::Expire { key, expires_at } => {
+                if let Some((_, expiry)) = recovered.get_mut(&key) {
+                    *expiry = expires_at;
+                }
+            }
+            Command::RemoveRange { start, end, .. } => {
+                recovered.retain(|key, _| !range_contains(&start, &end, key));
+            }
+            Command::Append {
+                key,
+                suffix,
+                expires_at,
+                ..
+            } => {
+                let (value, expiry) = recovered.entry(key).or_default();
+                value.extend_from_slice(&suffix);
+                *expiry = expires_at;
+            }
+            Command::Begin { .. } | Command::Commit { .. } => {}
+        }
+    }
+    Ok(true)
+}
+
+/// The combined effect of the commands of a log file on a key.
+enum Effect {
+    Set(CommandPos),
+    Remove,
+    /// The expiry of a key set in an earlier log file.
+    Expire(Option<u64>),
+}
+
+/// The last value a log file put in the trash for a key.
+enum TrashEffect {
+    /// A value set in the log file itself.
+    Value(Trashed),
+    /// The value of the key set in an earlier log file, removed at the given time.
+    Previous(u64),
+}
+
+/// A log file replayed on its own.
+pub(crate) struct Replay {
+    /// The last effect on every key the log file mentions.
+    effects: HashMap<String, Effect>,
+    /// The values removed with a retention.
+    trash: HashMap<String, TrashEffect>,
+    /// Whether the log file has tombstones of values not kept in the trash.
+    pub(crate) has_tombstones: bool,
+    /// Bytes of the log file that can be saved after a compaction.
+    uncompacted: u64,
+    /// The stamped versions, in log order. A version without a stamp is the
+    /// one of the key before the log file stamped its first version of it.
+    versions: Vec<(String, Option<Stamp>, Option<CommandPos>)>,
+    /// The largest sequence number of the stamped versions and the expiries.
+    last_seq: u64,
+    /// The ranges removed, in log order, with the number of versions before
+    /// each.
+    ranges: Vec<(usize, RangeRemoval)>,
+}
+
+/// A range of keys removed by a log file.
+struct RangeRemoval {
+    start: Bound<String>,
+    end: Bound<String>,
+    stamp: Option<Stamp>,
+    /// The keys in the range the log file mentions before the removal, which
+    /// takes effect on them as a "remove" command would.
+    mentioned: HashSet<String>,
+}
+
+impl RangeRemoval {
+    /// Adds the removal of the other keys in the range, set by earlier log
+    /// files, to the history and the kept versions.
+    fn push_versions(
+        &self,
+        index: &Keydir,
+        history: &mut History,
+        versions: &mut Versions,
+    ) -> Result<()> {
+        let stamp = match self.stamp {
+            Some(stamp) => stamp,
+            None => return Ok(()),
+        };
+        for (key, _) in index_range(index, &self.start, &self.end)? {
+            if !self.mentioned.contains(&*key) {
+                history.push(index, &key, Some(stamp), None)?;
+                versions.push(&key, stamp, None);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Replay {
+    /// Load the whole log file, or its hint file if it has a valid one, and
+    /// collect the effect of its commands on every key.
+    ///
+    /// The records before `start` are skipped. With `torn_tail`, a last
+    /// record cut short by a crash is truncated rather than failing the load.
+    fn read(
+        vfs: &dyn Vfs,
+        path: &Path,
+        gen: u64,
+        start: u64,
+        torn_tail: bool,
+        keyring: &Keyring,
+    ) -> Result<Replay> {
+        if start == 0 {
+            let log_len = vfs.file_len(&log_path(path, gen))?;
+            if let Some(hints) = hint::read(vfs, path, gen, log_len)? {
+                return Replay::from_hints(hints.into_iter().map(Ok), gen);
+            }
+        }
+        let mut end = start;
+        let mut torn = None;
+        let hints = read_hints(vfs, path, gen, start, keyring)?.map_while(|hint| match hint {
+            Err(e) if torn_tail && is_torn(&e) => {
+                torn = Some(e);
+                None
+            }
+            hint => {
+                if let Ok(hint) = &hint {
+                    end = hint.pos + hint.len;
+                }
+                Some(hint)
+            }
+        });
+        let replay = Replay::from_hints(hints, gen)?;
+        if let Some(e) = torn {
+            let file_path = log_path(path, gen);
+            warn!(
+                "Truncating the torn record at offset {} of {:?}: {}",
+                end, file_path, e
+            );
+            vfs.truncate(&file_path, end)?;
+        }
+        Ok(replay)
+    }
+
+    /// Collects the effect of the records of the log file `gen`, described by
+    /// `hints` in log order.
+    ///
+    /// The records of a batch take effect once its commit marker is read. A
+    /// batch without it, cut short by a crash, is discarded.
+    fn from_hints(hints: impl Iterator<Item = Result<Hint>>, gen: u64) -> Result<Replay> {
+        let mut replay = Replay {
+            effects: HashMap::new(),
+            trash: HashMap::new(),
+            has_tombstones: false,
+            uncompacted: 0,
+            versions: Vec::new(),
+            last_seq: 0,
+            ranges: Vec::new(),
+        };
+        let mut versioned = HashSet::new();
+        let mut batch: Option<PendingBatch> = None;
+
+        for hint in hints {
+            let hint = hint?;
+            if let Some(pending) = &mut batch {
+                if pending.accepts(&hint) {
+                    pending.hints.push(hint);
+                    continue;
+                }
+                let pending = batch.take().unwrap();
+                if pending.is_committed_by(&hint) {
+                    for hint in pending.hints.into_iter().chain(Some(hint)) {
+                        replay.push(hint, gen, &mut versioned);
+                    }
+                    replay.uncompacted += pending.begin_len;
+                    continue;
+                }
+                replay.discard(pending, gen);
+            }
+            match hint.kind {
+                HintKind::Begin { count } => {
+                    batch = Some(PendingBatch {
+                        count,
+                        begin_pos: hint.pos,
+                        begin_len: hint.len,
+                        hints: Vec::new(),
+                    })
+                }
+                _ => replay.push(hint, gen, &mut versioned),
+            }
+        }
+        if let Some(pending) = batch {
+            replay.discard(pending, gen);
+        }
+
+        Ok(replay)
+    }
+
+    /// Collects the effect of a record, described by `hint`. `versioned` has
+    /// the keys with a stamped version in the log file so far.
+    fn push(&mut self, hint: Hint, gen: u64, versioned: &mut HashSet<String>) {
+        let Hint {
+            key,
+            pos,
+            len,
+            kind,
+        } = hint;
+        let new_pos = pos + len;
+        match kind {
+            HintKind::Set { expires_at, stamp } => {
+                let mut cmd_pos = CommandPos::from((gen, pos..new_pos));
+                cmd_pos.expires_at = expires_at;
+                if let Some(stamp) = stamp {
+                    if versioned.insert(key.clone()) {
+                        if let Some(base) = base_version(&self.effects, &key) {
+                            self.versions.push((key.clone(), None, base));
+                        }
+                    }
+                    self.versions
+                        .push((key.clone(), Some(stamp), Some(cmd_pos)));
+                    self.last_seq = self.last_seq.max(stamp.seq);
+                }
+                if let Some(Effect::Set(old_cmd)) = self.effects.insert(key, Effect::Set(cmd_pos)) {
+                    self.uncompacted += old_cmd.len;
+                }
+            }
+            HintKind::Append { expires_at, stamp } => {
+                // Like a "set" command, rewritten on compaction.
+                let hint = Hint {
+                    key: key.clone(),
+                    pos,
+                    len,
+                    kind: HintKind::Set { expires_at, stamp },
+                };
+                self.push(hint, gen, versioned);
+                if let Some(Effect::Set(cmd_pos)) = self.effects.get_mut(&key) {
+                    cmd_pos.expiry_changed = true;
+                }
+            }
+            HintKind::Remove { removed_at, stamp } => {
+                if let Some(stamp) = stamp {
+                    if versioned.insert(key.clone()) {
+                        if let Some(base) = base_version(&self.effects, &key) {
+                            self.versions.push((key.clone(), None, base));
+                        }
+                    }
+                    self.versions.push((key.clone(), Some(stamp), None));
+                    self.last_seq = self.last_seq.max(stamp.seq);
+                }
+                let old = self.effects.insert(key.clone(), Effect::Remove);
+                if let Some(Effect::Set(old_cmd)) = old {
+                    self.uncompacted += old_cmd.len;
+                }
+                if let Some(removed_at) = removed_at {
+                    let trashed = match old {
+                        Some(Effect::Set(pos)) => TrashEffect::Value(Trashed { pos, removed_at }),
+                        _ => TrashEffect::Previous(removed_at),
+                    };
+                    self.trash.insert(key, trashed);
+                } else {
+                    self.has_tombstones = true;
+                }
+
+                // The "remove" command itself can be deleted in the next compaction so we add
+                // its length to `uncompacted`.
+                self.uncompacted += len;
+            }
+            HintKind::Expire { expires_at, lsn } => {
+                self.last_seq = self.last_seq.max(lsn);
+                match self.effects.get_mut(&key) {
+                    Some(Effect::Set(cmd_pos)) => {
+                        cmd_pos.expires_at = expires_at;
+                        cmd_pos.expiry_changed = true;
+                    }
+                    Some(Effect::Remove) => {}
+                    Some(Effect::Expire(old)) => *old = expires_at,
+                    None => {
+                        self.effects.insert(key, Effect::Expire(expires_at));
+                    }
+                }
+                self.uncompacted += len;
+            }
+            HintKind::RemoveRange { start, end, stamp } => {
+                let mentioned: HashSet<String> = self
+                    .effects
+                    .keys()
+                    .filter(|key| range_contains(&start, &end, key))
+                    .cloned()
+                    .collect();
+                for key in &mentioned {
+                    if !matches!(self.effects[key], Effect::Remove) {
+                        let kind = HintKind::Remove {
+                            removed_at: None,
+                            stamp,
+                        };
+                        let hint = Hint {
+                            key: key.clone(),
+                            pos,
+                            len: 0,
+                            kind,
+                        };
+                        self.push(hint, gen, versioned);
+                    }
+                }
+                if let Some(stamp) = stamp {
+                    self.last_seq = self.last_seq.max(stamp.seq);
+                }
+                self.has_tombstones = true;
+                self.uncompacted += len;
+                let removal = RangeRemoval {
+                    start,
+                    end,
+                    stamp,
+                    mentioned,
+                };
+                self.ranges.push((self.versions.len(), removal));
+            }
+            // Stale once compacted, like the "remove" command.
+            HintKind::Begin { .. } | HintKind::Commit { .. } => self.uncompacted += len,
+        }
+    }
+
+    /// Discards the records of a batch without its commit marker. Their bytes
+    /// can be saved after a compaction.
+    fn discard(&mut self, batch: PendingBatch, gen: u64) {
+        warn!(
+            "Discarding an uncommitted batch at offset {} of {}.log",
+            batch.begin_pos, gen
+        );
+        self.uncompacted += batch.begin_len;
+        self.uncompacted += batch.hints.iter().map(|hint| hint.len).sum::<u64>();
+    }
+
+    /// Applies the effects to the index, the trash, the history and the kept versions built from
+    /// the earlier log files.
+    ///
+    /// Returns `uncompacted`, which is number of bytes that can be saved after a compaction.
+    pub(crate) fn apply(
+        self,
+        index: &Keydir,
+        trash: &mut BTreeMap<String, Trashed>,
+        history: &mut History,
+        versions: &mut Versions,
+    ) -> Result<u64> {
+        // The versions before this log file are the ones in the index, and so
+        // are the keys of a range removed not mentioned before it.
+        let mut next_range = 0;
+        for (i, (key, stamp, pos)) in self.versions.into_iter().enumerate() {
+            while let Some((_, range)) = self.ranges.get(next_range).filter(|(at, _)| *at == i) {
+                range.push_versions(index, history, versions)?;
+                next_range += 1;
+            }
+            history.push(index, &key, stamp, pos)?;
+            if let Some(stamp) = stamp {
+                versions.push(&key, stamp, pos);
+            }
+        }
+        for (_, range) in &self.ranges[next_range..] {
+            range.push_versions(index, history, versions)?;
+        }
+        history.last_seq = history.last_seq.max(self.last_seq);
+
+        // The previous values are the ones in the index before this log file.
+        for (key, trashed) in self.trash {
+            let trashed = match trashed {
+                TrashEffect::Value(trashed) => trashed,
+                TrashEffect::Previous(removed_at) => match index.get(key.as_str())? {
+                    Some(pos) => Trashed { pos, removed_at },
+                    None => continue,
+                },
+            };
+            trash.insert(key, trashed);
+        }
+
+        // The keys set by earlier log files in the ranges removed, before the
+        // effects of the keys set again since.
+        let mut uncompacted = self.uncompacted;
+        for (_, range) in &self.ranges {
+            for (key, _) in index_range(index, &range.start, &range.end)? {
+                if let Some(old_cmd) = index.remove(&key)? {
+                    uncompacted += old_cmd.len;
+                }
+            }
+        }
+        for (key, effect) in self.effects {
+            match effect {
+                Effect::Set(cmd_pos) => {
+                    if let Some(old_cmd) = index.insert(key.into(), cmd_pos)? {
+                        uncompacted += old_cmd.len;
+                    }
+                }
+                Effect::Remove => {
+                    if let Some(old_cmd) = index.remove(key.as_str())? {
+                        uncompacted += old_cmd.len;
+                    }
+                }
+                Effect::Expire(expires_at) => {
+                    if let Some(mut cmd_pos) = index.get(key.as_str())? {
+                        cmd_pos.expires_at = expires_at;
+                        cmd_pos.expiry_changed = true;
+                        index.insert(key.into(), cmd_pos)?;
+                    }
+                }
+            }
+        }
+        Ok(uncompacted)
+    }
+}
+
+/// The records of a batch read so far, until its commit marker.
+struct PendingBatch {
+    count: u32,
+    begin_pos: u64,
+    begin_len: u64,
+    hints: Vec<Hint>,
+}
+
+impl PendingBatch {
+    /// Returns whether `hint` is one of the records of the batch.
+    fn accepts(&self, hint: &Hint) -> bool {
+        matches!(hint.kind, HintKind::Set { .. } | HintKind::Remove { .. })
+            && self.hints.len() < self.count as usize
+    }
+
+    /// Returns whether `hint` is the commit marker of the complete batch.
+    fn is_committed_by(&self, hint: &Hint) -> bool {
+        matches!(hint.kind, HintKind::Commit { count } if count == self.count)
+            && self.hints.len() == self.count as usize
+    }
+}
+
+/// Returns an iterator decoding the records of the log file `gen` from `start`
+/// into hints, decrypting them with the keys of `keyring`.
+pub(crate) fn read_hints<'a>(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+    start: u64,
+    keyring: &'a Keyring,
+) -> Result<impl Iterator<Item = Result<Hint>> + 'a> {
+    let mut reader = BufReaderWithPos::new(segment::open(vfs, &log_path(path, gen))?)?;
+    reader.seek(SeekFrom::Start(start))?;
+    Ok(std::iter::from_fn(move || {
+        let pos = reader.pos;
+        Command::read_record(&mut reader, gen, pos, keyring)
+            .transpose()
+            .map(|record| {
+                let (cmd, lsn) = record?;
+                Ok(cmd.into_hint(pos..reader.pos, lsn))
+            })
+    }))
+}
+
+/// Returns the version of `key` set by the commands of a log file before the
+/// current one, `None` if they do not tell.
+fn base_version(effects: &HashMap<String, Effect>, key: &str) -> Option<Option<CommandPos>> {
+    match effects.get(key)? {
+        Effect::Set(cmd_pos) => Some(Some(*cmd_pos)),
+        Effect::Remove => Some(None),
+        Effect::Expire(_) => None,
+    }
+}
+
+/// Returns `true` if `err` comes from a record cut short by the end of its log
+/// file, as left by a crash in the middle of a write.
+fn is_torn(err: &KvsError) -> bool {
+    match err {
+        KvsError::Io(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        KvsError::Serde(err) => err.is_eof(),
+        _ => false,
+    }
+}
+
+/// Replays the log files of `segments`, each from the given offset, reporting
+/// to `progress` after each one. With the `threads` feature, they are replayed
+/// in parallel on the rayon thread pool since they are independent of each
+/// other.
+///
+/// With `torn_tail`, the last log file may end with a record cut short by a
+/// crash, which is truncated.
+pub(crate) fn replay_all(
+    vfs: &dyn Vfs,
+    path: &Path,
+    segments: &[(u64, u64)],
+    torn_tail: bool,
+    keyring: &Keyring,
+    progress: &mut ProgressCallback<'_>,
+) -> Result<Vec<Replay>> {
+    let newest = segments.last().map(|&(gen, _)| gen);
+    let mut lens = Vec::with_capacity(segments.len());
+    for &(gen, start) in segments {
+        let len = vfs.file_len(&log_path(path, gen))?;
+        lens.push((gen, start, len.saturating_sub(start)));
+    }
+    let state = OpenProgress {
+        segments_replayed: 0,
+        segments_total: lens.len() as u64,
+        bytes_replayed: 0,
+        bytes_total: lens.iter().map(|&(_, _, len)| len).sum(),
+    };
+    progress(&state);
+
+    let reporter = Mutex::new((state, progress));
+    let replay_one = |&(gen, start, len): &(u64, u64, u64)| -> Result<Replay> {
+        let torn_tail = torn_tail && Some(gen) == newest;
+        let replay = Replay::read(vfs, path, gen, start, torn_tail, keyring)?;
+        let mut reporter = reporter.lock().unwrap();
+        let (state, progress) = &mut *reporter;
+        state.segments_replayed += 1;
+        state.bytes_replayed += len;
+        progress(state);
+        Ok(replay)
+    };
+
+    #[cfg(feature = "threads")]
+    {
+        use rayon::prelude::*;
+
+        lens.par_iter().map(replay_one).collect()
+    }
+    #[cfg(not(feature = "threads"))]
+    {
+        lens.iter().map(replay_one).collect()
+    }
+}
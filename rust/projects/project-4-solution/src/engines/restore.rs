@@ -0,0 +1,128 @@
+//! This module provides the marker of a restore in progress in a `KvStore`
+//! data directory. `KvStore::restore` stages the restored log files in a
+//! subdirectory, then writes the marker before moving them into place, so that
+//! a restore interrupted by a crash is finished when the store is opened.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::checkpoint;
+use super::hint;
+use super::history::HistoryState;
+use super::manifest::manifest_path;
+use super::segment::{log_path, sorted_gen_list};
+use crate::vfs::Vfs;
+use crate::Result;
+
+const MARKER_FILE: &str = "restore.json";
+const STAGING_DIR: &str = "restore";
+
+/// The generations given to the restored log files. Every log file of an
+/// older generation belongs to the replaced data set.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) struct RestoreMarker {
+    pub(crate) first_gen: u64,
+    pub(crate) last_gen: u64,
+}
+
+/// Returns the directory in which the restored log files are staged.
+pub(crate) fn staging_dir(dir: &Path) -> PathBuf {
+    dir.join(STAGING_DIR)
+}
+
+fn marker_path(dir: &Path) -> PathBuf {
+    dir.join(MARKER_FILE)
+}
+
+impl RestoreMarker {
+    /// Reads the marker of `dir`, if a restore is in progress.
+    pub(crate) fn read(vfs: &dyn Vfs, dir: &Path) -> Result<Option<RestoreMarker>> {
+        let mut bytes = Vec::new();
+        match vfs.open(&marker_path(dir)) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(serde_json::from_slice(&bytes)?))
+    }
+
+    /// Writes the marker to `dir`, through a temporary file renamed once
+    /// synced.
+    pub(crate) fn write(&self, vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        let path = marker_path(dir);
+        let tmp_path = path.with_extension("json.tmp");
+        let _ = vfs.remove_file(&tmp_path);
+        let mut file = vfs.open_append(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_data()?;
+        vfs.rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Removes the marker of `dir` once the restore is complete.
+    pub(crate) fn remove(vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        vfs.remove_file(&marker_path(dir))?;
+        Ok(())
+    }
+}
+
+/// Finishes the restore interrupted in `dir`, if any, and deletes the log
+/// files staged by a restore interrupted before they were all copied.
+pub(crate) fn finish_restore(vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+    if let Some(marker) = RestoreMarker::read(vfs, dir)? {
+        install_restored(vfs, dir, &marker)?;
+        remove_replaced(vfs, dir, marker.first_gen)?;
+        info!("Finished an interrupted restore");
+    }
+    clear_staging(vfs, dir)
+}
+
+/// Moves the staged log files of `marker` to the data directory. Those
+/// already moved are skipped.
+pub(crate) fn install_restored(vfs: &dyn Vfs, dir: &Path, marker: &RestoreMarker) -> Result<()> {
+    let staging = staging_dir(dir);
+    for gen in marker.first_gen..=marker.last_gen {
+        io_fail_point!("kvs::restore::rename");
+        match vfs.rename(&log_path(&staging, gen), &log_path(dir, gen)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the log files older than the restored ones, the manifest that
+/// describes them, the state of their history and the checkpoint, then the
+/// restore marker.
+pub(crate) fn remove_replaced(vfs: &dyn Vfs, dir: &Path, first_gen: u64) -> Result<()> {
+    for gen in sorted_gen_list(vfs, dir)? {
+        if gen < first_gen {
+            vfs.remove_file(&log_path(dir, gen))?;
+            hint::remove(vfs, dir, gen)?;
+        }
+    }
+    match vfs.remove_file(&manifest_path(dir)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    HistoryState::write(vfs, dir, None)?;
+    checkpoint::remove(vfs, dir)?;
+    RestoreMarker::remove(vfs, dir)
+}
+
+/// Deletes the files left in the staging directory of `dir`, if any.
+pub(crate) fn clear_staging(vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+    let files = match vfs.list_files(&staging_dir(dir)) {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for file in files {
+        vfs.remove_file(&file)?;
+    }
+    Ok(())
+}
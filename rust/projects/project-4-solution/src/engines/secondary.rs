@@ -0,0 +1,151 @@
+//! This module provides the secondary indexes of a `KvStore`, declared with
+//! `KvStore::create_index`, and the file recording their definitions in its
+//! data directory. The entries of the indexes are kept in memory only, and
+//! rebuilt from the values when the store is opened.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::vfs::Vfs;
+use crate::Result;
+
+const INDEXES_FILE: &str = "indexes.json";
+
+pub(crate) fn indexes_path(dir: &Path) -> PathBuf {
+    dir.join(INDEXES_FILE)
+}
+
+/// The secondary indexes of a store.
+///
+/// Only the writes add entries. An entry left by a key overwritten or
+/// removed since is stale, so the lookups check the keys against their
+/// current value and prune the stale entries.
+#[derive(Default)]
+pub(crate) struct SecondaryIndexes {
+    /// The JSON pointer of the field of every index, by name.
+    pointers: BTreeMap<String, String>,
+    /// The keys of every field value of every index, by name.
+    entries: HashMap<String, HashMap<String, BTreeSet<String>>>,
+}
+
+impl SecondaryIndexes {
+    /// Reads the definitions of the indexes of `dir`, without entries.
+    pub(crate) fn read(vfs: &dyn Vfs, dir: &Path) -> Result<SecondaryIndexes> {
+        let mut bytes = Vec::new();
+        match vfs.open(&indexes_path(dir)) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(SecondaryIndexes {
+            pointers: serde_json::from_slice(&bytes)?,
+            entries: HashMap::new(),
+        })
+    }
+
+    /// Replaces the definitions of the indexes of `dir`, through a temporary
+    /// file renamed once synced.
+    pub(crate) fn write(&self, vfs: &dyn Vfs, dir: &Path) -> Result<()> {
+        let path = indexes_path(dir);
+        let tmp_path = path.with_extension("json.tmp");
+        let _ = vfs.remove_file(&tmp_path);
+        let mut file = vfs.open_append(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(&self.pointers)?)?;
+        file.sync_data()?;
+        vfs.rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.pointers.is_empty()
+    }
+
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.pointers.contains_key(name)
+    }
+
+    /// Adds the index `name` on the field at `pointer`, without entries.
+    pub(crate) fn create(&mut self, name: &str, pointer: &str) {
+        self.pointers.insert(name.to_owned(), pointer.to_owned());
+    }
+
+    /// Removes the index `name`. Returns `false` if there is none.
+    pub(crate) fn drop_index(&mut self, name: &str) -> bool {
+        self.entries.remove(name);
+        self.pointers.remove(name).is_some()
+    }
+
+    /// Removes the entries of every index, to be added again.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Adds the entries of `key` set to `value`, in every index or only in
+    /// the index `only`.
+    pub(crate) fn insert(&mut self, key: &str, value: &[u8], only: Option<&str>) {
+        let mut json = None;
+        for (name, pointer) in &self.pointers {
+            if only.is_some_and(|only| only != name) {
+                continue;
+            }
+            let json = json.get_or_insert_with(|| serde_json::from_slice::<Value>(value).ok());
+            if let Some(field) = json.as_ref().and_then(|json| field(json, pointer)) {
+                self.entries
+                    .entry(name.clone())
+                    .or_default()
+                    .entry(field)
+                    .or_default()
+                    .insert(key.to_owned());
+            }
+        }
+    }
+
+    /// Returns the keys with an entry for `field` in the index `name`, or
+    /// `None` if there is no such index.
+    pub(crate) fn candidates(&self, name: &str, field: &str) -> Option<Vec<String>> {
+        self.pointers.get(name)?;
+        Some(
+            self.entries
+                .get(name)
+                .and_then(|fields| fields.get(field))
+                .map(|keys| keys.iter().cloned().collect())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Returns whether `value` has `field` in the index `name`.
+    pub(crate) fn matches(&self, name: &str, field: &str, value: &[u8]) -> bool {
+        match (
+            self.pointers.get(name),
+            serde_json::from_slice::<Value>(value),
+        ) {
+            (Some(pointer), Ok(json)) => self::field(&json, pointer).as_deref() == Some(field),
+            _ => false,
+        }
+    }
+
+    /// Removes the stale entry of `key` for `field` in the index `name`.
+    pub(crate) fn prune(&mut self, name: &str, field: &str, key: &str) {
+        if let Some(fields) = self.entries.get_mut(name) {
+            if let Some(keys) = fields.get_mut(field) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    fields.remove(field);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the field at `pointer` of a JSON value as indexed: a string as
+/// it is, a number or a boolean as JSON. Other fields are not indexed.
+fn field(json: &Value, pointer: &str) -> Option<String> {
+    match json.pointer(pointer)? {
+        Value::String(s) => Some(s.clone()),
+        field @ (Value::Number(_) | Value::Bool(_)) => Some(field.to_string()),
+        _ => None,
+    }
+}
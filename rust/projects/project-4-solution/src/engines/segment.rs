@@ -0,0 +1,385 @@
+//! This module provides the log files of a `KvStore` data directory, named
+//! after their generation, and the compressed ones written by compactions with
+//! `KvStoreBuilder::segment_compression`. A compressed log file holds the
+//! bytes of a plain one in blocks compressed independently, so that reading a
+//! record only decompresses the blocks it spans. Positions in a compressed
+//! log file are those of its plain bytes, so the index, the hint files and
+//! the checkpoint do not tell them apart.
+
+use std::ffi::OsStr;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::record::{compress, decompress};
+use super::Compression;
+use crate::vfs::{Vfs, VfsFile};
+use crate::Result;
+
+/// The first bytes of a compressed log file. A plain log file starts with a
+/// record, which never starts with `0xff`.
+const MAGIC: [u8; 4] = *b"\xffKVZ";
+/// Every block is preceded by the length of its plain bytes, the length of
+/// its compressed bytes and their CRC32C, as little-endian `u32`s.
+const BLOCK_HEADER_LEN: usize = 12;
+/// The length of the plain bytes of every block but the last one.
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+pub(crate) type LogFile = Box<dyn VfsFile>;
+
+/// Returns the generation of the log file at `path`, `None` if it is not one.
+pub(crate) fn log_gen(path: &Path) -> Option<u64> {
+    if path.extension() != Some("log".as_ref()) {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// A wrapper of BufReader of the log file
+pub(crate) struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pub(crate) pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    pub(crate) fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::Current(0))?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> BufRead for BufReaderWithPos<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.pos += amt as u64;
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// A wrapper of BufWriter of the log file
+pub(crate) struct BufWriterWithPos<W: Write + Seek> {
+    pub(crate) writer: BufWriter<W>,
+    pub(crate) pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    pub(crate) fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.seek(SeekFrom::Current(0))?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Log files are named after a generation number with a "log" extension name.
+///
+/// Returns sorted generation numbers in the given directory
+pub(crate) fn sorted_gen_list(vfs: &dyn Vfs, path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = vfs
+        .list_files(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+pub(crate) fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Create a new log file with given generation number.
+///
+/// Returns the writer to the log.
+pub(crate) fn new_log_file(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+) -> Result<BufWriterWithPos<LogFile>> {
+    let path = log_path(&path, gen);
+    let writer = BufWriterWithPos::new(vfs.open_append(&path)?)?;
+    Ok(writer)
+}
+
+/// A block of a compressed log file.
+struct Block {
+    /// The position of its plain bytes in the plain log file.
+    plain_start: u64,
+    plain_len: u32,
+    /// The position of its compressed bytes in the compressed log file.
+    stored_start: u64,
+    stored_len: u32,
+    checksum: u32,
+}
+
+/// A compressed log file opened for reading, which reads and seeks through
+/// its plain bytes.
+struct CompressedLog {
+    file: Box<dyn VfsFile>,
+    blocks: Vec<Block>,
+    /// The length of the plain bytes.
+    len: u64,
+    pos: u64,
+    /// The index and the plain bytes of the last block read.
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl CompressedLog {
+    fn read_block(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let block = &self.blocks[index];
+        let mut stored = vec![0; block.stored_len as usize];
+        self.file.seek(SeekFrom::Start(block.stored_start))?;
+        self.file.read_exact(&mut stored)?;
+        decode_block(block, &stored).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupted block at offset {}", block.stored_start),
+            )
+        })
+    }
+}
+
+impl Read for CompressedLog {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let pos = self.pos;
+        let index = self
+            .blocks
+            .partition_point(|block| block.plain_start + u64::from(block.plain_len) <= pos);
+        if !matches!(self.cached, Some((cached, _)) if cached == index) {
+            let plain = self.read_block(index)?;
+            self.cached = Some((index, plain));
+        }
+        let plain = &self.cached.as_ref().unwrap().1;
+        let offset = (pos - self.blocks[index].plain_start) as usize;
+        let len = buf.len().min(plain.len() - offset);
+        buf[..len].copy_from_slice(&plain[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for CompressedLog {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = new_pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+}
+
+impl Write for CompressedLog {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("compressed log files are read-only"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl VfsFile for CompressedLog {
+    fn sync_data(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Opens the log file at `path` for reading, decompressing it if it is
+/// compressed.
+pub(crate) fn open(vfs: &dyn Vfs, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+    let mut file = vfs.open(path)?;
+    let mut magic = [0; MAGIC.len()];
+    if read_up_to(&mut file, &mut magic)? < MAGIC.len() || magic != MAGIC {
+        file.seek(SeekFrom::Start(0))?;
+        return Ok(file);
+    }
+
+    let file_len = vfs.file_len(path)?;
+    let mut blocks = Vec::new();
+    let mut stored_start = MAGIC.len() as u64;
+    let mut plain_start = 0;
+    let mut header = [0; BLOCK_HEADER_LEN];
+    while stored_start < file_len {
+        file.read_exact(&mut header)?;
+        let block = parse_header(&header, plain_start, stored_start + BLOCK_HEADER_LEN as u64);
+        stored_start = block.stored_start + u64::from(block.stored_len);
+        if stored_start > file_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        plain_start += u64::from(block.plain_len);
+        file.seek(SeekFrom::Start(stored_start))?;
+        blocks.push(block);
+    }
+    Ok(Box::new(CompressedLog {
+        file,
+        blocks,
+        len: plain_start,
+        pos: 0,
+        cached: None,
+    }))
+}
+
+/// Returns the plain bytes of the log file read whole into `bytes`, as is if
+/// it is not compressed, with the number of damaged blocks skipped and their
+/// length.
+#[cfg(feature = "fs")]
+pub(crate) fn decompress_lossy(bytes: Vec<u8>) -> (Vec<u8>, u64, u64) {
+    if !is_compressed(&bytes) {
+        return (bytes, 0, 0);
+    }
+    let mut plain = Vec::with_capacity(bytes.len());
+    let (mut blocks_dropped, mut bytes_dropped) = (0, 0);
+    let mut pos = MAGIC.len();
+    while pos < bytes.len() {
+        let stored_start = pos + BLOCK_HEADER_LEN;
+        let block = match bytes.get(pos..stored_start) {
+            Some(header) => parse_header(header, 0, stored_start as u64),
+            None => {
+                blocks_dropped += 1;
+                bytes_dropped += (bytes.len() - pos) as u64;
+                break;
+            }
+        };
+        let stored_end = stored_start + block.stored_len as usize;
+        match bytes
+            .get(stored_start..stored_end)
+            .and_then(|stored| decode_block(&block, stored))
+        {
+            Some(block_plain) => plain.extend(block_plain),
+            None => {
+                // The lengths of a damaged block cannot be trusted.
+                blocks_dropped += 1;
+                bytes_dropped += (bytes.len() - pos) as u64;
+                break;
+            }
+        }
+        pos = stored_end;
+    }
+    (plain, blocks_dropped, bytes_dropped)
+}
+
+/// Returns whether a log file starting with `bytes` is compressed.
+#[cfg(feature = "fs")]
+pub(crate) fn is_compressed(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+/// Rewrites the plain log file at `path` as a compressed one, with `codec`.
+/// Returns its new length.
+pub(crate) fn compress_file(vfs: &dyn Vfs, path: &Path, codec: Compression) -> Result<u64> {
+    let mut plain = vfs.open(path)?;
+    let tmp_path = path.with_extension("log.tmp");
+    let _ = vfs.remove_file(&tmp_path);
+    let mut file = BufWriter::new(vfs.open_append(&tmp_path)?);
+    file.write_all(&MAGIC)?;
+    let mut len = MAGIC.len() as u64;
+    let mut block = Vec::new();
+    loop {
+        block.clear();
+        (&mut plain).take(BLOCK_SIZE).read_to_end(&mut block)?;
+        if block.is_empty() {
+            break;
+        }
+        let stored = compress(codec, &block)?;
+        file.write_all(&(block.len() as u32).to_le_bytes())?;
+        file.write_all(&(stored.len() as u32).to_le_bytes())?;
+        file.write_all(&crc32c::crc32c(&stored).to_le_bytes())?;
+        file.write_all(&stored)?;
+        len += (BLOCK_HEADER_LEN + stored.len()) as u64;
+    }
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    file.sync_data()?;
+    vfs.rename(&tmp_path, path)?;
+    Ok(len)
+}
+
+fn parse_header(header: &[u8], plain_start: u64, stored_start: u64) -> Block {
+    let field =
+        |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+    Block {
+        plain_start,
+        plain_len: field(0),
+        stored_start,
+        stored_len: field(4),
+        checksum: field(8),
+    }
+}
+
+/// Returns the plain bytes of `block` from its compressed bytes, or `None` if
+/// they are corrupted.
+fn decode_block(block: &Block, stored: &[u8]) -> Option<Vec<u8>> {
+    if crc32c::crc32c(stored) != block.checksum {
+        return None;
+    }
+    decompress(stored).filter(|plain| plain.len() == block.plain_len as usize)
+}
+
+/// Reads into `buf` until it is full or the end of `reader`, and returns the
+/// number of bytes read.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
@@ -0,0 +1,295 @@
+use std::fmt::Debug;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::batch::BatchOp;
+use super::{KvsEngine, WriteBatch};
+use crate::{ErrorKind, LatencyStats, OpLatency, Result};
+
+/// The most operations waiting to be mirrored. Operations beyond are dropped
+/// rather than slowing down the primary engine.
+const QUEUE_LEN: usize = 10_000;
+
+/// The result of an operation as compared between the engines: the value
+/// formatted with `Debug`, or the kind of the error.
+type Outcome = std::result::Result<String, ErrorKind>;
+
+/// An operation to run on the shadow engine, with the outcome it had on the
+/// primary engine.
+struct Mirrored<S> {
+    op: &'static str,
+    key: String,
+    primary: Outcome,
+    run: Box<dyn FnOnce(&S) -> Outcome + Send>,
+}
+
+#[derive(Default)]
+struct Counters {
+    mirrored: AtomicU64,
+    mismatched: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// What a `ShadowEngine` observed so far.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowReport {
+    /// The number of operations run on the shadow engine.
+    pub mirrored: u64,
+    /// The number of mirrored operations whose result differed between the
+    /// engines.
+    pub mismatched: u64,
+    /// The number of operations not mirrored because the shadow engine was
+    /// too far behind.
+    pub dropped: u64,
+    /// The latency percentiles of the operations on the primary engine.
+    pub primary: Vec<OpLatency>,
+    /// The latency percentiles of the operations on the shadow engine.
+    pub shadow: Vec<OpLatency>,
+}
+
+/// A `KvsEngine` serving every operation from a primary engine and mirroring
+/// it to a shadow engine, e.g. to validate a migration from `KvStore` to
+/// `SledKvsEngine` against production traffic before switching.
+///
+/// The operations are mirrored asynchronously, in the order they completed on
+/// the primary engine, by a background thread which owns the shadow engine.
+/// The results of both engines are compared: values must be equal and errors
+/// of the same kind, otherwise the divergence is counted and logged. The
+/// latencies of both engines are recorded to compare them in a
+/// `ShadowReport`.
+///
+/// Concurrent writes to the same key may reach the shadow engine in another
+/// order than they were applied to the primary engine, and be reported as
+/// divergences. `flush`, `restore` and `compact` are not mirrored.
+///
+/// Cloning gives another handle to the same engines.
+#[derive(Clone)]
+pub struct ShadowEngine<P: KvsEngine, S: KvsEngine> {
+    primary: P,
+    tx: SyncSender<Mirrored<S>>,
+    counters: Arc<Counters>,
+    primary_stats: LatencyStats,
+    shadow_stats: LatencyStats,
+}
+
+impl<P: KvsEngine, S: KvsEngine> ShadowEngine<P, S> {
+    /// Serves from `primary` and mirrors to `shadow` in a background thread,
+    /// which stops once every handle to the engine is dropped.
+    pub fn new(primary: P, shadow: S) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Mirrored<S>>(QUEUE_LEN);
+        let counters = Arc::new(Counters::default());
+        let shadow_stats = LatencyStats::new();
+        {
+            let counters = Arc::clone(&counters);
+            let shadow_stats = shadow_stats.clone();
+            thread::spawn(move || {
+                for job in rx {
+                    let Mirrored {
+                        op,
+                        key,
+                        primary,
+                        run,
+                    } = job;
+                    let outcome = shadow_stats.time(op, || run(&shadow));
+                    counters.mirrored.fetch_add(1, Ordering::SeqCst);
+                    if outcome != primary {
+                        counters.mismatched.fetch_add(1, Ordering::SeqCst);
+                        warn!(
+                            op,
+                            key = key.as_str(),
+                            primary = ?primary,
+                            shadow = ?outcome,
+                            "Shadow engine diverged"
+                        );
+                    }
+                }
+            });
+        }
+        ShadowEngine {
+            primary,
+            tx,
+            counters,
+            primary_stats: LatencyStats::new(),
+            shadow_stats,
+        }
+    }
+
+    /// Returns the divergences and latencies observed so far.
+    pub fn report(&self) -> ShadowReport {
+        ShadowReport {
+            mirrored: self.counters.mirrored.load(Ordering::SeqCst),
+            mismatched: self.counters.mismatched.load(Ordering::SeqCst),
+            dropped: self.counters.dropped.load(Ordering::SeqCst),
+            primary: self.primary_stats.summaries(),
+            shadow: self.shadow_stats.summaries(),
+        }
+    }
+
+    /// Runs `primary` on the primary engine and queues `shadow` to be run on
+    /// the shadow engine.
+    fn mirror<T, F, G>(&self, op: &'static str, key: &str, primary: F, shadow: G) -> Result<T>
+    where
+        T: Debug,
+        F: FnOnce(&P) -> Result<T>,
+        G: FnOnce(&S) -> Result<T> + Send + 'static,
+    {
+        let res = self.primary_stats.time(op, || primary(&self.primary));
+        let job = Mirrored {
+            op,
+            key: key.to_owned(),
+            primary: outcome(&res),
+            run: Box::new(move |engine: &S| outcome(&shadow(engine))),
+        };
+        if self.tx.try_send(job).is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+        res
+    }
+}
+
+fn outcome<T: Debug>(res: &Result<T>) -> Outcome {
+    match res {
+        Ok(value) => Ok(format!("{:?}", value)),
+        Err(e) => Err(e.kind()),
+    }
+}
+
+impl<P: KvsEngine, S: KvsEngine> KvsEngine for ShadowEngine<P, S> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let (k, v) = (key.clone(), value.clone());
+        self.mirror(
+            "set",
+            &key,
+            |engine| engine.set(key.clone(), value),
+            move |engine| engine.set(k, v),
+        )
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let k = key.clone();
+        self.mirror(
+            "get",
+            &key,
+            |engine| engine.get(key.clone()),
+            move |engine| engine.get(k),
+        )
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let (k, v) = (key.clone(), value.clone());
+        self.mirror(
+            "set_bytes",
+            &key,
+            |engine| engine.set_bytes(key.clone(), value),
+            move |engine| engine.set_bytes(k, v),
+        )
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let k = key.clone();
+        self.mirror(
+            "get_bytes",
+            &key,
+            |engine| engine.get_bytes(key.clone()),
+            move |engine| engine.get_bytes(k),
+        )
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let k = key.clone();
+        self.mirror(
+            "remove",
+            &key,
+            |engine| engine.remove(key.clone()),
+            move |engine| engine.remove(k),
+        )
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        // Mirrored as a whole, and logged under its first key.
+        let key = batch.ops.first().map_or("", BatchOp::key).to_owned();
+        let b = batch.clone();
+        self.mirror(
+            "write_batch",
+            &key,
+            |engine| engine.write_batch(batch),
+            move |engine| engine.write_batch(b),
+        )
+    }
+
+    fn remove_if_exists(&self, key: String) -> Result<bool> {
+        let k = key.clone();
+        self.mirror(
+            "remove_if_exists",
+            &key,
+            |engine| engine.remove_if_exists(key.clone()),
+            move |engine| engine.remove_if_exists(k),
+        )
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let (k, s) = (key.clone(), suffix.clone());
+        self.mirror(
+            "append",
+            &key,
+            |engine| engine.append(key.clone(), suffix),
+            move |engine| engine.append(k, s),
+        )
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        let (k, v) = (key.clone(), value.clone());
+        self.mirror(
+            "set_nx",
+            &key,
+            |engine| engine.set_nx(key.clone(), value),
+            move |engine| engine.set_nx(k, v),
+        )
+    }
+
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        let (k, v) = (key.clone(), value.clone());
+        self.mirror(
+            "set_xx",
+            &key,
+            |engine| engine.set_xx(key.clone(), value),
+            move |engine| engine.set_xx(k, v),
+        )
+    }
+
+    fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        let k = key.clone();
+        self.mirror(
+            "expire",
+            &key,
+            |engine| engine.expire(key.clone(), ttl),
+            move |engine| engine.expire(k, ttl),
+        )
+    }
+
+    fn persist(&self, key: String) -> Result<bool> {
+        let k = key.clone();
+        self.mirror(
+            "persist",
+            &key,
+            |engine| engine.persist(key.clone()),
+            move |engine| engine.persist(k),
+        )
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.primary.flush()
+    }
+
+    fn restore(&self, from: &Path) -> Result<()> {
+        self.primary.restore(from)
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.primary.compact()
+    }
+}
@@ -0,0 +1,238 @@
+//! This module provides `ShardedKvStore`, which spreads the keys over several
+//! `KvStore`s so that writes to different shards do not wait for each other.
+
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::batch::BatchOp;
+use super::marker::{check_engine, SHARDED_ENGINE};
+use super::{KvStore, KvsEngine, WriteBatch};
+use crate::clock::Clock;
+#[cfg(feature = "fs")]
+use crate::clock::SystemClock;
+#[cfg(feature = "fs")]
+use crate::vfs::OsFs;
+use crate::vfs::Vfs;
+use crate::{KvsError, Result};
+
+const SHARDS_FILE: &str = "SHARDS";
+const SHARD_PREFIX: &str = "shard-";
+
+/// A `KvsEngine` hashing every key to one of several `KvStore`s, its shards,
+/// each in a directory of its own in the data directory, e.g. `shard-0`.
+///
+/// Every shard has its own writer, log files and compactions, so concurrent
+/// writes to keys of different shards run in parallel, where a single
+/// `KvStore` serializes them. The number of shards is recorded in the data
+/// directory when it is created, and cannot change.
+///
+/// The writes of a `write_batch` are atomic within each shard only.
+/// Transactions must only read and write keys of a single shard. Restores
+/// are not supported.
+///
+/// Cloning gives another handle to the same shards.
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Arc<[KvStore]>,
+}
+
+impl ShardedKvStore {
+    /// Opens the store in `path` with `shards` shards, creating it if needed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if `shards` is 0 or the store was
+    /// created with another number of shards, and propagates the errors of
+    /// `KvStore::open` for every shard.
+    #[cfg(feature = "fs")]
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<Self> {
+        Self::open_with_vfs(path, shards, Arc::new(OsFs), Arc::new(SystemClock))
+    }
+
+    /// Opens the store like `ShardedKvStore::open`, on the given file system
+    /// and with the given clock, like `KvStore::open_with_vfs`.
+    ///
+    /// # Errors
+    ///
+    /// It returns the errors of `ShardedKvStore::open`.
+    pub fn open_with_vfs(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        vfs: Arc<dyn Vfs>,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let path = path.into();
+        if shards == 0 {
+            return Err(KvsError::StringError(
+                "a sharded store needs at least one shard".to_owned(),
+            ));
+        }
+        vfs.create_dir_all(&path)?;
+        check_engine(&*vfs, &path, SHARDED_ENGINE)?;
+        check_shard_count(&*vfs, &path, shards)?;
+        let shards = (0..shards)
+            .map(|shard| {
+                let dir = path.join(format!("{}{}", SHARD_PREFIX, shard));
+                KvStore::open_with_vfs(dir, Arc::clone(&vfs), Arc::clone(&clock))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedKvStore {
+            shards: shards.into(),
+        })
+    }
+
+    /// Returns the shards, e.g. to read their statistics or compact them.
+    pub fn shards(&self) -> &[KvStore] {
+        &self.shards
+    }
+
+    /// Returns the index of the shard of `key`, which never changes.
+    pub fn shard_of(&self, key: &str) -> usize {
+        crc32fast::hash(key.as_bytes()) as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[self.shard_of(key)]
+    }
+
+    /// Returns the shard of every key of `keys`, or an error if they are not
+    /// all in the same one. An empty set of keys is in the first shard.
+    fn single_shard<'a>(&self, mut keys: impl Iterator<Item = &'a str>) -> Result<&KvStore> {
+        let shard = keys.next().map_or(0, |key| self.shard_of(key));
+        if keys.any(|key| self.shard_of(key) != shard) {
+            return Err(KvsError::StringError(
+                "transactions across shards are not supported".to_owned(),
+            ));
+        }
+        Ok(&self.shards[shard])
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.shard(&key).set_bytes(key, value)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.shard(&key).get_bytes(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)?;
+        Ok(())
+    }
+
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        self.shard(&key).append(key, suffix)?;
+        Ok(())
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        KvsEngine::set_nx(self.shard(&key), key, value)
+    }
+
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        KvsEngine::set_xx(self.shard(&key), key, value)
+    }
+
+    /// Splits `batch` by shard, and applies the writes of every shard
+    /// atomically, one shard after the other. A failure may leave the writes
+    /// of only some of the shards applied.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut batches = vec![WriteBatch::new(); self.shards.len()];
+        for op in batch.ops {
+            let key = match &op {
+                BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+            };
+            batches[self.shard_of(key)].ops.push(op);
+        }
+        for (shard, batch) in self.shards.iter().zip(batches) {
+            if !batch.is_empty() {
+                shard.write_batch(batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the value of `key` with its version in its shard. The versions
+    /// of keys of different shards are not comparable.
+    fn get_with_version(&self, key: String) -> Result<Option<(String, u64)>> {
+        self.shard(&key).get_with_version(key)
+    }
+
+    /// Applies `batch` like `KvStore` does, if `reads` and `batch` only have
+    /// keys of a single shard. Fails with `KvsError::StringError` otherwise.
+    fn write_batch_if_unchanged(
+        &self,
+        reads: Vec<(String, Option<u64>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let keys = reads
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .chain(batch.ops.iter().map(|op| match op {
+                BatchOp::Set { key, .. } | BatchOp::Remove { key } => key.as_str(),
+            }));
+        self.single_shard(keys)?
+            .write_batch_if_unchanged(reads, batch)
+    }
+
+    fn expire(&self, key: String, ttl: Duration) -> Result<bool> {
+        self.shard(&key).expire(key, ttl)
+    }
+
+    fn persist(&self, key: String) -> Result<bool> {
+        self.shard(&key).persist(key)
+    }
+
+    /// Flushes every shard.
+    fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::flush)
+    }
+
+    /// Compacts every shard, one after the other.
+    fn compact(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvsEngine::compact)
+    }
+}
+
+/// Checks that the store in `dir` has `shards` shards, and records it if it
+/// has no `SHARDS` file yet.
+fn check_shard_count(vfs: &dyn Vfs, dir: &Path, shards: usize) -> Result<()> {
+    let path = dir.join(SHARDS_FILE);
+    let mut recorded = String::new();
+    match vfs.open(&path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut recorded)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let tmp_path = path.with_extension("tmp");
+            let _ = vfs.remove_file(&tmp_path);
+            let mut file = vfs.open_append(&tmp_path)?;
+            writeln!(file, "{}", shards)?;
+            file.sync_data()?;
+            vfs.rename(&tmp_path, &path)?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    if recorded.trim().parse() != Ok(shards) {
+        return Err(KvsError::StringError(format!(
+            "the store has {} shards, not {}",
+            recorded.trim(),
+            shards
+        )));
+    }
+    Ok(())
+}
@@ -1,6 +1,16 @@
-use sled::{Db, Tree};
+#[cfg(feature = "fs")]
+use std::fs;
+#[cfg(feature = "fs")]
+use std::path::Path;
 
-use super::KvsEngine;
+use sled::{Batch, Db, Tree};
+
+use super::batch::BatchOp;
+#[cfg(feature = "fs")]
+use super::marker::{check_engine, SLED_ENGINE};
+use super::{KvsEngine, WriteBatch};
+#[cfg(feature = "fs")]
+use crate::vfs::OsFs;
 use crate::{KvsError, Result};
 
 /// Wrapper of `sled::Db`.
@@ -12,14 +22,32 @@ impl SledKvsEngine {
     pub fn new(db: Db) -> Self {
         Self(db)
     }
+
+    /// Opens the sled database in the directory `path`, which is created if
+    /// it does not exist, and marks the directory as sled's in its `ENGINE`
+    /// file.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::WrongEngine` if the directory belongs to
+    /// `KvStore`, and propagates I/O and sled errors.
+    #[cfg(feature = "fs")]
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        check_engine(&OsFs, path, SLED_ENGINE)?;
+        Ok(Self(sled::open(path)?))
+    }
 }
 
 impl KvsEngine for SledKvsEngine {
+    #[instrument(level = "debug", skip(self, value))]
     fn set(&self, key: String, value: String) -> Result<()> {
         let tree: &Tree = &self.0;
         Ok(tree.insert(key, value.into_bytes()).map(|_| ())?)
     }
 
+    #[instrument(level = "debug", skip(self))]
     fn get(&self, key: String) -> Result<Option<String>> {
         let tree: &Tree = &self.0;
 
@@ -30,6 +58,19 @@ impl KvsEngine for SledKvsEngine {
             .transpose()?)
     }
 
+    #[instrument(level = "debug", skip(self, value))]
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let tree: &Tree = &self.0;
+        Ok(tree.insert(key, value).map(|_| ())?)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let tree: &Tree = &self.0;
+        Ok(tree.get(key)?.map(|i_vec| i_vec.to_vec()))
+    }
+
+    #[instrument(level = "debug", skip(self))]
     fn remove(&self, key: String) -> Result<()> {
         let tree: &Tree = &self.0;
         tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
@@ -37,4 +78,57 @@ impl KvsEngine for SledKvsEngine {
 
         Ok(())
     }
+
+    #[instrument(level = "debug", skip(self, batch), fields(len = batch.len()))]
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let tree: &Tree = &self.0;
+        let mut sled_batch = Batch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => sled_batch.insert(key.into_bytes(), value),
+                BatchOp::Remove { key } => sled_batch.remove(key.into_bytes()),
+            }
+        }
+        Ok(tree.apply_batch(sled_batch)?)
+    }
+
+    #[instrument(level = "debug", skip(self, suffix))]
+    fn append(&self, key: String, suffix: String) -> Result<()> {
+        let tree: &Tree = &self.0;
+        tree.fetch_and_update(key, |old| {
+            let mut value = old.map(<[u8]>::to_vec).unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            Some(value)
+        })?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self, value))]
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        let res = tree.compare_and_swap(key, None as Option<&[u8]>, Some(value.into_bytes()))?;
+        Ok(res.is_ok())
+    }
+
+    #[instrument(level = "debug", skip(self, value))]
+    fn set_xx(&self, key: String, value: String) -> Result<bool> {
+        let tree: &Tree = &self.0;
+        // Retry until the value we replace is still the one we read.
+        loop {
+            let old = match tree.get(&key)? {
+                Some(old) => old,
+                None => return Ok(false),
+            };
+            let res = tree.compare_and_swap(&key, Some(old), Some(value.as_bytes()))?;
+            if res.is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
 }
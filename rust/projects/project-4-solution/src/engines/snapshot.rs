@@ -0,0 +1,78 @@
+//! This module provides the retention of the snapshots of a `KvStore`, which
+//! are backups to a `DirTarget`.
+
+#[cfg(feature = "fs")]
+use std::cmp::Reverse;
+#[cfg(feature = "fs")]
+use std::fs;
+#[cfg(feature = "fs")]
+use std::io;
+#[cfg(feature = "fs")]
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[cfg(feature = "fs")]
+use crate::Result;
+
+/// Snapshot directories are named after this prefix and the time they were
+/// taken at, in milliseconds since the Unix epoch.
+pub(crate) const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+/// Which snapshots of `KvStore::snapshot_every` are kept. The older ones are
+/// deleted after every new snapshot, but the newest one is always kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SnapshotRetention {
+    /// Keeps at most this many snapshots. `None` keeps any number of them.
+    pub max_count: Option<usize>,
+    /// Deletes the snapshots taken longer ago than this. `None` keeps them
+    /// regardless of their age.
+    pub max_age: Option<Duration>,
+}
+
+/// Where and how `KvStore::snapshot_every` takes snapshots.
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone)]
+pub(crate) struct SnapshotSchedule {
+    pub(crate) dest: PathBuf,
+    pub(crate) retention: SnapshotRetention,
+}
+
+/// Deletes the snapshots of `dest` that `retention` does not keep.
+///
+/// Returns the number of deleted snapshots.
+#[cfg(feature = "fs")]
+pub(crate) fn prune_snapshots(
+    dest: &Path,
+    retention: SnapshotRetention,
+    now: u64,
+) -> Result<usize> {
+    let mut snapshots: Vec<(u64, PathBuf)> = fs::read_dir(dest)?
+        .flat_map(|res| -> io::Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_dir())
+        .filter_map(|path| {
+            let taken_at = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(SNAPSHOT_PREFIX)?
+                .parse()
+                .ok()?;
+            Some((taken_at, path))
+        })
+        .collect();
+    snapshots.sort_unstable_by_key(|&(taken_at, _)| Reverse(taken_at));
+
+    let mut pruned = 0;
+    // The newest snapshot is always kept.
+    for (i, (taken_at, path)) in snapshots.iter().enumerate().skip(1) {
+        let too_many = matches!(retention.max_count, Some(max_count) if i >= max_count);
+        let too_old = matches!(
+            retention.max_age,
+            Some(max_age) if now.saturating_sub(*taken_at) > max_age.as_millis() as u64
+        );
+        if too_many || too_old {
+            fs::remove_dir_all(path)?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
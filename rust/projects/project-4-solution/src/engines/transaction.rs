@@ -0,0 +1,103 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+
+use super::{KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+
+/// A transaction over an engine, started with `KvsEngine::begin`.
+///
+/// Its writes are buffered until `Transaction::commit`, which applies them
+/// atomically if none of the keys it read has changed since, and fails with
+/// `KvsError::TransactionConflict` otherwise. Nothing is locked meanwhile:
+/// transactions that conflict are expected to be rare and retried as a whole.
+///
+/// # Example
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine};
+/// # fn main() -> kvs::Result<()> {
+/// let store = KvStore::open("data")?;
+/// let mut txn = store.begin();
+/// let log = txn.get("log".to_owned())?.unwrap_or_default();
+/// txn.set("log".to_owned(), log + "entry;");
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    /// The version of every key read, `None` if it did not exist.
+    reads: BTreeMap<String, Option<u64>>,
+    /// The value of every key written, `None` if it is removed.
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Self {
+        Transaction {
+            engine,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of `key`, as written by the transaction if it was.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if the key changed since
+    /// the transaction first read it, and propagates the errors of
+    /// `KvsEngine::get_with_version`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let found = self.engine.get_with_version(key.clone())?;
+        let version = found.as_ref().map(|&(_, version)| version);
+        match self.reads.entry(key) {
+            Entry::Vacant(entry) => {
+                entry.insert(version);
+            }
+            // The commit would fail anyway.
+            Entry::Occupied(entry) if *entry.get() != version => {
+                return Err(KvsError::TransactionConflict)
+            }
+            Entry::Occupied(_) => {}
+        }
+        Ok(found.map(|(value, _)| value))
+    }
+
+    /// Sets `key` to `value` once the transaction is committed.
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes `key` once the transaction is committed. Removing a missing
+    /// key is not an error.
+    pub fn remove(&mut self, key: String) {
+        self.writes.insert(key, None);
+    }
+
+    /// Applies the writes of the transaction atomically, if none of the keys
+    /// it read has changed since.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if a key read has changed,
+    /// and propagates the errors of `KvsEngine::write_batch_if_unchanged`.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.engine
+            .write_batch_if_unchanged(self.reads.into_iter().collect(), batch)
+    }
+
+    /// Discards the writes of the transaction, like dropping it.
+    pub fn rollback(self) {}
+}
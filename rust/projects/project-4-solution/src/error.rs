@@ -1,9 +1,16 @@
 use failure::Fail;
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::io;
 use std::string;
 
 /// Error type. It represents the ways a kvs could be invalid.
+///
+/// New variants may be added in minor releases, so matches on this type
+/// must include a wildcard arm. Prefer matching on `KvsError::kind()`,
+/// which is stable.
 #[derive(Fail, Debug)]
+#[non_exhaustive]
 pub enum KvsError {
     /// An IO error. Wraps a `std::io::Error`.
     #[fail(display = "IO error: {}", _0)]
@@ -11,6 +18,9 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "serde_json error: {}", _0)]
     Serde(#[fail(cause)] serde_json::Error),
+    /// Binary serialization or deserialization error.
+    #[fail(display = "bincode error: {}", _0)]
+    Bincode(#[fail(cause)] bincode::Error),
     /// Removing non-existent key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -21,12 +31,232 @@ pub enum KvsError {
     /// Error with a string message.
     #[fail(display = "{}", _0)]
     StringError(String),
+    /// A log record failed its checksum: the log file of generation `gen` is
+    /// corrupted at `offset`.
+    #[fail(display = "Corrupted log record in {}.log at offset {}", gen, offset)]
+    Corruption {
+        /// The generation of the corrupted log file.
+        gen: u64,
+        /// The offset of the corrupted record in the log file.
+        offset: u64,
+    },
+    /// A log record is encrypted with a key of a version the store was not
+    /// opened with, see `KvStoreBuilder::encryption_key`.
+    #[fail(display = "Encryption key of version {} not available", version)]
+    KeyUnavailable {
+        /// The version of the key the record is encrypted with.
+        version: u32,
+    },
     /// Sled error.
+    #[cfg(feature = "sled")]
     #[fail(display = "sled error: {}", _0)]
     Sled(#[fail(cause)] sled::Error),
     /// Utf8 error.
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[fail(cause)] string::FromUtf8Error),
+    /// The peer does not speak the kvs protocol, or a frame was damaged in
+    /// transit.
+    #[fail(display = "protocol error: {}", _0)]
+    Protocol(String),
+    /// The free space of the disk is below the minimum set with
+    /// `KvStore::set_min_free_space`. Reads still work.
+    #[fail(display = "Disk full")]
+    DiskFull,
+    /// The history of the store does not go back to the point read with
+    /// `KvStore::get_as_of`, or is not retained.
+    #[fail(display = "History not retained that far back")]
+    HistoryUnavailable,
+    /// The version read with `KvStore::get_at_version` is neither the current
+    /// version of the key nor one of the versions kept.
+    #[fail(display = "Version not kept")]
+    VersionUnavailable,
+    /// A compaction dropped some of the changes read with
+    /// `KvStore::changes_since`.
+    #[fail(display = "Changes compacted")]
+    ChangesUnavailable,
+    /// A key read by a `Transaction` changed before it was committed. The
+    /// transaction may be run again.
+    #[fail(display = "Transaction conflict")]
+    TransactionConflict,
+    /// The secondary index read with `KvStore::get_by_index` does not exist.
+    #[fail(display = "Index not found")]
+    IndexNotFound,
+    /// The data directory was created by another engine, named in its
+    /// `ENGINE` file.
+    #[fail(
+        display = "Wrong engine: the data directory belongs to {}, not {}",
+        found, expected
+    )]
+    WrongEngine {
+        /// The engine opening the directory.
+        expected: String,
+        /// The engine which created the directory.
+        found: String,
+    },
+    /// A record read by `KvStore::import` is malformed.
+    #[fail(display = "Invalid import at line {}: {}", line, message)]
+    InvalidImport {
+        /// The line of the input the record starts at, from 1.
+        line: u64,
+        /// What is wrong with the record.
+        message: String,
+    },
+    /// The server is in read-only mode, set with `KvsServer::read_only` or
+    /// `KvsClient::set_read_only`, and rejects writes.
+    #[fail(display = "Server is read-only")]
+    ReadOnly,
+    /// The server is serving as many requests as it allows at once and
+    /// rejected this one. It may be sent again later.
+    #[fail(display = "Server busy")]
+    ServerBusy,
+    /// The request is not allowed where it was sent, e.g. an admin request
+    /// sent to an address of a `KvsServer` other than its admin address.
+    #[fail(display = "{}", _0)]
+    PermissionDenied(String),
+    /// An error reported by a `KvsServer`, of the kind it had there.
+    #[fail(display = "{}", message)]
+    Remote {
+        /// The kind of the error on the server.
+        kind: ErrorKind,
+        /// The message of the error on the server.
+        message: String,
+    },
+}
+
+/// A coarse classification of a `KvsError`.
+///
+/// Callers should decide how to react to an error (retry, report, give up)
+/// from its kind rather than from its message. Existing kinds keep their
+/// meaning across releases; new `KvsError` variants are mapped onto one of
+/// them or onto a newly added kind, so this enum is non-exhaustive as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The given key does not exist.
+    KeyNotFound,
+    /// Data on disk could not be decoded. It indicates a corrupted log or a
+    /// program bug.
+    Corruption,
+    /// Malformed input, e.g. a value that is not valid UTF-8.
+    InvalidData,
+    /// The operation did not complete in time.
+    TimedOut,
+    /// The connection was reset, aborted or refused by the peer.
+    ConnectionReset,
+    /// The resource is busy: the operation was interrupted or would block.
+    Busy,
+    /// There is not enough free disk space to write.
+    DiskFull,
+    /// Writes are rejected until the server leaves read-only mode.
+    ReadOnly,
+    /// The request is not allowed where it was sent.
+    PermissionDenied,
+    /// A concurrent write invalidated the operation, which may be run again
+    /// from the start.
+    Conflict,
+    /// Any other I/O error.
+    Io,
+    /// An error without a more specific classification. A kind unknown to
+    /// this version, reported by a newer server, is read as this one.
+    #[serde(other)]
+    Other,
+}
+
+impl ErrorKind {
+    /// Returns a stable, human readable name of the kind.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::KeyNotFound => "key not found",
+            Self::Corruption => "corruption",
+            Self::InvalidData => "invalid data",
+            Self::TimedOut => "timed out",
+            Self::ConnectionReset => "connection reset",
+            Self::Busy => "busy",
+            Self::DiskFull => "disk full",
+            Self::ReadOnly => "read only",
+            Self::PermissionDenied => "permission denied",
+            Self::Conflict => "conflict",
+            Self::Io => "I/O error",
+            Self::Other => "other error",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl KvsError {
+    /// Returns the kind of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Io(err) => io_error_kind(err),
+            Self::Serde(err) => match err.classify() {
+                serde_json::error::Category::Io | serde_json::error::Category::Eof => ErrorKind::Io,
+                _ => ErrorKind::Corruption,
+            },
+            Self::Bincode(err) => match &**err {
+                bincode::ErrorKind::Io(err) => io_error_kind(err),
+                _ => ErrorKind::Corruption,
+            },
+            Self::KeyNotFound => ErrorKind::KeyNotFound,
+            Self::UnexpectedCommandType => ErrorKind::Corruption,
+            Self::StringError(_) => ErrorKind::Other,
+            Self::Corruption { .. } => ErrorKind::Corruption,
+            Self::KeyUnavailable { .. } => ErrorKind::Other,
+            #[cfg(feature = "sled")]
+            Self::Sled(sled::Error::Io(err)) => io_error_kind(err),
+            #[cfg(feature = "sled")]
+            Self::Sled(sled::Error::Corruption { .. }) => ErrorKind::Corruption,
+            #[cfg(feature = "sled")]
+            Self::Sled(_) => ErrorKind::Other,
+            Self::Utf8(_) => ErrorKind::InvalidData,
+            Self::Protocol(_) => ErrorKind::InvalidData,
+            Self::DiskFull => ErrorKind::DiskFull,
+            Self::HistoryUnavailable => ErrorKind::Other,
+            Self::VersionUnavailable => ErrorKind::Other,
+            Self::ChangesUnavailable => ErrorKind::Other,
+            Self::TransactionConflict => ErrorKind::Conflict,
+            Self::IndexNotFound => ErrorKind::Other,
+            Self::WrongEngine { .. } => ErrorKind::Other,
+            Self::InvalidImport { .. } => ErrorKind::InvalidData,
+            Self::ReadOnly => ErrorKind::ReadOnly,
+            Self::ServerBusy => ErrorKind::Busy,
+            Self::PermissionDenied(_) => ErrorKind::PermissionDenied,
+            Self::Remote { kind, .. } => *kind,
+        }
+    }
+
+    /// Returns `true` if the error is caused by a transient condition (a
+    /// timeout, a reset connection, a busy resource, a conflicting write) so
+    /// that the same operation may succeed when retried.
+    ///
+    /// Permanent errors such as a missing key or a corrupted log return
+    /// `false`.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::TimedOut
+                | ErrorKind::ConnectionReset
+                | ErrorKind::Busy
+                | ErrorKind::Conflict
+        )
+    }
+}
+
+fn io_error_kind(err: &io::Error) -> ErrorKind {
+    match err.kind() {
+        io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::BrokenPipe => ErrorKind::ConnectionReset,
+        io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => ErrorKind::Busy,
+        io::ErrorKind::InvalidData => ErrorKind::Corruption,
+        _ => ErrorKind::Io,
+    }
 }
 
 impl From<io::Error> for KvsError {
@@ -41,6 +271,13 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(error: bincode::Error) -> Self {
+        Self::Bincode(error)
+    }
+}
+
+#[cfg(feature = "sled")]
 impl From<sled::Error> for KvsError {
     fn from(error: sled::Error) -> Self {
         Self::Sled(error)
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use hdrhistogram::Histogram;
+use serde::{Deserialize, Serialize};
+
+/// Highest trackable latency: one minute, in microseconds.
+const MAX_LATENCY_US: u64 = 60 * 1_000_000;
+/// Number of significant decimal digits kept by the histograms.
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+/// Latency percentiles of one operation, in microseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpLatency {
+    /// Name of the operation, e.g. `get`.
+    pub op: String,
+    /// Number of recorded operations.
+    pub count: u64,
+    /// Median latency.
+    pub p50_us: u64,
+    /// 95th percentile latency.
+    pub p95_us: u64,
+    /// 99th percentile latency.
+    pub p99_us: u64,
+    /// Maximum latency.
+    pub max_us: u64,
+}
+
+/// Per-operation latency histograms of a `KvsServer`.
+///
+/// Averages hide the tail latency introduced by compaction, so every engine
+/// call is recorded in an HDR histogram and reported as percentiles.
+/// Cloning gives another handle to the same histograms.
+#[derive(Clone, Default)]
+pub struct LatencyStats {
+    histograms: Arc<Mutex<BTreeMap<&'static str, Histogram<u64>>>>,
+}
+
+impl LatencyStats {
+    /// Creates empty histograms.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `f` and records its duration as a sample of `op`.
+    pub fn time<F, R>(&self, op: &'static str, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let start = Instant::now();
+        let result = f();
+        self.record(op, start.elapsed().as_micros() as u64);
+        result
+    }
+
+    /// Records one sample of `op` taking `latency_us` microseconds.
+    pub fn record(&self, op: &'static str, latency_us: u64) {
+        let mut histograms = self.histograms.lock().unwrap();
+        histograms
+            .entry(op)
+            .or_insert_with(|| {
+                Histogram::new_with_bounds(1, MAX_LATENCY_US, SIGNIFICANT_DIGITS)
+                    .expect("invalid histogram bounds")
+            })
+            .saturating_record(latency_us.max(1));
+    }
+
+    /// Returns the percentiles of every recorded operation, sorted by name.
+    pub fn summaries(&self) -> Vec<OpLatency> {
+        let histograms = self.histograms.lock().unwrap();
+        histograms
+            .iter()
+            .map(|(op, histogram)| OpLatency {
+                op: op.to_string(),
+                count: histogram.len(),
+                p50_us: histogram.value_at_quantile(0.5),
+                p95_us: histogram.value_at_quantile(0.95),
+                p99_us: histogram.value_at_quantile(0.99),
+                max_us: histogram.max(),
+            })
+            .collect()
+    }
+
+    /// Renders the percentiles in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP kvs_request_duration_seconds Latency of engine operations.\n");
+        out.push_str("# TYPE kvs_request_duration_seconds summary\n");
+        for summary in self.summaries() {
+            for &(quantile, value) in &[
+                ("0.5", summary.p50_us),
+                ("0.95", summary.p95_us),
+                ("0.99", summary.p99_us),
+                ("1", summary.max_us),
+            ] {
+                let _ = writeln!(
+                    out,
+                    "kvs_request_duration_seconds{{op=\"{}\",quantile=\"{}\"}} {}",
+                    summary.op,
+                    quantile,
+                    value as f64 / 1e6
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kvs_request_duration_seconds_count{{op=\"{}\"}} {}",
+                summary.op, summary.count
+            );
+        }
+        out
+    }
+}
@@ -1,20 +1,60 @@
 //! # Kvs
 //!
 //! A simple in-memory key/value store
+//!
+//! The OS file system, the network client and server, and the thread pools are
+//! behind the default `fs`, `net` and `threads` features, and `SledKvsEngine`
+//! behind the default `sled` feature. Without them, the
+//! crate builds for `wasm32-unknown-unknown`, where a `KvStore` can run over
+//! `vfs::MemFs` or another `vfs::Vfs` implementation.
 
 #![deny(missing_docs)]
 
 #[macro_use]
-extern crate log;
+extern crate tracing;
+#[cfg(feature = "failpoints")]
+#[macro_use]
+extern crate fail;
 
+pub mod backup;
+#[cfg(feature = "net")]
 mod client;
+pub mod clock;
+#[cfg(feature = "net")]
 mod common;
+#[cfg(feature = "fs")]
+pub mod debug;
 mod engines;
 mod error;
+mod latency;
+mod rng;
+#[cfg(feature = "net")]
 mod server;
+pub mod testing;
+#[cfg(feature = "threads")]
 pub mod thread_pool;
+pub mod vfs;
+pub mod workload;
 
+#[cfg(feature = "net")]
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
-pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use engines::AsOf;
+pub use engines::ExportFormat;
+#[cfg(feature = "test-util")]
+pub use engines::MockKvsEngine;
+pub use engines::ShardedKvStore;
+#[cfg(feature = "sled")]
+pub use engines::SledKvsEngine;
+pub use engines::SnapshotRetention;
+pub use engines::Transaction;
+pub use engines::WriteBatch;
+pub use engines::{
+    Change, Changes, CompactionEvent, CompactionReport, CompactionStats, Compression, Drain,
+    Durability, Entry, IntoIter, Iter, Keys, KvStore, KvStoreBuilder, KvsEngine, OpenProgress,
+    RepairReport, Scan, SegmentArchive, StoreStats, WarmUp,
+};
+pub use engines::{ShadowEngine, ShadowReport};
+pub use error::{ErrorKind, KvsError, Result};
+pub use latency::{LatencyStats, OpLatency};
+#[cfg(feature = "net")]
+pub use server::{ClientInfo, KvsServer, SocketOptions};
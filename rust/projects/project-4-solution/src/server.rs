@@ -1,16 +1,377 @@
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use serde_json::Deserializer;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 
-use crate::common::{GetResponse, RemoveResponse, Request, SetResponse};
+use crate::common::{
+    read_frame, write_frame, AnyResponse, ClientKillResponse, ClientListResponse, CompactResponse,
+    ExpireResponse, GetBytesResponse, GetResponse, RemoveIfExistsResponse, RemoveResponse, Request,
+    Response, RestoreResponse, SetIfResponse, SetReadOnlyResponse, SetResponse, StatsResponse,
+    TaggedRequest, TaggedResponse, TransactionOp, TransactionResponse,
+};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, Result};
+use crate::{KvsEngine, KvsError, LatencyStats, Result, Transaction};
+
+/// The most requests served out of order at once by default, across all the
+/// connections.
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// Responses larger than this are compressed by default.
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 16 * 1024;
+
+/// Requests larger than this are rejected by default.
+const DEFAULT_MAX_REQUEST_SIZE: usize = 64 * 1024 * 1024;
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     thread_pool: P,
+    latency_stats: LatencyStats,
+    compress_above: Option<usize>,
+    max_request_size: Option<usize>,
+    socket_options: SocketOptions,
+    connections: Connections,
+    read_only: Arc<AtomicBool>,
+    in_flight: Arc<InFlight>,
+    admin_addrs: Vec<SocketAddr>,
+    backup_root: Option<Arc<PathBuf>>,
+}
+
+/// TCP options of the listening socket and of the connections of a
+/// `KvsServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sets `TCP_NODELAY` on the connections, so small responses are sent
+    /// at once rather than delayed by Nagle's algorithm. Defaults to `true`.
+    pub nodelay: bool,
+    /// Sets `SO_REUSEADDR` on the listening socket, so a restarted server can
+    /// bind the address of connections still in `TIME_WAIT`. Defaults to
+    /// `true`.
+    pub reuse_address: bool,
+    /// The size of the send buffer of the connections, `None` for the OS
+    /// default.
+    pub send_buffer_size: Option<usize>,
+    /// The size of the receive buffer of the connections, `None` for the OS
+    /// default.
+    pub recv_buffer_size: Option<usize>,
+    /// The most connections waiting to be accepted. Defaults to 128.
+    pub backlog: i32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            reuse_address: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            backlog: 128,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Binds a listening socket to the first of `addr` which can be bound.
+    fn bind<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpListener> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match self.bind_one(addr) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    fn bind_one(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(self.reuse_address)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog)?;
+        Ok(socket.into())
+    }
+
+    /// Applies the options to an accepted connection.
+    fn configure(&self, tcp: &TcpStream) -> io::Result<()> {
+        tcp.set_nodelay(self.nodelay)?;
+        let socket = SockRef::from(tcp);
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+/// A connection served by a `KvsServer`, as listed by
+/// `KvsClient::client_list`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    /// The ID to close the connection with, unique per server.
+    pub id: u64,
+    /// The address of the client.
+    pub peer: String,
+    /// The identity the client authenticated as, if any.
+    pub identity: Option<String>,
+    /// The database selected by the client.
+    pub db: u32,
+    /// The time since the connection was established, in milliseconds.
+    pub age_ms: u64,
+    /// The time since the last request was received, in milliseconds.
+    pub idle_ms: u64,
+    /// The operation of the last request, e.g. `get`.
+    pub last_op: Option<String>,
+    /// The number of requests received.
+    pub requests: u64,
+    /// The number of requests which failed.
+    pub errors: u64,
+    /// The number of bytes read from the client.
+    pub bytes_read: u64,
+    /// The number of bytes written to the client.
+    pub bytes_written: u64,
+}
+
+/// The protocol options of a connection, the server defaults until the client
+/// negotiates others.
+#[derive(Debug, Clone, Copy)]
+struct ProtocolOptions {
+    /// The size in bytes above which responses are compressed.
+    compress_above: Option<usize>,
+    /// The size in bytes above which requests are rejected.
+    max_request_size: Option<usize>,
+}
+
+/// The state of a client connection, kept for as long as it is served.
+#[derive(Debug, Clone)]
+struct Session {
+    id: u64,
+    peer: SocketAddr,
+    /// The identity the client authenticated as, if any.
+    identity: Option<String>,
+    /// The database the requests of the client apply to.
+    db: u32,
+    options: ProtocolOptions,
+    /// Whether the connection was accepted on an admin address, and may send
+    /// admin requests.
+    admin: bool,
+    connected_at: Instant,
+    last_request_at: Instant,
+    last_op: Option<&'static str>,
+    /// The number of requests received.
+    requests: u64,
+    /// The number of requests which failed.
+    errors: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl Session {
+    fn new(id: u64, peer: SocketAddr, options: ProtocolOptions, admin: bool) -> Self {
+        let now = Instant::now();
+        Session {
+            id,
+            peer,
+            identity: None,
+            db: 0,
+            options,
+            admin,
+            connected_at: now,
+            last_request_at: now,
+            last_op: None,
+            requests: 0,
+            errors: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            peer: self.peer.to_string(),
+            identity: self.identity.clone(),
+            db: self.db,
+            age_ms: self.connected_at.elapsed().as_millis() as u64,
+            idle_ms: self.last_request_at.elapsed().as_millis() as u64,
+            last_op: self.last_op.map(str::to_owned),
+            requests: self.requests,
+            errors: self.errors,
+            bytes_read: self.bytes_read,
+            bytes_written: self.bytes_written,
+        }
+    }
+}
+
+/// The connections being served, shared by the workers. Every connection is
+/// kept with a handle to its socket, to close it, and a copy of its session
+/// updated as requests are served.
+#[derive(Clone, Default)]
+struct Connections {
+    inner: Arc<Mutex<ConnectionTable>>,
+}
+
+#[derive(Default)]
+struct ConnectionTable {
+    next_id: u64,
+    connections: HashMap<u64, (TcpStream, Session)>,
+}
+
+impl Connections {
+    /// Registers the connection of `tcp`, accepted on an admin address if
+    /// `admin`, and returns its session, removed from the table when the
+    /// returned guard is dropped.
+    fn register(
+        &self,
+        tcp: &TcpStream,
+        options: ProtocolOptions,
+        admin: bool,
+    ) -> Result<(Session, Registered)> {
+        let peer = tcp.peer_addr()?;
+        let handle = tcp.try_clone()?;
+        let mut table = self.inner.lock().unwrap();
+        table.next_id += 1;
+        let session = Session::new(table.next_id, peer, options, admin);
+        table
+            .connections
+            .insert(session.id, (handle, session.clone()));
+        let registered = Registered {
+            connections: self.clone(),
+            id: session.id,
+        };
+        Ok((session, registered))
+    }
+
+    fn update(&self, session: &Session) {
+        if let Some((_, copy)) = self.inner.lock().unwrap().connections.get_mut(&session.id) {
+            copy.clone_from(session);
+        }
+    }
+
+    /// Returns the connections, the oldest first.
+    fn list(&self) -> Vec<ClientInfo> {
+        let table = self.inner.lock().unwrap();
+        let mut clients: Vec<ClientInfo> = table
+            .connections
+            .values()
+            .map(|(_, session)| session.info())
+            .collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    /// Closes the connection with the given ID. Its worker sees the end of the
+    /// stream and drops it.
+    fn kill(&self, id: u64) -> bool {
+        match self.inner.lock().unwrap().connections.remove(&id) {
+            Some((tcp, _)) => {
+                if let Err(e) = tcp.shutdown(Shutdown::Both) {
+                    warn!("Unable to close connection {}: {}", id, e);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Removes a connection from the table once it is no longer served.
+struct Registered {
+    connections: Connections,
+    id: u64,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.connections
+            .inner
+            .lock()
+            .unwrap()
+            .connections
+            .remove(&self.id);
+    }
+}
+
+/// Counts the requests served out of order, across all the connections of a
+/// server, so that they do not take more than `max` threads.
+struct InFlight {
+    count: AtomicUsize,
+    max: usize,
+}
+
+impl InFlight {
+    fn new(max: usize) -> Self {
+        InFlight {
+            count: AtomicUsize::new(0),
+            max,
+        }
+    }
+
+    /// Counts one more request, or returns `false` if `max` are in flight.
+    fn acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                if count < self.max {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .is_ok()
+    }
+
+    fn release(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts the bytes read from or written to a stream.
+struct Counted<S> {
+    inner: S,
+    bytes: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S) -> Self {
+        Counted { inner, bytes: 0 }
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -19,74 +380,575 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
         Self {
             engine,
             thread_pool,
+            latency_stats: LatencyStats::new(),
+            compress_above: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            max_request_size: Some(DEFAULT_MAX_REQUEST_SIZE),
+            socket_options: SocketOptions::default(),
+            connections: Connections::default(),
+            read_only: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(InFlight::new(DEFAULT_MAX_IN_FLIGHT)),
+            admin_addrs: Vec::new(),
+            backup_root: None,
         }
     }
 
+    /// Sets the size in bytes above which responses are compressed, or `None`
+    /// to never compress them. Defaults to 16 KiB.
+    ///
+    /// `KvsClient` decompresses responses transparently.
+    pub fn compress_responses_above(mut self, threshold: Option<usize>) -> Self {
+        self.compress_above = threshold;
+        self
+    }
+
+    /// Sets the size in bytes above which requests are rejected, or `None` for
+    /// no limit. Defaults to 64 MiB.
+    ///
+    /// The size of a request is checked, from its frame header, before its
+    /// payload is read, and again once decompressed. The connection of a
+    /// request too large is closed with a protocol error.
+    pub fn max_request_size(mut self, limit: Option<usize>) -> Self {
+        self.max_request_size = limit;
+        self
+    }
+
+    /// Sets the most requests served out of order at once, across all the
+    /// connections. Defaults to 64.
+    ///
+    /// Each takes a thread of its own while it is served. Those beyond the
+    /// limit are rejected with `KvsError::ServerBusy`.
+    pub fn max_in_flight(mut self, limit: usize) -> Self {
+        self.in_flight = Arc::new(InFlight::new(limit));
+        self
+    }
+
+    /// Sets the directory the backups restored with `KvsClient::restore` are
+    /// read from. Their paths are relative to it, and may not lead out of
+    /// it. Without a backup root, restores are rejected.
+    pub fn backup_root<R: Into<PathBuf>>(mut self, root: R) -> Self {
+        self.backup_root = Some(Arc::new(root.into()));
+        self
+    }
+
+    /// Sets the TCP options of the listening socket and of the connections.
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// Sets whether the server starts in read-only mode, rejecting the
+    /// requests which would modify the data set with `KvsError::ReadOnly`.
+    /// Clients may toggle it with `KvsClient::set_read_only`.
+    pub fn read_only(self, read_only: bool) -> Self {
+        self.read_only.store(read_only, Ordering::SeqCst);
+        self
+    }
+
+    /// Listens on `addr` as well, and serves the admin requests, e.g.
+    /// `KvsClient::set_read_only`, there only. Repeat it for several admin
+    /// addresses. Bind it to an internal interface: the clients connecting to
+    /// it are not authenticated.
+    ///
+    /// The admin requests sent to the other addresses, or to a server
+    /// without an admin address, fail with `KvsError::PermissionDenied`.
+    pub fn admin_addr(mut self, addr: SocketAddr) -> Self {
+        self.admin_addrs.push(addr);
+        self
+    }
+
+    /// Returns a handle to the latency histograms of the engine operations
+    /// served by this server, e.g. to export them.
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.latency_stats.clone()
+    }
+
     /// Run the server listening on the given address
     pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
-            debug!("Connection established");
+        let listener = self.socket_options.bind(addr)?;
+        self.serve_listeners(vec![listener])
+    }
 
-            let engine = self.engine.clone();
+    /// Run the server listening on all the given addresses, e.g. on both an
+    /// IPv4 and an IPv6 address, or on a public and an internal address.
+    ///
+    /// IPv6 addresses are bound to IPv6 only, so that `0.0.0.0:4000` and
+    /// `[::]:4000` can be bound together.
+    pub fn run_on(self, addrs: &[SocketAddr]) -> Result<()> {
+        let listeners = addrs
+            .iter()
+            .map(|addr| self.socket_options.bind(addr))
+            .collect::<io::Result<Vec<_>>>()?;
+        self.serve_listeners(listeners)
+    }
+
+    /// Accepts the connections of every listener and of the admin
+    /// addresses, each in a thread of its own, and serves them in the thread
+    /// pool.
+    fn serve_listeners(self, listeners: Vec<TcpListener>) -> Result<()> {
+        let admin_listeners = self
+            .admin_addrs
+            .iter()
+            .map(|addr| self.socket_options.bind(addr))
+            .collect::<io::Result<Vec<_>>>()?;
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| (listener, false))
+            .chain(admin_listeners.into_iter().map(|listener| (listener, true)));
 
-            self.thread_pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = serve(engine, stream) {
-                        error!("Error on serving client: {}", e);
+        let (tx, rx) = mpsc::channel();
+        for (listener, admin) in listeners {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if tx.send((stream, admin)).is_err() {
+                        break;
                     }
                 }
-                Err(e) => error!("Unable to connect: {}", e),
-            })
+            });
         }
+        drop(tx);
 
+        for (stream, admin) in rx {
+            self.dispatch(stream, admin);
+        }
         Ok(())
     }
-}
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> Result<()> {
-    let peer_addr = tcp.peer_addr()?;
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+    /// Serves a connection, accepted on an admin address if `admin`.
+    fn dispatch(&self, stream: io::Result<TcpStream>, admin: bool) {
+        debug!(admin, "Connection established");
+
+        let engine = self.engine.clone();
+        let shared = Shared {
+            latency_stats: self.latency_stats.clone(),
+            connections: self.connections.clone(),
+            read_only: Arc::clone(&self.read_only),
+            in_flight: Arc::clone(&self.in_flight),
+            backup_root: self.backup_root.clone(),
+        };
+        let options = ProtocolOptions {
+            compress_above: self.compress_above,
+            max_request_size: self.max_request_size,
+        };
+        let socket_options = self.socket_options;
+        let accepted_at = Instant::now();
 
-    macro_rules! send_resp {
-        ($resp:expr) => {{
-            let resp = $resp;
-            serde_json::to_writer(&mut writer, &resp)?;
-            writer.flush()?;
-            debug!("Response sent to {}: {:?}", peer_addr, resp);
-        };};
+        self.thread_pool.spawn(move || match stream {
+            Ok(stream) => {
+                debug!(
+                    queued_us = accepted_at.elapsed().as_micros() as u64,
+                    "Connection picked up by worker"
+                );
+                if let Err(e) = socket_options.configure(&stream) {
+                    warn!("Unable to set the socket options: {}", e);
+                }
+                let res = match shared.connections.register(&stream, options, admin) {
+                    Ok((session, _registered)) => serve(engine, &shared, session, stream),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = res {
+                    error!("Error on serving client: {}", e);
+                }
+            }
+            Err(e) => error!("Unable to connect: {}", e),
+        })
     }
+}
 
-    for request in req_reader {
-        let req = request?;
-        debug!("Received request from {}: {:?}", peer_addr, req);
+/// The state of a server its connections share.
+struct Shared {
+    latency_stats: LatencyStats,
+    connections: Connections,
+    read_only: Arc<AtomicBool>,
+    in_flight: Arc<InFlight>,
+    backup_root: Option<Arc<PathBuf>>,
+}
 
+fn serve<E: KvsEngine>(engine: E, shared: &Shared, session: Session, tcp: TcpStream) -> Result<()> {
+    let options = session.options;
+    let peer_addr = session.peer;
+    let mut reader = BufReader::new(Counted::new(&tcp));
+    let handler = Handler {
+        shared,
+        session: Mutex::new(session),
+        transaction: Mutex::new(None),
+        writer: Mutex::new(BufWriter::new(Counted::new(&tcp))),
+    };
+
+    thread::scope(|scope| -> Result<()> {
+        while let Some(request) = read_frame(&mut reader, options.max_request_size)? {
+            let TaggedRequest {
+                id,
+                request,
+                out_of_order,
+            } = request;
+            {
+                let mut session = handler.session.lock().unwrap();
+                session.requests += 1;
+                session.last_op = Some(request.op());
+                session.last_request_at = Instant::now();
+                session.bytes_read = reader.get_ref().bytes;
+                shared.connections.update(&session);
+            }
+            if !out_of_order {
+                handler.handle(&engine, id, request, false)?;
+                continue;
+            }
+            if !shared.in_flight.acquire() {
+                warn!(
+                    id,
+                    op = request.op(),
+                    "Too many requests in flight, rejected"
+                );
+                handler.send(id, true, &error_response(&request, KvsError::ServerBusy))?;
+                continue;
+            }
+            let handler = &handler;
+            // Engines are `Send` but not `Sync`: every thread has its own
+            // handle.
+            let engine = engine.clone();
+            scope.spawn(move || {
+                if let Err(e) = handler.handle(&engine, id, request, true) {
+                    error!("Error on serving client: {}", e);
+                }
+                shared.in_flight.release();
+            });
+        }
+        Ok(())
+    })?;
+
+    let session = handler.session.lock().unwrap();
+    debug!(
+        peer = %peer_addr,
+        requests = session.requests,
+        errors = session.errors,
+        connected_ms = session.connected_at.elapsed().as_millis() as u64,
+        "Connection closed"
+    );
+    Ok(())
+}
+
+/// Serves the requests of a connection, from the thread reading them or, for
+/// the requests which may be answered out of order, from threads of their
+/// own.
+struct Handler<'a, E: KvsEngine> {
+    shared: &'a Shared,
+    session: Mutex<Session>,
+    /// The transaction begun by the client, if any.
+    transaction: Mutex<Option<Transaction<E>>>,
+    writer: Mutex<BufWriter<Counted<&'a TcpStream>>>,
+}
+
+impl<E: KvsEngine> Handler<'_, E> {
+    /// Executes `req` and sends its response, tagged with `id` if `tagged`.
+    fn handle(&self, engine: &E, id: u64, req: Request, tagged: bool) -> Result<()> {
+        let span = {
+            let session = self.session.lock().unwrap();
+            info_span!(
+                "request",
+                id,
+                conn = session.id,
+                peer = %session.peer,
+                identity = session.identity.as_deref(),
+                db = session.db,
+                op = req.op(),
+                key = req.key().as_deref()
+            )
+        };
+        let _enter = span.enter();
+        let start = Instant::now();
+        debug!("Received request: {:?}", req);
+
+        let resp = self.execute(engine, req);
+        self.send(id, tagged, &resp)?;
+        debug!(
+            duration_us = start.elapsed().as_micros() as u64,
+            "Request handled"
+        );
+        Ok(())
+    }
+
+    fn execute(&self, engine: &E, req: Request) -> AnyResponse {
+        let latency_stats = &self.shared.latency_stats;
+        let connections = &self.shared.connections;
+        let writable = || {
+            if self.shared.read_only.load(Ordering::SeqCst) {
+                Err(KvsError::ReadOnly)
+            } else {
+                Ok(())
+            }
+        };
+        let admin = || {
+            if self.session.lock().unwrap().admin {
+                Ok(())
+            } else {
+                Err(KvsError::PermissionDenied(
+                    "admin requests are only served on the admin address".to_owned(),
+                ))
+            }
+        };
+        let req = match self.execute_in_transaction(req) {
+            Ok(resp) => return resp,
+            Err(req) => req,
+        };
         match req {
             Request::Set { key, value } => {
-                let engine_response = match engine.set(key, value) {
+                let engine_response = match latency_stats
+                    .time("set", || writable().and_then(|()| engine.set(key, value)))
+                {
                     Ok(_) => SetResponse::Ok(()),
-                    Err(err) => SetResponse::Err(format!("{}", err)),
+                    Err(err) => SetResponse::Err(err.into()),
                 };
-                send_resp!(engine_response);
+                engine_response.into()
             }
             Request::Get { key } => {
-                let engine_response = match engine.get(key) {
+                let engine_response = match latency_stats.time("get", || engine.get(key)) {
                     Ok(value) => GetResponse::Ok(value),
-                    Err(err) => GetResponse::Err(format!("{}", err)),
+                    Err(err) => GetResponse::Err(err.into()),
                 };
-                send_resp!(engine_response);
+                engine_response.into()
             }
             Request::Remove { key } => {
-                let engine_response = match engine.remove(key) {
+                let engine_response = match latency_stats
+                    .time("remove", || writable().and_then(|()| engine.remove(key)))
+                {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(err) => RemoveResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::RemoveIfExists { key } => {
+                let engine_response = match latency_stats.time("remove", || {
+                    writable().and_then(|()| engine.remove_if_exists(key))
+                }) {
+                    Ok(removed) => RemoveIfExistsResponse::Ok(removed),
+                    Err(err) => RemoveIfExistsResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::SetNx { key, value } => {
+                let engine_response = match latency_stats.time("set", || {
+                    writable().and_then(|()| engine.set_nx(key, value))
+                }) {
+                    Ok(set) => SetIfResponse::Ok(set),
+                    Err(err) => SetIfResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::SetXx { key, value } => {
+                let engine_response = match latency_stats.time("set", || {
+                    writable().and_then(|()| engine.set_xx(key, value))
+                }) {
+                    Ok(set) => SetIfResponse::Ok(set),
+                    Err(err) => SetIfResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::Expire { key, ttl_millis } => {
+                let ttl = Duration::from_millis(ttl_millis);
+                let engine_response = match latency_stats.time("expire", || {
+                    writable().and_then(|()| engine.expire(key, ttl))
+                }) {
+                    Ok(set) => ExpireResponse::Ok(set),
+                    Err(err) => ExpireResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::Persist { key } => {
+                let engine_response = match latency_stats
+                    .time("persist", || writable().and_then(|()| engine.persist(key)))
+                {
+                    Ok(persisted) => ExpireResponse::Ok(persisted),
+                    Err(err) => ExpireResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::Stats => StatsResponse::Ok(latency_stats.summaries()).into(),
+            Request::SetBytes { key, value } => {
+                let res = latency_stats.time("set", || {
+                    writable()?;
+                    engine.set_bytes(utf8(key)?, value)
+                });
+                let engine_response = match res {
+                    Ok(_) => SetResponse::Ok(()),
+                    Err(err) => SetResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::GetBytes { key } => {
+                let res = latency_stats.time("get", || engine.get_bytes(utf8(key)?));
+                let engine_response = match res {
+                    Ok(value) => GetBytesResponse::Ok(value.map(ByteBuf::from)),
+                    Err(err) => GetBytesResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::RemoveBytes { key } => {
+                let res = latency_stats.time("remove", || {
+                    writable()?;
+                    engine.remove(utf8(key)?)
+                });
+                let engine_response = match res {
                     Ok(_) => RemoveResponse::Ok(()),
-                    Err(err) => RemoveResponse::Err(format!("{}", err)),
+                    Err(err) => RemoveResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::Restore { from } => {
+                let res = latency_stats.time("restore", || {
+                    admin()?;
+                    writable()?;
+                    engine.restore(&self.backup_path(&from)?)
+                });
+                let engine_response = match res {
+                    Ok(()) => RestoreResponse::Ok(()),
+                    Err(err) => RestoreResponse::Err(err.into()),
                 };
-                send_resp!(engine_response);
+                engine_response.into()
+            }
+            Request::Compact => {
+                let res = latency_stats.time("compact", || engine.compact());
+                let engine_response = match res {
+                    Ok(()) => CompactResponse::Ok(()),
+                    Err(err) => CompactResponse::Err(err.into()),
+                };
+                engine_response.into()
+            }
+            Request::ClientList => match admin() {
+                Ok(()) => ClientListResponse::Ok(connections.list()).into(),
+                Err(err) => ClientListResponse::Err(err.into()).into(),
+            },
+            Request::ClientKill { id } => match admin() {
+                Ok(()) => ClientKillResponse::Ok(connections.kill(id)).into(),
+                Err(err) => ClientKillResponse::Err(err.into()).into(),
+            },
+            Request::SetReadOnly { read_only } => match admin() {
+                Ok(()) => {
+                    self.shared.read_only.store(read_only, Ordering::SeqCst);
+                    info!(read_only, "Read-only mode changed");
+                    SetReadOnlyResponse::Ok(()).into()
+                }
+                Err(err) => SetReadOnlyResponse::Err(err.into()).into(),
+            },
+            Request::Transaction { op } => {
+                let mut transaction = self.transaction.lock().unwrap();
+                let res = match (op, transaction.take()) {
+                    (TransactionOp::Begin, None) => {
+                        *transaction = Some(engine.begin());
+                        Ok(())
+                    }
+                    (TransactionOp::Begin, Some(txn)) => {
+                        *transaction = Some(txn);
+                        Err(KvsError::StringError(
+                            "a transaction is already begun".to_owned(),
+                        ))
+                    }
+                    (TransactionOp::Commit, Some(txn)) => {
+                        latency_stats.time("commit", || writable().and_then(|()| txn.commit()))
+                    }
+                    (TransactionOp::Rollback, Some(txn)) => {
+                        txn.rollback();
+                        Ok(())
+                    }
+                    (_, None) => Err(KvsError::StringError("no transaction begun".to_owned())),
+                };
+                let engine_response = match res {
+                    Ok(()) => TransactionResponse::Ok(()),
+                    Err(err) => TransactionResponse::Err(err.into()),
+                };
+                engine_response.into()
             }
         }
     }
 
-    Ok(())
+    /// Resolves the path of a backup to restore, relative to the backup root.
+    fn backup_path(&self, from: &str) -> Result<PathBuf> {
+        let root = self.shared.backup_root.as_ref().ok_or_else(|| {
+            KvsError::PermissionDenied("no backup root is set on the server".to_owned())
+        })?;
+        let root = root.canonicalize()?;
+        let path = root.join(from).canonicalize()?;
+        if !path.starts_with(&root) {
+            return Err(KvsError::PermissionDenied(format!(
+                "{} is not in the backup root",
+                from
+            )));
+        }
+        Ok(path)
+    }
+
+    /// Executes `req` in the transaction of the client if it has one and the
+    /// request goes through it, or gives it back.
+    fn execute_in_transaction(&self, req: Request) -> std::result::Result<AnyResponse, Request> {
+        let mut transaction = self.transaction.lock().unwrap();
+        let txn = match transaction.as_mut() {
+            Some(txn) => txn,
+            None => return Err(req),
+        };
+        let resp = match req {
+            Request::Get { key } => match txn.get(key) {
+                Ok(value) => GetResponse::Ok(value).into(),
+                Err(err) => GetResponse::Err(err.into()).into(),
+            },
+            Request::Set { key, value } => {
+                txn.set(key, value);
+                SetResponse::Ok(()).into()
+            }
+            Request::Remove { key } => {
+                txn.remove(key);
+                RemoveResponse::Ok(()).into()
+            }
+            req => return Err(req),
+        };
+        Ok(resp)
+    }
+
+    fn send(&self, id: u64, tagged: bool, resp: &AnyResponse) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        {
+            let mut session = self.session.lock().unwrap();
+            if resp.is_err() {
+                session.errors += 1;
+            }
+            let compress_above = session.options.compress_above;
+            if tagged {
+                let resp = TaggedResponse { id, response: resp };
+                write_frame(&mut *writer, &resp, compress_above)?;
+            } else {
+                write_frame(&mut *writer, resp, compress_above)?;
+            }
+            // Updated before the client can see the response.
+            session.bytes_written = writer.get_ref().bytes + writer.buffer().len() as u64;
+            self.shared.connections.update(&session);
+        }
+        writer.flush()?;
+        debug!("Response sent: {:?}", resp);
+        Ok(())
+    }
+}
+
+/// The response to `req` failing with `err` before it is executed.
+fn error_response(req: &Request, err: KvsError) -> AnyResponse {
+    let err = err.into();
+    match req {
+        Request::Set { .. } | Request::SetBytes { .. } => SetResponse::Err(err).into(),
+        Request::Get { .. } => GetResponse::Err(err).into(),
+        Request::GetBytes { .. } => GetBytesResponse::Err(err).into(),
+        Request::Remove { .. } | Request::RemoveBytes { .. } => RemoveResponse::Err(err).into(),
+        Request::RemoveIfExists { .. } => RemoveIfExistsResponse::Err(err).into(),
+        Request::SetNx { .. } | Request::SetXx { .. } => SetIfResponse::Err(err).into(),
+        Request::Expire { .. } | Request::Persist { .. } => ExpireResponse::Err(err).into(),
+        Request::Stats => StatsResponse::Err(err).into(),
+        Request::Restore { .. } => RestoreResponse::Err(err).into(),
+        Request::Compact => CompactResponse::Err(err).into(),
+        Request::ClientList => ClientListResponse::Err(err).into(),
+        Request::ClientKill { .. } => ClientKillResponse::Err(err).into(),
+        Request::SetReadOnly { .. } => SetReadOnlyResponse::Err(err).into(),
+        Request::Transaction { .. } => TransactionResponse::Err(err).into(),
+    }
+}
+
+/// Converts a binary key for the engine, which only stores string keys.
+fn utf8(bytes: Vec<u8>) -> Result<String> {
+    Ok(String::from_utf8(bytes)?)
 }
@@ -0,0 +1,310 @@
+//! This module provides test harnesses for `KvsEngine` implementations.
+//!
+//! `apply_ops` runs a sequence of operations against an engine and cross-checks
+//! every result against a `HashMap` model. With the `fuzzing` feature, `Op`
+//! implements `arbitrary::Arbitrary`, so cargo-fuzz targets can generate the
+//! sequences.
+//!
+//! `CrashTest` runs a random workload against an engine in a child process,
+//! kills the child right after a random acknowledged write, reopens the
+//! directory and checks that every acknowledged write survived. The child is
+//! the test binary itself, re-executed with only the calling test selected, so
+//! the harness works from any `#[test]` function:
+//!
+//! ```no_run
+//! # use kvs::testing::CrashTest;
+//! # use kvs::KvStore;
+//! # use std::path::Path;
+//! #[test]
+//! fn survives_crashes() {
+//!     let dir = tempfile::TempDir::new().unwrap();
+//!     CrashTest::new("survives_crashes", |path: &Path| KvStore::open(path))
+//!         .run(dir.path())
+//!         .unwrap();
+//! }
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::rng::Rng;
+use crate::{KvsEngine, KvsError, Result};
+
+const CHILD_DIR_ENV: &str = "KVS_CRASH_TEST_DIR";
+const CHILD_SEED_ENV: &str = "KVS_CRASH_TEST_SEED";
+const ACK_PREFIX: &str = "kvs-crash-test ack ";
+
+/// An engine operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "fuzzing", derive(arbitrary::Arbitrary))]
+pub enum Op {
+    /// `KvsEngine::set`.
+    Set {
+        /// The key.
+        key: String,
+        /// The value.
+        value: String,
+    },
+    /// `KvsEngine::get`.
+    Get {
+        /// The key.
+        key: String,
+    },
+    /// `KvsEngine::remove`.
+    Remove {
+        /// The key.
+        key: String,
+    },
+}
+
+/// Applies `ops` to `engine` in order, checking every result against a
+/// `HashMap` model of the engine.
+///
+/// The engine should be empty when called.
+///
+/// # Errors
+///
+/// It returns `KvsError::StringError` describing the first operation whose
+/// result differs from the model, and propagates any other engine error.
+pub fn apply_ops<E: KvsEngine>(engine: &E, ops: &[Op]) -> Result<()> {
+    let mut model = HashMap::new();
+    for (i, op) in ops.iter().enumerate() {
+        let mismatch = |actual: &dyn std::fmt::Debug, expected: &dyn std::fmt::Debug| {
+            KvsError::StringError(format!(
+                "operation {} ({:?}) returned {:?}, expected {:?}",
+                i, op, actual, expected
+            ))
+        };
+        match op {
+            Op::Set { key, value } => {
+                engine.set(key.clone(), value.clone())?;
+                model.insert(key.clone(), value.clone());
+            }
+            Op::Get { key } => {
+                let actual = engine.get(key.clone())?;
+                let expected = model.get(key);
+                if actual.as_ref() != expected {
+                    return Err(mismatch(&actual, &expected));
+                }
+            }
+            Op::Remove { key } => {
+                let actual = match engine.remove(key.clone()) {
+                    Ok(()) => true,
+                    Err(KvsError::KeyNotFound) => false,
+                    Err(e) => return Err(e),
+                };
+                let expected = model.remove(key).is_some();
+                if actual != expected {
+                    return Err(mismatch(&actual, &expected));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A crash-recovery test of the engine returned by `open`.
+pub struct CrashTest<F> {
+    test_name: String,
+    open: F,
+    ops: usize,
+    keys: usize,
+    runs: usize,
+    seed: u64,
+}
+
+impl<E, F> CrashTest<F>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    /// Creates a crash test run from the test function `test_name`, opening
+    /// the engine with `open`.
+    ///
+    /// `test_name` must select exactly the calling test, as it is passed to
+    /// the test binary with `--exact`.
+    pub fn new(test_name: impl Into<String>, open: F) -> Self {
+        CrashTest {
+            test_name: test_name.into(),
+            open,
+            ops: 500,
+            keys: 50,
+            runs: 5,
+            seed: 0x5eed,
+        }
+    }
+
+    /// Sets the number of operations of each workload. Defaults to 500.
+    pub fn ops(mut self, ops: usize) -> Self {
+        self.ops = ops;
+        self
+    }
+
+    /// Sets the number of distinct keys of each workload. Defaults to 50.
+    pub fn keys(mut self, keys: usize) -> Self {
+        self.keys = keys.max(1);
+        self
+    }
+
+    /// Sets the number of crash-and-recover runs. Defaults to 5.
+    pub fn runs(mut self, runs: usize) -> Self {
+        self.runs = runs;
+        self
+    }
+
+    /// Sets the seed of the workloads and of the kill points.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Runs the test, using a fresh subdirectory of `dir` for every run.
+    ///
+    /// In the child process this runs the workload and never returns.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` describing the first lost or
+    /// unexpected value, and propagates errors from spawning the child and
+    /// from the engine.
+    pub fn run(&self, dir: &Path) -> Result<()> {
+        if let Some(dir) = env::var_os(CHILD_DIR_ENV) {
+            let seed = env::var(CHILD_SEED_ENV)
+                .ok()
+                .and_then(|seed| seed.parse().ok())
+                .unwrap_or(self.seed);
+            self.run_child(Path::new(&dir), seed)
+        }
+
+        let mut rng = Rng::new(self.seed);
+        for run in 0..self.runs {
+            let run_dir = dir.join(format!("run-{}", run));
+            let seed = rng.next();
+            let kill_after = 1 + rng.below(self.ops as u64) as usize;
+            self.run_parent(&run_dir, seed, kill_after)?;
+        }
+        Ok(())
+    }
+
+    fn run_child(&self, dir: &Path, seed: u64) -> ! {
+        let result = (|| -> Result<()> {
+            let engine = (self.open)(dir)?;
+            let stdout = io::stdout();
+            for (i, op) in self.workload(seed).into_iter().enumerate() {
+                match op {
+                    Op::Set { key, value } => engine.set(key, value)?,
+                    Op::Remove { key } => engine.remove(key)?,
+                    Op::Get { key } => {
+                        engine.get(key)?;
+                    }
+                }
+                let mut stdout = stdout.lock();
+                writeln!(stdout, "{}{}", ACK_PREFIX, i)?;
+                stdout.flush()?;
+            }
+            Ok(())
+        })();
+        match result {
+            Ok(()) => std::process::exit(0),
+            Err(e) => {
+                eprintln!("crash test workload failed: {}", e);
+                std::process::exit(1)
+            }
+        }
+    }
+
+    fn run_parent(&self, dir: &Path, seed: u64, kill_after: usize) -> Result<()> {
+        let mut child = Command::new(env::current_exe()?)
+            .args([
+                self.test_name.as_str(),
+                "--exact",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env(CHILD_DIR_ENV, dir)
+            .env(CHILD_SEED_ENV, seed.to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let mut acked = 0;
+        let stdout = child.stdout.take().expect("child stdout is piped");
+        for line in BufReader::new(stdout).lines() {
+            if line?.starts_with(ACK_PREFIX) {
+                acked += 1;
+                if acked == kill_after {
+                    break;
+                }
+            }
+        }
+        // The child may already have exited after finishing the workload.
+        let _ = child.kill();
+        child.wait()?;
+
+        self.check(dir, seed, acked)
+    }
+
+    /// Checks that the first `acked` operations survived. Operations issued
+    /// after the last acknowledgement may or may not have been applied.
+    fn check(&self, dir: &Path, seed: u64, acked: usize) -> Result<()> {
+        let workload = self.workload(seed);
+        let mut allowed: BTreeMap<&str, Vec<Option<&str>>> = BTreeMap::new();
+        for op in &workload[..acked] {
+            if let Some((key, value)) = op.write() {
+                allowed.insert(key, vec![value]);
+            }
+        }
+        for op in &workload[acked..] {
+            if let Some((key, value)) = op.write() {
+                allowed.entry(key).or_insert_with(|| vec![None]).push(value);
+            }
+        }
+
+        let engine = (self.open)(dir)?;
+        for (key, values) in allowed {
+            let actual = engine.get(key.to_owned())?;
+            if !values.contains(&actual.as_deref()) {
+                return Err(KvsError::StringError(format!(
+                    "crash after {} acknowledged operations (seed {}): key {:?} is {:?}, expected one of {:?}",
+                    acked, seed, key, actual, values
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates a workload of sets and removes. A key is only removed while
+    /// it is present, so every operation succeeds.
+    fn workload(&self, seed: u64) -> Vec<Op> {
+        let mut rng = Rng::new(seed);
+        let mut present = vec![false; self.keys];
+        (0..self.ops)
+            .map(|i| {
+                let k = rng.below(self.keys as u64) as usize;
+                let key = format!("key{}", k);
+                if present[k] && rng.below(4) == 0 {
+                    present[k] = false;
+                    Op::Remove { key }
+                } else {
+                    present[k] = true;
+                    let value = format!("value{}-{}", i, rng.next());
+                    Op::Set { key, value }
+                }
+            })
+            .collect()
+    }
+}
+
+impl Op {
+    /// Returns the key written by the operation and the value it leaves.
+    fn write(&self) -> Option<(&str, Option<&str>)> {
+        match self {
+            Op::Set { key, value } => Some((key, Some(value))),
+            Op::Remove { key } => Some((key, None)),
+            Op::Get { .. } => None,
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! This module provides the file system abstraction used by `KvStore`. All file
+//! systems should implement the `Vfs` trait.
+//!
+//! `OsFs` is backed by the real file system. `MemFs` keeps everything in memory,
+//! so tests can run many store lifecycles quickly and reproducibly, including
+//! simulated power losses.
+
+use std::collections::BTreeMap;
+#[cfg(feature = "fs")]
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// An open file.
+pub trait VfsFile: Read + Write + Seek + Send {
+    /// Flushes the file content to durable storage.
+    fn sync_data(&self) -> io::Result<()>;
+}
+
+/// The trait that all file systems should implement.
+pub trait Vfs: Send + Sync + 'static {
+    /// Recursively creates a directory and all of its parents if they are missing.
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Returns the paths of the regular files directly inside `dir`.
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Opens a file for appending, creating it if it does not exist.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Removes a file. Handles opened before remain readable.
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns the size of a file in bytes.
+    fn file_len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Returns the free space in bytes available to the process on the file
+    /// system of `dir`, or `None` if it is unknown.
+    ///
+    /// The default implementation returns `None`.
+    fn available_space(&self, _dir: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Renames a file, replacing `to` if it exists.
+    ///
+    /// The default implementation copies the file and removes the original,
+    /// so it is not atomic. File systems should override it with an atomic
+    /// rename when they can.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut src = self.open(from)?;
+        if self.file_len(to).is_ok() {
+            self.remove_file(to)?;
+        }
+        let mut dst = self.open_append(to)?;
+        io::copy(&mut src, &mut dst)?;
+        dst.sync_data()?;
+        self.remove_file(from)
+    }
+
+    /// Maps a file into memory for reading, or returns `None` if the file
+    /// system cannot. The file must not be modified while it is mapped, but
+    /// may be appended to.
+    ///
+    /// The default implementation returns `None`.
+    #[cfg(feature = "mmap")]
+    fn map(&self, _path: &Path) -> io::Result<Option<memmap2::Mmap>> {
+        Ok(None)
+    }
+
+    /// Shortens a file to its first `len` bytes, and syncs it.
+    ///
+    /// The default implementation copies them to a temporary file renamed
+    /// over the original.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let tmp_path = path.with_extension("truncate.tmp");
+        let _ = self.remove_file(&tmp_path);
+        let mut src = self.open(path)?.take(len);
+        let mut dst = self.open_append(&tmp_path)?;
+        io::copy(&mut src, &mut dst)?;
+        dst.sync_data()?;
+        self.rename(&tmp_path, path)
+    }
+}
+
+/// The operating system's file system.
+#[cfg(feature = "fs")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFs;
+
+#[cfg(feature = "fs")]
+impl VfsFile for File {
+    fn sync_data(&self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+#[cfg(feature = "fs")]
+impl Vfs for OsFs {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(dir)?
+            .flat_map(|res| -> io::Result<_> { Ok(res?.path()) })
+            .filter(|path| path.is_file())
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+
+    fn available_space(&self, dir: &Path) -> io::Result<Option<u64>> {
+        fs2::available_space(dir).map(Some)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    #[cfg(feature = "mmap")]
+    fn map(&self, path: &Path) -> io::Result<Option<memmap2::Mmap>> {
+        let file = File::open(path)?;
+        // SAFETY: callers only map files which are not modified while they
+        // are mapped, apart from appends past the mapped bytes.
+        Ok(Some(unsafe { memmap2::Mmap::map(&file)? }))
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_data()
+    }
+}
+
+#[derive(Default)]
+struct MemFileData {
+    bytes: Vec<u8>,
+    /// Length of the prefix of `bytes` that survives a power loss.
+    synced_len: usize,
+}
+
+/// An in-memory file system.
+///
+/// Cloning gives another handle to the same file system, so a store can be
+/// dropped and reopened on it. Iteration order is deterministic.
+#[derive(Default, Clone)]
+pub struct MemFs {
+    inner: Arc<Mutex<MemFsInner>>,
+}
+
+#[derive(Default)]
+struct MemFsInner {
+    dirs: Vec<PathBuf>,
+    files: BTreeMap<PathBuf, Arc<Mutex<MemFileData>>>,
+    available_space: Option<u64>,
+}
+
+impl MemFs {
+    /// Creates an empty file system.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Simulates a power loss: every file loses the data written since its
+    /// last `sync_data`.
+    ///
+    /// Stores opened on this file system should be dropped before and
+    /// reopened after calling this.
+    pub fn power_loss(&self) {
+        let inner = self.inner.lock().unwrap();
+        for file in inner.files.values() {
+            let mut file = file.lock().unwrap();
+            let synced_len = file.synced_len;
+            file.bytes.truncate(synced_len);
+        }
+    }
+
+    /// Sets the free space reported by `Vfs::available_space`, `None` by
+    /// default. Writes are not limited by it.
+    pub fn set_available_space(&self, bytes: Option<u64>) {
+        self.inner.lock().unwrap().available_space = bytes;
+    }
+
+    fn file(&self, path: &Path) -> io::Result<Arc<Mutex<MemFileData>>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+impl Vfs for MemFs {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.dirs.iter().any(|d| d == dir) {
+            inner.dirs.push(dir.to_owned());
+        }
+        Ok(())
+    }
+
+    fn list_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let inner = self.inner.lock().unwrap();
+        if !inner.dirs.iter().any(|d| d == dir) {
+            return Err(not_found(dir));
+        }
+        Ok(inner
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(MemFile {
+            data: self.file(path)?,
+            pos: 0,
+            append: false,
+        }))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        let mut inner = self.inner.lock().unwrap();
+        let parent = path.parent().ok_or_else(|| not_found(path))?;
+        if !inner.dirs.iter().any(|d| d == parent) {
+            return Err(not_found(path));
+        }
+        let data = inner.files.entry(path.to_owned()).or_default();
+        let pos = data.lock().unwrap().bytes.len() as u64;
+        Ok(Box::new(MemFile {
+            data: Arc::clone(data),
+            pos,
+            append: true,
+        }))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn file_len(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.file(path)?.lock().unwrap().bytes.len() as u64)
+    }
+
+    fn available_space(&self, _dir: &Path) -> io::Result<Option<u64>> {
+        Ok(self.inner.lock().unwrap().available_space)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let data = inner.files.remove(from).ok_or_else(|| not_found(from))?;
+        inner.files.insert(to.to_owned(), data);
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = self.file(path)?;
+        let mut data = file.lock().unwrap();
+        data.bytes.truncate(len as usize);
+        data.synced_len = data.bytes.len();
+        Ok(())
+    }
+}
+
+struct MemFile {
+    data: Arc<Mutex<MemFileData>>,
+    pos: u64,
+    append: bool,
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let start = (self.pos as usize).min(data.bytes.len());
+        let len = buf.len().min(data.bytes.len() - start);
+        buf[..len].copy_from_slice(&data.bytes[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.append {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is opened read-only",
+            ));
+        }
+        let mut data = self.data.lock().unwrap();
+        data.bytes.extend_from_slice(buf);
+        self.pos = data.bytes.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.data.lock().unwrap().bytes.len() as i64;
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            ));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl VfsFile for MemFile {
+    fn sync_data(&self) -> io::Result<()> {
+        let mut data = self.data.lock().unwrap();
+        data.synced_len = data.bytes.len();
+        Ok(())
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{}: no such file or directory", path.display()),
+    )
+}
@@ -0,0 +1,341 @@
+//! This module provides a generator of YCSB-like workloads, for benchmarking
+//! `KvsEngine` implementations.
+//!
+//! A workload has a load phase, which inserts every record once, and a run
+//! phase, which reads and updates records picked from a key distribution:
+//!
+//! ```no_run
+//! # use kvs::workload::Workload;
+//! # use kvs::{KvStore, Result};
+//! # fn main() -> Result<()> {
+//! let store = KvStore::open("bench-dir")?;
+//! let workload = Workload::ycsb_b(10_000).operations(100_000);
+//! workload.load(&store)?;
+//! let report = workload.run(&store)?;
+//! println!("{:.0} ops/s", report.throughput());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+use crate::rng::Rng;
+use crate::testing::Op;
+#[cfg(feature = "threads")]
+use crate::thread_pool::ThreadPool;
+use crate::{KvsEngine, LatencyStats, OpLatency, Result};
+
+/// How the run phase picks the records it accesses.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyDistribution {
+    /// Every record is equally likely.
+    Uniform,
+    /// A few records are much more popular than the others. `theta` in
+    /// `(0, 1)` is the skew; YCSB uses 0.99.
+    Zipfian {
+        /// The skew of the distribution.
+        theta: f64,
+    },
+}
+
+impl KeyDistribution {
+    /// The zipfian distribution used by YCSB.
+    pub const YCSB_ZIPFIAN: KeyDistribution = KeyDistribution::Zipfian { theta: 0.99 };
+}
+
+/// A YCSB-like workload.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    records: u64,
+    operations: u64,
+    read_ratio: f64,
+    distribution: KeyDistribution,
+    value_size: RangeInclusive<usize>,
+    seed: u64,
+}
+
+/// The outcome of a run phase.
+#[derive(Debug, Clone)]
+pub struct WorkloadReport {
+    /// Number of operations executed.
+    pub operations: u64,
+    /// Wall-clock time of the run phase.
+    pub elapsed: Duration,
+    /// Latency percentiles of each kind of operation.
+    pub latency: Vec<OpLatency>,
+}
+
+impl WorkloadReport {
+    /// Returns the throughput in operations per second.
+    pub fn throughput(&self) -> f64 {
+        self.operations as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl Workload {
+    /// Creates a workload of `records` records and as many operations, with
+    /// 50% reads, uniformly distributed keys and 100-byte values.
+    pub fn new(records: u64) -> Self {
+        Workload {
+            records: records.max(1),
+            operations: records,
+            read_ratio: 0.5,
+            distribution: KeyDistribution::Uniform,
+            value_size: 100..=100,
+            seed: 0,
+        }
+    }
+
+    /// YCSB workload A: update heavy, 50% reads, zipfian keys.
+    pub fn ycsb_a(records: u64) -> Self {
+        Self::new(records)
+            .read_ratio(0.5)
+            .distribution(KeyDistribution::YCSB_ZIPFIAN)
+    }
+
+    /// YCSB workload B: read mostly, 95% reads, zipfian keys.
+    pub fn ycsb_b(records: u64) -> Self {
+        Self::new(records)
+            .read_ratio(0.95)
+            .distribution(KeyDistribution::YCSB_ZIPFIAN)
+    }
+
+    /// YCSB workload C: read only, zipfian keys.
+    pub fn ycsb_c(records: u64) -> Self {
+        Self::new(records)
+            .read_ratio(1.0)
+            .distribution(KeyDistribution::YCSB_ZIPFIAN)
+    }
+
+    /// Sets the number of operations of the run phase.
+    pub fn operations(mut self, operations: u64) -> Self {
+        self.operations = operations;
+        self
+    }
+
+    /// Sets the fraction of reads in the run phase; the rest are updates.
+    pub fn read_ratio(mut self, read_ratio: f64) -> Self {
+        self.read_ratio = read_ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Sets the key distribution of the run phase.
+    pub fn distribution(mut self, distribution: KeyDistribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Sets the range of value sizes in bytes.
+    pub fn value_size(mut self, value_size: RangeInclusive<usize>) -> Self {
+        self.value_size = value_size;
+        self
+    }
+
+    /// Sets the seed of the generated keys and values.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Returns the operations of the load phase: one set per record.
+    pub fn load_ops(&self) -> impl Iterator<Item = Op> + '_ {
+        let mut rng = Rng::new(self.seed);
+        (0..self.records).map(move |i| Op::Set {
+            key: record_key(i),
+            value: self.value(&mut rng),
+        })
+    }
+
+    /// Returns the operations of the run phase.
+    pub fn run_ops(&self) -> impl Iterator<Item = Op> + '_ {
+        let mut rng = Rng::new(self.seed.wrapping_add(1));
+        let mut keys = KeyChooser::new(self.records, self.distribution);
+        (0..self.operations).map(move |_| {
+            let key = record_key(keys.next(&mut rng));
+            if rng.next_f64() < self.read_ratio {
+                Op::Get { key }
+            } else {
+                Op::Set {
+                    key,
+                    value: self.value(&mut rng),
+                }
+            }
+        })
+    }
+
+    /// Runs the load phase against `engine`.
+    pub fn load<E: KvsEngine>(&self, engine: &E) -> Result<()> {
+        for op in self.load_ops() {
+            execute(engine, op)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the run phase against `engine`, which should have been loaded.
+    pub fn run<E: KvsEngine>(&self, engine: &E) -> Result<WorkloadReport> {
+        let latency = LatencyStats::new();
+        let start = Instant::now();
+        for op in self.run_ops() {
+            latency.time(op_name(&op), || execute(engine, op))?;
+        }
+        Ok(WorkloadReport {
+            operations: self.operations,
+            elapsed: start.elapsed(),
+            latency: latency.summaries(),
+        })
+    }
+
+    /// Runs the run phase against `engine` from `clients` concurrent clients
+    /// spawned on `pool`. The operations are dealt round-robin to the clients.
+    ///
+    /// # Errors
+    ///
+    /// It propagates the first engine error, and fails if a client panics.
+    #[cfg(feature = "threads")]
+    pub fn run_on<E, P>(&self, engine: &E, pool: &P, clients: usize) -> Result<WorkloadReport>
+    where
+        E: KvsEngine,
+        P: ThreadPool,
+    {
+        let clients = clients.max(1);
+        let mut partitions = vec![Vec::new(); clients];
+        for (i, op) in self.run_ops().enumerate() {
+            partitions[i % clients].push(op);
+        }
+
+        let latency = LatencyStats::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let start = Instant::now();
+        for ops in partitions {
+            let engine = engine.clone();
+            let latency = latency.clone();
+            let tx = tx.clone();
+            pool.spawn(move || {
+                let res = ops.into_iter().try_for_each(|op| {
+                    let name = op_name(&op);
+                    latency.time(name, || execute(&engine, op))
+                });
+                let _ = tx.send(res);
+            });
+        }
+        // A panicking client drops its sender without sending.
+        drop(tx);
+        let results: Vec<Result<()>> = rx.iter().collect();
+        let elapsed = start.elapsed();
+        if results.len() < clients {
+            return Err(crate::KvsError::StringError(
+                "a workload client panicked".to_owned(),
+            ));
+        }
+        results.into_iter().collect::<Result<()>>()?;
+
+        Ok(WorkloadReport {
+            operations: self.operations,
+            elapsed,
+            latency: latency.summaries(),
+        })
+    }
+
+    fn value(&self, rng: &mut Rng) -> String {
+        let min = *self.value_size.start();
+        let max = (*self.value_size.end()).max(min);
+        let len = min + rng.below((max - min + 1) as u64) as usize;
+        (0..len)
+            .map(|_| (b'a' + rng.below(26) as u8) as char)
+            .collect()
+    }
+}
+
+fn record_key(i: u64) -> String {
+    format!("user{}", i)
+}
+
+fn op_name(op: &Op) -> &'static str {
+    match op {
+        Op::Set { .. } => "set",
+        Op::Get { .. } => "get",
+        Op::Remove { .. } => "remove",
+    }
+}
+
+fn execute<E: KvsEngine>(engine: &E, op: Op) -> Result<()> {
+    match op {
+        Op::Set { key, value } => engine.set(key, value),
+        Op::Get { key } => engine.get(key).map(|_| ()),
+        Op::Remove { key } => engine.remove_if_exists(key).map(|_| ()),
+    }
+}
+
+enum KeyChooser {
+    Uniform(u64),
+    Zipfian(Zipfian),
+}
+
+impl KeyChooser {
+    fn new(records: u64, distribution: KeyDistribution) -> Self {
+        match distribution {
+            KeyDistribution::Uniform => KeyChooser::Uniform(records),
+            KeyDistribution::Zipfian { theta } => KeyChooser::Zipfian(Zipfian::new(records, theta)),
+        }
+    }
+
+    fn next(&mut self, rng: &mut Rng) -> u64 {
+        match self {
+            KeyChooser::Uniform(records) => rng.below(*records),
+            // Scatter the popular ranks over the key space, like YCSB's
+            // scrambled zipfian, so they are not all adjacent records.
+            KeyChooser::Zipfian(zipfian) => fnv1a(zipfian.next(rng)) % zipfian.items,
+        }
+    }
+}
+
+/// The zipfian generator of "Quickly Generating Billion-Record Synthetic
+/// Databases" (Gray et al.), as used by YCSB. It returns ranks in
+/// `0..items`, rank 0 being the most popular.
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64, theta: f64) -> Self {
+        let zeta2 = zeta(2, theta);
+        let zetan = zeta(items, theta);
+        Zipfian {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn next(&self, rng: &mut Rng) -> u64 {
+        let u = rng.next_f64();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.items - 1);
+        }
+        let rank = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        rank.min(self.items - 1)
+    }
+}
+
+fn zeta(n: u64, theta: f64) -> f64 {
+    (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum()
+}
+
+fn fnv1a(n: u64) -> u64 {
+    n.to_le_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3)
+        })
+}
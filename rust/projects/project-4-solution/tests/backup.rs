@@ -0,0 +1,298 @@
+use kvs::backup::{BackupTarget, DirTarget};
+use kvs::{CompactionEvent, KvStore, KvsEngine, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+/// Notifies of a put, and waits to resume it.
+type PutPause = (Mutex<Sender<()>>, Mutex<Receiver<()>>);
+
+/// A backup target keeping the files in memory.
+#[derive(Default)]
+struct MemTarget {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    committed: Mutex<Vec<String>>,
+    aborted: Mutex<Vec<String>>,
+    /// Fails to put the files with this name.
+    fail_on: Option<String>,
+    /// Notified of every put, which then waits to be resumed, until the
+    /// resuming sender is dropped.
+    paused: Option<PutPause>,
+}
+
+impl BackupTarget for MemTarget {
+    fn put(&self, backup: &str, name: &str, reader: &mut dyn Read, len: u64) -> io::Result<()> {
+        if let Some((put_tx, resume_rx)) = &self.paused {
+            let _ = put_tx.lock().unwrap().send(());
+            let _ = resume_rx.lock().unwrap().recv();
+        }
+        if self.fail_on.as_deref() == Some(name) {
+            return Err(io::Error::other("injected failure"));
+        }
+        let mut content = Vec::new();
+        reader.take(len).read_to_end(&mut content)?;
+        assert_eq!(content.len() as u64, len);
+        let path = format!("{}/{}", backup, name);
+        self.files.lock().unwrap().insert(path, content);
+        Ok(())
+    }
+
+    fn commit(&self, backup: &str) -> io::Result<()> {
+        self.committed.lock().unwrap().push(backup.to_owned());
+        Ok(())
+    }
+
+    fn abort(&self, backup: &str) -> io::Result<()> {
+        self.aborted.lock().unwrap().push(backup.to_owned());
+        Ok(())
+    }
+}
+
+impl MemTarget {
+    /// Writes the files of `backup` to `dir`.
+    fn restore(&self, backup: &str, dir: &std::path::Path) {
+        let prefix = format!("{}/", backup);
+        for (path, content) in self.files.lock().unwrap().iter() {
+            if let Some(name) = path.strip_prefix(&prefix) {
+                fs::write(dir.join(name), content).unwrap();
+            }
+        }
+    }
+}
+
+// Should put every log file to the target, then commit the backup
+#[test]
+fn backup_to_custom_target() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let target = MemTarget::default();
+    let backup = store.backup_to(&target)?;
+    assert!(backup.starts_with("snapshot-"));
+    assert_eq!(*target.committed.lock().unwrap(), vec![backup.clone()]);
+    assert!(target.aborted.lock().unwrap().is_empty());
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    target.restore(&backup, restore_dir.path());
+    let restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key0".to_owned())?, None);
+    assert_eq!(restored.get("key99".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should abort the backup when a file cannot be put
+#[test]
+fn abort_failed_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    let target = MemTarget {
+        fail_on: Some("1.log".to_owned()),
+        ..MemTarget::default()
+    };
+    assert!(store.backup_to(&target).is_err());
+    assert!(target.committed.lock().unwrap().is_empty());
+    assert_eq!(target.aborted.lock().unwrap().len(), 1);
+
+    // The directory target deletes what it wrote.
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let dir_target = DirTarget::new(dest.path());
+    store.backup_to(&dir_target)?;
+    assert_eq!(fs::read_dir(dest.path())?.count(), 1);
+    Ok(())
+}
+
+// Should accept writes while a backup runs, and keep the files it copies
+#[test]
+fn write_during_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "before".to_owned())?;
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+        let events = Arc::clone(&events);
+        store.on_compaction(move |event| {
+            if let CompactionEvent::Started { .. } = event {
+                events.lock().unwrap().push("compaction");
+            }
+        });
+    }
+
+    let (put_tx, put_rx) = mpsc::channel();
+    let (resume_tx, resume_rx) = mpsc::channel();
+    let target = Arc::new(MemTarget {
+        paused: Some((Mutex::new(put_tx), Mutex::new(resume_rx))),
+        ..MemTarget::default()
+    });
+    let handle = {
+        let store = store.clone();
+        let target = Arc::clone(&target);
+        thread::spawn(move || store.backup_to(&*target))
+    };
+
+    put_rx.recv().unwrap();
+    // Enough stale commands to compact the log, were it not deferred.
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("after{}", iter))?;
+        }
+    }
+    events.lock().unwrap().push("resume");
+    drop(resume_tx);
+    let backup = handle.join().unwrap()?;
+    // The compaction waited for the backup.
+    assert_eq!(*events.lock().unwrap(), vec!["resume", "compaction"]);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    target.restore(&backup, restore_dir.path());
+    let restored = KvStore::open(restore_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            restored.get(format!("key{}", key_id))?,
+            Some("before".to_owned())
+        );
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("after19".to_owned()));
+    Ok(())
+}
+
+// Should replace the data set with a snapshot while the store stays open
+#[test]
+fn restore_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "before".to_owned())?;
+    }
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = store.snapshot(dest.path())?;
+
+    store.set("key0".to_owned(), "after".to_owned())?;
+    store.set("new".to_owned(), "after".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let reader = store.clone();
+    store.restore(&snapshot)?;
+    assert_eq!(reader.get("key0".to_owned())?, Some("before".to_owned()));
+    assert_eq!(reader.get("key1".to_owned())?, Some("before".to_owned()));
+    assert_eq!(reader.get("new".to_owned())?, None);
+    assert_eq!(store.stats()?.keys, 100);
+
+    // Writes after the restore go on from the restored data set.
+    store.set("key2".to_owned(), "restored".to_owned())?;
+    drop(reader);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("before".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("restored".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, None);
+
+    // A directory without log files is refused, and the data set kept.
+    let empty = TempDir::new().expect("unable to create temporary working directory");
+    assert!(store.restore(empty.path()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("restored".to_owned()));
+    Ok(())
+}
+
+// Should back up to a directory, linking the log files which never change
+#[test]
+fn backup_to_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "compacted".to_owned())?;
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "active".to_owned())?;
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let backup = dest.path().join("backup");
+    store.backup(&backup)?;
+    assert!(store.backup(&backup).is_err());
+    store.set("key1".to_owned(), "after".to_owned())?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let linked = fs::read_dir(&backup)?
+            .map(|entry| entry.unwrap().metadata().unwrap())
+            .filter(|metadata| metadata.nlink() > 1)
+            .count();
+        assert_eq!(linked, 1);
+    }
+
+    let reader = store.clone();
+    store.restore(&backup)?;
+    assert_eq!(reader.get("key0".to_owned())?, Some("active".to_owned()));
+    assert_eq!(reader.get("key1".to_owned())?, Some("compacted".to_owned()));
+    drop(reader);
+    drop(store);
+    let opened = KvStore::open(&backup)?;
+    assert_eq!(opened.get("key0".to_owned())?, Some("active".to_owned()));
+    assert_eq!(
+        opened.get("key99".to_owned())?,
+        Some("compacted".to_owned())
+    );
+    Ok(())
+}
+
+// Should back up only the log files created or grown since the previous
+// backup of a chain, and restore from the whole chain
+#[test]
+fn incremental_backups() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "first".to_owned())?;
+    }
+    store.compact()?;
+    let chain = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(store.backup_incremental(chain.path())?, "increment-1");
+
+    store.set("key0".to_owned(), "second".to_owned())?;
+    assert_eq!(store.backup_incremental(chain.path())?, "increment-2");
+    let logs = |increment: &str| -> Vec<String> {
+        let mut logs: Vec<String> = fs::read_dir(chain.path().join(increment))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        logs.sort();
+        logs
+    };
+    // The compacted log file is only in the first backup.
+    assert_eq!(logs("increment-1").len(), 2);
+    assert_eq!(logs("increment-2").len(), 1);
+
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "third".to_owned())?;
+    assert_eq!(store.backup_incremental(chain.path())?, "increment-3");
+    store.set("key3".to_owned(), "after".to_owned())?;
+
+    let reader = store.clone();
+    store.restore_incremental(chain.path())?;
+    assert_eq!(reader.get("key0".to_owned())?, Some("second".to_owned()));
+    assert_eq!(reader.get("key1".to_owned())?, None);
+    assert_eq!(reader.get("key2".to_owned())?, Some("third".to_owned()));
+    assert_eq!(reader.get("key3".to_owned())?, Some("first".to_owned()));
+    assert_eq!(store.stats()?.keys, 99);
+    assert!(!chain.path().join("restore.tmp").exists());
+
+    // An empty chain is refused, and the data set kept.
+    let empty = TempDir::new().expect("unable to create temporary working directory");
+    assert!(store.restore_incremental(empty.path()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("third".to_owned()));
+    Ok(())
+}
@@ -1,6 +1,10 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -171,6 +175,106 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+#[test]
+fn cli_json_log_format() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&["--log-format", "json", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    // The request is logged as handled after the response is sent.
+    thread::sleep(Duration::from_millis(200));
+    child.kill().expect("server exited before killed");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    let events: Vec<serde_json::Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("log line is not JSON"))
+        .collect();
+    assert!(events.iter().all(|event| event.get("timestamp").is_some()));
+    assert!(events.iter().all(|event| event.get("level").is_some()));
+    assert!(events.iter().any(|event| {
+        event["span"]["op"] == "set"
+            && event["span"]["key"] == "key1"
+            && event.get("duration_us").is_some()
+    }));
+}
+
+#[test]
+fn cli_latency_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = cmd
+        .args(&[
+            "--addr",
+            "127.0.0.1:4007",
+            "--metrics-addr",
+            "127.0.0.1:4008",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("p99(us)")
+                .and(contains("get"))
+                .and(contains("set")),
+        );
+
+    let mut stream = TcpStream::connect("127.0.0.1:4008").unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let mut stream = TcpStream::connect("127.0.0.1:4008").unwrap();
+    stream
+        .write_all(b"GET /ready HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut ready = String::new();
+    stream.read_to_string(&mut ready).unwrap();
+    child.kill().expect("server exited before killed");
+
+    assert!(ready.starts_with("HTTP/1.1 200 OK"));
+    assert!(ready.ends_with("ready\n"));
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("kvs_request_duration_seconds{op=\"get\",quantile=\"0.99\"}"));
+    assert!(response.contains("kvs_request_duration_seconds_count{op=\"set\"} 1"));
+}
+
+#[cfg(feature = "sled")]
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -275,6 +379,88 @@ fn cli_access_server(engine: &str, addr: &str) {
         .failure()
         .stderr(contains("Key not found"));
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--ignore-missing", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value3", "--nx", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value4", "--nx", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key exists"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key4", "value4", "--xx", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value5", "--xx", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value5\n");
+
+    if engine == "kvs" {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["expire", "key4", "60", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("Key not found"));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["expire", "key3", "60", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["persist", "key3", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["persist", "key3", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("Key not found or has no expiry\n");
+    }
+
     Command::cargo_bin("kvs-client")
         .unwrap()
         .args(&["set", "key2", "value3", "--addr", addr])
@@ -331,7 +517,369 @@ fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
 
+#[cfg(feature = "sled")]
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Without the `sled` feature, `kvs-server --engine sled` should fail and not
+// claim the directory for sled.
+#[cfg(not(feature = "sled"))]
+#[test]
+fn cli_sled_engine_unavailable() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4005"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("without the sled feature"));
+    assert!(!temp_dir.path().join("engine").exists());
+}
+
+// `kvs debug dump-log` should list the records and flag corrupted ones.
+#[test]
+fn cli_debug_dump_log() {
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("1.log");
+    fs::write(
+        &path,
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}{\"Remove\":{\"key\":\"key1\"}}",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["debug", "dump-log", path.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(
+            contains("set")
+                .and(contains("remove"))
+                .and(contains("2 records, 0 invalid")),
+        );
+
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"garbage")
+        .unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["debug", "dump-log", path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stdout(contains("invalid").and(contains("3 records, 1 invalid")));
+}
+
+// `kvs debug segments` should summarize the compacted log files.
+#[test]
+fn cli_debug_segments() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["debug", "segments", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("0 compacted log files, about 0 keys"));
+}
+
+// `kvs-admin restore` should swap a backup into a running server.
+#[test]
+fn cli_admin_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let backup_root = TempDir::new().unwrap();
+    fs::create_dir(backup_root.path().join("backup")).unwrap();
+    fs::write(
+        backup_root.path().join("backup").join("1.log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"restored\"}}",
+    )
+    .unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            "127.0.0.1:4036",
+            "--admin-addr",
+            "127.0.0.1:4054",
+            "--backup-root",
+            backup_root.path().to_str().unwrap(),
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4036"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", "127.0.0.1:4036"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["restore", "--from", "backup", "--addr", "127.0.0.1:4036"])
+        .assert()
+        .failure()
+        .stderr(contains("only served on the admin address"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["restore", "--from", "backup", "--addr", "127.0.0.1:4054"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4036"])
+        .assert()
+        .success()
+        .stdout("restored\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", "127.0.0.1:4036"])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["restore", "--from", "missing", "--addr", "127.0.0.1:4054"])
+        .assert()
+        .failure();
+    // Backups out of the backup root are rejected, the data directory of the
+    // server included.
+    for from in &["..", temp_dir.path().to_str().unwrap()] {
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(&["restore", "--from", from, "--addr", "127.0.0.1:4054"])
+            .assert()
+            .failure()
+            .stderr(contains("is not in the backup root"));
+    }
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-admin compact` should compact the data set of a running server.
+#[test]
+fn cli_admin_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4049"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4049"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["compact", "--addr", "127.0.0.1:4049"])
+        .assert()
+        .success()
+        .stdout("Compacted\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4049"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-admin clients` should list the connections, and `kvs-admin kill` close one.
+#[test]
+fn cli_admin_clients() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "127.0.0.1:4038", "--admin-addr", "127.0.0.1:4057"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["clients", "--addr", "127.0.0.1:4038"])
+        .assert()
+        .failure()
+        .stderr(contains("only served on the admin address"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["clients", "--addr", "127.0.0.1:4057"])
+        .assert()
+        .success()
+        .stdout(contains("LAST_OP").and(contains("client_list")));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["kill", "--id", "1000", "--addr", "127.0.0.1:4057"])
+        .assert()
+        .failure()
+        .stderr(contains("No connection 1000"));
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server --pool --threads` should serve with the chosen thread pool.
+#[test]
+fn cli_server_thread_pool() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--pool", "fibers", "--addr", "127.0.0.1:4040"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--threads", "0", "--addr", "127.0.0.1:4040"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    for pool in &["shared-queue", "naive"] {
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--pool", pool, "--threads", "2", "--addr", "127.0.0.1:4040"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", pool, "--addr", "127.0.0.1:4040"])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", "127.0.0.1:4040"])
+            .assert()
+            .success()
+            .stdout(format!("{}\n", pool));
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+    }
+}
+
+// `kvs-server --read-only` should reject writes until `kvs-admin read-only off`.
+#[test]
+fn cli_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--read-only",
+            "--addr",
+            "127.0.0.1:4042",
+            "--admin-addr",
+            "127.0.0.1:4053",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4042"])
+        .assert()
+        .failure()
+        .stderr(contains("Server is read-only"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["read-only", "off", "--addr", "127.0.0.1:4042"])
+        .assert()
+        .failure()
+        .stderr(contains("only served on the admin address"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(&["read-only", "off", "--addr", "127.0.0.1:4053"])
+        .assert()
+        .success()
+        .stdout("Read-only mode off\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4042"])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server --addr` should be repeatable, and accept IPv6 addresses.
+#[test]
+fn cli_multiple_addresses() {
+    let temp_dir = TempDir::new().unwrap();
+    // IPv6 is skipped where the loopback has no IPv6 address.
+    let ipv6 = TcpListener::bind("[::1]:0").is_ok();
+    let mut args = vec!["--addr", "127.0.0.1:4047"];
+    if ipv6 {
+        args.extend(&["--addr", "[::1]:4047"]);
+    }
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&args)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4047"])
+        .assert()
+        .success();
+    if ipv6 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", "[::1]:4047"])
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server --shadow-engine` should mirror the writes to the shadow engine.
+#[test]
+fn cli_shadow_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let shadow_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--shadow-engine",
+            "kvs",
+            "--shadow-dir",
+            shadow_dir.path().to_str().unwrap(),
+            "--addr",
+            "127.0.0.1:4048",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4048"])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(200));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let shadow = KvStore::open(shadow_dir.path()).unwrap();
+    assert_eq!(
+        shadow.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
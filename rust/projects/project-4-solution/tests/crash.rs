@@ -0,0 +1,16 @@
+use kvs::testing::CrashTest;
+use kvs::{KvStore, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+// Should keep every acknowledged write after being killed mid-workload
+#[test]
+fn kv_store_survives_crashes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    CrashTest::new("kv_store_survives_crashes", |path: &Path| {
+        KvStore::open(path)
+    })
+    .ops(2000)
+    .runs(5)
+    .run(temp_dir.path())
+}
@@ -0,0 +1,92 @@
+use kvs::debug::{dump_log, segments};
+use kvs::{CompactionEvent, KvStore, KvsEngine, Result};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+// Should decode every record of a log file
+#[test]
+fn dump_valid_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let records = dump_log(temp_dir.path().join("1.log"))?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].offset, 0);
+    assert_eq!(records[0].op, "set");
+    assert_eq!(records[0].key, Some("key1".to_owned()));
+    assert_eq!(records[0].value_len, Some(6));
+    assert_eq!(records[1].offset, records[0].len);
+    assert_eq!(records[1].op, "remove");
+    assert!(records.iter().all(|record| record.is_valid()));
+    assert_ne!(records[0].checksum, records[1].checksum);
+    Ok(())
+}
+
+// Should report the corrupted tail of a log file as invalid
+#[test]
+fn dump_corrupted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let valid_len = path.metadata()?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&path)?
+        .write_all(b"{\"Set\":{\"key\":\"ke")?;
+
+    let records = dump_log(&path)?;
+    assert_eq!(records.len(), 2);
+    assert!(records[0].is_valid());
+    assert!(!records[1].is_valid());
+    assert_eq!(records[1].op, "invalid");
+    assert_eq!(records[1].offset, valid_len);
+    assert_eq!(records[1].len, 17);
+    Ok(())
+}
+
+// Should summarize the compacted log file in the manifest
+#[test]
+fn compacted_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(segments(temp_dir.path())?.is_empty());
+
+    let store = KvStore::open(temp_dir.path())?;
+    let finished = Arc::new(Mutex::new(false));
+    {
+        let finished = Arc::clone(&finished);
+        store.on_compaction(move |event| {
+            if let CompactionEvent::Finished { .. } = event {
+                *finished.lock().unwrap() = true;
+            }
+        });
+    }
+    for iter in 0..100 {
+        for key_id in 10..110 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if *finished.lock().unwrap() {
+            break;
+        }
+    }
+    assert!(*finished.lock().unwrap(), "no compaction finished");
+    drop(store);
+
+    let segments = segments(temp_dir.path())?;
+    assert_eq!(segments.len(), 1);
+    let segment = &segments[0];
+    assert_eq!(segment.keys, 100);
+    assert_eq!(segment.min_key.as_deref(), Some("key10"));
+    assert_eq!(segment.max_key.as_deref(), Some("key99"));
+    assert!((10..110).all(|key_id| segment.may_contain(&format!("key{}", key_id))));
+    assert!(!segment.may_contain("key0"));
+    assert!(!segment.may_contain("zzz"));
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::io;
+
+use kvs::{ErrorKind, KvsError};
+
+#[test]
+fn transient_io_errors_are_retryable() {
+    for kind in &[
+        io::ErrorKind::TimedOut,
+        io::ErrorKind::ConnectionReset,
+        io::ErrorKind::ConnectionAborted,
+        io::ErrorKind::BrokenPipe,
+        io::ErrorKind::WouldBlock,
+        io::ErrorKind::Interrupted,
+    ] {
+        let err = KvsError::from(io::Error::new(*kind, "transient"));
+        assert!(err.is_retryable(), "{:?} should be retryable", kind);
+    }
+}
+
+#[test]
+fn busy_servers_and_conflicts_are_retryable() {
+    assert_eq!(KvsError::ServerBusy.kind(), ErrorKind::Busy);
+    assert!(KvsError::ServerBusy.is_retryable());
+
+    assert_eq!(KvsError::TransactionConflict.kind(), ErrorKind::Conflict);
+    assert!(KvsError::TransactionConflict.is_retryable());
+}
+
+#[test]
+fn permanent_errors_are_not_retryable() {
+    assert_eq!(KvsError::KeyNotFound.kind(), ErrorKind::KeyNotFound);
+    assert!(!KvsError::KeyNotFound.is_retryable());
+
+    assert_eq!(
+        KvsError::UnexpectedCommandType.kind(),
+        ErrorKind::Corruption
+    );
+    assert!(!KvsError::UnexpectedCommandType.is_retryable());
+
+    let err = KvsError::Corruption { gen: 1, offset: 0 };
+    assert_eq!(err.kind(), ErrorKind::Corruption);
+    assert!(!err.is_retryable());
+
+    let err = KvsError::from(serde_json::from_str::<u32>("{").unwrap_err());
+    assert!(!err.is_retryable());
+
+    assert_eq!(KvsError::DiskFull.kind(), ErrorKind::DiskFull);
+    assert!(!KvsError::DiskFull.is_retryable());
+
+    assert_eq!(KvsError::ReadOnly.kind(), ErrorKind::ReadOnly);
+    assert!(!KvsError::ReadOnly.is_retryable());
+
+    let err = KvsError::from(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+    assert_eq!(err.kind(), ErrorKind::Io);
+    assert!(!err.is_retryable());
+
+    // A record cut short in a log file is not fixed by retrying.
+    let err = KvsError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+    assert_eq!(err.kind(), ErrorKind::Io);
+    assert!(!err.is_retryable());
+}
+
+#[test]
+fn error_kind_display() {
+    assert_eq!(ErrorKind::KeyNotFound.to_string(), "key not found");
+    assert_eq!(KvsError::KeyNotFound.kind().to_string(), "key not found");
+}
@@ -0,0 +1,221 @@
+//! Crash-consistency tests driven by the fault-injection points of `KvStore`.
+//!
+//! Run with `cargo test --features failpoints`.
+#![cfg(feature = "failpoints")]
+
+use std::sync::{Arc, Mutex};
+
+use fail::FailScenario;
+use kvs::{CompactionEvent, KvStore, KvsEngine, Result};
+use tempfile::TempDir;
+
+// A record written to the log before the index is updated is not visible
+// until the store is reopened and the log replayed.
+#[test]
+fn crash_after_write_before_index_update() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    fail::cfg("kvs::write::after", "return").unwrap();
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    fail::remove("kvs::write::after");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    scenario.teardown();
+    Ok(())
+}
+
+// A failed write leaves the store usable.
+#[test]
+fn failed_write_is_not_persisted() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    fail::cfg("kvs::write", "return(disk on fire)").unwrap();
+    let err = store
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("disk on fire"));
+    fail::remove("kvs::write");
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    scenario.teardown();
+    Ok(())
+}
+
+// A failed compaction keeps all data and is reported to the listeners.
+#[test]
+fn failed_compaction_keeps_data() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let failures = Arc::new(Mutex::new(0));
+    {
+        let failures = Arc::clone(&failures);
+        store.on_compaction(move |event| {
+            if let CompactionEvent::Failed { .. } = event {
+                *failures.lock().unwrap() += 1;
+            }
+        });
+    }
+
+    fail::cfg("kvs::compaction::copy", "return").unwrap();
+    let mut errors = 0;
+    for iter in 0..20 {
+        for key_id in 0..10 {
+            if store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .is_err()
+            {
+                errors += 1;
+            }
+        }
+    }
+    fail::remove("kvs::compaction::copy");
+    assert!(errors > 0);
+    assert_eq!(*failures.lock().unwrap(), errors);
+    assert_eq!(store.compaction_stats().failures, errors);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    scenario.teardown();
+    Ok(())
+}
+
+// A restore interrupted once its log files are staged is finished on open.
+#[test]
+fn interrupted_restore_is_finished_on_open() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "before".to_owned())?;
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = store.snapshot(dest.path())?;
+    store.set("key1".to_owned(), "after".to_owned())?;
+    store.set("key2".to_owned(), "after".to_owned())?;
+
+    fail::cfg("kvs::restore::install", "return").unwrap();
+    assert!(store.restore(&snapshot).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    fail::remove("kvs::restore::install");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("before".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    scenario.teardown();
+    Ok(())
+}
+
+// A bulk load synced but not renamed into place by a crash loads nothing,
+// and can be retried.
+#[test]
+fn crash_after_bulk_load_sync_before_rename() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "before".to_owned())?;
+    let pairs = || (1..10).map(|i| (format!("key{}", i), "loaded".to_owned()));
+
+    fail::cfg("kvs::bulk_load::rename", "return").unwrap();
+    assert!(store.bulk_load(pairs()).is_err());
+    fail::remove("kvs::bulk_load::rename");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("before".to_owned()));
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, None);
+    }
+
+    assert_eq!(store.bulk_load(pairs())?, 9);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("before".to_owned()));
+    for i in 1..10 {
+        assert_eq!(store.get(format!("key{}", i))?, Some("loaded".to_owned()));
+    }
+
+    scenario.teardown();
+    Ok(())
+}
+
+// A hint file synced but not renamed into place by a crash leaves the
+// compaction file to be replayed from its records.
+#[test]
+fn crash_after_hint_sync_before_rename() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    fail::cfg("kvs::hint::rename", "return").unwrap();
+    store.compact()?;
+    fail::remove("kvs::hint::rename");
+    let hints = std::fs::read_dir(temp_dir.path())?
+        .filter(|entry| {
+            let path = entry.as_ref().unwrap().path();
+            path.extension().is_some_and(|ext| ext == "hint")
+        })
+        .count();
+    assert_eq!(hints, 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("4".to_owned()));
+    }
+
+    scenario.teardown();
+    Ok(())
+}
+
+// A checkpoint synced but not renamed into place by a crash is not loaded:
+// the store is replayed from the log.
+#[test]
+fn crash_after_checkpoint_sync_before_rename() -> Result<()> {
+    let scenario = FailScenario::setup();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    fail::cfg("kvs::checkpoint::rename", "return").unwrap();
+    assert!(store.checkpoint().is_err());
+    fail::remove("kvs::checkpoint::rename");
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.checkpoint()?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    scenario.teardown();
+    Ok(())
+}
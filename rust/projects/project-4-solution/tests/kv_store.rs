@@ -1,6 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::clock::ManualClock;
+use kvs::debug::dump_log;
+use kvs::vfs::OsFs;
+use kvs::{
+    AsOf, Change, CompactionEvent, Compression, ErrorKind, ExportFormat, KvStore, KvsEngine,
+    KvsError, Result, Scan, SnapshotRetention, WarmUp, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -71,6 +80,18 @@ fn remove_non_existent_key() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn remove_if_exists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove_if_exists("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.remove_if_exists("key1".to_owned())?);
+    Ok(())
+}
+
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -127,6 +148,53 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+#[test]
+fn compaction_events() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+        let events = Arc::clone(&events);
+        store.on_compaction(move |event| events.lock().unwrap().push(event.clone()));
+    }
+
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if !events.lock().unwrap().is_empty() {
+            break;
+        }
+    }
+
+    // Only look at the first compaction.
+    let events = events.lock().unwrap();
+    let end = events
+        .iter()
+        .position(|event| matches!(event, CompactionEvent::Finished { .. }))
+        .expect("no compaction finished");
+    let events = &events[..=end];
+    match events.first() {
+        Some(CompactionEvent::Started { entries, .. }) => assert_eq!(*entries, 100),
+        other => panic!("expected a started event, got {:?}", other),
+    }
+    let percents: Vec<u8> = events
+        .iter()
+        .filter_map(|event| match event {
+            CompactionEvent::Progress { percent, .. } => Some(*percent),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(percents, vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 100]);
+    match events.last() {
+        Some(CompactionEvent::Finished {
+            bytes_reclaimed, ..
+        }) => assert!(*bytes_reclaimed > 0),
+        other => panic!("expected a finished event, got {:?}", other),
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -158,6 +226,34 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Should not fail reads while concurrent writes trigger compactions
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..2000 {
+                let key = format!("key{}", (i + thread_id * 25) % 100);
+                store.get(key).unwrap();
+            }
+        }));
+    }
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 100), "value".to_owned())?;
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +305,2189 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// One `KvStore` shared behind an `Arc` should serve reads and writes from many threads.
+#[test]
+fn shared_through_arc() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(KvStore::open(temp_dir.path())?);
+
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = Arc::clone(&store);
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key = format!("key{}-{}", thread_id, i);
+                store.set(key.clone(), format!("value{}", i)).unwrap();
+                assert_eq!(store.get(key).unwrap(), Some(format!("value{}", i)));
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn entry_api() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.entry("key1".to_owned()).key(), "key1");
+    assert_eq!(
+        store
+            .entry("key1".to_owned())
+            .or_insert("value1".to_owned())?,
+        "value1"
+    );
+    assert_eq!(
+        store
+            .entry("key1".to_owned())
+            .or_insert_with(|| panic!("the key is present"))?,
+        "value1"
+    );
+    assert_eq!(
+        store
+            .entry("key1".to_owned())
+            .and_modify(|value| value.push('!'))?
+            .or_insert("unused".to_owned())?,
+        "value1!"
+    );
+    store
+        .entry("key2".to_owned())
+        .and_modify(|_| panic!("the key is missing"))?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1!".to_owned()));
+    Ok(())
+}
+
+// Should not lose updates made through entries from concurrent threads
+#[test]
+fn entry_is_atomic() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                store
+                    .entry("counter".to_owned())
+                    .and_modify(|n| *n = (n.parse::<u64>().unwrap() + 1).to_string())
+                    .unwrap()
+                    .or_insert("1".to_owned())
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn update() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let new = store.update("key1".to_owned(), |old| {
+        assert_eq!(old, None);
+        Some("value1".to_owned())
+    })?;
+    assert_eq!(new, Some("value1".to_owned()));
+    let new = store.update("key1".to_owned(), |old| old.map(|old| old + "!"))?;
+    assert_eq!(new, Some("value1!".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1!".to_owned()));
+
+    // Returning `None` removes the key, missing or not.
+    assert_eq!(store.update("key1".to_owned(), |_| None)?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.update("key2".to_owned(), |_| None)?, None);
+
+    // Concurrent increments are not lost.
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                store
+                    .update("counter".to_owned(), |old| {
+                        let n: u64 = old.map_or(0, |old| old.parse().unwrap());
+                        Some((n + 1).to_string())
+                    })
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn insert() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.insert("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.insert("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Every value but the last one is replaced exactly once.
+    let replaced = Arc::new(Mutex::new(Vec::new()));
+    let mut handles = Vec::new();
+    for t in 0..4 {
+        let store = store.clone();
+        let replaced = Arc::clone(&replaced);
+        handles.push(thread::spawn(move || {
+            for i in 0..100 {
+                let old = store.insert("shared".to_owned(), format!("{}", t * 100 + i));
+                if let Some(old) = old.unwrap() {
+                    replaced.lock().unwrap().push(old);
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let mut values = replaced.lock().unwrap().clone();
+    values.extend(store.get("shared".to_owned())?);
+    values.sort_by_key(|value| value.parse::<u32>().unwrap());
+    assert_eq!(values, (0..400).map(|i| i.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
+
+#[test]
+fn take() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.take("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.take("key1".to_owned())?, None);
+
+    // Every job of a queue is taken exactly once.
+    for i in 0..100 {
+        store.set(format!("job{}", i), format!("{}", i))?;
+    }
+    let taken = Arc::new(Mutex::new(Vec::new()));
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let store = store.clone();
+        let taken = Arc::clone(&taken);
+        handles.push(thread::spawn(move || {
+            for i in 0..100 {
+                if let Some(job) = store.take(format!("job{}", i)).unwrap() {
+                    taken.lock().unwrap().push(job);
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let mut taken = taken.lock().unwrap().clone();
+    taken.sort_by_key(|job| job.parse::<u32>().unwrap());
+    assert_eq!(taken, (0..100).map(|i| i.to_string()).collect::<Vec<_>>());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("job0".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("blue".to_owned(), "v1".to_owned())?;
+    store.set("green".to_owned(), "v2".to_owned())?;
+
+    store.swap("blue".to_owned(), "green".to_owned())?;
+    assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(store.get("green".to_owned())?, Some("v1".to_owned()));
+
+    // A missing key takes the value of the other one.
+    store.swap("green".to_owned(), "missing".to_owned())?;
+    assert_eq!(store.get("green".to_owned())?, None);
+    assert_eq!(store.get("missing".to_owned())?, Some("v1".to_owned()));
+    store.swap("none1".to_owned(), "none2".to_owned())?;
+    assert_eq!(store.get("none1".to_owned())?, None);
+    assert_eq!(store.get("none2".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("blue".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(store.get("missing".to_owned())?, Some("v1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn conditional_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.set_xx("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_nx("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.set_xx("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    // Only one of the threads racing for a lock gets it.
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            store
+                .set_nx("lock".to_owned(), format!("{}", thread_id))
+                .unwrap()
+        }));
+    }
+    let acquired = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|&acquired| acquired)
+        .count();
+    assert_eq!(acquired, 1);
+    Ok(())
+}
+
+#[test]
+fn iterate_in_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["b", "c", "a", "d"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let pairs: Vec<(String, String)> = store.iter().collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "value-a".to_owned()),
+            ("b".to_owned(), "value-b".to_owned()),
+            ("d".to_owned(), "value-d".to_owned()),
+        ]
+    );
+
+    let mut keys = Vec::new();
+    for pair in &store {
+        keys.push(pair?.0);
+    }
+    assert_eq!(keys, vec!["a", "b", "d"]);
+
+    let values: Vec<String> = store
+        .clone()
+        .into_iter()
+        .map(|pair| pair.map(|(_, value)| value))
+        .collect::<Result<_>>()?;
+    assert_eq!(values, vec!["value-a", "value-b", "value-d"]);
+    assert_eq!(store.get("a".to_owned())?, Some("value-a".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn keys_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let store = KvStore::open_with_vfs(temp_dir.path(), Arc::new(OsFs), Arc::new(clock.clone()))?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+    assert_eq!(store.keys().next(), None);
+
+    for key in &["b", "c", "a", "d"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+    store.expire("a".to_owned(), Duration::from_secs(10))?;
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["a", "b", "d"]);
+
+    // Expired keys are left out before they are compacted.
+    clock.advance(Duration::from_secs(10));
+    assert!(!store.is_empty());
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.keys().collect::<Vec<_>>(), ["b", "d"]);
+    Ok(())
+}
+
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key3".to_owned())?;
+    drop(store);
+
+    // The values are spread over two log files.
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    let keys: Vec<String> = ["key5", "key1", "key3", "key10", "key0", "key5"]
+        .iter()
+        .map(|key| key.to_string())
+        .collect();
+    let expected = vec![
+        Some("value5".to_owned()),
+        Some("value1b".to_owned()),
+        None,
+        None,
+        Some("value0".to_owned()),
+        Some("value5".to_owned()),
+    ];
+    assert_eq!(store.get_many(&keys)?, expected);
+    assert_eq!(store.get_many(&[])?, Vec::<Option<String>>::new());
+
+    compact_with_filler(&store)?;
+    assert_eq!(store.get_many(&keys)?, expected);
+    Ok(())
+}
+
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let store = KvStore::open_with_vfs(temp_dir.path(), Arc::new(OsFs), Arc::new(clock.clone()))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    assert!(store.contains_key("key1"));
+    assert!(store.contains_key("key2"));
+    assert!(!store.contains_key("key3"));
+    assert!(!store.contains_key("key4"));
+
+    clock.advance(Duration::from_secs(10));
+    assert!(!store.contains_key("key2"));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key3"));
+    Ok(())
+}
+
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["b", "c", "a", "d", "e"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    let keys = |pairs: Scan| -> Result<Vec<String>> {
+        pairs.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    let pairs: Vec<(String, String)> = store
+        .scan("b".to_owned().."e".to_owned())
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("b".to_owned(), "value-b".to_owned()),
+            ("d".to_owned(), "value-d".to_owned()),
+        ]
+    );
+    assert_eq!(
+        keys(store.scan("b".to_owned()..="e".to_owned()))?,
+        ["b", "d", "e"]
+    );
+    assert_eq!(keys(store.scan("bb".to_owned()..))?, ["d", "e"]);
+    assert_eq!(keys(store.scan(..="a".to_owned()))?, ["a"]);
+    assert_eq!(keys(store.scan(..))?.len(), 4);
+    assert_eq!(store.scan("f".to_owned()..).count(), 0);
+    Ok(())
+}
+
+#[test]
+fn drain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let drained: Vec<(String, String)> = store.drain().take(3).collect::<Result<_>>()?;
+    assert_eq!(drained[0], ("key0".to_owned(), "value0".to_owned()));
+    assert_eq!(drained.len(), 3);
+    assert_eq!(store.iter().count(), 7);
+
+    assert_eq!(store.drain().count(), 7);
+    assert_eq!(store.iter().count(), 0);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().count(), 0);
+    Ok(())
+}
+
+#[test]
+fn extend_and_open_from_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs = (0..3000).map(|i| (format!("key{}", i), format!("value{}", i)));
+    let mut store = KvStore::open_from_iter(temp_dir.path(), pairs)?;
+    assert_eq!(store.iter().count(), 3000);
+
+    store.extend(vec![
+        ("key0".to_owned(), "new".to_owned()),
+        ("extra".to_owned(), "value".to_owned()),
+    ]);
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().count(), 3001);
+    for i in 1..3000 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("extra".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn warm_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("user{}", i), format!("value{}", i))?;
+        store.set(format!("item{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.warm_up(WarmUp::Prefix("user".to_owned()))?, 10);
+    assert_eq!(store.warm_up(WarmUp::Prefix("".to_owned()))?, 20);
+    assert_eq!(store.warm_up(WarmUp::Prefix("missing".to_owned()))?, 0);
+    assert_eq!(
+        store.warm_up(WarmUp::Keys(vec![
+            "item3".to_owned(),
+            "missing".to_owned(),
+            "user7".to_owned(),
+        ]))?,
+        2
+    );
+    assert_eq!(store.get("user7".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
+
+// Should load sorted pairs all at once, or none of them
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0001".to_owned(), "old".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let pairs = (0..3000).map(|i| (format!("key{:04}", i), format!("value{}", i)));
+    assert_eq!(store.bulk_load(pairs)?, 3000);
+    assert_eq!(store.get("key0001".to_owned())?, Some("value1".to_owned()));
+    store.set("key0002".to_owned(), "new".to_owned())?;
+
+    let unsorted = vec![
+        ("zz".to_owned(), "value".to_owned()),
+        ("key0003".to_owned(), "bad".to_owned()),
+    ];
+    assert!(store.bulk_load(unsorted).is_err());
+    assert_eq!(store.get("zz".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().count(), 3001);
+    assert_eq!(store.get("key0002".to_owned())?, Some("new".to_owned()));
+    assert_eq!(
+        store.get("key2999".to_owned())?,
+        Some("value2999".to_owned())
+    );
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    let leftovers = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .to_string_lossy()
+                .ends_with(".tmp")
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+    Ok(())
+}
+
+// Should replay many log files in generation order
+#[test]
+fn replay_many_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for round in 0..20 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", round), format!("value{}", round))?;
+        store.set("last".to_owned(), format!("{}", round))?;
+        if round > 0 {
+            store.remove(format!("key{}", round - 1))?;
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("last".to_owned())?, Some("19".to_owned()));
+    assert_eq!(store.get("key19".to_owned())?, Some("value19".to_owned()));
+    for round in 0..19 {
+        assert_eq!(store.get(format!("key{}", round))?, None);
+    }
+    Ok(())
+}
+
+// Should read the JSON records of logs written by older versions, next to the
+// binary records written since
+#[test]
+fn read_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\
+         {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\
+         {\"Remove\":{\"key\":\"key2\"}}",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    compact_with_filler(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should detect the records failing their checksum, on load and on read
+#[test]
+fn detect_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&path)?;
+    let value_pos = log.windows(6).position(|w| w == b"value2").unwrap();
+    log[value_pos] = b'V';
+    fs::write(&path, &log)?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let err = store.get("key2".to_owned()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Corruption);
+    let offset = match err {
+        KvsError::Corruption { gen: 1, offset } => offset,
+        err => panic!("unexpected error: {}", err),
+    };
+    assert!(offset > 0 && offset < value_pos as u64);
+    drop(store);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { gen: 1, offset: at }) => assert_eq!(at, offset),
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Should fail a recovery reading a corrupted record rather than recover a part
+// of the data set, and refuse a destination with files before reading the log
+#[test]
+fn recover_to_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("db"))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let point = AsOf::Seq(store.last_seq());
+
+    let path = temp_dir.path().join("db").join("1.log");
+    let mut log = fs::read(&path)?;
+    let value_pos = log.windows(6).position(|w| w == b"value2").unwrap();
+    log[value_pos] = b'V';
+    fs::write(&path, &log)?;
+
+    let err = store
+        .recover_to(temp_dir.path().join("recovered"), point)
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Corruption);
+
+    let dest = temp_dir.path().join("taken");
+    fs::create_dir(&dest)?;
+    fs::write(dest.join("file"), b"")?;
+    let err = store.recover_to(&dest, point).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Io);
+    Ok(())
+}
+
+// Should compress the records of large values, and read them without the setting
+#[test]
+fn compression() -> Result<()> {
+    let large = format!("{{\"items\": [{}]}}", vec!["\"item\""; 200].join(", "));
+    for &codec in &[Compression::Lz4, Compression::Snappy] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .compression(codec, 100)
+            .open(temp_dir.path())?;
+        store.set("small".to_owned(), "value".to_owned())?;
+        store.set("large".to_owned(), large.clone())?;
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        drop(store);
+
+        let records = dump_log(temp_dir.path().join("1.log"))?;
+        assert_eq!(records[0].value_len, Some(5));
+        assert_eq!(records[1].value_len, Some(large.len()));
+        assert!(records[1].len < large.len() as u64 / 4);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+        store.compact()?;
+        assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    }
+    Ok(())
+}
+
+// Should compress the files written by compactions, and read them back
+#[test]
+fn segment_compression() -> Result<()> {
+    let fill = |store: &KvStore| -> Result<()> {
+        for i in 0..2000 {
+            store.set(
+                format!("key{:04}", i),
+                format!("{{\"value\": {}}}", i).repeat(5),
+            )?;
+        }
+        store.compact()
+    };
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1 << 30)
+        .open(plain_dir.path())?;
+    fill(&store)?;
+    let plain_bytes = store.stats()?.disk_bytes;
+
+    for &codec in &[Compression::Lz4, Compression::Snappy] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .compaction_threshold(1 << 30)
+            .segment_compression(codec)
+            .open(temp_dir.path())?;
+        fill(&store)?;
+        assert!(store.stats()?.disk_bytes < plain_bytes / 2);
+        let value = |i: usize| Some(format!("{{\"value\": {}}}", i).repeat(5));
+        assert_eq!(store.get("key0000".to_owned())?, value(0));
+        assert_eq!(store.get("key1999".to_owned())?, value(1999));
+        store.set("key0000".to_owned(), "new".to_owned())?;
+        drop(store);
+
+        // Without its hint file, the compaction file is replayed from its records.
+        for entry in fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("hint") {
+                fs::remove_file(path)?;
+            }
+        }
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0000".to_owned())?, Some("new".to_owned()));
+        assert_eq!(store.get("key1234".to_owned())?, value(1234));
+        assert_eq!(store.keys().count(), 2000);
+        let records = dump_log(temp_dir.path().join("2.log"))?;
+        assert_eq!(records.len(), 2000);
+        assert!(records.iter().all(|record| record.op == "set"));
+    }
+    Ok(())
+}
+
+// Should read through memory maps the values written since the mapping, and
+// the values of compressed log files
+#[cfg(feature = "mmap")]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1 << 30)
+        .segment_compression(Compression::Lz4)
+        .open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    }
+    store.compact()?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+
+    let store = KvStore::builder().mmap_reads(false).open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Should keep the index under its budget, the entries of the compaction file
+// being looked up in its hint file
+#[test]
+fn index_budget() -> Result<()> {
+    let fill = |store: &KvStore| -> Result<()> {
+        for i in 0..2000 {
+            store.set(format!("key{:04}", i), format!("value{}", i))?;
+        }
+        store.compact()
+    };
+    let full_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1 << 30)
+        .open(full_dir.path())?;
+    fill(&store)?;
+    let full_bytes = store.stats()?.index_bytes;
+
+    let budget = 8 * 1024;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_threshold(1 << 30)
+            .index_budget(budget)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    fill(&store)?;
+    assert!(store.stats()?.index_bytes < full_bytes / 10);
+    for i in (0..2000).step_by(7) {
+        assert_eq!(
+            store.get(format!("key{:04}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert!(store.stats()?.index_bytes <= budget + 100);
+
+    store.remove("key0010".to_owned())?;
+    store.set("key0011".to_owned(), "new".to_owned())?;
+    store.set("key9999".to_owned(), "value9999".to_owned())?;
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0000".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key0010".to_owned())?, None);
+        assert_eq!(store.get("key0011".to_owned())?, Some("new".to_owned()));
+        assert_eq!(
+            store.get("key1999".to_owned())?,
+            Some("value1999".to_owned())
+        );
+        assert_eq!(
+            store.get("key9999".to_owned())?,
+            Some("value9999".to_owned())
+        );
+        assert!(!store.contains_key("key0010"));
+        assert_eq!(store.len(), 2000);
+        assert_eq!(store.stats()?.keys, 2000);
+        let keys: Vec<String> = store.keys().collect();
+        assert_eq!(keys.len(), 2000);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        let pairs = store
+            .scan("key0009".to_owned().."key0013".to_owned())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            pairs,
+            vec![
+                ("key0009".to_owned(), "value9".to_owned()),
+                ("key0011".to_owned(), "new".to_owned()),
+                ("key0012".to_owned(), "value12".to_owned()),
+            ]
+        );
+        Ok(())
+    };
+    check(&store)?;
+    // The compaction leaves the removed key out of the new hint file.
+    store.compact()?;
+    check(&store)?;
+    store.remove("key0012".to_owned())?;
+    assert_eq!(store.len(), 1999);
+    store.set("key0012".to_owned(), "value12".to_owned())?;
+    drop(store);
+
+    // Reopened from the hint file, with and without a budget.
+    let store = open()?;
+    check(&store)?;
+    assert!(store.stats()?.index_bytes < full_bytes / 10);
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// Should evict from memory the entries of the index the hint file holds,
+// whatever the budget
+#[test]
+fn shrink_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1 << 30)
+        .index_budget(1 << 20)
+        .open(temp_dir.path())?;
+    for i in 0..2000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    // Under its budget, the index keeps the entries written before the
+    // compaction.
+    store.compact()?;
+    store.set("key9999".to_owned(), "value9999".to_owned())?;
+    let full_bytes = store.stats()?.index_bytes;
+
+    let released = store.shrink_index()?;
+    assert!(released > full_bytes / 2);
+    assert_eq!(store.stats()?.index_bytes, full_bytes - released);
+    assert_eq!(store.len(), 2001);
+    assert_eq!(store.get("key0042".to_owned())?, Some("value42".to_owned()));
+    assert_eq!(
+        store.get("key9999".to_owned())?,
+        Some("value9999".to_owned())
+    );
+
+    // The entries read are resident again, until the next shrink.
+    for i in 0..2000 {
+        store.get(format!("key{:04}", i))?;
+    }
+    assert_eq!(store.stats()?.index_bytes, full_bytes);
+    assert_eq!(store.shrink_index()?, released);
+
+    // Without a budget, every entry stays in memory.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.shrink_index()?, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should count the keys of a budgeted index, expired ones left out, without
+// reading its hint file
+#[test]
+fn index_budget_len_with_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let open = || {
+        KvStore::builder()
+            .compaction_threshold(1 << 30)
+            .index_budget(1024)
+            .clock(Arc::new(clock.clone()))
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for i in 0..100 {
+        let ttl = Duration::from_secs(10);
+        store.set_with_ttl(format!("key{:04}", i), format!("value{}", i), ttl)?;
+    }
+    for i in 100..500 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    store.compact()?;
+    assert_eq!(store.len(), 500);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.len(), 500);
+    store.remove("key0000".to_owned())?;
+    store.set("key0001".to_owned(), "new".to_owned())?;
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(store.len(), 499 - 98);
+    assert_eq!(store.keys().count(), 499 - 98);
+    Ok(())
+}
+
+// Should drop the damaged records when opened with repair
+#[test]
+fn open_with_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&path)?;
+    let value_pos = log.windows(6).position(|w| w == b"value2").unwrap();
+    log[value_pos] = b'V';
+    fs::write(&path, &log)?;
+    let err = KvStore::open(temp_dir.path()).map(|_| ()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Corruption);
+
+    let (store, report) = KvStore::open_with_repair(temp_dir.path())?;
+    assert_eq!(report.segments_scanned, 1);
+    assert_eq!(report.segments_repaired, 1);
+    assert_eq!(report.entries_dropped, 1);
+    assert!(report.bytes_dropped > 6);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let (store, report) = KvStore::open_with_repair(temp_dir.path())?;
+    assert_eq!(report.segments_repaired, 0);
+    assert_eq!(report.entries_dropped, 0);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should truncate a last record cut short by a crash, rather than fail to open
+#[test]
+fn torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let whole = fs::metadata(&path)?.len();
+    let records = dump_log(&path)?;
+    OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(whole - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(&path)?.len(), records[1].offset);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // Legacy JSON records too.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}{\"Set\":{\"key\":\"ke",
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should report the progress of the log replay on open
+#[test]
+fn open_with_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for round in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        store.set(format!("key{}", round), format!("value{}", round))?;
+    }
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let reports_clone = Arc::clone(&reports);
+    let store = KvStore::open_with_progress(temp_dir.path(), move |progress| {
+        reports_clone.lock().unwrap().push(*progress);
+    })?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    let reports = reports.lock().unwrap();
+    // One report before the replay, and one per log file.
+    assert_eq!(reports.len(), 6);
+    assert_eq!(reports[0].segments_replayed, 0);
+    assert_eq!(reports[0].bytes_replayed, 0);
+    assert!(reports[0].bytes_total > 0);
+    assert!(reports
+        .windows(2)
+        .all(|pair| pair[0].bytes_replayed <= pair[1].bytes_replayed));
+    let last = reports.last().unwrap();
+    assert_eq!(last.segments_replayed, last.segments_total);
+    assert_eq!(last.bytes_replayed, last.bytes_total);
+    Ok(())
+}
+
+// Should defer compactions while paused and catch up when resumed
+#[test]
+fn pause_and_resume_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let started = Arc::new(Mutex::new(0));
+    let started_clone = Arc::clone(&started);
+    store.on_compaction(move |event| {
+        if let CompactionEvent::Started { .. } = event {
+            *started_clone.lock().unwrap() += 1;
+        }
+    });
+
+    store.pause_compaction();
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(*started.lock().unwrap(), 0);
+
+    store.resume_compaction()?;
+    assert_eq!(*started.lock().unwrap(), 1);
+    assert_eq!(store.get("key".to_owned())?, Some("999".to_owned()));
+
+    // Nothing is left to compact.
+    store.resume_compaction()?;
+    assert_eq!(*started.lock().unwrap(), 1);
+    Ok(())
+}
+
+// Should count the keys and estimate the memory of the index
+#[test]
+fn index_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = store.stats()?;
+    assert_eq!(empty.keys, 0);
+    assert_eq!(empty.index_bytes, 0);
+
+    store.set("short".to_owned(), "value".to_owned())?;
+    let short = store.stats()?;
+    assert_eq!(short.keys, 1);
+    assert!(short.index_bytes > "short".len() as u64);
+
+    // Only the length of the key adds up, not its value nor its spare capacity.
+    let mut long_key = String::with_capacity(1000);
+    long_key.push_str("a much longer key");
+    store.set(long_key.clone(), "v".to_owned())?;
+    store.set(long_key.clone(), "another value".to_owned())?;
+    let both = store.stats()?;
+    assert_eq!(both.keys, 2);
+    assert_eq!(
+        both.index_bytes - short.index_bytes,
+        short.index_bytes - "short".len() as u64 + long_key.len() as u64
+    );
+
+    store.remove("short".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.index_bytes, both.index_bytes - short.index_bytes);
+    Ok(())
+}
+
+// Should take a snapshot that opens as a store of its own
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "before".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let snapshot_dir = store.snapshot(dest.path())?;
+    assert_eq!(snapshot_dir.parent(), Some(dest.path()));
+    store.set("key1".to_owned(), "after".to_owned())?;
+
+    let snapshot = KvStore::open(&snapshot_dir)?;
+    assert_eq!(snapshot.get("key0".to_owned())?, None);
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("before".to_owned()));
+    assert_eq!(snapshot.get("key99".to_owned())?, Some("before".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("after".to_owned()));
+    Ok(())
+}
+
+fn snapshot_dirs(dest: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(dest)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+// Should take snapshots periodically and delete the ones too old to keep
+#[test]
+fn snapshot_every() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = TempDir::new().expect("unable to create temporary working directory");
+    let clock = ManualClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000));
+    let store = KvStore::open_with_vfs(temp_dir.path(), Arc::new(OsFs), Arc::new(clock.clone()))?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.snapshot(dest.path())?;
+    clock.advance(Duration::from_secs(10));
+    store.snapshot(dest.path())?;
+    assert_eq!(snapshot_dirs(dest.path()).len(), 2);
+
+    clock.advance(Duration::from_secs(10));
+    let retention = SnapshotRetention {
+        max_count: Some(3),
+        max_age: Some(Duration::from_secs(5)),
+    };
+    store.snapshot_every(Duration::from_millis(10), dest.path(), retention)?;
+    let expected = dest.path().join("snapshot-1000020000");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while snapshot_dirs(dest.path()) != vec![expected.clone()] {
+        assert!(Instant::now() < deadline, "old snapshots were not deleted");
+        thread::sleep(Duration::from_millis(10));
+    }
+    store.stop_snapshots();
+
+    let snapshot = KvStore::open(&expected)?;
+    assert_eq!(snapshot.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+/// Overwrites a filler key until the log is compacted.
+fn compact_with_filler(store: &KvStore) -> Result<()> {
+    let compactions = Arc::new(Mutex::new(0));
+    {
+        let compactions = Arc::clone(&compactions);
+        store.on_compaction(move |event| {
+            if let CompactionEvent::Finished { .. } = event {
+                *compactions.lock().unwrap() += 1;
+            }
+        });
+    }
+    let mut iter = 0;
+    while *compactions.lock().unwrap() == 0 {
+        store.set("filler".to_owned(), format!("filler{}", iter))?;
+        iter += 1;
+    }
+    Ok(())
+}
+
+fn hint_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    files.sort();
+    files
+}
+
+// Should write a hint file for every compaction file, and open from it
+#[test]
+fn open_from_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    compact_with_filler(&store)?;
+    drop(store);
+
+    let hints = hint_files(temp_dir.path());
+    assert_eq!(hints.len(), 1);
+    let log_path = hints[0].with_extension("log");
+    assert!(log_path.exists());
+
+    // The index comes from the hints: a damaged value is only noticed when read.
+    let mut log = fs::read(&log_path)?;
+    let value_pos = log.windows(6).position(|w| w == b"value1").unwrap();
+    log[value_pos] = b'V';
+    fs::write(&log_path, &log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap_err().kind(),
+        ErrorKind::Corruption
+    );
+    drop(store);
+
+    // A damaged hint file is ignored.
+    log[value_pos] = b'v';
+    fs::write(&log_path, &log)?;
+    fs::write(&hints[0], b"garbage")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The next compaction replaces the hint file with its own.
+    compact_with_filler(&store)?;
+    let new_hints = hint_files(temp_dir.path());
+    assert_eq!(new_hints.len(), 1);
+    assert_ne!(new_hints, hints);
+    Ok(())
+}
+
+// Should open from the checkpoint, replaying only the log written after it
+#[test]
+fn open_from_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint = temp_dir.path().join("checkpoint");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.checkpoint()?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert!(checkpoint.exists());
+
+    // The records before the checkpoint are not replayed: a damaged value is
+    // only noticed when read.
+    let log_path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let value_pos = log.windows(6).position(|w| w == b"value1").unwrap();
+    log[value_pos] = b'V';
+    fs::write(&log_path, &log)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap_err().kind(),
+        ErrorKind::Corruption
+    );
+    drop(store);
+    log[value_pos] = b'v';
+    fs::write(&log_path, &log)?;
+
+    // Written on close, and removed by compactions.
+    fs::remove_file(&checkpoint)?;
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_checkpoint_interval(Some(Duration::from_secs(3600)))?;
+    drop(store);
+    assert!(checkpoint.exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    compact_with_filler(&store)?;
+    assert!(!checkpoint.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should store binary values as they are, across reopening and compaction
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes("key1".to_owned(), vec![0xff, 0x00, 0xfe])?;
+    store.set("key2".to_owned(), "välue2".to_owned())?;
+    assert_eq!(
+        store.get_bytes("key1".to_owned())?,
+        Some(vec![0xff, 0x00, 0xfe])
+    );
+    assert_eq!(
+        store.get_bytes("key2".to_owned())?,
+        Some("välue2".as_bytes().to_vec())
+    );
+    assert_eq!(store.get_bytes("key3".to_owned())?, None);
+    match store.get("key1".to_owned()) {
+        Err(KvsError::Utf8(_)) => {}
+        other => panic!("expected a UTF-8 error, got {:?}", other),
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    compact_with_filler(&store)?;
+    assert_eq!(
+        store.get_bytes("key1".to_owned())?,
+        Some(vec![0xff, 0x00, 0xfe])
+    );
+    assert_eq!(store.get("key2".to_owned())?, Some("välue2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_bytes("key1".to_owned())?,
+        Some(vec![0xff, 0x00, 0xfe])
+    );
+    Ok(())
+}
+
+// Should apply a batch as a whole, and discard it on open if it was not committed
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned())
+        .set("key2".to_owned(), "value3".to_owned())
+        .remove("key4".to_owned());
+    assert_eq!(batch.len(), 4);
+    store.write_batch(batch)?;
+    store.write_batch(WriteBatch::new())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Cut the batch short of its commit marker, as a crash would.
+    let log_path = temp_dir.path().join("2.log");
+    let records = dump_log(&log_path)?;
+    let ops: Vec<_> = records.iter().map(|record| record.op).collect();
+    assert_eq!(ops, ["begin", "set", "remove", "commit"]);
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(records[3].offset)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // Writes after the discarded batch are replayed.
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Should read the values as of past points from the retained history
+#[test]
+fn get_as_of() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let t0 = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let hour = Duration::from_secs(3600);
+    let clock = ManualClock::new(t0);
+    let open = || KvStore::open_with_vfs(temp_dir.path(), Arc::new(OsFs), Arc::new(clock.clone()));
+    let store = open()?;
+    store.set("config".to_owned(), "v0".to_owned())?;
+    let err = store
+        .get_as_of("config".to_owned(), AsOf::Time(t0))
+        .unwrap_err();
+    assert!(matches!(err, KvsError::HistoryUnavailable));
+    assert_eq!(err.kind(), ErrorKind::Other);
+
+    store.set_history_retention(Some(24 * hour))?;
+    clock.advance(hour);
+    store.set("config".to_owned(), "v1".to_owned())?;
+    store.set("other".to_owned(), "o1".to_owned())?;
+    let seq1 = store.last_seq();
+    assert_eq!(seq1, 3);
+    clock.advance(hour);
+    store.set("config".to_owned(), "v2".to_owned())?;
+    store.remove("other".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        let get = |key: &str, as_of| store.get_as_of(key.to_owned(), as_of);
+        assert!(get("config", AsOf::Time(t0 - hour)).is_err());
+        assert_eq!(get("config", AsOf::Time(t0))?, Some("v0".to_owned()));
+        assert_eq!(get("config", AsOf::Seq(seq1))?, Some("v1".to_owned()));
+        assert_eq!(get("config", AsOf::Time(t0 + hour))?, Some("v1".to_owned()));
+        assert_eq!(
+            get("config", AsOf::Time(t0 + 2 * hour))?,
+            Some("v2".to_owned())
+        );
+        assert_eq!(get("other", AsOf::Time(t0))?, None);
+        assert_eq!(get("other", AsOf::Seq(seq1))?, Some("o1".to_owned()));
+        assert_eq!(get("other", AsOf::Time(t0 + 2 * hour))?, None);
+        Ok(())
+    };
+    check(&store)?;
+
+    // The history survives reopening the store, and compactions within the retention.
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+    compact_with_filler(&store)?;
+    check(&store)?;
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+
+    // Compactions drop the writes older than the retention.
+    clock.advance(30 * hour);
+    compact_with_filler(&store)?;
+    assert!(store
+        .get_as_of("config".to_owned(), AsOf::Time(t0 + hour))
+        .is_err());
+    assert_eq!(
+        store.get_as_of("config".to_owned(), AsOf::Time(t0 + 8 * hour))?,
+        Some("v2".to_owned())
+    );
+    assert_eq!(
+        store.get_as_of("other".to_owned(), AsOf::Time(t0 + 8 * hour))?,
+        None
+    );
+
+    store.set_history_retention(None)?;
+    assert!(store
+        .get_as_of("config".to_owned(), AsOf::Time(t0 + 32 * hour))
+        .is_err());
+    Ok(())
+}
+
+// Should read the last versions of a key until the next compaction
+#[test]
+fn versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || KvStore::builder().keep_versions(2).open(temp_dir.path());
+    let store = open()?;
+    store.set("key".to_owned(), "v1".to_owned())?;
+    let (_, v1) = store.get_with_version("key".to_owned())?.unwrap();
+    store.set("key".to_owned(), "v2".to_owned())?;
+    store.set("key".to_owned(), "v3".to_owned())?;
+    let (value, v3) = store.get_with_version("key".to_owned())?.unwrap();
+    assert_eq!(value, "v3");
+    assert!(v3 > v1);
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get_with_version("key".to_owned())?, None);
+
+    let check = |store: &KvStore| -> Result<()> {
+        // Only the last two versions are kept, the removal being the last one.
+        let err = store.get_at_version("key".to_owned(), v1).unwrap_err();
+        assert!(matches!(err, KvsError::VersionUnavailable));
+        assert_eq!(
+            store.get_at_version("key".to_owned(), v3)?,
+            Some("v3".to_owned())
+        );
+        assert_eq!(store.get_at_version("key".to_owned(), v3 + 1)?, None);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+
+    // Versions keep increasing after a compaction dropped the removal.
+    compact_with_filler(&store)?;
+    assert!(store.get_at_version("key".to_owned(), v3).is_err());
+    drop(store);
+    let store = open()?;
+    store.set("key".to_owned(), "v5".to_owned())?;
+    let (_, v5) = store.get_with_version("key".to_owned())?.unwrap();
+    assert!(v5 > v3 + 1);
+    assert_eq!(
+        store.get_at_version("key".to_owned(), v5)?,
+        Some("v5".to_owned())
+    );
+
+    // Without versions kept, writes are versioned all the same.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "p".to_owned())?;
+    assert_eq!(
+        store.get_with_version("plain".to_owned())?,
+        Some(("p".to_owned(), v5 + 1))
+    );
+    assert_eq!(
+        store.get_at_version("plain".to_owned(), v5 + 1)?,
+        Some("p".to_owned())
+    );
+    Ok(())
+}
+
+// Should give every command a monotonic log sequence number, kept across reopens
+#[test]
+fn log_sequence_numbers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let lsn1 = store.set_with_lsn("key1".to_owned(), "value1".to_owned())?;
+    let lsn2 = store.set_with_lsn("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(lsn2, lsn1 + 1);
+    assert!(store.expire("key2".to_owned(), Duration::from_secs(60))?);
+    let lsn4 = store.remove_with_lsn("key1".to_owned())?;
+    assert_eq!(lsn4, lsn2 + 2);
+    assert_eq!(store.last_seq(), lsn4);
+    assert!(store.expire("key2".to_owned(), Duration::from_secs(60))?);
+    drop(store);
+
+    let records = dump_log(temp_dir.path().join("1.log"))?;
+    let lsns: Vec<_> = records.iter().map(|record| record.lsn).collect();
+    assert_eq!(lsns, (lsn1..=lsn4 + 1).collect::<Vec<_>>());
+
+    // The last expiry is counted on reopen.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_seq(), lsn4 + 1);
+    let lsn6 = store.set_with_lsn("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(lsn6, lsn4 + 2);
+
+    // Every write returns its number, a batch the one of its last write.
+    let ttl = Duration::from_secs(60);
+    let lsn7 = store.set_with_ttl_lsn("key4".to_owned(), "value4".to_owned(), ttl)?;
+    assert_eq!(lsn7, lsn6 + 1);
+    let lsn8 = store.set_bytes_with_lsn("key5".to_owned(), b"value5".to_vec())?;
+    assert_eq!(lsn8, lsn7 + 1);
+    let mut batch = WriteBatch::new();
+    batch.set("key6".to_owned(), "value6".to_owned());
+    batch.remove("key5".to_owned());
+    assert_eq!(store.write_batch_with_lsn(batch)?, lsn8 + 2);
+    assert_eq!(store.write_batch_with_lsn(WriteBatch::new())?, lsn8 + 2);
+    assert_eq!(
+        store.append_with_lsn("key6".to_owned(), "!".to_owned())?,
+        lsn8 + 3
+    );
+    Ok(())
+}
+
+// Should keep the keys of namespaces apart, and drop a namespace alone
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    store.set("key1".to_owned(), "main".to_owned())?;
+    let sessions = store.namespace("sessions")?;
+    sessions.set("key1".to_owned(), "session".to_owned())?;
+    store
+        .namespace("users")?
+        .set("key2".to_owned(), "user".to_owned())?;
+    assert!(store.namespace("../users").is_err());
+    assert!(store.namespace("").is_err());
+
+    assert_eq!(store.get("key1".to_owned())?, Some("main".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(sessions.get("key1".to_owned())?, Some("session".to_owned()));
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("key2".to_owned())?, Some("user".to_owned()));
+    drop(users);
+
+    // Namespaces compact on their own.
+    sessions.set("key1".to_owned(), "session2".to_owned())?;
+    sessions.compact()?;
+    assert_eq!(store.compaction_stats().compactions, 0);
+    assert_eq!(
+        sessions.get("key1".to_owned())?,
+        Some("session2".to_owned())
+    );
+
+    assert!(store.drop_namespace("sessions").is_err());
+    drop(sessions);
+    assert!(store.drop_namespace("sessions")?);
+    assert!(!store.drop_namespace("sessions")?);
+    assert_eq!(store.namespace("sessions")?.get("key1".to_owned())?, None);
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("main".to_owned()));
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("key2".to_owned())?, Some("user".to_owned()));
+    Ok(())
+}
+
+// Should look up values by a secondary index kept up to date with the writes
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let user = |city: &str| format!(r#"{{"address":{{"city":"{}"}}}}"#, city);
+    let keys = |found: Vec<(String, String)>| -> Vec<String> {
+        found.into_iter().map(|(key, _)| key).collect()
+    };
+    store.set("user:1".to_owned(), user("London"))?;
+    store.set("user:2".to_owned(), "not json".to_owned())?;
+    store.create_index("by-city", "/address/city")?;
+    assert!(store.create_index("by-city", "/address").is_err());
+    assert!(matches!(
+        store.get_by_index("by-age", "42"),
+        Err(KvsError::IndexNotFound)
+    ));
+    assert_eq!(
+        store.get_by_index("by-city", "London")?,
+        vec![("user:1".to_owned(), user("London"))]
+    );
+
+    store.set("user:3".to_owned(), user("London"))?;
+    store.set("user:1".to_owned(), user("Paris"))?;
+    let mut batch = WriteBatch::new();
+    batch.set("user:4".to_owned(), user("Paris"));
+    batch.remove("user:3".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(
+        keys(store.get_by_index("by-city", "London")?),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        keys(store.get_by_index("by-city", "Paris")?),
+        vec!["user:1", "user:4"]
+    );
+    drop(store);
+
+    // The definition is kept, and the entries rebuilt on open.
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        keys(store.get_by_index("by-city", "Paris")?),
+        vec!["user:1", "user:4"]
+    );
+    assert!(store.drop_index("by-city")?);
+    assert!(!store.drop_index("by-city")?);
+    assert!(store.get_by_index("by-city", "Paris").is_err());
+    Ok(())
+}
+
+// Should remove a range of keys with a single record, until compaction drops it
+#[test]
+fn delete_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for key in &["a", "b1", "b2", "b3", "c"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    let lsn = store.last_seq();
+    assert_eq!(store.delete_range("b2".to_owned().."c".to_owned())?, 2);
+    assert_eq!(store.remove_prefix("b")?, 1);
+    assert_eq!(store.remove_prefix("b")?, 0);
+    store.set("b4".to_owned(), "value".to_owned())?;
+    let keys: Vec<_> = store.keys().collect();
+    assert_eq!(keys, vec!["a", "b4", "c"]);
+    assert!(matches!(
+        store.changes_since(lsn)?.next().transpose()?,
+        Some(Change::RemoveRange { .. })
+    ));
+    drop(store);
+
+    let records = dump_log(temp_dir.path().join("1.log"))?;
+    let ops: Vec<_> = records.iter().map(|record| record.op).collect();
+    assert_eq!(
+        ops,
+        vec![
+            "set",
+            "set",
+            "set",
+            "set",
+            "set",
+            "remove-range",
+            "remove-range",
+            "set"
+        ]
+    );
+
+    // The keys set after the removal in the same log file are kept.
+    let store = open()?;
+    let keys: Vec<_> = store.keys().collect();
+    assert_eq!(keys, vec!["a", "b4", "c"]);
+    store.compact()?;
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            assert!(dump_log(&path)?
+                .iter()
+                .all(|record| record.op != "remove-range"));
+        }
+    }
+    let store = open()?;
+    let keys: Vec<_> = store.keys().collect();
+    assert_eq!(keys, vec!["a", "b4", "c"]);
+    // The keys removed from earlier log files stay removed.
+    assert_eq!(store.remove_prefix("")?, 3);
+    assert!(store.is_empty());
+    drop(store);
+    assert!(open()?.is_empty());
+    Ok(())
+}
+
+// Should log only the suffixes appended, and fold them on compaction
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    store.append("log".to_owned(), "a".to_owned())?;
+    store.set("key".to_owned(), "x".repeat(1000))?;
+    let lsn = store.append_with_lsn("key".to_owned(), "y".to_owned())?;
+    // Longer chains than a read follows are written whole.
+    for i in 0..40 {
+        store.append("log".to_owned(), i.to_string())?;
+    }
+    let log: String = std::iter::once("a".to_owned())
+        .chain((0..40).map(|i| i.to_string()))
+        .collect();
+    assert_eq!(store.get("log".to_owned())?, Some(log.clone()));
+    assert_eq!(store.get("key".to_owned())?, Some("x".repeat(1000) + "y"));
+    assert_eq!(
+        store.changes_since(lsn - 1)?.next().transpose()?,
+        Some(Change::Append {
+            lsn,
+            key: "key".to_owned(),
+            suffix: b"y".to_vec(),
+        })
+    );
+    drop(store);
+
+    let records = dump_log(temp_dir.path().join("1.log"))?;
+    let appended = records.iter().find(|record| record.lsn == lsn).unwrap();
+    assert_eq!(appended.op, "append");
+    assert_eq!(appended.value_len, Some(1));
+
+    let store = open()?;
+    assert_eq!(store.get("log".to_owned())?, Some(log.clone()));
+    store.compact()?;
+    assert_eq!(store.get("log".to_owned())?, Some(log.clone()));
+    assert_eq!(store.get("key".to_owned())?, Some("x".repeat(1000) + "y"));
+    drop(store);
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            assert!(dump_log(&path)?.iter().all(|record| record.op != "append"));
+        }
+    }
+    let store = open()?;
+    store.append("log".to_owned(), "!".to_owned())?;
+    assert_eq!(store.get("log".to_owned())?, Some(log + "!"));
+    Ok(())
+}
+
+// Should refuse to open a data directory created by the other engine
+#[cfg(feature = "sled")]
+#[test]
+fn wrong_engine() -> Result<()> {
+    use kvs::SledKvsEngine;
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(kvs_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(fs::read_to_string(kvs_dir.path().join("ENGINE"))?, "kvs\n");
+    assert!(matches!(
+        SledKvsEngine::open(kvs_dir.path()),
+        Err(KvsError::WrongEngine { .. })
+    ));
+
+    // A directory without the file is told apart by its files.
+    fs::remove_file(kvs_dir.path().join("ENGINE"))?;
+    assert!(SledKvsEngine::open(kvs_dir.path()).is_err());
+    let store = KvStore::open(kvs_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(sled_dir.path())?);
+    assert!(matches!(
+        KvStore::open(sled_dir.path()),
+        Err(KvsError::WrongEngine { .. })
+    ));
+    fs::remove_file(sled_dir.path().join("ENGINE"))?;
+    assert!(KvStore::open(sled_dir.path()).is_err());
+    drop(SledKvsEngine::open(sled_dir.path())?);
+    Ok(())
+}
+
+// Should stream the changes after a sequence number, until a compaction drops them
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_threshold(u64::MAX)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    let set = |lsn, key: &str, value: &str| Change::Set {
+        lsn,
+        key: key.to_owned(),
+        value: value.as_bytes().to_vec(),
+    };
+    let remove = |lsn, key: &str| Change::Remove {
+        lsn,
+        key: key.to_owned(),
+    };
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.remove("a".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("c".to_owned(), "3".to_owned())
+        .remove("b".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.last_seq(), 5);
+
+    let changes = store.changes_since(0)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        changes,
+        vec![
+            set(1, "a", "1"),
+            set(2, "b", "2"),
+            remove(3, "a"),
+            set(4, "c", "3"),
+            remove(5, "b"),
+        ]
+    );
+    let lsns: Vec<u64> = store.changes_since(3)?.map(|c| c.unwrap().lsn()).collect();
+    assert_eq!(lsns, vec![4, 5]);
+
+    // A caught up iterator yields the later writes, across log files too.
+    let mut changes = store.changes_since(5)?;
+    assert!(changes.next().is_none());
+    store.set("d".to_owned(), "4".to_owned())?;
+    assert_eq!(changes.next().transpose()?, Some(set(6, "d", "4")));
+    drop(store);
+    let store = open()?;
+    store.set("e".to_owned(), "5".to_owned())?;
+    assert_eq!(changes_keys(&store, 5)?, vec!["d", "e"]);
+
+    // A compaction drops the changes before it, also for the iterators
+    // which did not read them yet.
+    let mut pending = store.changes_since(0)?;
+    store.compact()?;
+    assert!(matches!(
+        pending.next(),
+        Some(Err(KvsError::ChangesUnavailable))
+    ));
+    assert!(matches!(
+        store.changes_since(6),
+        Err(KvsError::ChangesUnavailable)
+    ));
+    assert!(changes_keys(&store, 7)?.is_empty());
+    store.set("f".to_owned(), "6".to_owned())?;
+    drop(store);
+    let store = open()?;
+    assert!(store.changes_since(0).is_err());
+    assert_eq!(changes_keys(&store, 7)?, vec!["f"]);
+    Ok(())
+}
+
+fn changes_keys(store: &KvStore, lsn: u64) -> Result<Vec<String>> {
+    store
+        .changes_since(lsn)?
+        .map(|change| change.map(|change| change.key().unwrap().to_owned()))
+        .collect()
+}
+
+// Should commit transactions whose reads are unchanged, and only those
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "100".to_owned())?;
+
+    let mut txn = store.begin();
+    assert_eq!(txn.get("from".to_owned())?, Some("100".to_owned()));
+    assert_eq!(txn.get("to".to_owned())?, None);
+    txn.set("from".to_owned(), "90".to_owned());
+    txn.set("to".to_owned(), "10".to_owned());
+    txn.remove("pending".to_owned());
+    // The transaction reads its own writes, the store does not see them yet.
+    assert_eq!(txn.get("to".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, None);
+    txn.commit()?;
+    assert_eq!(store.get("from".to_owned())?, Some("90".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+
+    // A write of a key read, even of the same value, fails the commit.
+    let mut txn = store.begin();
+    txn.get("from".to_owned())?;
+    txn.set("to".to_owned(), "20".to_owned());
+    store.set("from".to_owned(), "90".to_owned())?;
+    let err = txn.commit().unwrap_err();
+    assert!(matches!(err, KvsError::TransactionConflict));
+    assert_eq!(err.kind(), ErrorKind::Conflict);
+    assert_eq!(store.get("to".to_owned())?, Some("10".to_owned()));
+
+    // So does the creation of a key read as missing.
+    let mut txn = store.begin();
+    txn.get("new".to_owned())?;
+    txn.set("other".to_owned(), "o".to_owned());
+    store.set("new".to_owned(), "n".to_owned())?;
+    assert!(txn.commit().is_err());
+
+    // Writes of other keys do not, nor do rolled back transactions.
+    let mut txn = store.begin();
+    txn.get("from".to_owned())?;
+    txn.set("to".to_owned(), "20".to_owned());
+    let mut other = store.begin();
+    other.set("from".to_owned(), "0".to_owned());
+    other.rollback();
+    store.set("new".to_owned(), "m".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("to".to_owned())?, Some("20".to_owned()));
+    assert_eq!(store.get("from".to_owned())?, Some("90".to_owned()));
+    Ok(())
+}
+
+// Should compact on demand, below the threshold and while compactions are paused
+#[test]
+fn compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1 << 20)
+        .open(temp_dir.path())?;
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    store.pause_compaction();
+    let before = dir_size();
+    store.compact()?;
+    assert!(dir_size() < before);
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// Should report what the compactions since the store was opened did
+#[test]
+fn compaction_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1 << 20)
+        .segment_size(512)
+        .open(temp_dir.path())?;
+    assert_eq!(store.compaction_stats().compactions, 0);
+    assert_eq!(store.compaction_stats().last, None);
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+        store.set("key2".to_owned(), format!("value{}", iter))?;
+    }
+    store.compact()?;
+    let stats = store.compaction_stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.failures, 0);
+    let last = stats.last.expect("no compaction reported");
+    assert_eq!(last.entries_copied, 2);
+    assert!(last.segments_deleted > 1);
+    assert!(last.bytes_reclaimed > 0);
+    assert_eq!(stats.bytes_written, last.bytes_written);
+    assert_eq!(stats.bytes_reclaimed, last.bytes_reclaimed);
+
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    let stats = store.compaction_stats();
+    assert_eq!(stats.compactions, 2);
+    assert_eq!(stats.last.map(|last| last.entries_copied), Some(1));
+    assert_eq!(
+        stats.bytes_written,
+        last.bytes_written + stats.last.unwrap().bytes_written
+    );
+    Ok(())
+}
+
+// Should export the live pairs and import them back, in both formats
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    store.set("comma,key".to_owned(), "a \"quoted\"\r\nvalue".to_owned())?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    for format in [ExportFormat::JsonLines, ExportFormat::Csv] {
+        let mut exported = Vec::new();
+        assert_eq!(store.export(&mut exported, format)?, 2);
+        let imported = KvStore::open(temp_dir.path().join(format!("{:?}", format)))?;
+        assert_eq!(imported.import(&exported[..], format)?, 2);
+        let pairs: Vec<(String, String)> = imported.iter().collect::<Result<_>>()?;
+        assert_eq!(pairs, store.iter().collect::<Result<Vec<_>>>()?);
+    }
+
+    let mut csv = Vec::new();
+    store.export(&mut csv, ExportFormat::Csv)?;
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "key,value\r\n\"comma,key\",\"a \"\"quoted\"\"\r\nvalue\"\r\nplain,value\r\n"
+    );
+
+    // Hand-written input: a missing final line break, and malformed records.
+    let imported = KvStore::open(temp_dir.path().join("hand"))?;
+    assert_eq!(
+        imported.import(&b"key,value\na,1\n\nb,2"[..], ExportFormat::Csv)?,
+        2
+    );
+    assert_eq!(imported.get("b".to_owned())?, Some("2".to_owned()));
+    assert!(imported.import(&b"a,1\n"[..], ExportFormat::Csv).is_err());
+    assert!(imported
+        .import(&b"key,value\na,1,2\n"[..], ExportFormat::Csv)
+        .is_err());
+    let err = imported
+        .import(
+            &b"{\"key\":\"c\",\"value\":\"3\"}\nnot json\n"[..],
+            ExportFormat::JsonLines,
+        )
+        .unwrap_err();
+    assert!(matches!(err, KvsError::InvalidImport { line: 2, .. }));
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(imported.get("c".to_owned())?, None);
+    Ok(())
+}
+
+// Should encrypt the values in the log, and only read them with the key
+#[test]
+fn encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [7; 32];
+    let store = KvStore::builder()
+        .encryption_key(1, key)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "secret-value".to_owned())?;
+    store.set("key2".to_owned(), "other-secret".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.expect("unable to read the data directory");
+        if entry.file_type().is_file() {
+            let bytes = fs::read(entry.path())?;
+            assert!(!bytes.windows(6).any(|window| window == b"secret"));
+        }
+    }
+    let records = dump_log(temp_dir.path().join("1.log"))?;
+    assert!(records.iter().all(|record| record.op == "encrypted"));
+
+    let store = KvStore::builder()
+        .encryption_key(1, key)
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::KeyUnavailable { version: 1 }) => {}
+        res => panic!("unexpected result {:?}", res.map(|_| ())),
+    }
+    let res = KvStore::builder()
+        .encryption_key(1, [8; 32])
+        .open(temp_dir.path());
+    assert_eq!(res.err().map(|err| err.kind()), Some(ErrorKind::Corruption));
+    Ok(())
+}
+
+// Should repair an encrypted log with the keys of the builder
+#[test]
+fn open_with_repair_encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [7; 32];
+    let store = KvStore::builder()
+        .encryption_key(1, key)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let path = temp_dir.path().join("1.log");
+    let records = dump_log(&path)?;
+    let mut log = fs::read(&path)?;
+    let last = (records[1].offset + records[1].len - 1) as usize;
+    log[last] ^= 0xff;
+    fs::write(&path, &log)?;
+
+    let (store, report) = KvStore::builder()
+        .encryption_key(1, key)
+        .open_with_repair(temp_dir.path())?;
+    assert_eq!(report.segments_repaired, 1);
+    assert_eq!(report.entries_dropped, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should encrypt the records of older keys with the new key during compaction
+#[test]
+fn rotate_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (old_key, new_key) = ([1; 32], [2; 32]);
+    // The records written before the store was encrypted are rotated too.
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = KvStore::builder()
+        .encryption_key(1, old_key)
+        .open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    assert_eq!(store.rotate_key(new_key)?, 2);
+    for key_id in 10..30 {
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    drop(store);
+
+    // The records of the old key are still read with it.
+    let open = |keys: &[(u32, [u8; 32])]| {
+        keys.iter()
+            .fold(KvStore::builder(), |builder, &(version, key)| {
+                builder.encryption_key(version, key)
+            })
+            .open(temp_dir.path())
+    };
+    assert!(matches!(
+        open(&[(2, new_key)]),
+        Err(KvsError::KeyUnavailable { version: 1 })
+    ));
+    let store = open(&[(1, old_key), (2, new_key)])?;
+    assert_eq!(store.get("key0".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key29".to_owned())?, Some("new".to_owned()));
+    store.compact()?;
+    drop(store);
+
+    let store = open(&[(2, new_key)])?;
+    assert_eq!(store.get("plain".to_owned())?, Some("value".to_owned()));
+    for key_id in 0..30 {
+        let value = if key_id < 10 { "old" } else { "new" };
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.to_owned()));
+    }
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use kvs::{KvStore, KvsEngine, Result};
+use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+use metrics_util::MetricKind;
+use tempfile::TempDir;
+
+#[test]
+fn engine_operations_are_counted() -> Result<()> {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+
+    metrics::with_local_recorder(&recorder, || -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key1".to_owned(), "value2".to_owned())?;
+        store.get("key1".to_owned())?;
+        store.remove("key1".to_owned())?;
+        Ok(())
+    })?;
+
+    let snapshot = snapshotter.snapshot().into_hashmap();
+    let counter = |name: &str| {
+        snapshot
+            .iter()
+            .find(|(key, _)| key.kind() == MetricKind::Counter && key.key().name() == name)
+            .map(|(_, (_, _, value))| match value {
+                DebugValue::Counter(value) => *value,
+                _ => unreachable!(),
+            })
+            .unwrap_or(0)
+    };
+    assert_eq!(counter("kvs_sets_total"), 2);
+    assert_eq!(counter("kvs_gets_total"), 1);
+    assert_eq!(counter("kvs_removes_total"), 1);
+    assert!(counter("kvs_bytes_written_total") > 0);
+    Ok(())
+}
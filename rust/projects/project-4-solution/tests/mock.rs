@@ -0,0 +1,84 @@
+#![cfg(feature = "test-util")]
+
+use kvs::testing::Op;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvsClient, KvsEngine, KvsError, KvsServer, MockKvsEngine, Result};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Should record calls and answer from memory by default
+#[test]
+fn records_calls() -> Result<()> {
+    let engine = MockKvsEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    assert_eq!(
+        engine.calls(),
+        vec![
+            Op::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned()
+            },
+            Op::Get {
+                key: "key1".to_owned()
+            },
+            Op::Remove {
+                key: "key1".to_owned()
+            },
+            Op::Remove {
+                key: "key1".to_owned()
+            },
+        ]
+    );
+    Ok(())
+}
+
+// Should return scripted responses in order, then fall back to memory
+#[test]
+fn scripted_responses() -> Result<()> {
+    let engine = MockKvsEngine::new();
+    engine.script_get(Ok(Some("scripted".to_owned())));
+    engine.script_get(Err(KvsError::StringError("injected".to_owned())));
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("scripted".to_owned()));
+    assert!(engine.get("key1".to_owned()).is_err());
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should delay every call
+#[test]
+fn delay() -> Result<()> {
+    let engine = MockKvsEngine::new();
+    engine.set_delay(Duration::from_millis(50));
+    let start = Instant::now();
+    engine.get("key1".to_owned())?;
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    Ok(())
+}
+
+// Should let a server be tested without touching the disk
+#[test]
+fn serve_mock_engine() -> Result<()> {
+    let engine = MockKvsEngine::new();
+    engine.script_set(Err(KvsError::StringError("disk full".to_owned())));
+    let server = KvsServer::new(engine.clone(), SharedQueueThreadPool::new(1)?);
+    thread::spawn(move || server.run("127.0.0.1:4020"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4020")?;
+    match client.set("key1".to_owned(), "value1".to_owned()) {
+        Err(KvsError::StringError(message)) => assert_eq!(message, "disk full"),
+        other => panic!("expected the scripted error, got {:?}", other),
+    }
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    assert_eq!(engine.calls().len(), 2);
+    Ok(())
+}
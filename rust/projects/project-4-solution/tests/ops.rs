@@ -0,0 +1,59 @@
+use kvs::testing::{apply_ops, Op};
+#[cfg(feature = "sled")]
+use kvs::SledKvsEngine;
+use kvs::{KvStore, KvsEngine, Result};
+use rand::prelude::*;
+use tempfile::TempDir;
+
+fn random_ops(seed: u64, len: usize) -> Vec<Op> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len)
+        .map(|i| {
+            let key = format!("key{}", rng.gen_range(0, 20));
+            match rng.gen_range(0, 3) {
+                0 => Op::Set {
+                    key,
+                    value: format!("value{}", i),
+                },
+                1 => Op::Get { key },
+                _ => Op::Remove { key },
+            }
+        })
+        .collect()
+}
+
+fn check_engine<E: KvsEngine>(open: impl Fn(&TempDir) -> Result<E>) -> Result<()> {
+    for seed in 0..10 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = open(&temp_dir)?;
+        apply_ops(&engine, &random_ops(seed, 500))?;
+    }
+    Ok(())
+}
+
+// Should match the model on random operation sequences
+#[test]
+fn kv_store_matches_model() -> Result<()> {
+    check_engine(|dir| KvStore::open(dir.path()))
+}
+
+// Should match the model on random operation sequences
+#[cfg(feature = "sled")]
+#[test]
+fn sled_matches_model() -> Result<()> {
+    check_engine(|dir| Ok(SledKvsEngine::new(sled::Db::open(dir.path())?)))
+}
+
+// Should report the first operation diverging from the model
+#[test]
+fn apply_ops_reports_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let ops = vec![Op::Get {
+        key: "key1".to_owned(),
+    }];
+    assert!(apply_ops(&store, &ops).is_err());
+    Ok(())
+}
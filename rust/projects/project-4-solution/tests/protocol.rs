@@ -0,0 +1,466 @@
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{ErrorKind, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result, SocketOptions};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Should carry binary keys and values, and share keys with the string API
+#[test]
+fn bytes_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4030"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4030")?;
+    client.set_bytes(b"key1".to_vec(), "välue\n\"1\"".as_bytes().to_vec())?;
+    assert_eq!(
+        client.get_bytes(b"key1".to_vec())?,
+        Some("välue\n\"1\"".as_bytes().to_vec())
+    );
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some("välue\n\"1\"".to_owned())
+    );
+
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        client.get_bytes(b"key2".to_vec())?,
+        Some(b"value2".to_vec())
+    );
+    client.remove_bytes(b"key2".to_vec())?;
+    assert_eq!(client.get_bytes(b"key2".to_vec())?, None);
+    assert!(matches!(
+        client.remove_bytes(b"key2".to_vec()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // `KvStore` stores binary values as they are, but not as strings.
+    client.set_bytes(b"key3".to_vec(), vec![0xff, 0x00, 0xfe])?;
+    assert_eq!(
+        client.get_bytes(b"key3".to_vec())?,
+        Some(vec![0xff, 0x00, 0xfe])
+    );
+    match client.get("key3".to_owned()) {
+        Err(err @ KvsError::Remote { .. }) => {
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("UTF-8"));
+        }
+        other => panic!("expected a UTF-8 error, got {:?}", other),
+    }
+
+    // Keys are strings, so invalid UTF-8 is rejected by the server, which
+    // keeps serving the connection.
+    match client.set_bytes(vec![0xff, 0x00, 0xfe], b"value4".to_vec()) {
+        Err(err @ KvsError::Remote { .. }) => {
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(err.to_string().contains("UTF-8"));
+        }
+        other => panic!("expected a UTF-8 error, got {:?}", other),
+    }
+    assert_eq!(
+        client.get_bytes(b"key3".to_vec())?,
+        Some(vec![0xff, 0x00, 0xfe])
+    );
+    Ok(())
+}
+
+// Should drop a connection that does not speak the protocol, and keep serving others
+#[test]
+fn server_rejects_foreign_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    thread::spawn(move || server.run("127.0.0.1:4031"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect("127.0.0.1:4031")?;
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert!(response.is_empty());
+
+    let mut client = KvsClient::connect("127.0.0.1:4031")?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should report a protocol error when connected to something else than a kvs server
+#[test]
+fn client_rejects_foreign_protocol() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4032")?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 64];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        }
+    });
+
+    let mut client = KvsClient::connect("127.0.0.1:4032")?;
+    match client.get("key1".to_owned()) {
+        Err(e @ KvsError::Protocol(_)) => {
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+            assert!(e.to_string().contains("bad magic"), "{}", e);
+        }
+        other => panic!("expected a protocol error, got {:?}", other),
+    }
+    Ok(())
+}
+
+// Should compress large responses, transparently for `KvsClient`
+#[test]
+fn compressed_responses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .compress_responses_above(Some(1024));
+    thread::spawn(move || server.run("127.0.0.1:4033"));
+    thread::sleep(Duration::from_millis(500));
+
+    let large = "value ".repeat(20_000);
+    let mut client = KvsClient::connect("127.0.0.1:4033")?;
+    client.set("large".to_owned(), large.clone())?;
+    client.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(client.get("small".to_owned())?, Some("value".to_owned()));
+
+    // Check the frames on the wire.
+    let mut stream = TcpStream::connect("127.0.0.1:4033")?;
+    let (flags, len) = raw_get(&mut stream, "large")?;
+    assert_eq!(flags, 0x01);
+    assert!(len < large.len() / 10, "{} bytes", len);
+    let (flags, _) = raw_get(&mut stream, "small")?;
+    assert_eq!(flags, 0);
+    Ok(())
+}
+
+/// Sends a get request and returns the flags and payload length of the response.
+fn raw_get(stream: &mut TcpStream, key: &str) -> Result<(u8, usize)> {
+    let payload = format!(r#"{{"id":1,"request":{{"Get":{{"key":"{}"}}}}}}"#, key);
+    let mut frame = b"KV\x01\x00".to_vec();
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload.as_bytes()).to_be_bytes());
+    frame.extend_from_slice(payload.as_bytes());
+    stream.write_all(&frame)?;
+
+    let mut header = [0; 12];
+    stream.read_exact(&mut header)?;
+    assert_eq!(&header[0..3], b"KV\x01");
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((header[3], len))
+}
+
+// Should list the connections served, and close one on request
+#[test]
+fn client_list_and_kill() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    )
+    .admin_addr("127.0.0.1:4055".parse().unwrap());
+    thread::spawn(move || server.run("127.0.0.1:4037"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut victim = KvsClient::connect("127.0.0.1:4037")?;
+    // Only the admin address serves the connections.
+    assert!(matches!(
+        victim.client_list(),
+        Err(KvsError::PermissionDenied(_))
+    ));
+    assert!(matches!(
+        victim.client_kill(1),
+        Err(KvsError::PermissionDenied(_))
+    ));
+    victim.set("key1".to_owned(), "value1".to_owned())?;
+    let mut admin = KvsClient::connect("127.0.0.1:4055")?;
+    let clients = admin.client_list()?;
+    assert_eq!(clients.len(), 2);
+    let info = &clients[0];
+    assert_eq!(info.last_op.as_deref(), Some("set"));
+    assert_eq!(info.requests, 3);
+    assert_eq!(info.errors, 2);
+    assert!(info.bytes_read > 0 && info.bytes_written > 0);
+    assert_eq!(clients[1].last_op.as_deref(), Some("client_list"));
+
+    assert!(admin.client_kill(info.id)?);
+    assert!(!admin.client_kill(info.id)?);
+    assert!(victim.get("key1".to_owned()).is_err());
+    let clients = admin.client_list()?;
+    assert_eq!(clients.len(), 1);
+    assert_eq!(clients[0].requests, 4);
+    Ok(())
+}
+
+/// An engine whose reads of keys starting with `slow` take half a second.
+#[derive(Clone)]
+struct SlowReads(KvStore);
+
+impl KvsEngine for SlowReads {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key.starts_with("slow") {
+            thread::sleep(Duration::from_millis(500));
+        }
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+// Should serve the pipelined reads of a connection concurrently
+#[test]
+fn pipelined_gets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        SlowReads(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::new(2)?,
+    )
+    .admin_addr("127.0.0.1:4056".parse().unwrap());
+    thread::spawn(move || server.run("127.0.0.1:4039"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4039")?;
+    let keys: Vec<String> = (0..4)
+        .map(|i| format!("slow{}", i))
+        .chain(vec!["fast".to_owned(), "missing".to_owned()])
+        .collect();
+    for key in &keys[..5] {
+        client.set(key.clone(), format!("{}-value", key))?;
+    }
+
+    let start = Instant::now();
+    let values = client.get_pipelined(keys.clone())?;
+    assert!(start.elapsed() < Duration::from_millis(1500));
+    let mut expected: Vec<Option<String>> = keys[..5]
+        .iter()
+        .map(|key| Some(format!("{}-value", key)))
+        .collect();
+    expected.push(None);
+    assert_eq!(values, expected);
+
+    // The connection still serves requests in order afterwards.
+    assert_eq!(
+        client.get("fast".to_owned())?,
+        Some("fast-value".to_owned())
+    );
+    let mut admin = KvsClient::connect("127.0.0.1:4056")?;
+    assert_eq!(admin.client_list()?[0].requests, 12);
+    Ok(())
+}
+
+// Should reject writes in read-only mode, until it is turned off
+// Should reject the reads served out of order beyond the limit of the server
+#[test]
+fn max_in_flight() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        SlowReads(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::new(2)?,
+    )
+    .max_in_flight(1);
+    thread::spawn(move || server.run("127.0.0.1:4051"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4051")?;
+    let keys = vec!["slow1".to_owned(), "slow2".to_owned()];
+    let err = client.get_pipelined(keys).unwrap_err();
+    assert!(matches!(err, KvsError::ServerBusy));
+    assert!(err.is_retryable());
+
+    // The limit is shared by the connections.
+    let mut other = KvsClient::connect("127.0.0.1:4051")?;
+    let err = other.get_pipelined(vec!["fast".to_owned()]).unwrap_err();
+    assert!(matches!(err, KvsError::ServerBusy));
+
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(other.get_pipelined(vec!["fast".to_owned()])?, vec![None]);
+    Ok(())
+}
+
+#[test]
+fn read_only_mode() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?)
+        .read_only(true)
+        .admin_addr("127.0.0.1:4052".parse().unwrap());
+    thread::spawn(move || server.run("127.0.0.1:4041"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4041")?;
+    let err = client
+        .set("key1".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::ReadOnly));
+    assert_eq!(err.kind(), ErrorKind::ReadOnly);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(
+        client.set_bytes(b"key2".to_vec(), b"value2".to_vec()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Only the admin address may turn it off.
+    let err = client.set_read_only(false).unwrap_err();
+    assert!(matches!(err, KvsError::PermissionDenied(_)));
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    KvsClient::connect("127.0.0.1:4052")?.set_read_only(false)?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should buffer the writes of a transaction until it is committed
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let server = KvsServer::new(store, SharedQueueThreadPool::new(2)?);
+    thread::spawn(move || server.run("127.0.0.1:4050"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4050")?;
+    let mut other = KvsClient::connect("127.0.0.1:4050")?;
+    client.begin()?;
+    assert!(client.begin().is_err());
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get("key2".to_owned())?, None);
+    client.commit()?;
+    assert_eq!(other.get("key1".to_owned())?, None);
+    assert_eq!(other.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    client.begin()?;
+    client.get("key2".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    other.set("key2".to_owned(), "changed".to_owned())?;
+    let err = client.commit().unwrap_err();
+    assert!(matches!(err, KvsError::TransactionConflict));
+    assert!(client.commit().is_err());
+
+    client.begin()?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    client.rollback()?;
+    assert_eq!(client.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+/// Frames `payload` as a compressed request would be.
+fn compressed_frame(payload: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(payload).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let mut frame = b"KV\x01\x01".to_vec();
+    frame.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&compressed).to_be_bytes());
+    frame.extend_from_slice(&compressed);
+    frame
+}
+
+// Should close the connection of requests larger than the limit, before or after decompression
+#[test]
+fn max_request_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .max_request_size(Some(1024));
+    thread::spawn(move || server.run("127.0.0.1:4043"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4043")?;
+    client.set("key1".to_owned(), "a".repeat(900))?;
+    assert!(client.set("key1".to_owned(), "b".repeat(2000)).is_err());
+
+    let request = format!(
+        "{{\"id\":1,\"request\":{{\"Set\":{{\"key\":\"key1\",\"value\":\"{}\"}}}}}}",
+        "c".repeat(100_000)
+    );
+    let mut stream = TcpStream::connect("127.0.0.1:4043")?;
+    stream.write_all(&compressed_frame(request.as_bytes()))?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    assert!(response.is_empty());
+
+    let mut client = KvsClient::connect("127.0.0.1:4043")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("a".repeat(900)));
+    Ok(())
+}
+
+// Should serve with tuned socket options, with or without TCP_NODELAY on the client
+#[test]
+fn socket_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .socket_options(SocketOptions {
+        nodelay: false,
+        send_buffer_size: Some(64 * 1024),
+        recv_buffer_size: Some(64 * 1024),
+        backlog: 16,
+        ..SocketOptions::default()
+    });
+    thread::spawn(move || server.run("127.0.0.1:4044"));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect("127.0.0.1:4044")?;
+    client.set_nodelay(false)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set_nodelay(true)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should serve the same store on every address, IPv6 included
+#[test]
+fn multiple_addresses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    );
+    let addrs = [
+        "127.0.0.1:4045".parse().unwrap(),
+        "127.0.0.1:4046".parse().unwrap(),
+    ];
+    thread::spawn(move || server.run_on(&addrs));
+    thread::sleep(Duration::from_millis(500));
+
+    KvsClient::connect("127.0.0.1:4045")?.set("key1".to_owned(), "value1".to_owned())?;
+    let mut client = KvsClient::connect("127.0.0.1:4046")?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
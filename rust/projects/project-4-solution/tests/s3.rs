@@ -0,0 +1,152 @@
+//! Tests of `S3Target` against a fake S3 server.
+//!
+//! Run with `cargo test --features s3`.
+#![cfg(feature = "s3")]
+
+use kvs::backup::{BackupTarget, S3Target};
+use kvs::{KvStore, KvsEngine, Result};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+
+/// The objects and pending multipart uploads of the fake server, by key.
+#[derive(Default)]
+struct Bucket {
+    objects: HashMap<String, Vec<u8>>,
+    parts: HashMap<String, Vec<(u32, Vec<u8>)>>,
+    authorizations: Vec<String>,
+}
+
+/// Serves path-style S3 requests on `addr`, without checking signatures.
+fn serve(addr: &str) -> Arc<Mutex<Bucket>> {
+    let bucket = Arc::new(Mutex::new(Bucket::default()));
+    let listener = TcpListener::bind(addr).unwrap();
+    {
+        let bucket = Arc::clone(&bucket);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                handle(stream.unwrap(), &bucket);
+            }
+        });
+    }
+    bucket
+}
+
+fn handle(stream: TcpStream, bucket: &Mutex<Bucket>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).unwrap();
+    let mut content_length = 0;
+    let mut anonymous = false;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(": ").unwrap();
+        match name {
+            "content-length" => content_length = value.parse().unwrap(),
+            "authorization" => {
+                anonymous = value.starts_with("AWS4-HMAC-SHA256 Credential=/");
+                bucket.lock().unwrap().authorizations.push(value.to_owned());
+            }
+            _ => {}
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+
+    let mut parts = request_line.split(' ');
+    let method = parts.next().unwrap();
+    let target = parts.next().unwrap();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let key = path.strip_prefix("/bucket/").unwrap().to_owned();
+    let mut bucket = bucket.lock().unwrap();
+    let response = match (method, query) {
+        _ if anonymous => {
+            "HTTP/1.1 403 Forbidden\r\n\r\n<Error><Code>AccessDenied</Code></Error>".to_owned()
+        }
+        ("POST", "uploads=") => {
+            bucket.parts.insert(key, Vec::new());
+            // Chunked, as some servers answer.
+            let xml = "<InitiateMultipartUploadResult><UploadId>upload1</UploadId>\
+                       </InitiateMultipartUploadResult>";
+            format!(
+                "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                xml.len(),
+                xml
+            )
+        }
+        ("PUT", query) if query.starts_with("partNumber=") => {
+            let number: u32 = query["partNumber=".len()..query.find('&').unwrap()]
+                .parse()
+                .unwrap();
+            bucket.parts.get_mut(&key).unwrap().push((number, body));
+            format!("HTTP/1.1 200 OK\r\nETag: \"etag{}\"\r\n\r\n", number)
+        }
+        ("POST", "uploadId=upload1") => {
+            let mut parts = bucket.parts.remove(&key).unwrap();
+            parts.sort_by_key(|(number, _)| *number);
+            let content = parts.into_iter().flat_map(|(_, part)| part).collect();
+            bucket.objects.insert(key, content);
+            "HTTP/1.1 200 OK\r\n\r\n<CompleteMultipartUploadResult/>".to_owned()
+        }
+        ("PUT", "") => {
+            bucket.objects.insert(key, body);
+            "HTTP/1.1 200 OK\r\n\r\n".to_owned()
+        }
+        _ => "HTTP/1.1 400 Bad Request\r\n\r\n<Error><Code>Unexpected</Code></Error>".to_owned(),
+    };
+    let mut stream = reader.into_inner();
+    stream.write_all(response.as_bytes()).unwrap();
+}
+
+// Should upload every log file, the large ones in parts, then mark the backup complete
+#[test]
+fn backup_to_s3() -> Result<()> {
+    let bucket = serve("127.0.0.1:4034");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+
+    let target = S3Target::new("http://127.0.0.1:4034", "bucket")?
+        .credentials("access", "secret")
+        .prefix("kvs/")
+        .part_size(1000);
+    let backup = store.backup_to(&target)?;
+
+    let bucket = bucket.lock().unwrap();
+    let log = std::fs::read(temp_dir.path().join("1.log"))?;
+    assert!(log.len() > 1000);
+    assert_eq!(bucket.objects[&format!("kvs/{}/1.log", backup)], log);
+    assert_eq!(bucket.objects[&format!("kvs/{}/COMPLETE", backup)], b"");
+    assert!(bucket.parts.is_empty());
+    assert!(bucket
+        .authorizations
+        .iter()
+        .all(
+            |authorization| authorization.starts_with("AWS4-HMAC-SHA256 Credential=access/")
+                && authorization.contains("/us-east-1/s3/aws4_request")
+        ));
+    Ok(())
+}
+
+// Should report the errors of the server
+#[test]
+fn s3_errors() {
+    assert!(S3Target::new("https://s3.amazonaws.com", "bucket").is_err());
+
+    serve("127.0.0.1:4035");
+    // Without credentials.
+    let target = S3Target::new("http://127.0.0.1:4035", "bucket").unwrap();
+    let err = target.commit("backup").unwrap_err();
+    assert!(err.to_string().contains("403"));
+    assert!(err.to_string().contains("AccessDenied"));
+}
@@ -0,0 +1,50 @@
+use kvs::{KvStore, KvsEngine, Result, ShadowEngine, ShadowReport};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Waits until `count` operations were mirrored.
+fn mirrored<P: KvsEngine, S: KvsEngine>(engine: &ShadowEngine<P, S>, count: u64) -> ShadowReport {
+    let start = Instant::now();
+    loop {
+        let report = engine.report();
+        if report.mirrored >= count || start.elapsed() > Duration::from_secs(10) {
+            return report;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// Should serve from the primary engine, mirror to the shadow one and report divergences
+#[test]
+fn shadow_engine() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shadow_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shadow = KvStore::open(shadow_dir.path())?;
+    let engine = ShadowEngine::new(KvStore::open(primary_dir.path())?, shadow.clone());
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key2".to_owned())?;
+    assert!(engine.remove("key2".to_owned()).is_err());
+    assert!(!engine.set_xx("key2".to_owned(), "value2".to_owned())?);
+
+    let report = mirrored(&engine, 6);
+    assert_eq!(report.mirrored, 6);
+    assert_eq!(report.mismatched, 0);
+    assert_eq!(report.dropped, 0);
+    assert_eq!(shadow.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(shadow.get("key2".to_owned())?, None);
+    let ops: Vec<&str> = report.shadow.iter().map(|op| op.op.as_str()).collect();
+    assert_eq!(ops, ["get", "remove", "set", "set_xx"]);
+    assert_eq!(report.primary.len(), 4);
+
+    // A write the primary engine did not see makes the engines diverge.
+    shadow.set("key1".to_owned(), "changed".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    let report = mirrored(&engine, 7);
+    assert_eq!(report.mirrored, 7);
+    assert_eq!(report.mismatched, 1);
+    Ok(())
+}
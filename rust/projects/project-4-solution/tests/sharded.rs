@@ -0,0 +1,72 @@
+use kvs::{KvStore, KvsEngine, KvsError, Result, ShardedKvStore, WriteBatch};
+use std::thread;
+use tempfile::TempDir;
+
+// Should spread the keys over the shards, write to them concurrently and
+// reopen with the same number of shards only
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    store.set(format!("key{}-{}", thread_id, key_id), "value".to_owned())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let lens: Vec<usize> = store.shards().iter().map(KvStore::len).collect();
+    assert_eq!(lens.iter().sum::<usize>(), 400);
+    assert!(lens.iter().all(|&len| len > 0));
+
+    // A batch is split by shard.
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key0-0".to_owned(), "batched".to_owned())
+        .set("key1-0".to_owned(), "batched".to_owned())
+        .remove("key2-0".to_owned());
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key0-0".to_owned())?, Some("batched".to_owned()));
+    assert_eq!(store.get("key1-0".to_owned())?, Some("batched".to_owned()));
+    assert_eq!(store.get("key2-0".to_owned())?, None);
+    store.remove("key3-0".to_owned())?;
+    assert!(matches!(
+        store.remove("key3-0".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    // Transactions must stay within a shard.
+    let a = "key0-1".to_owned();
+    let b = (2..)
+        .map(|key_id| format!("key0-{}", key_id))
+        .find(|key| store.shard_of(key) != store.shard_of(&a))
+        .unwrap();
+    let mut tx = store.begin();
+    tx.get(a.clone())?;
+    tx.set(b, "tx".to_owned());
+    assert!(tx.commit().is_err());
+    let mut tx = store.begin();
+    tx.get(a.clone())?;
+    tx.set(a.clone(), "tx".to_owned());
+    tx.commit()?;
+    assert_eq!(store.get(a)?, Some("tx".to_owned()));
+
+    drop(store);
+    assert!(ShardedKvStore::open(temp_dir.path(), 2).is_err());
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::WrongEngine { .. })
+    ));
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key1-99".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key1-0".to_owned())?, Some("batched".to_owned()));
+    Ok(())
+}
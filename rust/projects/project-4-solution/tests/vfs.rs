@@ -0,0 +1,797 @@
+use kvs::clock::{Clock, ManualClock};
+use kvs::vfs::{MemFs, Vfs};
+use kvs::{
+    AsOf, CompactionEvent, Durability, ErrorKind, KvStore, KvsEngine, KvsError, Result,
+    SegmentArchive, WriteBatch,
+};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+fn open(fs: &MemFs, clock: &ManualClock) -> Result<KvStore> {
+    KvStore::open_with_vfs("/db", Arc::new(fs.clone()), Arc::new(clock.clone()))
+}
+
+fn open_at(fs: &MemFs, clock: &ManualClock, dir: &str) -> Result<KvStore> {
+    KvStore::open_with_vfs(dir, Arc::new(fs.clone()), Arc::new(clock.clone()))
+}
+
+// Should persist data across reopens of the same in-memory file system
+#[test]
+fn mem_fs_reopen() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+
+    let store = open(&fs, &clock)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let store = open(&fs, &clock)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!fs.list_files(Path::new("/db"))?.is_empty());
+
+    Ok(())
+}
+
+// Should compact on the in-memory file system and time it with the given clock
+#[test]
+fn mem_fs_compaction() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+        let events = Arc::clone(&events);
+        store.on_compaction(move |event| events.lock().unwrap().push(event.clone()));
+    }
+
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        clock.advance(Duration::from_secs(1));
+    }
+
+    let events = events.lock().unwrap();
+    let finished = events
+        .iter()
+        .filter_map(|event| match event {
+            CompactionEvent::Finished { duration, .. } => Some(*duration),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(!finished.is_empty(), "no compaction finished");
+    // The manual clock does not move during a compaction.
+    assert!(finished
+        .iter()
+        .all(|duration| *duration == Duration::from_secs(0)));
+    // Only the compacted log and the active log remain, next to the hint file
+    // of the compacted log, the manifest and the `ENGINE` file.
+    let mut files = fs.list_files(Path::new("/db"))?;
+    files.sort();
+    assert_eq!(files.len(), 5);
+    assert_eq!(files[0].with_extension("log"), files[1]);
+    assert_eq!(files[3], Path::new("/db/ENGINE"));
+    assert_eq!(files[4], Path::new("/db/manifest.json"));
+
+    drop(store);
+    let store = open(&fs, &clock)?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should drop unsynced data on a simulated power loss
+#[test]
+fn mem_fs_power_loss() -> Result<()> {
+    let fs = MemFs::new();
+    fs.create_dir_all(Path::new("/dir"))?;
+    let path = Path::new("/dir/file");
+
+    let mut file = fs.open_append(path)?;
+    file.write_all(b"synced")?;
+    file.sync_data()?;
+    file.write_all(b" lost")?;
+    drop(file);
+    assert_eq!(fs.file_len(path)?, 11);
+
+    fs.power_loss();
+
+    let mut content = String::new();
+    fs.open(path)?.read_to_string(&mut content)?;
+    assert_eq!(content, "synced");
+
+    Ok(())
+}
+
+// Should keep the writes acknowledged before a flush across a power loss
+#[test]
+fn flush_survives_power_loss() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+
+    let store = open(&fs, &clock)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    fs.power_loss();
+
+    let store = open(&fs, &clock)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should sync every write with `Durability::Always`
+#[test]
+fn durability_always() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+
+    let store = open(&fs, &clock)?;
+    store.set_durability(Durability::Always)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    // Lose power while the store is still open.
+    fs.power_loss();
+
+    let store = open(&fs, &clock)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should sync in the background and on close with `Durability::EveryNMillis`
+#[test]
+fn durability_every_n_millis() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+
+    let store = open(&fs, &clock)?;
+    store.set_durability(Durability::EveryNMillis(10))?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(200));
+    fs.power_loss();
+    drop(store);
+
+    let store = open(&fs, &clock)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // An interval too long to elapse during the test: the close syncs.
+    store.set_durability(Durability::EveryNMillis(60_000))?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    fs.power_loss();
+
+    let store = open(&fs, &clock)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Should expire keys given a TTL, across reopens and compactions
+#[test]
+fn expire() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+
+    assert!(!store.expire("key1".to_owned(), Duration::from_secs(10))?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.expire("key1".to_owned(), Duration::from_secs(10))?);
+    assert!(store.expire("key2".to_owned(), Duration::from_secs(10))?);
+    // A later expiry replaces the earlier one.
+    assert!(store.expire("key2".to_owned(), Duration::from_secs(30))?);
+    drop(store);
+
+    let store = open(&fs, &clock)?;
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!store.expire("key1".to_owned(), Duration::from_secs(10))?);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    // Expired keys can be set again, without a TTL.
+    assert!(store.set_nx("key1".to_owned(), "value1".to_owned())?);
+
+    // Compactions keep the expiry of the live keys.
+    for iter in 0..200 {
+        store.set("key3".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    let store = open(&fs, &clock)?;
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should set keys with a TTL in a single record, and purge them once expired
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.ttl("key1"), Some(Duration::from_secs(10)));
+    assert_eq!(store.ttl("key2"), None);
+    assert_eq!(store.ttl("key3"), None);
+    drop(store);
+
+    let store = open(&fs, &clock)?;
+    clock.advance(Duration::from_secs(4));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.ttl("key1"), Some(Duration::from_secs(6)));
+    clock.advance(Duration::from_secs(6));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.ttl("key1"), None);
+
+    // Compactions drop the expired keys from the log.
+    let finished = Arc::new(Mutex::new(false));
+    let finished_clone = Arc::clone(&finished);
+    store.on_compaction(move |event| {
+        if let CompactionEvent::Finished { .. } = event {
+            *finished_clone.lock().unwrap() = true;
+        }
+    });
+    let mut iter = 0;
+    while !*finished.lock().unwrap() {
+        store.set("key3".to_owned(), format!("{}", iter))?;
+        iter += 1;
+    }
+    for path in fs.list_files(Path::new("/db"))? {
+        let mut content = Vec::new();
+        fs.open(&path)?.read_to_end(&mut content)?;
+        assert!(!content.windows(4).any(|w| w == b"key1"));
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should make keys permanent again by removing their expiry
+#[test]
+fn persist() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+
+    assert!(!store.persist("key1".to_owned())?);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(!store.persist("key1".to_owned())?);
+    assert!(store.expire("key1".to_owned(), Duration::from_secs(10))?);
+    assert!(store.persist("key1".to_owned())?);
+    drop(store);
+
+    let store = open(&fs, &clock)?;
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Compactions drop the expiry folded into the "set" command too.
+    assert!(store.expire("key1".to_owned(), Duration::from_secs(10))?);
+    for iter in 0..200 {
+        store.set("key2".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.persist("key1".to_owned())?);
+    for iter in 0..200 {
+        store.set("key2".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    let store = open(&fs, &clock)?;
+    clock.advance(Duration::from_secs(20));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should reject writes while the disk is almost full, but still read and compact
+#[test]
+fn min_free_space() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+    store.pause_compaction();
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    fs.set_available_space(Some(1000));
+    store.set_min_free_space(4096);
+
+    let err = store
+        .set("key2".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::DiskFull);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+    let finished = Arc::new(Mutex::new(false));
+    let finished_clone = Arc::clone(&finished);
+    store.on_compaction(move |event| {
+        if let CompactionEvent::Finished { .. } = event {
+            *finished_clone.lock().unwrap() = true;
+        }
+    });
+    store.resume_compaction()?;
+    assert!(*finished.lock().unwrap());
+
+    // The free space is measured again after a while.
+    fs.set_available_space(Some(8192));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    clock.advance(Duration::from_secs(1));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should undelete removed values within the trash retention
+#[test]
+fn undelete() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(!store.undelete("key1".to_owned())?);
+
+    store.set_trash_retention(Some(Duration::from_secs(60)));
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.undelete("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    // The key exists again.
+    assert!(!store.undelete("key1".to_owned())?);
+
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key2".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    // The trash survives reopens and compactions.
+    let store = open(&fs, &clock)?;
+    store.set_trash_retention(Some(Duration::from_secs(60)));
+    for iter in 0..200 {
+        store.set("key3".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    let store = open(&fs, &clock)?;
+    store.set_trash_retention(Some(Duration::from_secs(60)));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("new".to_owned()));
+    assert!(!store.undelete("key2".to_owned())?);
+    assert!(store.undelete("key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Values are gone after the retention.
+    store.remove("key1".to_owned())?;
+    clock.advance(Duration::from_secs(61));
+    assert!(!store.undelete("key1".to_owned())?);
+
+    Ok(())
+}
+
+// Should compact the log once a tombstone is older than the maximum age
+#[test]
+fn max_tombstone_age() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+    let compactions = Arc::new(Mutex::new(0));
+    let compactions_clone = Arc::clone(&compactions);
+    store.on_compaction(move |event| {
+        if let CompactionEvent::Finished { .. } = event {
+            *compactions_clone.lock().unwrap() += 1;
+        }
+    });
+    store.set_max_tombstone_age(Some(Duration::from_secs(10)))?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(*compactions.lock().unwrap(), 0);
+
+    clock.advance(Duration::from_secs(10));
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(*compactions.lock().unwrap(), 1);
+    for path in fs.list_files(Path::new("/db"))? {
+        let mut content = Vec::new();
+        fs.open(&path)?.read_to_end(&mut content)?;
+        assert!(!content.windows(4).any(|w| w == b"key1"));
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Nothing is left to purge.
+    clock.advance(Duration::from_secs(10));
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(*compactions.lock().unwrap(), 1);
+
+    Ok(())
+}
+
+// Should compact the log once the expired entries add up to the threshold
+#[test]
+fn expiry_sweep() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = KvStore::builder()
+        .vfs(Arc::new(fs.clone()))
+        .clock(Arc::new(clock.clone()))
+        .compaction_threshold(2000)
+        .expiry_sweep_interval(Duration::from_millis(10))
+        .open("/db")?;
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("expiring{}", key_id),
+            "v".repeat(100),
+            Duration::from_secs(60),
+        )?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.compaction_stats().compactions, 0);
+
+    // Expired but too small to compact.
+    clock.advance(Duration::from_secs(60));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.compaction_stats().compactions, 0);
+
+    for key_id in 10..20 {
+        store.set_with_ttl(
+            format!("expiring{}", key_id),
+            "v".repeat(100),
+            Duration::from_secs(1),
+        )?;
+    }
+    clock.advance(Duration::from_secs(1));
+    thread::sleep(Duration::from_millis(200));
+    let stats = store.compaction_stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.entries_expired, 20);
+    let last = stats.last.unwrap();
+    assert_eq!(last.entries_copied, 1);
+    assert!(last.bytes_expired > 2000);
+    assert!(last.bytes_reclaimed >= last.bytes_expired);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    // Nothing is left to purge.
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.compaction_stats().compactions, 1);
+    Ok(())
+}
+
+// Should open with the options set on the builder
+#[test]
+fn builder() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let open = |threshold| {
+        KvStore::builder()
+            .vfs(Arc::new(fs.clone()))
+            .clock(Arc::new(clock.clone()))
+            .compaction_threshold(threshold)
+            .trash_retention(Duration::from_secs(60))
+            .open("/db")
+    };
+    let count_compactions = |store: &KvStore| {
+        let compactions = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&compactions);
+        store.on_compaction(move |event| {
+            if let CompactionEvent::Finished { .. } = event {
+                *counter.lock().unwrap() += 1;
+            }
+        });
+        compactions
+    };
+
+    let store = open(1 << 20)?;
+    let compactions = count_compactions(&store);
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("value{}", iter))?;
+    }
+    assert_eq!(*compactions.lock().unwrap(), 0);
+    store.remove("key".to_owned())?;
+    assert!(store.undelete("key".to_owned())?);
+    drop(store);
+
+    // With the default threshold, the stale commands are compacted on the next write.
+    let store = open(1024)?;
+    let compactions = count_compactions(&store);
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(*compactions.lock().unwrap(), 1);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should compact in slices run after the writes, keeping the writes made
+// between them, also when the store is closed in the middle of a compaction
+#[test]
+fn compaction_slices() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let open = || {
+        KvStore::builder()
+            .vfs(Arc::new(fs.clone()))
+            .clock(Arc::new(clock.clone()))
+            .compaction_slice_keys(10)
+            .open("/db")
+    };
+    let store = open()?;
+    // The events of the compactions, with `None` for every write.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+        let events = Arc::clone(&events);
+        store.on_compaction(move |event| events.lock().unwrap().push(Some(event.clone())));
+    }
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+            events.lock().unwrap().push(None);
+        }
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    {
+        let events = events.lock().unwrap();
+        let started = events
+            .iter()
+            .position(|event| matches!(event, Some(CompactionEvent::Started { .. })))
+            .expect("no compaction started");
+        let finished = events[started..]
+            .iter()
+            .position(|event| matches!(event, Some(CompactionEvent::Finished { .. })))
+            .expect("no compaction finished");
+        let writes = events[started..started + finished]
+            .iter()
+            .filter(|event| event.is_none())
+            .count();
+        assert!(writes >= 9, "compacted within {} writes", writes);
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..100 {
+            let expected = if key_id < 10 {
+                None
+            } else {
+                Some("19".to_owned())
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // Close in the middle of a compaction.
+    let in_progress = || {
+        let events = events.lock().unwrap();
+        let last = events.iter().rev().flatten().next();
+        matches!(
+            last,
+            Some(CompactionEvent::Started { .. }) | Some(CompactionEvent::Progress { .. })
+        )
+    };
+    while !in_progress() {
+        store.set("other".to_owned(), "value".to_owned())?;
+    }
+    store.set("key10".to_owned(), "19".to_owned())?;
+    assert!(in_progress());
+    drop(store);
+    let store = open()?;
+    check(&store)?;
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should roll over to a new log file once the active one is full
+#[test]
+fn segment_size() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let open = || {
+        KvStore::builder()
+            .vfs(Arc::new(fs.clone()))
+            .clock(Arc::new(clock.clone()))
+            .compaction_threshold(1 << 20)
+            .segment_size(1024)
+            .open("/db")
+    };
+    let log_lens = || -> Result<Vec<u64>> {
+        let mut lens = Vec::new();
+        for path in fs.list_files(Path::new("/db"))? {
+            if path.extension().and_then(|ext| ext.to_str()) == Some("log") {
+                lens.push(fs.file_len(&path)?);
+            }
+        }
+        Ok(lens)
+    };
+
+    let store = open()?;
+    let value = "v".repeat(100);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let lens = log_lens()?;
+    assert!(lens.len() >= 10, "{} log files", lens.len());
+    // A file is only rolled over after the write reaching the limit.
+    assert!(lens.iter().all(|&len| len < 1024 + 200));
+
+    drop(store);
+    let store = open()?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+    Ok(())
+}
+
+// Should report the live keys, the log files and the disk usage
+#[test]
+fn store_stats() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = KvStore::builder()
+        .vfs(Arc::new(fs.clone()))
+        .clock(Arc::new(clock.clone()))
+        .compaction_threshold(1 << 20)
+        .segment_size(1024)
+        .open("/db")?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.segments, 1);
+    // Only the `ENGINE` file has content.
+    assert_eq!(stats.disk_bytes, "kvs\n".len() as u64);
+    assert_eq!(stats.uncompacted_bytes, 0);
+
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "v".repeat(100))?;
+    }
+    store.set("key0".to_owned(), "value".to_owned())?;
+    store.expire("key1".to_owned(), Duration::from_secs(1))?;
+    clock.advance(Duration::from_secs(2));
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 20);
+    assert_eq!(stats.live_keys, 19);
+    assert!(stats.segments > 1);
+    let mut disk_bytes = 0;
+    for path in fs.list_files(Path::new("/db"))? {
+        disk_bytes += fs.file_len(&path)?;
+    }
+    assert_eq!(stats.disk_bytes, disk_bytes);
+    assert!(stats.uncompacted_bytes > 100);
+
+    store.compact()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 19);
+    assert_eq!(stats.segments, 2);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    Ok(())
+}
+
+// Should move the stale log files of compactions to the archive rather than
+// deleting them
+#[test]
+fn segment_archive() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+    store.set_segment_archive(Some(SegmentArchive::Dir("/archive".into())));
+    store.set("key".to_owned(), "value1".to_owned())?;
+    store.set("key".to_owned(), "value2".to_owned())?;
+    let live = fs.list_files(Path::new("/db"))?;
+    store.compact()?;
+    let archived = fs.list_files(Path::new("/archive"))?;
+    assert_eq!(archived, vec![Path::new("/archive/1.log")]);
+    assert!(live.contains(&Path::new("/db/1.log").to_path_buf()));
+    assert!(fs.file_len(Path::new("/db/1.log")).is_err());
+
+    // A failed archive keeps the file, for the next compaction to archive.
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    {
+        let calls = Arc::clone(&calls);
+        store.set_segment_archive(Some(SegmentArchive::callback(move |gen, _path| {
+            let mut calls = calls.lock().unwrap();
+            calls.push(gen);
+            if calls.len() == 1 {
+                return Err(io::Error::other("archive down"));
+            }
+            Ok(())
+        })));
+    }
+    store.set("key".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    let first = calls.lock().unwrap().clone();
+    assert_eq!(first.len(), 2);
+    assert!(fs.file_len(&Path::new("/db").join(format!("{}.log", first[0])))? > 0);
+    assert!(fs
+        .file_len(&Path::new("/db").join(format!("{}.log", first[1])))
+        .is_err());
+
+    store.set("key".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    assert!(calls.lock().unwrap()[2..].contains(&first[0]));
+    assert!(fs
+        .file_len(&Path::new("/db").join(format!("{}.log", first[0])))
+        .is_err());
+    assert_eq!(store.get("key".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// Should recover the data set as it was at a past point from the archived and
+// the live log files
+#[test]
+fn recover_to() -> Result<()> {
+    let fs = MemFs::new();
+    let clock = ManualClock::default();
+    let store = open(&fs, &clock)?;
+    store.set_segment_archive(Some(SegmentArchive::Dir("/archive".into())));
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "1".to_owned())?;
+    store.append("a".to_owned(), "2".to_owned())?;
+    let first = store.last_seq();
+    clock.advance(Duration::from_secs(10));
+    let first_time = clock.now();
+    clock.advance(Duration::from_secs(10));
+
+    store.compact()?;
+    store.remove("b".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("c".to_owned(), "3".to_owned());
+    batch.set("d".to_owned(), "4".to_owned());
+    store.write_batch(batch)?;
+    store.compact()?;
+    store.set("a".to_owned(), "last".to_owned())?;
+
+    let recovered = |dir: &str| open_at(&fs, &clock, dir);
+    assert_eq!(store.recover_to("/first", AsOf::Seq(first))?, 2);
+    let first_store = recovered("/first")?;
+    assert_eq!(first_store.get("a".to_owned())?, Some("12".to_owned()));
+    assert_eq!(first_store.get("b".to_owned())?, Some("1".to_owned()));
+
+    assert_eq!(store.recover_to("/first_time", AsOf::Time(first_time))?, 2);
+    assert_eq!(
+        recovered("/first_time")?.get("a".to_owned())?,
+        Some("12".to_owned())
+    );
+
+    // A batch is recovered whole or not at all.
+    assert_eq!(store.recover_to("/mid_batch", AsOf::Seq(first + 2))?, 1);
+    assert_eq!(store.recover_to("/batch", AsOf::Seq(first + 3))?, 3);
+    let batch_store = recovered("/batch")?;
+    assert_eq!(batch_store.get("b".to_owned())?, None);
+    assert_eq!(batch_store.get("d".to_owned())?, Some("4".to_owned()));
+    assert_eq!(batch_store.get("a".to_owned())?, Some("12".to_owned()));
+
+    assert_eq!(store.recover_to("/now", AsOf::Seq(store.last_seq()))?, 3);
+    assert_eq!(
+        recovered("/now")?.get("a".to_owned())?,
+        Some("last".to_owned())
+    );
+    assert!(store.recover_to("/now", AsOf::Seq(first)).is_err());
+
+    // Without the archive, the writes before the last compaction are gone.
+    store.set_segment_archive(None);
+    assert!(matches!(
+        store.recover_to("/gone", AsOf::Seq(first)),
+        Err(KvsError::HistoryUnavailable)
+    ));
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use kvs::testing::Op;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::workload::{KeyDistribution, Workload};
+use kvs::{KvStore, KvsEngine, Result};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+fn key_counts(workload: &Workload) -> HashMap<String, u64> {
+    let mut counts = HashMap::new();
+    for op in workload.run_ops() {
+        let key = match op {
+            Op::Set { key, .. } | Op::Get { key } | Op::Remove { key } => key,
+        };
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    counts
+}
+
+// Should generate the same operations from the same seed
+#[test]
+fn deterministic() {
+    let workload = Workload::ycsb_a(100).operations(1000).seed(7);
+    assert!(workload.run_ops().eq(workload.clone().run_ops()));
+    assert!(!workload.run_ops().eq(workload.clone().seed(8).run_ops()));
+}
+
+// Should honor the read ratio and the value sizes
+#[test]
+fn read_ratio_and_value_size() {
+    let workload = Workload::new(100)
+        .operations(10_000)
+        .read_ratio(0.9)
+        .value_size(10..=20);
+    let mut reads = 0;
+    for op in workload.run_ops() {
+        match op {
+            Op::Get { .. } => reads += 1,
+            Op::Set { value, .. } => assert!(value.len() >= 10 && value.len() <= 20),
+            Op::Remove { .. } => panic!("workloads do not remove"),
+        }
+    }
+    assert!(reads > 8_500 && reads < 9_500, "{} reads", reads);
+}
+
+// Should access a few keys much more often with a zipfian distribution
+#[test]
+fn zipfian_is_skewed() {
+    let uniform = key_counts(&Workload::new(1000).operations(100_000));
+    let zipfian = key_counts(
+        &Workload::new(1000)
+            .operations(100_000)
+            .distribution(KeyDistribution::YCSB_ZIPFIAN),
+    );
+    let max = |counts: &HashMap<String, u64>| *counts.values().max().unwrap();
+    assert!(max(&uniform) < 200);
+    assert!(max(&zipfian) > 5_000);
+}
+
+// Should load every record and run against an engine
+#[test]
+fn load_and_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let workload = Workload::ycsb_b(100).operations(500);
+
+    workload.load(&store)?;
+    for i in 0..100 {
+        assert!(store.get(format!("user{}", i))?.is_some());
+    }
+    let report = workload.run(&store)?;
+    assert_eq!(report.operations, 500);
+    let count: u64 = report.latency.iter().map(|latency| latency.count).sum();
+    assert_eq!(count, 500);
+    Ok(())
+}
+
+// Should spread the operations over concurrent clients
+#[test]
+fn run_on_thread_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let workload = Workload::ycsb_a(100).operations(1000);
+
+    workload.load(&store)?;
+    let report = workload.run_on(&store, &pool, 4)?;
+    let count: u64 = report.latency.iter().map(|latency| latency.count).sum();
+    assert_eq!(count, 1000);
+    Ok(())
+}